@@ -1,7 +1,18 @@
 use core::{mem::size_of, slice};
 
+/// Checks whether `image` is a 64-bit little-endian x86_64 ELF file.
+pub fn validate(image: *const u8) -> bool {
+    let header = unsafe { &*(image as *const Header) };
+
+    header.magic == ELF_MAGIC
+        && header.bits == ELF_CLASS_64
+        && header.endian == ELF_LITTLE_ENDIAN
+        && header.machine_type == ELF_MACHINE_X86_64
+}
+
 /// Calculates the required buffer size for preparing the given ELF image.
 pub fn get_size(image: *const u8) -> usize {
+    assert!(validate(image), "Kernel image is not a 64-bit little-endian x86_64 ELF file");
     let header = unsafe { &*(image as *const Header) };
 
     let mut size = 0usize;
@@ -19,6 +30,51 @@ pub fn get_size(image: *const u8) -> usize {
     size
 }
 
+/// Returns the GNU build ID of the given ELF image of `image_size` bytes, if it contains one.
+/// 
+/// Note segments that do not lie within the image are ignored. The build ID is the descriptor of the first note named "GNU" of type `NT_GNU_BUILD_ID`
+/// in a `PT_NOTE` segment. Only 20 byte (SHA-1) build IDs are supported.
+pub fn get_build_id(image: *const u8, image_size: usize) -> Option<[u8; 20]> {
+    let header = unsafe { &*(image as *const Header) };
+
+    let ph_list = unsafe { slice::from_raw_parts(image.offset(header.ph_offset as isize) as *const SegmentHeader, header.ph_entry_count as usize) };
+    for s in ph_list.iter().filter(|s| s.seg_type == SEGTYPE_NOTE) {
+        let data_end = s.data_offset.checked_add(s.data_size);
+        if !matches!(data_end, Some(end) if end <= image_size as u64) {
+            continue;
+        }
+        let notes = unsafe { slice::from_raw_parts(image.offset(s.data_offset as isize), s.data_size as usize) };
+
+        // Every note consists of a header followed by the name and the descriptor, each padded to 4 bytes.
+        // Notes whose name or descriptor would reach past the segment end the search.
+        let mut offs = 0;
+        while offs + size_of::<NoteHeader>() <= notes.len() {
+            let note = unsafe { (notes.as_ptr().add(offs) as *const NoteHeader).read_unaligned() };
+            let name_start = offs + size_of::<NoteHeader>();
+            let desc_start = name_start + align4(note.name_size as usize);
+            let next = desc_start + align4(note.desc_size as usize);
+            if next > notes.len() {
+                break;
+            }
+
+            if note.note_type == NT_GNU_BUILD_ID && &notes[name_start..name_start + note.name_size as usize] == b"GNU\0" && note.desc_size == 20 {
+                let mut id = [0u8; 20];
+                id.copy_from_slice(&notes[desc_start..desc_start + 20]);
+                return Some(id);
+            }
+
+            offs = next;
+        }
+    }
+
+    None
+}
+
+/// Rounds `val` up to a multiple of 4.
+fn align4(val: usize) -> usize {
+    (val + 3) & !3
+}
+
 /// Compares two null-terminated strings
 #[cfg(debug_assertions)]
 unsafe fn strcmp(mut a: *const u8, mut b: *const u8) -> bool {
@@ -54,14 +110,30 @@ pub fn get_text_addr(image: *const u8, process: *const u8) -> u64 {
     0
 }
 
-/// Prepares a given `image` into the `dest` buffer by
+/// Checks that segment `seg` lies within the raw image of `image_size` bytes 
+/// and within the destination buffer of `dest_size` bytes.
+fn validate_segment(image_size: usize, dest_size: usize, seg: &SegmentHeader) -> bool {
+    let data_end = seg.data_offset.checked_add(seg.data_size);
+    let virt_end = seg.virt_addr.checked_add(seg.virt_size);
+
+    matches!(data_end, Some(end) if end <= image_size as u64)
+        && matches!(virt_end, Some(end) if end <= dest_size as u64)
+        && seg.data_size <= seg.virt_size
+}
+
+/// Prepares a given `image` of `image_size` bytes into the `dest` buffer of `dest_size` bytes by
 /// resolving relocations, expanding zero-padded segments, etc.
-pub fn prepare(image: *const u8, dest: *mut u8) -> u64 {
+pub fn prepare(image: *const u8, image_size: usize, dest: *mut u8, dest_size: usize) -> u64 {
+    assert!(validate(image), "Kernel image is not a 64-bit little-endian x86_64 ELF file");
     let header = unsafe { &*(image as *const Header) };
 
     let ph_list = unsafe { slice::from_raw_parts(image.offset(header.ph_offset as isize) as *const SegmentHeader, header.ph_entry_count as usize) };
-    for s in ph_list {
+    for (i, s) in ph_list.iter().enumerate() {
         if s.seg_type == SEGTYPE_LOAD {
+            assert!(validate_segment(image_size, dest_size, s), 
+                "Segment {} out of bounds: data_offset={:#X}, data_size={:#X}, virt_addr={:#X}, virt_size={:#X} (image size {:#X}, buffer size {:#X})",
+                i, s.data_offset, s.data_size, s.virt_addr, s.virt_size, image_size, dest_size);
+
             unsafe {
                 let src = image.offset(s.data_offset as isize);
                 let dst = dest.offset(s.virt_addr as isize);
@@ -72,6 +144,7 @@ pub fn prepare(image: *const u8, dest: *mut u8) -> u64 {
         } else if s.seg_type == SEGTYPE_DYNAMIC {
             let mut rela_addr = 0;
             let mut rela_count = 0;
+            let mut symtab_addr = 0;
 
             let mut dyn_entry = unsafe{dest.offset(s.virt_addr as isize) as *const DynamicEntry};
             loop {
@@ -84,6 +157,9 @@ pub fn prepare(image: *const u8, dest: *mut u8) -> u64 {
                     DE_TAG_RELASZ => {
                         rela_count = de.value / size_of::<RelA>() as u64;
                     }
+                    DE_TAG_SYMTAB => {
+                        symtab_addr = de.value;
+                    }
                     _ => {}
                 }
 
@@ -95,6 +171,7 @@ pub fn prepare(image: *const u8, dest: *mut u8) -> u64 {
                 let rela = unsafe{&*rela_entry};
 
                 let rel_type = rela.info as u32;
+                let sym_index = (rela.info >> 32) as u32;
                 let target = dest as u64 + rela.addr;
                 let addend = (rela.addend as u64).wrapping_add(dest as u64);
 
@@ -104,6 +181,18 @@ pub fn prepare(image: *const u8, dest: *mut u8) -> u64 {
                             *(target as *mut u64) = addend;
                         }
                     }
+                    R_64 => {
+                        let symbol = find_symbol_by_index(dest, symtab_addr, sym_index);
+                        unsafe {
+                            *(target as *mut u64) = symbol.wrapping_add(rela.addend as u64);
+                        }
+                    }
+                    R_GLOB_DAT => {
+                        let symbol = find_symbol_by_index(dest, symtab_addr, sym_index);
+                        unsafe {
+                            *(target as *mut u64) = symbol;
+                        }
+                    }
                     _ => panic!("Unsupported relocation ({}) while preparing kernel image", rel_type)
                 }
 
@@ -115,6 +204,19 @@ pub fn prepare(image: *const u8, dest: *mut u8) -> u64 {
     dest as u64 + header.entry_point
 }
 
+/// Returns the address of symbol `sym_index` in the dynamic symbol table at `symtab_addr` (as found in the `DT_SYMTAB` entry)
+/// of the prepared image `dest`.
+/// 
+/// Panics if the symbol is undefined, as there is nothing to link the kernel against.
+fn find_symbol_by_index(dest: *const u8, symtab_addr: u64, sym_index: u32) -> u64 {
+    assert!(symtab_addr != 0, "Relocation references a symbol, but the kernel image has no dynamic symbol table");
+
+    let symbol = unsafe { &*(dest.offset(symtab_addr as isize) as *const Symbol).offset(sym_index as isize) };
+    assert!(symbol.section_index != SHN_UNDEF, "Relocation references undefined symbol {}", sym_index);
+
+    dest as u64 + symbol.value
+}
+
 #[repr(C)]
 struct Header {
     magic: u32,
@@ -138,6 +240,12 @@ struct Header {
     name_string_table_index: u16,
 }
 
+/// "\x7FELF" read as a little-endian u32.
+const ELF_MAGIC: u32 = 0x464C_457F;
+const ELF_CLASS_64: u8 = 2;
+const ELF_LITTLE_ENDIAN: u8 = 1;
+const ELF_MACHINE_X86_64: u16 = 0x3E;
+
 #[repr(C)]
 struct SegmentHeader {
     seg_type: u32,
@@ -152,6 +260,16 @@ struct SegmentHeader {
 
 const SEGTYPE_LOAD: u32 = 1;
 const SEGTYPE_DYNAMIC: u32 = 2;
+const SEGTYPE_NOTE: u32 = 4;
+
+#[repr(C)]
+struct NoteHeader {
+    name_size: u32,
+    desc_size: u32,
+    note_type: u32,
+}
+
+const NT_GNU_BUILD_ID: u32 = 3;
 
 #[repr(C)]
 struct DynamicEntry {
@@ -161,6 +279,19 @@ struct DynamicEntry {
 
 const DE_TAG_RELA: i64 = 7;
 const DE_TAG_RELASZ: i64 = 8;
+const DE_TAG_SYMTAB: i64 = 6;
+
+#[repr(C)]
+struct Symbol {
+    name_offset: u32,
+    info: u8,
+    other: u8,
+    section_index: u16,
+    value: u64,
+    size: u64,
+}
+
+const SHN_UNDEF: u16 = 0;
 
 #[repr(C)]
 struct RelA {
@@ -169,6 +300,8 @@ struct RelA {
     addend: i64,
 }
 
+const R_64: u32 = 1;
+const R_GLOB_DAT: u32 = 6;
 const R_RELATIVE: u32 = 8;
 
 #[cfg(debug_assertions)]
@@ -16,13 +16,21 @@ pub struct FileData {
 /// # Notes
 /// `path` should use `\` as path separator
 pub fn read_file(system_table: &SystemTable<Boot>, path: &str) -> FileData {
+    try_read_file(system_table, path).expect("Failed to open file")
+}
+
+/// Reads a file from the given `path`, or returns `None` if the file cannot be opened.
+/// 
+/// # Notes
+/// `path` should use `\` as path separator
+pub fn try_read_file(system_table: &SystemTable<Boot>, path: &str) -> Option<FileData> {
     let mut volume;
     unsafe {
         let fs = &mut *super::FILESYSTEM;
         volume = fs.open_volume().expect("Failed to open FileSystem root").split().1;
     }
 
-    let mut file = volume.open(path, FileMode::Read, FileAttribute::empty()).expect("Failed to open file").split().1;
+    let mut file = volume.open(path, FileMode::Read, FileAttribute::empty()).ok()?.split().1;
 
     let size;
     {
@@ -40,8 +48,8 @@ pub fn read_file(system_table: &SystemTable<Boot>, path: &str) -> FileData {
         _ => panic!("Not a file")
     }
 
-    FileData {
+    Some(FileData {
         size,
         data: buffer,
-    }
+    })
 }
@@ -6,9 +6,9 @@
 #![feature(alloc_error_handler)]
 #![feature(asm)]
 
-use core::{panic::PanicInfo, slice, ptr::null_mut};
+use core::{panic::PanicInfo, slice};
 
-use uefi::{prelude::*, proto::{console::{gop::{GraphicsOutput, PixelFormat}, text::Output}, loaded_image::LoadedImage, media::fs::SimpleFileSystem}, table::boot::{AllocateType, MemoryType}};
+use uefi::{Guid, prelude::*, proto::{console::{gop::{GraphicsOutput, ModeInfo, PixelFormat}, text::{Key, Output}}, loaded_image::LoadedImage, media::fs::SimpleFileSystem}, table::{boot::{AllocateType, MemoryAttribute, MemoryDescriptor, MemoryType}, cfg}};
 use core::fmt::Write;
 
 mod allocator;
@@ -16,8 +16,23 @@ mod io;
 mod elf;
 mod paging;
 mod platform;
-
-use common_structures::{Format, KernelHeader, MemorySegment, MemorySegmentState, config};
+mod variables;
+
+use common_structures::{Format, KERNEL_HEADER_VERSION, KernelHeader, MemorySegment, MemorySegmentState, config};
+
+/// Time in seconds the user has to press a key to enter the boot menu.
+const BOOT_MENU_TIMEOUT: usize = 3;
+/// Interval in microseconds in which the keyboard is polled.
+const KEY_POLL_INTERVAL: usize = 10_000;
+/// Argument put in front of the kernel command line by [`BootOption::Verbose`].
+const VERBOSE_CMDLINE_ARG: &[u8] = b"loglevel=verbose";
+
+/// The options of the boot menu.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BootOption {
+    Normal,
+    Verbose,
+}
 
 /// Used by the [panic_handler()] to print error messages
 static mut STDOUT: *mut Output = core::ptr::null_mut();
@@ -54,6 +69,7 @@ extern "efiapi" fn efi_main(img_handle: Handle, system_table: SystemTable<Boot>)
 
     // Allocate storage for the KernelHeader that will be passed to the kernel entry point
     let mut kernel_header = allocator::allocate_object::<KernelHeader>(&system_table, MemoryType::LOADER_DATA);
+    kernel_header.version = KERNEL_HEADER_VERSION;
 
     // select best video mode and enable it
     write!(system_table.stdout(), "Switching video mode...\r\n").unwrap();
@@ -66,7 +82,7 @@ extern "efiapi" fn efi_main(img_handle: Handle, system_table: SystemTable<Boot>)
             let info = m.info();
 
             // restrict to width of 1920, else VMs tend to give huge resolutions
-            if info.resolution().0 <= 1920 && info.resolution().0 > res_best_x && (info.pixel_format() == PixelFormat::Bgr || info.pixel_format() == PixelFormat::Rgb) {
+            if info.resolution().0 <= 1920 && info.resolution().0 > res_best_x && screen_format(info).is_some() {
                 res_best_x = info.resolution().0;
                 res_best_mode = Some(m);
             }
@@ -79,11 +95,7 @@ extern "efiapi" fn efi_main(img_handle: Handle, system_table: SystemTable<Boot>)
         kernel_header.screen_height = m.info().resolution().1 as u32;
         kernel_header.screen_scanline_width = m.info().stride() as u32;
         kernel_header.screen_buffer = gfx.frame_buffer().as_mut_ptr();
-        kernel_header.screen_format = match m.info().pixel_format() {
-            PixelFormat::Rgb => Format::RGB,
-            PixelFormat::Bgr => Format::BGR,
-            _ => Format::RGB,
-        };
+        kernel_header.screen_format = screen_format(m.info()).unwrap();
     }
 
     write!(system_table.stdout(), "Initializing Paging...\r\n").unwrap();
@@ -92,28 +104,69 @@ extern "efiapi" fn efi_main(img_handle: Handle, system_table: SystemTable<Boot>)
     // Since we want the kernel to be located in the higher memory half, but the UEFI page table
     // will contain only an identity mapping (virtual address == physical address), we have to clone this mapping to the higher memory half.
     paging::init(&system_table, &mut kernel_header.paging_info);
+    let high_memory_base = kernel_header.paging_info.high_memory_base();
 
     // convert kernel_header address to the corresponding higher memory half address,
     // so that the kernel can use the header.
-    kernel_header = unsafe{&mut *paging::ptr_to_kernelspace(kernel_header)};
-    kernel_header.screen_buffer = paging::ptr_to_kernelspace(kernel_header.screen_buffer);
+    kernel_header = unsafe{&mut *paging::ptr_to_kernelspace(high_memory_base, kernel_header)};
+    kernel_header.screen_buffer = paging::ptr_to_kernelspace(high_memory_base, kernel_header.screen_buffer);
+
+    write!(system_table.stdout(), "High memory starting at {:#016X}\r\n", high_memory_base).unwrap();
+
+    // The ACPI 2.0 RSDP is listed in the UEFI configuration table.
+    kernel_header.acpi_rsdp = find_config_table(&system_table, cfg::ACPI2_GUID).unwrap_or(0);
+    write!(system_table.stdout(), "ACPI RSDP at {:#016X}\r\n", kernel_header.acpi_rsdp).unwrap();
+
+    // Prefer the SMBIOS 3.0 entry point, as it can describe tables above 4GB.
+    kernel_header.smbios3_ptr = find_config_table(&system_table, cfg::SMBIOS3_GUID).unwrap_or(0);
+    kernel_header.smbios2_ptr = if kernel_header.smbios3_ptr == 0 {
+        find_config_table(&system_table, cfg::SMBIOS_GUID).unwrap_or(0)
+    } else {
+        0
+    };
+
+    // The kernel command line is stored in a UEFI variable, so it can be changed without rebuilding the boot image.
+    kernel_header.cmdline_len = variables::read_variable(&system_table, "SimpleOS-CmdLine", &mut kernel_header.cmdline_buf).unwrap_or(0) as u32;
+    if let Ok(cmdline) = core::str::from_utf8(&kernel_header.cmdline_buf[..kernel_header.cmdline_len as usize]) {
+        write!(system_table.stdout(), "Kernel command line: {}\r\n", cmdline).unwrap();
+    }
 
-    write!(system_table.stdout(), "High memory starting at {:#016X}\r\n", paging::ptr_to_kernelspace(null_mut::<u8>()) as u64).unwrap();
+    if boot_menu(&system_table) == BootOption::Verbose {
+        // The kernel uses the first loglevel argument, so this overrides the one stored in the variable.
+        let len = kernel_header.cmdline_len as usize;
+        let total_len = (VERBOSE_CMDLINE_ARG.len() + 1 + len).min(kernel_header.cmdline_buf.len());
+        kernel_header.cmdline_buf.copy_within(..total_len - VERBOSE_CMDLINE_ARG.len() - 1, VERBOSE_CMDLINE_ARG.len() + 1);
+        kernel_header.cmdline_buf[..VERBOSE_CMDLINE_ARG.len()].copy_from_slice(VERBOSE_CMDLINE_ARG);
+        kernel_header.cmdline_buf[VERBOSE_CMDLINE_ARG.len()] = b' ';
+        kernel_header.cmdline_len = total_len as u32;
+    }
 
     write!(system_table.stdout(), "Loading modules...\r\n").unwrap();
 
     // read the raw kernel ELF file from disk
     let kernel_image = io::read_file(&system_table, "EFI\\BOOT\\kernel.sys");
+    #[cfg(feature="secure-boot-hash")]
+    verify_kernel_hash(&system_table, &kernel_image);
     // find out how much virtual address space the kernel will take after being prepared
     let kernel_elf_size = elf::get_size(kernel_image.data);
 
     write!(system_table.stdout(), "Kernel size: {}\r\n", kernel_elf_size).unwrap();
+    if let Some(build_id) = elf::get_build_id(kernel_image.data, kernel_image.size as usize) {
+        write!(system_table.stdout(), "Kernel build ID: ").unwrap();
+        for b in build_id.iter() {
+            write!(system_table.stdout(), "{:02x}", b).unwrap();
+        }
+        write!(system_table.stdout(), "\r\n").unwrap();
+    }
     write!(system_table.stdout(), "Preparing kernel...\r\n").unwrap();
 
     // allocate memory for the prepared kernel image
-    let process_buffer = paging::ptr_to_kernelspace(allocator::allocate(&system_table, kernel_elf_size, MemoryType::LOADER_DATA));
+    let process_buffer_phys = allocator::allocate(&system_table, kernel_elf_size, MemoryType::LOADER_DATA);
+    // the kernel image contains code, so it must not be marked as No-Execute.
+    paging::allow_execute(process_buffer_phys as u64, kernel_elf_size as u64);
+    let process_buffer = paging::ptr_to_kernelspace(high_memory_base, process_buffer_phys);
     // prepare the kernel and retrieve the kernel entry point
-    let entry_point = elf::prepare(kernel_image.data, process_buffer);
+    let entry_point = elf::prepare(kernel_image.data, kernel_image.size as usize, process_buffer, kernel_elf_size);
 
     write!(system_table.stdout(), "Kernel at {:#016X} (entry point {:#016X})\r\n", process_buffer as u64, entry_point).unwrap();
 
@@ -132,8 +185,36 @@ extern "efiapi" fn efi_main(img_handle: Handle, system_table: SystemTable<Boot>)
     // free the raw kernel image as we only need the prepared image from now on
     allocator::free(&system_table, kernel_image.data, kernel_image.size as usize);
 
-    // allocate a stack for the kernel
-    let kernel_stack = allocator::allocate(&system_table, config::KERNEL_STACK_SIZE as usize, MemoryType::LOADER_DATA);
+    // The initial RAM disk is optional. It stays in LOADER_DATA memory, so the kernel will not reuse it.
+    match io::try_read_file(&system_table, "EFI\\BOOT\\initrd.img") {
+        Some(initrd) => {
+            kernel_header.initrd_base = paging::ptr_to_kernelspace(high_memory_base, initrd.data) as u64;
+            kernel_header.initrd_size = initrd.size;
+            write!(system_table.stdout(), "Initrd size: {}\r\n", initrd.size).unwrap();
+        }
+        None => {
+            kernel_header.initrd_base = 0;
+            kernel_header.initrd_size = 0;
+        }
+    }
+
+    // allocate a stack for the kernel.
+    // The page below the stack is reserved as well, so that the kernel can unmap it as a guard page.
+    let kernel_stack_buffer = allocator::allocate(&system_table, config::KERNEL_STACK_SIZE as usize + 4096, MemoryType::LOADER_DATA);
+    let kernel_stack = unsafe{kernel_stack_buffer.add(4096)};
+
+    // The runtime services stay available after exit_boot_services, check that they work while errors can still be printed.
+    match system_table.runtime_services().get_time() {
+        Ok(time) => {
+            let time = time.split().1;
+            write!(system_table.stdout(), "Firmware time: {:04}-{:02}-{:02} {:02}:{:02}:{:02}\r\n", time.year(), time.month(), time.day(), time.hour(), time.minute(), time.second()).unwrap();
+            kernel_header.efi_runtime_services = system_table.runtime_services() as *const _ as u64;
+        }
+        Err(e) => {
+            write!(system_table.stdout(), "GetTime() failed with {:?}, runtime services disabled\r\n", e.status()).unwrap();
+            kernel_header.efi_runtime_services = 0;
+        }
+    }
 
     write!(system_table.stdout(), "Starting kernel...\r\n").unwrap();
 
@@ -141,15 +222,25 @@ extern "efiapi" fn efi_main(img_handle: Handle, system_table: SystemTable<Boot>)
     // Add one page for safety, as the allocation of the memory map buffer might
     // grow the memory map, resulting in more space being needed to retrieve the memory map.
     let mmap_pages = (system_table.boot_services().memory_map_size() + 4095) / 4096 + 1;
-    // Allocate buffer for retrieving the memory map (reserve twice the required size, 
-    // we will need the second buffer for converting to kernel_header format).
-    let mmap_buffer = system_table.boot_services().allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, mmap_pages * 2).expect("Failed to allocate mmap buffer").split().1 as *mut u8;
+    // Allocate buffer for retrieving the memory map (reserve three times the required size, 
+    // we will need the second buffer for converting to kernel_header format and the third one for the runtime services map).
+    let mmap_buffer = system_table.boot_services().allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, mmap_pages * 3).expect("Failed to allocate mmap buffer").split().1 as *mut u8;
     // ensure that the buffer allocation didn't grow the memory map too much (should never happen)
     let mmap_pages_2 = (system_table.boot_services().memory_map_size() + 4095) / 4096;
     if mmap_pages_2 > mmap_pages {
         panic!("Memory Map unexpectedly grew too much");
     }
 
+    // Check a snapshot of the memory map while error messages can still be printed.
+    // The second half of mmap_buffer is used as scratch space, just like for the final conversion below.
+    {
+        let (_mmap_key, uefi_memory_map) = system_table.boot_services().memory_map(unsafe{slice::from_raw_parts_mut(mmap_buffer, mmap_pages * 4096)}).expect("Failed to retrieve memory map").split().1;
+        let memory_map = unsafe{slice::from_raw_parts_mut(mmap_buffer.offset(mmap_pages as isize * 4096) as *mut MemorySegment, uefi_memory_map.len())};
+        convert_memory_map(uefi_memory_map, memory_map);
+        check_memory_map_consistency(memory_map);
+        write!(system_table.stdout(), "Memory map OK, {} entries\r\n", memory_map.len()).unwrap();
+    }
+
     // This call signals to the UEFI firmware that we are finished booting up.
     // exit_boot_services makes the UEFI boot services unavailable, so e.g. memory allocations have to be handled manually.
     // It also stops the so called WatchDog timer, which is around 5 minutes. When this timer runs out before exit_boot_services is called,
@@ -160,6 +251,113 @@ extern "efiapi" fn efi_main(img_handle: Handle, system_table: SystemTable<Boot>)
     let memory_map_entries = uefi_memory_map.len();
     let memory_map = unsafe{slice::from_raw_parts_mut(mmap_buffer.offset(mmap_pages as isize * 4096) as *mut MemorySegment, memory_map_entries)};
 
+    let runtime_map = unsafe{slice::from_raw_parts_mut(mmap_buffer.offset(mmap_pages as isize * 2 * 4096) as *mut MemorySegment, memory_map_entries)};
+    let runtime_map_entries = convert_runtime_map(uefi_memory_map.clone(), runtime_map);
+
+    convert_memory_map(uefi_memory_map, memory_map);
+
+    kernel_header.efi_runtime_map = paging::ptr_to_kernelspace(high_memory_base, runtime_map.as_mut_ptr());
+    kernel_header.efi_runtime_map_count = runtime_map_entries as u32;
+    kernel_header.memory_map = paging::ptr_to_kernelspace(high_memory_base, memory_map.as_mut_ptr());
+    kernel_header.memory_map_entries = memory_map_entries as u64;
+    kernel_header.high_memory_base = high_memory_base;
+    kernel_header.kernel_stack_base = paging::ptr_to_kernelspace(high_memory_base, kernel_stack) as u64;
+
+    // Jump to the kernel
+    platform::goto_entrypoint(kernel_header, entry_point, paging::ptr_to_kernelspace(high_memory_base, kernel_stack));
+}
+
+/// Gives the user [`BOOT_MENU_TIMEOUT`] seconds to press a key and shows the boot menu if they do.
+///
+/// Returns [`BootOption::Normal`] if no key was pressed.
+fn boot_menu(system_table: &SystemTable<Boot>) -> BootOption {
+    // Discard keys pressed while the firmware was still booting.
+    let _ = system_table.stdin().reset(false);
+
+    write!(system_table.stdout(), "Press any key within {} seconds to enter boot menu...\r\n", BOOT_MENU_TIMEOUT).unwrap();
+    let polls = BOOT_MENU_TIMEOUT * 1_000_000 / KEY_POLL_INTERVAL;
+    if !(0..polls).any(|_| read_key(system_table).is_some()) {
+        return BootOption::Normal;
+    }
+
+    write!(system_table.stdout(), "\r\nBoot menu:\r\n").unwrap();
+    write!(system_table.stdout(), "  1) Boot normally\r\n").unwrap();
+    write!(system_table.stdout(), "  2) Boot with verbose logging\r\n").unwrap();
+    loop {
+        match read_key(system_table) {
+            Some(Key::Printable(c)) if char::from(c) == '1' || char::from(c) == '\r' => {
+                write!(system_table.stdout(), "Booting normally\r\n").unwrap();
+                return BootOption::Normal;
+            }
+            Some(Key::Printable(c)) if char::from(c) == '2' => {
+                write!(system_table.stdout(), "Booting with verbose logging\r\n").unwrap();
+                return BootOption::Verbose;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns the next key pressed within [`KEY_POLL_INTERVAL`] microseconds, if any.
+fn read_key(system_table: &SystemTable<Boot>) -> Option<Key> {
+    if let Ok(key) = system_table.stdin().read_key() {
+        if let Some(key) = key.split().1 {
+            return Some(key);
+        }
+    }
+    system_table.boot_services().stall(KEY_POLL_INTERVAL);
+    None
+}
+
+/// Compares the SHA-256 digest of `kernel_image` with the one in `EFI\\BOOT\\kernel.sha256` and halts if they differ.
+#[cfg(feature="secure-boot-hash")]
+fn verify_kernel_hash(system_table: &SystemTable<Boot>, kernel_image: &io::FileData) {
+    use common_structures::sha256;
+
+    let sidecar = io::try_read_file(system_table, "EFI\\BOOT\\kernel.sha256").expect("Kernel hash file EFI\\BOOT\\kernel.sha256 not found");
+    let expected = sha256::parse_hex_digest(unsafe{slice::from_raw_parts(sidecar.data, sidecar.size as usize)}).expect("Kernel hash file is invalid");
+    allocator::free(system_table, sidecar.data, sidecar.size as usize);
+
+    let actual = sha256::sha256(unsafe{slice::from_raw_parts(kernel_image.data, kernel_image.size as usize)});
+    if actual != expected {
+        panic!("Kernel image is corrupted, SHA-256 does not match EFI\\BOOT\\kernel.sha256");
+    }
+    write!(system_table.stdout(), "Kernel SHA-256 OK\r\n").unwrap();
+}
+
+/// Returns the [`Format`] of a video mode, or `None` if the kernel cannot draw to it.
+///
+/// Modes without a framebuffer (`BltOnly`) and bitmask modes other than 8 bits per color are not supported.
+fn screen_format(info: &ModeInfo) -> Option<Format> {
+    match info.pixel_format() {
+        PixelFormat::Rgb => Some(Format::RGB),
+        PixelFormat::Bgr => Some(Format::BGR),
+        PixelFormat::Bitmask => {
+            let mask = info.pixel_bitmask()?;
+            // The framebuffer is little endian, so the first byte of a pixel holds the lowest 8 bits.
+            match (mask.red, mask.green, mask.blue) {
+                (0x0000_00FF, 0x0000_FF00, 0x00FF_0000) => Some(Format::RGB),
+                (0x00FF_0000, 0x0000_FF00, 0x0000_00FF) => Some(Format::BGR),
+                // UEFI only knows reserved bits, which the video hardware ignores, so there is no alpha channel.
+                (0x0000_FF00, 0x00FF_0000, 0xFF00_0000) => Some(Format::XRGB),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Returns the physical address of the vendor table identified by `guid` in the UEFI configuration table, if present.
+fn find_config_table(system_table: &SystemTable<Boot>, guid: Guid) -> Option<u64> {
+    system_table.config_table().iter()
+        .find(|entry| entry.guid == guid)
+        .map(|entry| entry.address as u64)
+}
+
+/// Converts the UEFI memory map into the [`MemorySegment`] format passed to the kernel.
+/// 
+/// `memory_map` has to have exactly as many entries as `uefi_memory_map`.
+fn convert_memory_map<'a>(uefi_memory_map: impl Iterator<Item = &'a MemoryDescriptor>, memory_map: &mut [MemorySegment]) {
     for (i, entry) in uefi_memory_map.enumerate() {
         memory_map[i] = MemorySegment {
             start: entry.phys_start,
@@ -170,17 +368,46 @@ extern "efiapi" fn efi_main(img_handle: Handle, system_table: SystemTable<Boot>)
                 MemoryType::BOOT_SERVICES_DATA | 
                 MemoryType::CONVENTIONAL | 
                 MemoryType::LOADER_CODE => MemorySegmentState::Free,
+                MemoryType::MMIO |
+                MemoryType::MMIO_PORT_SPACE => MemorySegmentState::Mmio,
                 _ => MemorySegmentState::Occupied,
             },
         };
     }
+}
 
-    kernel_header.memory_map = paging::ptr_to_kernelspace(memory_map.as_mut_ptr());
-    kernel_header.memory_map_entries = memory_map_entries as u64;
-    kernel_header.high_memory_base = paging::ptr_to_kernelspace(null_mut::<u8>()) as u64;
+/// Copies the entries of `uefi_memory_map` used by the UEFI runtime services to `runtime_map` and returns their number.
+/// 
+/// `runtime_map` has to have at least as many entries as `uefi_memory_map`.
+fn convert_runtime_map<'a>(uefi_memory_map: impl Iterator<Item = &'a MemoryDescriptor>, runtime_map: &mut [MemorySegment]) -> usize {
+    let mut count = 0;
+    for entry in uefi_memory_map.filter(|entry| entry.att.contains(MemoryAttribute::RUNTIME)) {
+        runtime_map[count] = MemorySegment {
+            start: entry.phys_start,
+            page_count: entry.page_count,
+            state: MemorySegmentState::Occupied,
+        };
+        count += 1;
+    }
+    count
+}
 
-    // Jump to the kernel
-    platform::goto_entrypoint(kernel_header, entry_point, paging::ptr_to_kernelspace(kernel_stack));
+/// Sorts `memory_map` by start address and panics if it contains empty or overlapping entries.
+fn check_memory_map_consistency(memory_map: &mut [MemorySegment]) {
+    memory_map.sort_unstable_by_key(|entry| entry.start);
+
+    for entry in memory_map.iter() {
+        if entry.page_count == 0 {
+            panic!("Memory map contains an empty entry at {:#016X}", entry.start);
+        }
+    }
+
+    for pair in memory_map.windows(2) {
+        let (a, b) = (&pair[0], &pair[1]);
+        if a.end_addr() > b.start {
+            panic!("Memory map entries overlap: {:#016X} ({} pages) and {:#016X} ({} pages)", a.start, a.page_count, b.start, b.page_count);
+        }
+    }
 }
 
 /// Will be called by functions like panic!(), expect(), unwrap(), etc. when errors occur.
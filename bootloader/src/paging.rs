@@ -1,6 +1,6 @@
 use core::slice;
 
-use common_structures::PagingInfo;
+use common_structures::{PagingInfo, PagingLevel, config};
 use uefi::table::{Boot, SystemTable, boot::{AllocateType, MemoryType}};
 
 use core::fmt::Write;
@@ -17,6 +17,23 @@ mod platform {
     /// If this bit is set, writing to the given 
     /// page is allowed.
     const PML_RW: u64 = 0x2;
+    /// No-Execute bit of a page table entry.
+    /// If this bit is set, instruction fetches from the given
+    /// page fire a page fault. Only valid if EFER.NXE is set.
+    const PML_NX: u64 = 1 << 63;
+
+    /// Number of the Extended Feature Enable Register MSR.
+    const MSR_EFER: u32 = 0xC000_0080;
+    /// EFER.NXE: if set, the processor honors the No-Execute bit in page table entries.
+    const EFER_NXE: u64 = 1 << 11;
+
+    /// CR4.LA57: if set, the processor uses 5-level paging.
+    const CR4_LA57: u64 = 1 << 12;
+
+    /// Mask for the physical address field in a PML5 entry.
+    const PML5_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+    /// Our PML5 entries should be present and writable.
+    const PML5_ENTRY_BASE: u64 = PML_P | PML_RW;
 
     /// Mask for the physical address field in a PML4 entry.
     const PML4_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
@@ -32,22 +49,111 @@ mod platform {
     const PDE_ADDR_MASK: u64 = 0x000F_FFFF_FFE0_0000;
     /// Our Page Directory entries should be present and writable. 
     /// Bit 0x80 signals the processor that we use 2MB pages instead of 4KB pages.
-    const PDE_ENTRY_BASE: u64 = PML_P | PML_RW | 0x80;
+    const PDE_ENTRY_BASE: u64 = PML_P | PML_RW | PDE_HUGE;
+    /// Page Directory entry bit 7: the entry maps a 2MB page instead of pointing to a Page Table.
+    const PDE_HUGE: u64 = 0x80;
+    /// Page Directory entries pointing to a Page Table are present and writable,
+    /// the Page Table entries decide about the actual permissions.
+    const PDE_TABLE_BASE: u64 = PML_P | PML_RW;
+
+    /// Mask for the physical address field in a Page Table entry.
+    const PTE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+    /// Amount of physical memory that is mapped at most, which fills the lower half of the 48-bit address space.
+    const MAX_MAPPED_PHYSICAL_SIZE: u64 = 1 << 47;
+
+    /// The PML4 table, which is the root table with 4-level paging.
+    static mut PML4_TABLE: *mut u64 = core::ptr::null_mut();
+    /// Page Directory entries of the identity mapping, which is mirrored into the higher half.
+    static mut PD_TABLE: *mut u64 = core::ptr::null_mut();
+    static mut PD_ENTRIES: u64 = 0;
+    /// Physical address of the top level table, which will be loaded into CR3.
+    static mut ROOT_TABLE: u64 = 0;
+    /// Whether EFER.NXE was set, otherwise [`PML_NX`] must not be used.
+    static mut NX_ENABLED: bool = false;
+
+    /// Decides whether the initial page table uses 4 or 5 levels.
+    /// 
+    /// 5-level paging is used if it is requested in [`config::PAGING_LEVEL`] and supported by the CPU.
+    /// Since CR4.LA57 cannot be changed in long mode, the firmware must already have enabled it.
+    /// Conversely, if the firmware enabled it, we have no choice but to use it as well.
+    fn select_paging_level(system_table: &SystemTable<Boot>) -> PagingLevel {
+        let cr4: u64;
+        unsafe{asm!(
+            "mov {}, cr4",
+            out(reg) cr4,
+        )};
+        if cr4 & CR4_LA57 != 0 {
+            write!(system_table.stdout(), "Firmware uses 5-level paging\r\n").unwrap();
+            return PagingLevel::Pml5;
+        }
+
+        if config::PAGING_LEVEL == PagingLevel::Pml5 {
+            if !cpu_supports_la57() {
+                write!(system_table.stdout(), "5-level paging requested but not supported by the CPU\r\n").unwrap();
+            } else {
+                write!(system_table.stdout(), "5-level paging requested but not enabled by the firmware\r\n").unwrap();
+            }
+        }
+
+        PagingLevel::Pml4
+    }
+
+    /// Checks CPUID leaf 7, ECX bit 16 (LA57).
+    fn cpu_supports_la57() -> bool {
+        use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+        unsafe {
+            __cpuid(0).eax >= 7 && __cpuid_count(7, 0).ecx & (1 << 16) != 0
+        }
+    }
+
+    /// Checks CPUID leaf 0x80000001, EDX bit 20 (NX).
+    fn cpu_supports_nx() -> bool {
+        use core::arch::x86_64::__cpuid;
 
-    /// This variable will hold the first memory address in the higher memory half.
-    static mut HIGH_MEM_BASE: u64 = 0;
+        unsafe {
+            __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 20) != 0
+        }
+    }
+
+    /// Sets EFER.NXE if the CPU supports it. Returns whether the No-Execute bit can be used.
+    fn enable_nx() -> bool {
+        if !cpu_supports_nx() {
+            return false;
+        }
+
+        unsafe{asm!(
+            "rdmsr",
+            "or eax, {nxe:e}",
+            "wrmsr",
+            nxe=in(reg) EFER_NXE as u32,
+            in("ecx") MSR_EFER,
+            out("eax") _,
+            out("edx") _,
+        )};
+        true
+    }
 
     /// Initializes a page table that contains an identity mapping of physical memory
     /// in the lower memory half (0x0000000000000000 - 0x00007FFFFFFFFFFF) as well as the same mapping in the
     /// higher memory half (0xFFFFXXXXXXXXXXXX - 0xFFFFFFFFFFFFFFFF). 
+    /// 
+    /// If supported, every page is marked as not executable. Code regions have to be allowed via [`allow_execute()`]
+    /// before the page table is activated with [`activate()`].
     pub fn init(system_table: &SystemTable<Boot>, mut physical_size: u64, paging_info: &mut PagingInfo) {
         write!(system_table.stdout(), "Memory ranges from 0 to {:016X}\r\n", physical_size).unwrap();
+        assert!(physical_size <= 1 << config::MAX_PHYSICAL_MEMORY_BITS, "Physical memory exceeds MAX_PHYSICAL_MEMORY_BITS");
+
+        let paging_level = select_paging_level(system_table);
 
         /*
             The x86_64 page table is split up into multiple levels of tables.
             Each table entry points to 512 table entries of the next level.
             Since a table entry at every level is 8 bytes, every table takes up exactly one 4096 byte page.
             The structure is:
+                (Page Map Level 5, only with 5-level paging)
+                    V
                 Page Map Level 4
                     V
                 Page Directory Pointer Table
@@ -59,24 +165,39 @@ mod platform {
                 For more info see the AMD64 Architecture Programmer's Manual, Volume 2, Chapter 5 (especially 5.3).
         */
 
-        // Cut of bits 63-47 to ensure that physical memory only occupies half of virtual memory, which is 48 bits wide.
+        // Physical memory only occupies half of virtual memory, which is 48 bits wide.
         // On current x86_64 chips, physical memory can theoretically be 52 bits, which does not fit into virtual memory.
-        physical_size &= 0x0000_7FFF_FFFF_FFFF;
+        if physical_size > MAX_MAPPED_PHYSICAL_SIZE {
+            write!(system_table.stdout(), "Only the first {:#016X} bytes of physical memory are mapped\r\n", MAX_MAPPED_PHYSICAL_SIZE).unwrap();
+            physical_size = MAX_MAPPED_PHYSICAL_SIZE;
+        }
 
-        // Calculate how many page table entries of each type are needed.
-        let pml4_entries = (physical_size >> 39) + 1;
-        let pdp_entries = (physical_size >> 30) + 1;
-        let pd_entries = (physical_size >> 21) + 1;
+        // Calculate how many page table entries of each type are needed, rounded up to cover every byte.
+        let pml4_entries = (physical_size + (1 << 39) - 1) >> 39;
+        let pdp_entries = (physical_size + (1 << 30) - 1) >> 30;
+        let pd_entries = (physical_size + (1 << 21) - 1) >> 21;
 
         // Calculate how many memory pages are needed for every entry type.
-        let pml4_pages = (pml4_entries * 8 + 4095) / 4096;
+        // With 5-level paging, only entries 0 and 511 of the PML5 are used, which fit into one page.
+        let pml5_pages = if paging_level == PagingLevel::Pml5 { 1 } else { 0 };
+        let pml4_pages = 1;
         let pdp_pages = (pdp_entries * 8 + 4095) / 4096;
         let pd_pages = (pd_entries * 8 + 4095) / 4096;
-        let alloc_pages =  pml4_pages + pdp_pages + pd_pages;
+        let alloc_pages = pml5_pages + pml4_pages + pdp_pages + pd_pages;
 
-        // Since AMD64 spec currently only supports 48 bits of virtual address space, the PML4 table can
-        // only contain 512 entries / one memory page.
-        assert!(pml4_pages == 1, "PML4 larger than one page, should be impossible");
+        /*
+            Layout of the page buffer, every table is one page:
+                (PML5, only with 5-level paging)
+                PML4
+                PDP table 0 .. PDP table (pdp_pages - 1)
+                PD table 0 .. PD table (pd_pages - 1)
+
+            PML4 entry i points to PDP table i and PDP entry j (counted over every PDP table) points to PD table j,
+            so every PML4 entry needs exactly one PDP table and every PDP entry one PD table.
+            The PML4 has 512 entries, half of them hold the identity mapping and the other half the higher half mirror.
+        */
+        assert!(pml4_entries <= 256, "Physical memory does not fit into one half of the PML4");
+        assert!(pdp_pages == pml4_entries && pd_pages == pdp_entries, "Page table layout inconsistent");
 
         write!(system_table.stdout(), "pml4_entries={}, pdp_entries={}, pd_entries={}\r\n", pml4_entries, pdp_entries, pd_entries).unwrap();
         write!(system_table.stdout(), "Using {} physical pages for initial page table (pml4_pages={}, pdp_pages={}, pd_pages={})\r\n", alloc_pages, pml4_pages, pdp_pages, pd_pages).unwrap();
@@ -85,9 +206,30 @@ mod platform {
         let page_buffer_ptr = system_table.boot_services().allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, alloc_pages as usize).expect("Failed to allocate buffer for page table").split().1 as *mut u64;
         let page_buffer = unsafe{slice::from_raw_parts_mut(page_buffer_ptr, alloc_pages as usize * 4096)};
 
+        // Fill out the Page Map Level 5 (PML5) entries, if any.
+        // Entry 0 and 511 both point to the PML4 table, so the lower half identity mapping and the higher half mirror
+        // end up at the same addresses as with 4-level paging (bit 56 is sign extended).
+        if paging_level == PagingLevel::Pml5 {
+            let entry_addr = pml5_pages * 4096 + page_buffer_ptr as u64;
+            assert!((entry_addr & PML5_ADDR_MASK) == entry_addr, "PML5 Address field misaligned");
+
+            let entry = entry_addr | PML5_ENTRY_BASE;
+            page_buffer[0] = entry;
+            page_buffer[511] = entry;
+        }
+        // The remaining tables follow the PML5 table.
+        let page_buffer = &mut page_buffer[pml5_pages as usize * 512..];
+        let tables_ptr = page_buffer.as_mut_ptr();
+
+        // The mirror starts at a multiple of its size, see PagingInfo::high_memory_base().
+        // With a number of entries that is not a power of two, the last PML4 entries stay unused.
+        paging_info.pml4_entries = pml4_entries;
+        let high_memory_base = paging_info.high_memory_base();
+        let mirror_slot = (high_memory_base >> 39) & 0x1FF;
+
         // Fill out the Page Map Level 4 (PML4) entries.
         for pml4_entry in 0..pml4_entries {
-            let entry_addr = pml4_entry * 4096 + pml4_pages * 4096 + page_buffer_ptr as u64;
+            let entry_addr = pml4_entry * 4096 + pml4_pages * 4096 + tables_ptr as u64;
             assert!((entry_addr & PML4_ADDR_MASK) == entry_addr, "PML4 Address field misaligned");
 
             let entry = entry_addr | PML4_ENTRY_BASE;
@@ -96,55 +238,171 @@ mod platform {
             // without using double the storage for the page table,
             // we can just put the same PML4 entries into the higher half entries.
             page_buffer[pml4_entry as usize] = entry;
-            page_buffer[512 - pml4_entries as usize + pml4_entry as usize] = entry;
+            page_buffer[(mirror_slot + pml4_entry) as usize] = entry;
         }
 
         // Fill out the Page Directory Pointer Table (PDPT) entries.
         for pdp_entry in 0..pdp_entries {
-            let entry_addr = pdp_entry * 4096 + pml4_pages * 4096 + pdp_pages * 4096 + page_buffer_ptr as u64;
+            let entry_addr = pdp_entry * 4096 + pml4_pages * 4096 + pdp_pages * 4096 + tables_ptr as u64;
             assert!((entry_addr & PDPE_ADDR_MASK) == entry_addr, "PDP Address field misaligned");
 
             let entry = entry_addr | PDPE_ENTRY_BASE;
             page_buffer[pml4_pages as usize * 512 + pdp_entry as usize] = entry;
         }
 
+        // Data pages should never be executed. The NX bit is reserved (and fires a page fault) unless EFER.NXE is set.
+        let nx_enabled = enable_nx();
+        let pde_flags = if nx_enabled {
+            PDE_ENTRY_BASE | PML_NX
+        } else {
+            write!(system_table.stdout(), "CPU does not support the No-Execute bit\r\n").unwrap();
+            PDE_ENTRY_BASE
+        };
+
         // Fill out the Page Directory Table (PDT) entries.
         for pd_entry in 0..pd_entries {
             let entry_addr = pd_entry << 21;
             assert!((entry_addr & PDE_ADDR_MASK) == entry_addr, "PD Address field misaligned");
 
-            let entry = entry_addr | PDE_ENTRY_BASE;
+            let entry = entry_addr | pde_flags;
             page_buffer[pml4_pages as usize * 512 + pdp_pages as usize * 512 + pd_entry as usize] = entry;
         }
 
         unsafe {
-            HIGH_MEM_BASE = 0xFFFF_0000_0000_0000 | ((512 - pml4_entries) << 39);
-            write!(system_table.stdout(), "High memory start: {:#016X}\r\n", HIGH_MEM_BASE).unwrap();
+            PD_TABLE = page_buffer[pml4_pages as usize * 512 + pdp_pages as usize * 512..].as_mut_ptr();
+            PD_ENTRIES = pd_entries;
+            ROOT_TABLE = page_buffer_ptr as u64;
+            PML4_TABLE = tables_ptr;
+            NX_ENABLED = nx_enabled;
         }
 
-        paging_info.page_buffer = ptr_to_kernelspace(page_buffer_ptr);
+        paging_info.page_buffer = ptr_to_kernelspace(high_memory_base, page_buffer_ptr);
+        paging_info.page_buffer_size = alloc_pages;
         paging_info.pdp_pages = pdp_pages;
         paging_info.pd_pages = pd_pages;
-        paging_info.pml4_entries = pml4_entries;
+        paging_info.paging_levels = paging_level as u8;
+    }
+
+    /// Clears the No-Execute bit of every page overlapping `start..start + size` (physical addresses).
+    /// 
+    /// Unless split by [`map_4kb()`], pages are 2MB in size, so data next to the given region becomes executable as well.
+    pub fn allow_execute(start: u64, size: u64) {
+        if size == 0 {
+            return;
+        }
+
+        unsafe {
+            let first = start >> 21;
+            let end = (start + size - 1) >> 21;
+            let last = end.min(PD_ENTRIES - 1);
+            for pd_entry in first..=last {
+                let pde = PD_TABLE.offset(pd_entry as isize);
+                if *pde & PDE_HUGE != 0 {
+                    *pde &= !PML_NX;
+                    continue;
+                }
+
+                // Only the 4KB pages of a split 2MB page that overlap the region become executable.
+                let pt = (*pde & PTE_ADDR_MASK) as *mut u64;
+                let first_page = if pd_entry == first { (start >> 12) & 0x1FF } else { 0 };
+                let last_page = if pd_entry == end { ((start + size - 1) >> 12) & 0x1FF } else { 511 };
+                for page in first_page..=last_page {
+                    *pt.offset(page as isize) &= !PML_NX;
+                }
+            }
+        }
+    }
+
+    /// Maps the 4KB page at `virt` to `phys` with the Page Table entry flags `flags`.
+    /// 
+    /// The 2MB page containing `virt` is split into 4KB pages with the same mapping first, if necessary.
+    /// `virt` has to lie within the mapping created by [`init()`].
+    pub fn map_4kb(system_table: &SystemTable<Boot>, virt: u64, phys: u64, flags: u64) {
+        let flags = if unsafe{NX_ENABLED} {
+            flags
+        } else {
+            flags & !PML_NX
+        };
+
+        unsafe {
+            // Both halves use the same PML4 entries with 5-level paging, so the walk can always start at the PML4.
+            let pml4e = *PML4_TABLE.offset(table_index(virt, 39));
+            assert!(pml4e & PML_P != 0, "Address {:#016X} is not mapped", virt);
+            let pdpe = *((pml4e & PML4_ADDR_MASK) as *mut u64).offset(table_index(virt, 30));
+            assert!(pdpe & PML_P != 0, "Address {:#016X} is not mapped", virt);
+            let pde = ((pdpe & PDPE_ADDR_MASK) as *mut u64).offset(table_index(virt, 21));
+            assert!(*pde & PML_P != 0, "Address {:#016X} is not mapped", virt);
+
+            if *pde & PDE_HUGE != 0 {
+                split_2mb_page(system_table, pde);
+            }
+
+            let pt = (*pde & PTE_ADDR_MASK) as *mut u64;
+            *pt.offset(table_index(virt, 12)) = (phys & PTE_ADDR_MASK) | flags;
+        }
+    }
+
+    /// Replaces the 2MB page of the Page Directory entry `pde` with a new Page Table of 512 4KB pages
+    /// that map the same memory with the same flags.
+    /// 
+    /// The tables are identity mapped, so their physical addresses can be accessed directly.
+    unsafe fn split_2mb_page(system_table: &SystemTable<Boot>, pde: *mut u64) {
+        let pt = system_table.boot_services().allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, 1).expect("Failed to allocate Page Table").split().1 as *mut u64;
+        assert!((pt as u64 & PTE_ADDR_MASK) == pt as u64, "Page Table Address field misaligned");
+
+        let base = *pde & PDE_ADDR_MASK;
+        let flags = *pde & (PML_P | PML_RW | PML_NX);
+        for i in 0..512 {
+            *pt.offset(i as isize) = (base + i * 4096) | flags;
+        }
+        *pde = pt as u64 | PDE_TABLE_BASE;
+    }
 
-        // The CR3 register holds the physical address of the PML4 Table.
+    /// Returns the index into the table at the level whose entries cover `1 << shift` bytes.
+    fn table_index(virt: u64, shift: u32) -> isize {
+        ((virt >> shift) & 0x1FF) as isize
+    }
+
+    /// Loads the page table created by [`init()`].
+    pub fn activate() {
+        // The CR3 register holds the physical address of the PML4 Table (or PML5 Table with 5-level paging).
         // When written to, all TLB entries are invalidated automatically.
         unsafe{asm!(
             "mov cr3, {}",
-            in(reg) page_buffer_ptr
+            in(reg) ROOT_TABLE
         )};
     }
     
     /// Converts a pointer from the lower memory half to
-    /// the higher memory half (i.e. the "kernel memory space")
-    pub fn ptr_to_kernelspace<T>(ptr: *mut T) -> *mut T {
-        (ptr as u64 | unsafe{HIGH_MEM_BASE}) as *mut T
+    /// the higher memory half (i.e. the "kernel memory space"),
+    /// given the `high_memory_base` of [`PagingInfo::high_memory_base()`].
+    pub fn ptr_to_kernelspace<T>(high_memory_base: u64, ptr: *mut T) -> *mut T {
+        (ptr as u64 | high_memory_base) as *mut T
     }
 
 }
 
 pub use platform::ptr_to_kernelspace;
 
+/// Makes the physical memory region `start..start + size` executable.
+/// Has to be called for every region containing code that is loaded after [`init()`].
+pub fn allow_execute(start: u64, size: u64) {
+    platform::allow_execute(start, size);
+    // Reload the page table to get rid of stale TLB entries.
+    platform::activate();
+}
+
+/// Maps the 4KB page at `virt` to `phys`, where `flags` are the flags of the Page Table entry,
+/// e.g. to make a single page read-only or executable.
+/// 
+/// The higher half mirror uses the same tables as the identity mapping, so the page changes in both halves.
+#[allow(dead_code)]
+pub fn map_4kb(system_table: &SystemTable<Boot>, virt: u64, phys: u64, flags: u64) {
+    platform::map_4kb(system_table, virt, phys, flags);
+    // Reload the page table to get rid of the stale 2MB TLB entry.
+    platform::activate();
+}
+
 /// Initializes the platform dependent paging mechanism.
 /// See [`platform::init()`] for more info.
 pub fn init(system_table: &SystemTable<Boot>, paging_info: &mut PagingInfo) {
@@ -169,6 +427,17 @@ pub fn init(system_table: &SystemTable<Boot>, paging_info: &mut PagingInfo) {
     // call the platform dependent init function.
     platform::init(system_table, physical_size, paging_info);
 
+    // The bootloader and the firmware still have to be able to execute their code after switching to the new page table.
+    // The memory map has to be retrieved again, as allocating the page table changed it.
+    let (_mmap_key, mmap) = system_table.boot_services().memory_map(unsafe{slice::from_raw_parts_mut(mmap_buffer, mmap_pages * 4096)}).expect("Failed to retrieve memory map").split().1;
+    for e in mmap {
+        if e.ty == MemoryType::LOADER_CODE || e.ty == MemoryType::BOOT_SERVICES_CODE || e.ty == MemoryType::RUNTIME_SERVICES_CODE {
+            platform::allow_execute(e.phys_start, e.page_count * 4096);
+        }
+    }
+
+    platform::activate();
+
     // free the memory map buffer.
     let _ = system_table.boot_services().free_pages(mmap_buffer as u64, mmap_pages).expect("Failed to free memory map buffer");
 }
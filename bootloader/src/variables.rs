@@ -0,0 +1,44 @@
+use uefi::{Guid, Status, table::{Boot, SystemTable, runtime::RuntimeServices}};
+
+/// Vendor GUID of all UEFI variables read by SimpleOS.
+pub const SIMPLEOS_VENDOR_GUID: Guid = Guid::from_values(0x5e6f_2a1c, 0x8b3d, 0x4f7e, 0x9a01, [0x53, 0x69, 0x6d, 0x70, 0x4f, 0x53]);
+
+/// Signature of the GetVariable() runtime service as defined in the UEFI specification.
+type GetVariableFn = unsafe extern "efiapi" fn(name: *const u16, vendor: *const Guid, attributes: *mut u32, data_size: *mut usize, data: *mut u8) -> Status;
+
+/// Index of GetVariable() in the runtime services table, counted in pointer sized fields.
+///
+/// The table starts with the 24 byte table header, followed by
+/// GetTime, SetTime, GetWakeupTime, SetWakeupTime, SetVirtualAddressMap, ConvertPointer and GetVariable.
+const GET_VARIABLE_INDEX: usize = 9;
+
+/// Reads the UEFI variable `name` of the SimpleOS vendor into `buffer`.
+///
+/// Returns the size of the variable, or `None` if the variable does not exist or does not fit into `buffer`.
+///
+/// # Notes
+/// The uefi crate does not wrap GetVariable(), so the function is taken directly from the runtime services table.
+pub fn read_variable(system_table: &SystemTable<Boot>, name: &str, buffer: &mut [u8]) -> Option<usize> {
+    // UEFI variable names are null-terminated UCS-2 strings.
+    let mut name_buf = [0u16; 64];
+    assert!(name.len() < name_buf.len(), "UEFI variable name too long");
+    for (i, c) in name.chars().enumerate() {
+        name_buf[i] = c as u16;
+    }
+
+    let get_variable = unsafe {
+        let table = system_table.runtime_services() as *const RuntimeServices as *const GetVariableFn;
+        table.add(GET_VARIABLE_INDEX).read()
+    };
+
+    let mut size = buffer.len();
+    let status = unsafe {
+        get_variable(name_buf.as_ptr(), &SIMPLEOS_VENDOR_GUID, core::ptr::null_mut(), &mut size, buffer.as_mut_ptr())
+    };
+
+    if status == Status::SUCCESS {
+        Some(size)
+    } else {
+        None
+    }
+}
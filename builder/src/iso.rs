@@ -0,0 +1,192 @@
+//! Minimal ISO 9660 writer with an El Torito boot catalog for UEFI.
+//!
+//! UEFI firmware does not boot files of the ISO 9660 filesystem directly. The boot catalog points to
+//! a FAT image instead, which the firmware treats like an EFI system partition. The FAT partition
+//! built for the GPT image is reused for this, so both images contain the same bootloader and kernel.
+//!
+//! Layout of the image in 2048-byte sectors:
+//!
+//! | Sector | Contents                         |
+//! |--------|----------------------------------|
+//! | 0-15   | System area, unused              |
+//! | 16     | Primary Volume Descriptor        |
+//! | 17     | El Torito Boot Record            |
+//! | 18     | Volume Descriptor Set Terminator |
+//! | 19     | Boot catalog                     |
+//! | 20, 21 | Little and big endian path table |
+//! | 22     | Root directory                   |
+//! | 23-    | FAT image, as `EFI.IMG`          |
+
+use std::{fs, io::Write};
+
+const SECTOR_SIZE: usize = 2048;
+
+const PVD_SECTOR: u32 = 16;
+const BOOT_RECORD_SECTOR: u32 = 17;
+const TERMINATOR_SECTOR: u32 = 18;
+const CATALOG_SECTOR: u32 = 19;
+const L_PATH_TABLE_SECTOR: u32 = 20;
+const M_PATH_TABLE_SECTOR: u32 = 21;
+const ROOT_DIR_SECTOR: u32 = 22;
+const EFI_IMAGE_SECTOR: u32 = 23;
+
+/// Size of the only path table entry, the one of the root directory.
+const PATH_TABLE_SIZE: u32 = 10;
+/// Name of the FAT image in the root directory.
+const EFI_IMAGE_NAME: &[u8] = b"EFI.IMG;1";
+
+/// El Torito platform ID of UEFI.
+const PLATFORM_EFI: u8 = 0xEF;
+/// Boot catalog entry: bootable.
+const BOOT_INDICATOR_BOOTABLE: u8 = 0x88;
+
+/// Directory record flag: the entry is a directory.
+const FLAG_DIRECTORY: u8 = 0x02;
+/// Recording date of every directory record, 1970-01-01 00:00:00 UTC, so that the output is reproducible.
+const RECORDING_DATE: [u8; 7] = [70, 1, 1, 0, 0, 0, 0];
+
+/// Writes an ISO image to `iso_path` that boots the FAT image at `efi_image_path` on UEFI systems.
+pub fn build_iso(efi_image_path: &str, iso_path: &str, volume_id: &str) {
+    let efi_image = fs::read(efi_image_path).unwrap();
+    let efi_sectors = efi_image.len().div_ceil(SECTOR_SIZE) as u32;
+    let total_sectors = EFI_IMAGE_SECTOR + efi_sectors;
+
+    let mut iso = vec![0u8; total_sectors as usize * SECTOR_SIZE];
+    sector(&mut iso, PVD_SECTOR).copy_from_slice(&primary_volume_descriptor(total_sectors, volume_id));
+    sector(&mut iso, BOOT_RECORD_SECTOR).copy_from_slice(&boot_record());
+    sector(&mut iso, TERMINATOR_SECTOR)[..7].copy_from_slice(b"\xFFCD001\x01");
+    sector(&mut iso, CATALOG_SECTOR)[..64].copy_from_slice(&boot_catalog(efi_image.len()));
+    sector(&mut iso, L_PATH_TABLE_SECTOR)[..PATH_TABLE_SIZE as usize].copy_from_slice(&path_table_entry(false));
+    sector(&mut iso, M_PATH_TABLE_SECTOR)[..PATH_TABLE_SIZE as usize].copy_from_slice(&path_table_entry(true));
+
+    let mut root = Vec::new();
+    root.extend(dir_record(ROOT_DIR_SECTOR, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[0]));
+    root.extend(dir_record(ROOT_DIR_SECTOR, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[1]));
+    root.extend(dir_record(EFI_IMAGE_SECTOR, efi_image.len() as u32, 0, EFI_IMAGE_NAME));
+    sector(&mut iso, ROOT_DIR_SECTOR)[..root.len()].copy_from_slice(&root);
+
+    let efi_start = EFI_IMAGE_SECTOR as usize * SECTOR_SIZE;
+    iso[efi_start..efi_start + efi_image.len()].copy_from_slice(&efi_image);
+
+    let mut file = fs::File::create(iso_path).unwrap();
+    file.write_all(&iso).unwrap();
+}
+
+/// Returns the `index`th sector of `iso`.
+fn sector(iso: &mut [u8], index: u32) -> &mut [u8] {
+    let start = index as usize * SECTOR_SIZE;
+    &mut iso[start..start + SECTOR_SIZE]
+}
+
+fn primary_volume_descriptor(total_sectors: u32, volume_id: &str) -> Vec<u8> {
+    let mut pvd = vec![0u8; SECTOR_SIZE];
+    pvd[0] = 1;
+    pvd[1..6].copy_from_slice(b"CD001");
+    pvd[6] = 1;
+    padded(&mut pvd[8..40], b"");
+    // The volume ID may only contain upper case letters, digits and underscores.
+    let volume_id: Vec<u8> = volume_id.bytes()
+        .map(|b| if b.is_ascii_alphanumeric() { b.to_ascii_uppercase() } else { b'_' })
+        .collect();
+    padded(&mut pvd[40..72], &volume_id);
+    pvd[80..88].copy_from_slice(&both_endian_u32(total_sectors));
+    pvd[120..124].copy_from_slice(&both_endian_u16(1));
+    pvd[124..128].copy_from_slice(&both_endian_u16(1));
+    pvd[128..132].copy_from_slice(&both_endian_u16(SECTOR_SIZE as u16));
+    pvd[132..140].copy_from_slice(&both_endian_u32(PATH_TABLE_SIZE));
+    pvd[140..144].copy_from_slice(&L_PATH_TABLE_SECTOR.to_le_bytes());
+    pvd[148..152].copy_from_slice(&M_PATH_TABLE_SECTOR.to_be_bytes());
+    pvd[156..190].copy_from_slice(&dir_record(ROOT_DIR_SECTOR, SECTOR_SIZE as u32, FLAG_DIRECTORY, &[0]));
+    // Volume set, publisher, data preparer, application and the file identifiers.
+    padded(&mut pvd[190..813], b"");
+    // Creation, modification, expiration and effective date: not specified.
+    for date in pvd[813..881].chunks_mut(17) {
+        date[..16].copy_from_slice(b"0000000000000000");
+    }
+    // File structure version.
+    pvd[881] = 1;
+    pvd
+}
+
+fn boot_record() -> Vec<u8> {
+    let mut record = vec![0u8; SECTOR_SIZE];
+    record[1..6].copy_from_slice(b"CD001");
+    record[6] = 1;
+    record[7..30].copy_from_slice(b"EL TORITO SPECIFICATION");
+    record[71..75].copy_from_slice(&CATALOG_SECTOR.to_le_bytes());
+    record
+}
+
+/// Returns the validation entry and the default entry, which boots the FAT image of `efi_image_size` bytes.
+fn boot_catalog(efi_image_size: usize) -> [u8; 64] {
+    let mut catalog = [0u8; 64];
+
+    // Validation entry.
+    catalog[0] = 1;
+    catalog[1] = PLATFORM_EFI;
+    catalog[30] = 0x55;
+    catalog[31] = 0xAA;
+    // The 16-bit words of the validation entry have to add up to zero.
+    let sum = catalog[..32].chunks(2)
+        .fold(0u16, |sum, word| sum.wrapping_add(u16::from_le_bytes([word[0], word[1]])));
+    catalog[28..30].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+
+    // Default entry, no emulation. The size is given in 512-byte sectors, 0 means up to the end of the image.
+    let virtual_sectors = efi_image_size.div_ceil(512);
+    catalog[32] = BOOT_INDICATOR_BOOTABLE;
+    catalog[38..40].copy_from_slice(&(if virtual_sectors <= 0xFFFF { virtual_sectors as u16 } else { 0 }).to_le_bytes());
+    catalog[40..44].copy_from_slice(&EFI_IMAGE_SECTOR.to_le_bytes());
+
+    catalog
+}
+
+/// Returns the path table entry of the root directory, in big endian for the M path table.
+fn path_table_entry(big_endian: bool) -> [u8; PATH_TABLE_SIZE as usize] {
+    let mut entry = [0u8; PATH_TABLE_SIZE as usize];
+    entry[0] = 1;
+    if big_endian {
+        entry[2..6].copy_from_slice(&ROOT_DIR_SECTOR.to_be_bytes());
+        entry[6..8].copy_from_slice(&1u16.to_be_bytes());
+    } else {
+        entry[2..6].copy_from_slice(&ROOT_DIR_SECTOR.to_le_bytes());
+        entry[6..8].copy_from_slice(&1u16.to_le_bytes());
+    }
+    entry
+}
+
+/// Returns a directory record for the extent at `sector` with `size` bytes.
+fn dir_record(sector: u32, size: u32, flags: u8, name: &[u8]) -> Vec<u8> {
+    // Records have an even length.
+    let len = 33 + name.len() + (name.len() + 1) % 2;
+    let mut record = vec![0u8; len];
+    record[0] = len as u8;
+    record[2..10].copy_from_slice(&both_endian_u32(sector));
+    record[10..18].copy_from_slice(&both_endian_u32(size));
+    record[18..25].copy_from_slice(&RECORDING_DATE);
+    record[25] = flags;
+    record[28..32].copy_from_slice(&both_endian_u16(1));
+    record[32] = name.len() as u8;
+    record[33..33 + name.len()].copy_from_slice(name);
+    record
+}
+
+/// Copies `s` to `dest` and fills the rest with spaces.
+fn padded(dest: &mut [u8], s: &[u8]) {
+    let len = s.len().min(dest.len());
+    dest[..len].copy_from_slice(&s[..len]);
+    dest[len..].fill(b' ');
+}
+
+fn both_endian_u16(val: u16) -> [u8; 4] {
+    let mut bytes = [0u8; 4];
+    bytes[..2].copy_from_slice(&val.to_le_bytes());
+    bytes[2..].copy_from_slice(&val.to_be_bytes());
+    bytes
+}
+
+fn both_endian_u32(val: u32) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&val.to_le_bytes());
+    bytes[4..].copy_from_slice(&val.to_be_bytes());
+    bytes
+}
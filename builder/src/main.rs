@@ -1,38 +1,185 @@
-use std::{env, fs, io::{self, Seek}, process::{Command, exit}};
+mod iso;
+
+use common_structures::sha256;
+use std::{env, fs, io::{self, BufRead, Seek, Write}, path::Path, process::{Child, Command, Stdio, exit}, sync::mpsc, thread, time::{Duration, Instant}};
 
 const CARGO: &str = env!("CARGO");
 const ROOT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/..");
 
+/// Folders that contain OVMF_CODE.fd and OVMF_VARS.fd on common Linux distributions,
+/// the first one is the same default as in the Makefile.
+const OVMF_DIRS: &[&str] = &["/usr/share/OVMF", "/usr/share/edk2/ovmf", "/usr/share/edk2-ovmf/x64", "/usr/share/edk2/x64"];
+/// Port QEMU's gdb server listens on with `--run-debug`, the same as in debug-kernel.cmd.
+const GDB_PORT: u16 = 26000;
+/// Maximum time the kernel integration tests may take before QEMU is killed.
+const TEST_TIMEOUT: Duration = Duration::from_secs(120);
+/// Exit code of QEMU after the test runner reported success through the isa-debug-exit device.
+const QEMU_EXIT_SUCCESS: i32 = 1;
+
 fn print_usage() {
-    println!("Usage: cargo osbuild [--target=TARGET] [--release]");
+    println!("Usage: cargo osbuild [--target=TARGET] [--release] [--format=img|iso] [--secure-boot-hash] [--clippy [--deny-warnings]] [--test] [--run | --run-debug] [--qemu-args=ARGS] [--ovmf=DIR]");
+    println!();
+    println!("  --format=iso      also build a bootable ISO image next to the GPT image");
+    println!("  --secure-boot-hash let the bootloader verify the SHA-256 of the kernel before loading it");
+    println!("  --clippy          run clippy on every crate, exits with 1 if clippy fails");
+    println!("  --deny-warnings   let clippy fail on any warning");
+    println!("  --test            run the kernel integration tests in QEMU, --run-tests is an alias");
+    println!("  --run             boot the image in QEMU after building it");
+    println!("  --run-debug       like --run, but wait for gdb on port {}", GDB_PORT);
+    println!("  --qemu-args=ARGS  additional space separated arguments for QEMU");
+    println!("  --ovmf=DIR        folder containing OVMF_CODE.fd and OVMF_VARS.fd");
+}
+
+/// How QEMU is launched by `--run`, `--run-debug` and `--run-tests`.
+struct QemuOptions {
+    ovmf_dir: String,
+    extra_args: Vec<String>,
 }
 
 fn main() {
     let mut arch = "x86_64".to_owned();
     let mut release_mode = false;
+    let mut iso_format = false;
+    let mut secure_boot_hash = false;
     let mut clippy_mode = false;
+    let mut deny_warnings = false;
+    let mut test_mode = false;
+    let mut run_mode = false;
+    let mut debug_mode = false;
+    let mut ovmf_dir = None;
+    let mut extra_args = Vec::new();
 
     for arg in env::args() {
         if let Some(a) = arg.strip_prefix("--target=") {
             arch = a.to_owned();
         } else if arg == "--release" {
             release_mode = true;
+        } else if let Some(a) = arg.strip_prefix("--format=") {
+            match a {
+                "img" => iso_format = false,
+                "iso" => iso_format = true,
+                _ => {
+                    println!("Unknown format '{}', expected 'img' or 'iso'", a);
+                    exit(1);
+                }
+            }
+        } else if arg == "--secure-boot-hash" {
+            secure_boot_hash = true;
         } else if arg == "--clippy" {
-            clippy_mode = true;  
+            clippy_mode = true;
+        } else if arg == "--deny-warnings" {
+            deny_warnings = true;
+        } else if arg == "--test" || arg == "--run-tests" {
+            test_mode = true;
+        } else if arg == "--run" {
+            run_mode = true;
+        } else if arg == "--run-debug" {
+            run_mode = true;
+            debug_mode = true;
+        } else if let Some(a) = arg.strip_prefix("--qemu-args=") {
+            extra_args.extend(a.split_whitespace().map(str::to_owned));
+        } else if let Some(a) = arg.strip_prefix("--ovmf=") {
+            ovmf_dir = Some(a.to_owned());
         } else if arg == "--help" || arg == "-h" {
             print_usage();
             exit(0);
         }
     }
 
+    let qemu = QemuOptions {
+        ovmf_dir: ovmf_dir.unwrap_or_else(default_ovmf_dir),
+        extra_args,
+    };
+
+    let bootloader_features: &[&str] = if secure_boot_hash { &["secure-boot-hash"] } else { &[] };
+
     if clippy_mode {
-        run_clippy(arch);
+        run_clippy(arch, deny_warnings);
+    } else if test_mode {
+        let image_path = build(arch, release_mode, bootloader_features, &["integration-test"]);
+        run_tests(&image_path, &qemu);
+    } else if run_mode {
+        // CI scripts cannot see a panic in the serial output, so the kernel shuts down QEMU with an error instead.
+        let features: &[&str] = if env::var_os("CI").is_some() { &["qemu-exit"] } else { &[] };
+        let image_path = build(arch, release_mode, bootloader_features, features);
+        run(&image_path, &qemu, debug_mode);
     } else {
-        build(arch, release_mode);
+        let image_path = build(arch, release_mode, bootloader_features, &[]);
+        if iso_format {
+            build_iso(&image_path);
+        }
+    }
+}
+
+/// Builds `image.iso` next to the GPT image at `image_path`, from the same FAT partition.
+fn build_iso(image_path: &str) {
+    println!("-- Building ISO image");
+    let image_dir = Path::new(image_path).parent().unwrap();
+    let partition_path = image_dir.join("partition.img");
+    let iso_path = image_dir.join("image.iso");
+    iso::build_iso(partition_path.to_str().unwrap(), iso_path.to_str().unwrap(), "SimpleOS-rs");
+    println!("-- Finished {}", iso_path.display());
+}
+
+/// Returns the first of [`OVMF_DIRS`] that contains OVMF_CODE.fd, or the first one if none does.
+fn default_ovmf_dir() -> String {
+    OVMF_DIRS.iter()
+        .find(|dir| Path::new(dir).join("OVMF_CODE.fd").exists())
+        .unwrap_or(&OVMF_DIRS[0])
+        .to_string()
+}
+
+/// Returns a QEMU command that boots the given image with the firmware and extra arguments of `qemu`.
+fn qemu_command(image_path: &str, qemu: &QemuOptions) -> Command {
+    let mut command = Command::new("qemu-system-x86_64");
+    command
+        .arg("-m").arg("4096")
+        .arg("-machine").arg("q35")
+        .arg("-cpu").arg("qemu64")
+        .arg("-net").arg("none")
+        .arg("-drive").arg(format!("if=pflash,unit=0,format=raw,file={}/OVMF_CODE.fd,readonly=on", qemu.ovmf_dir))
+        .arg("-drive").arg(format!("if=pflash,unit=1,format=raw,file={}/OVMF_VARS.fd,readonly=on", qemu.ovmf_dir))
+        .arg("-drive").arg(format!("file={},if=ide", image_path));
+    command
+}
+
+/// Starts `command`, exiting with a helpful message if QEMU is not installed.
+fn spawn_qemu(command: &mut Command) -> Child {
+    match command.spawn() {
+        Ok(child) => child,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            println!("-- qemu-system-x86_64 was not found in PATH, install QEMU to use --run or --run-tests");
+            exit(1);
+        }
+        Err(e) => panic!("Failed to start qemu-system-x86_64: {}", e),
     }
 }
 
-fn run_clippy(arch: String) {
+/// Boots the given image in QEMU with the serial port on stdio, and exits with QEMU's exit code.
+///
+/// With `debug`, QEMU waits for gdb to connect before starting the firmware.
+/// A kernel built with the `qemu-exit` feature makes QEMU exit with 3 on panic.
+fn run(image_path: &str, qemu: &QemuOptions, debug: bool) {
+    println!("-- Running {}", image_path);
+
+    let mut command = qemu_command(image_path, qemu);
+    command
+        .arg("-serial").arg("stdio")
+        .arg("-device").arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
+    if debug {
+        println!("-- Waiting for gdb on port {}", GDB_PORT);
+        command.arg("-gdb").arg(format!("tcp::{}", GDB_PORT)).arg("-S");
+    }
+    command.args(&qemu.extra_args);
+
+    let status = spawn_qemu(&mut command).wait().unwrap();
+    exit(status.code().unwrap_or(1));
+}
+
+/// Runs clippy on every crate and exits with 1 if any run failed, e.g. because of a warning with `deny_warnings`.
+fn run_clippy(arch: String, deny_warnings: bool) {
+    let mut success = true;
+
     println!("-- Clippy bootloader");
     {
         let bootloader_target = format!("{}-unknown-uefi", &arch);
@@ -41,7 +188,7 @@ fn run_clippy(arch: String) {
             .arg("-Zbuild-std=core,compiler_builtins")
             .arg("-Zbuild-std-features=compiler-builtins-mem")
             .arg(format!("--target={}", &bootloader_target));
-        command.status().unwrap();
+        success &= run_clippy_command(&mut command, deny_warnings);
     }
     
     println!("-- Clippy kernel");
@@ -52,11 +199,34 @@ fn run_clippy(arch: String) {
             .arg("-Zbuild-std=core,compiler_builtins")
             .arg("-Zbuild-std-features=compiler-builtins-mem")
             .arg(format!("--target={}/{}", ROOT_DIR, &kernel_target));
-        command.status().unwrap();
+        success &= run_clippy_command(&mut command, deny_warnings);
     }
+
+    // common-structures is built for the host as well, so it needs no target.
+    println!("-- Clippy common-structures");
+    {
+        let mut command = Command::new(CARGO);
+        command.arg("clippy").arg("-p").arg("common-structures");
+        success &= run_clippy_command(&mut command, deny_warnings);
+    }
+
+    if !success {
+        println!("-- Clippy failed");
+        exit(1);
+    }
+}
+
+/// Runs the clippy `command` and returns whether it succeeded.
+fn run_clippy_command(command: &mut Command, deny_warnings: bool) -> bool {
+    if deny_warnings {
+        command.arg("--").arg("-D").arg("warnings");
+    }
+    command.status().unwrap().success()
 }
 
-fn build(arch: String, release_mode: bool) {
+/// Builds the bootloader and kernel (with the given `bootloader_features` and `kernel_features` enabled)
+/// and returns the path of the resulting disk image.
+fn build(arch: String, release_mode: bool, bootloader_features: &[&str], kernel_features: &[&str]) -> String {
     let profile_name = if release_mode { "release" } else { "debug" };
 
     println!("-- Building for {}", arch);
@@ -72,6 +242,9 @@ fn build(arch: String, release_mode: bool) {
         if release_mode {
             command.arg("--release");
         }
+        if !bootloader_features.is_empty() {
+            command.arg("--features").arg(bootloader_features.join(","));
+        }
 
         command.status().unwrap()
     };
@@ -85,9 +258,15 @@ fn build(arch: String, release_mode: bool) {
             .arg("-Zbuild-std=core,compiler_builtins")
             .arg("-Zbuild-std-features=compiler-builtins-mem")
             .arg(format!("--target={}/{}", ROOT_DIR, &kernel_target));
+        // The backtrace printed on panic follows the chain of frame pointers.
+        let rustflags = env::var("RUSTFLAGS").map(|flags| flags + " ").unwrap_or_default() + "-Cforce-frame-pointers=yes";
+        command.env("RUSTFLAGS", rustflags);
         if release_mode {
             command.arg("--release");
         }
+        if !kernel_features.is_empty() {
+            command.arg("--features").arg(kernel_features.join(","));
+        }
         
         command.status().unwrap()
     };
@@ -105,7 +284,7 @@ fn build(arch: String, release_mode: bool) {
 
     let bootloader_size = fs::metadata(&bootloader_path).unwrap().len();
     let kernel_size = fs::metadata(&kernel_path).unwrap().len();
-    let partition_size = MB + (bootloader_size + kernel_size + MB - 1) / MB * MB;
+    let partition_size = MB + (bootloader_size + kernel_size).div_ceil(MB) * MB;
     
     {
         let mut partition_file = fs::OpenOptions::new()
@@ -132,6 +311,12 @@ fn build(arch: String, release_mode: bool) {
         let mut kernel_out = partition.root_dir().create_file("EFI/BOOT/kernel.sys").unwrap();
         let mut kernel_in = fs::File::open(&kernel_path).unwrap();
         io::copy(&mut kernel_in, &mut kernel_out).unwrap();
+
+        // Checked by the bootloader if it was built with the secure-boot-hash feature.
+        let kernel_hash = sha256::sha256(&fs::read(&kernel_path).unwrap());
+        let mut hash_out = partition.root_dir().create_file("EFI/BOOT/kernel.sha256").unwrap();
+        let hex: String = kernel_hash.iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(hash_out, "{}  kernel.sys", hex).unwrap();
     }
 
     println!("-- Building system image");
@@ -167,4 +352,79 @@ fn build(arch: String, release_mode: bool) {
     }
 
     println!("-- Finished");
+
+    image_path
+}
+
+/// Boots the given image in QEMU and collects the results the kernel test runner
+/// writes to the serial port.
+/// 
+/// Exits with code 0 if every test passed, 1 if any test failed and 2 on timeout.
+/// QEMU's exit code is checked as well, so a kernel that crashes without reporting a failure does not pass.
+fn run_tests(image_path: &str, qemu: &QemuOptions) {
+    println!("-- Running kernel tests");
+
+    let mut command = qemu_command(image_path, qemu);
+    command
+        .arg("-display").arg("none")
+        .arg("-serial").arg("stdio")
+        .arg("-device").arg("isa-debug-exit,iobase=0xf4,iosize=0x04")
+        .args(&qemu.extra_args)
+        .stdout(Stdio::piped());
+    let mut child = spawn_qemu(&mut command);
+
+    // Read the serial output on a separate thread, so that the main thread can enforce the timeout.
+    let (sender, receiver) = mpsc::channel();
+    let stdout = child.stdout.take().unwrap();
+    thread::spawn(move || {
+        for line in io::BufReader::new(stdout).lines() {
+            match line {
+                Ok(line) => if sender.send(line).is_err() { break; },
+                Err(_) => break,
+            }
+        }
+    });
+
+    let mut passed = Vec::new();
+    let mut failed = Vec::new();
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining) {
+            Ok(line) => {
+                if let Some(name) = line.strip_prefix("TEST PASS: ") {
+                    println!("PASS  {}", name);
+                    passed.push(name.to_owned());
+                } else if let Some(name) = line.strip_prefix("TEST FAIL: ") {
+                    println!("FAIL  {}", name);
+                    failed.push(name.to_owned());
+                } else {
+                    println!("{}", line);
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let _ = child.kill();
+                println!("-- Tests timed out after {} seconds", TEST_TIMEOUT.as_secs());
+                exit(2);
+            }
+        }
+    }
+    let status = child.wait().unwrap();
+    // The test runner writes 0 to isa-debug-exit on success, which QEMU turns into (0 << 1) | 1.
+    let qemu_success = status.code() == Some(QEMU_EXIT_SUCCESS);
+
+    println!("-- {} passed, {} failed", passed.len(), failed.len());
+    for name in &failed {
+        println!("   failed: {}", name);
+    }
+    if !qemu_success {
+        println!("-- QEMU exited with {}", status);
+    }
+
+    if failed.is_empty() && !passed.is_empty() && qemu_success {
+        exit(0);
+    } else {
+        exit(1);
+    }
 }
@@ -1,3 +1,28 @@
 
 /// The size of the stack the bootloader should reserve for the kernel
 pub const KERNEL_STACK_SIZE: u64 = 1024 * 1024;
+
+/// The size of the user mode stack of every process
+pub const USER_STACK_SIZE: u64 = 2 * 1024 * 1024;
+
+/// The maximum number of processes the kernel creates
+pub const MAX_PROCESSES: usize = 256;
+
+/// The size of the kernel heap
+pub const KERNEL_HEAP_SIZE: usize = 16 * 1024 * 1024;
+
+/// The size of the interrupt and double fault stacks of every core
+pub const INTERRUPT_STACK_SIZE: usize = 16 * 1024;
+
+/// The paging mode the bootloader should use, if supported.
+/// 
+/// 5-level paging can only be used if the firmware already enabled it, 
+/// as CR4.LA57 cannot be changed while in long mode.
+#[cfg(target_arch="x86_64")]
+pub const PAGING_LEVEL: crate::PagingLevel = crate::PagingLevel::Pml4;
+
+/// Maximum physical address width supported by the kernel.
+pub const MAX_PHYSICAL_MEMORY_BITS: u64 = 52;
+
+// x86_64 page table entries cannot address more than 52 bits of physical memory.
+const _: () = assert!(MAX_PHYSICAL_MEMORY_BITS <= 52, "MAX_PHYSICAL_MEMORY_BITS must not exceed the 52-bit architectural limit");
@@ -1,7 +1,17 @@
 
+/// Layout version of [`KernelHeader`].
+/// 
+/// Has to be incremented whenever the layout of [`KernelHeader`] or any structure it contains changes,
+/// so that a kernel started by an incompatible bootloader can detect it.
+pub const KERNEL_HEADER_VERSION: u32 = 5;
+
 /// A structure containing various information passed to the kernel entry point
 #[repr(C)]
 pub struct KernelHeader {
+    /// Layout version of this structure, always [`KERNEL_HEADER_VERSION`].
+    /// Has to stay the first field.
+    pub version: u32,
+
     /// Pointer to the GPU framebuffer.
     /// Can be used to draw to the screen
     pub screen_buffer: *mut u8,
@@ -23,13 +33,57 @@ pub struct KernelHeader {
     
     /// base address of the physical memory mapping in the higher memory half.
     pub high_memory_base: u64,
+
+    /// lowest address of the kernel stack in the higher memory half.
+    /// The page below it is reserved as a guard page.
+    pub kernel_stack_base: u64,
+
+    /// physical address of the ACPI 2.0 RSDP, or 0 if the firmware does not provide one.
+    pub acpi_rsdp: u64,
+
+    /// kernel command line, encoded as UTF-8. Only the first `cmdline_len` bytes are valid.
+    pub cmdline_buf: [u8; 256],
+    /// length of the kernel command line in bytes.
+    pub cmdline_len: u32,
+
+    /// address of the initial RAM disk in the higher memory half, or 0 if there is none.
+    pub initrd_base: u64,
+    /// size of the initial RAM disk in bytes.
+    pub initrd_size: u64,
+
+    /// physical address of the SMBIOS 3.0 entry point, or 0 if the firmware does not provide one.
+    pub smbios3_ptr: u64,
+    /// physical address of the SMBIOS 2.x entry point.
+    /// Only set if there is no SMBIOS 3.0 entry point, 0 otherwise.
+    pub smbios2_ptr: u64,
+
+    /// physical address of the UEFI `EFI_RUNTIME_SERVICES` table, or 0 if `GetTime()` failed in the bootloader.
+    /// 
+    /// The runtime services can only be called while every segment in `efi_runtime_map` is mapped.
+    /// The firmware still uses physical addresses until `SetVirtualAddressMap()` is called.
+    /// That call has to pass every segment of `efi_runtime_map` with its new virtual address.
+    /// It can only be made once. Afterwards, the segments have to stay mapped at those addresses,
+    /// with code segments executable, and the pointers in the table are virtual addresses.
+    pub efi_runtime_services: u64,
+    /// array in the higher memory half containing the memory segments used by the UEFI runtime services.
+    /// These are the `EFI_MEMORY_RUNTIME` entries of the UEFI memory map, they are marked as occupied in `memory_map` as well.
+    pub efi_runtime_map: *mut MemorySegment,
+    /// number of entries in `efi_runtime_map`
+    pub efi_runtime_map_count: u32,
 }
 
+/// The order of the bytes of a 32-bit pixel in the framebuffer.
 #[repr(C)]
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Format {
+    /// Red, green, blue, unused.
     RGB,
+    /// Blue, green, red, unused.
     BGR,
+    /// Unused, red, green, blue.
+    XRGB,
+    /// Alpha, red, green, blue. Pixels have to be written with an alpha of 255 to be opaque.
+    ARGB,
 }
 
 #[repr(C)]
@@ -42,11 +96,25 @@ pub struct MemorySegment {
     pub state: MemorySegmentState,
 }
 
+impl MemorySegment {
+    /// Returns the physical address right after the segment.
+    pub fn end_addr(&self) -> u64 {
+        self.start + self.page_count * 4096
+    }
+
+    /// Whether the physical address `addr` lies within the segment.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end_addr()
+    }
+}
+
 #[repr(C)]
 #[derive(PartialEq, Eq)]
 pub enum MemorySegmentState {
     Free,
     Occupied,
+    /// Registers of a device. The kernel never allocates memory here, even if a free segment overlaps it.
+    Mmio,
 }
 
 #[cfg(target_arch="x86_64")]
@@ -57,9 +125,73 @@ pub struct PagingInfo {
     /// The table will have an identity mapping of physical memory
     /// as well as a mirror in the higher memory half.
     pub page_buffer: *mut u64,
+    /// Size of the allocation at `page_buffer` in pages.
+    pub page_buffer_size: u64,
     /// Number of pages used for the Page Directory Pointer Tables
     pub pdp_pages: u64,
     /// Number of pages used for the Page Directory Tables
     pub pd_pages: u64,
     pub pml4_entries: u64,
+    /// Number of page table levels, see [`PagingLevel`].
+    /// 
+    /// If 5, `page_buffer` starts with the PML5 table, followed by the PML4 table.
+    pub paging_levels: u8,
+}
+
+#[cfg(target_arch="x86_64")]
+impl PagingInfo {
+    /// Returns the first address of the physical memory mirror in the higher memory half.
+    /// 
+    /// The mirror starts at a multiple of its size, so physical addresses can simply be ORed onto it.
+    /// It therefore takes up the last `pml4_entries` PML4 entries, rounded up to a power of two.
+    pub fn high_memory_base(&self) -> u64 {
+        0xFFFF_0000_0000_0000 | ((512 - self.pml4_entries.next_power_of_two()) << 39)
+    }
+}
+
+/// The page table layouts supported on x86_64.
+#[cfg(target_arch="x86_64")]
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PagingLevel {
+    /// 4-level paging with 48-bit virtual addresses.
+    Pml4 = 4,
+    /// 5-level paging (LA57) with 57-bit virtual addresses.
+    Pml5 = 5,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_bounds() {
+        let segment = MemorySegment { start: 0x1000, page_count: 2, state: MemorySegmentState::Free };
+        assert_eq!(segment.end_addr(), 0x3000);
+        assert!(segment.contains(0x1000));
+        assert!(segment.contains(0x2FFF));
+        assert!(!segment.contains(0x0FFF));
+        assert!(!segment.contains(0x3000));
+
+        let empty = MemorySegment { start: 0x1000, page_count: 0, state: MemorySegmentState::Free };
+        assert!(!empty.contains(0x1000));
+    }
+
+    #[cfg(target_arch="x86_64")]
+    #[test]
+    fn high_memory_base() {
+        let paging_info = |pml4_entries| PagingInfo {
+            page_buffer: core::ptr::null_mut(),
+            page_buffer_size: 0,
+            pdp_pages: 0,
+            pd_pages: 0,
+            pml4_entries,
+            paging_levels: PagingLevel::Pml4 as u8,
+        };
+        assert_eq!(paging_info(1).high_memory_base(), 0xFFFF_FF80_0000_0000);
+        assert_eq!(paging_info(2).high_memory_base(), 0xFFFF_FF00_0000_0000);
+        // 1.5 TB are mirrored into the last 2 TB.
+        assert_eq!(paging_info(3).high_memory_base(), 0xFFFF_FE00_0000_0000);
+        assert_eq!(paging_info(256).high_memory_base(), 0xFFFF_8000_0000_0000);
+    }
 }
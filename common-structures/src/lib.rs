@@ -4,3 +4,5 @@ pub mod kernel_header;
 pub use kernel_header::*;
 
 pub mod config;
+
+pub mod sha256;
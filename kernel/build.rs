@@ -0,0 +1,15 @@
+use vergen::{Config, ShaKind, TimestampKind, vergen};
+
+fn main() {
+    // Emits VERGEN_GIT_SHA_SHORT and VERGEN_BUILD_DATE, see src/version.rs
+    let mut config = Config::default();
+    *config.build_mut().kind_mut() = TimestampKind::DateOnly;
+    *config.git_mut().sha_kind_mut() = ShaKind::Short;
+
+    if vergen(config).is_err() {
+        // Not built from a git checkout (e.g. a source archive), only emit the build date.
+        *config.git_mut().enabled_mut() = false;
+        vergen(config).expect("Failed to generate version information");
+        println!("cargo:rustc-env=VERGEN_GIT_SHA_SHORT=unknown");
+    }
+}
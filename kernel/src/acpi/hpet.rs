@@ -0,0 +1,45 @@
+//! Parser for the HPET Description Table, which holds the address of the High Precision Event Timer.
+
+use super::{find_table, read};
+
+/// Signature of the HPET table.
+const HPET_SIGNATURE: [u8; 4] = *b"HPET";
+/// Offset of the address space ID of the Generic Address Structure holding the base address.
+const ADDRESS_SPACE_OFFSET: usize = 40;
+/// Offset of the 64-bit base address in the Generic Address Structure.
+const ADDRESS_OFFSET: usize = 44;
+/// Address space ID of memory mapped registers.
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+
+/// Follows the RSDP at the physical address `rsdp` to the HPET table and returns the physical
+/// base address of the HPET registers, or `None` if there is no HPET.
+pub fn parse(rsdp: u64) -> Option<u64> {
+    parse_table(find_table(rsdp, &HPET_SIGNATURE)?)
+}
+
+/// Returns the base address in the HPET `table`, including its header.
+fn parse_table(table: &[u8]) -> Option<u64> {
+    if read::<u8>(table, ADDRESS_SPACE_OFFSET)? != ADDRESS_SPACE_MEMORY {
+        return None;
+    }
+    read::<u64>(table, ADDRESS_OFFSET).filter(|&addr| addr != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn base_address() {
+        let mut table = vec![0u8; 56];
+        table[ADDRESS_OFFSET..ADDRESS_OFFSET + 8].copy_from_slice(&0xFED0_0000u64.to_le_bytes());
+        assert_eq!(parse_table(&table), Some(0xFED0_0000));
+
+        // I/O port space is not supported.
+        table[ADDRESS_SPACE_OFFSET] = 1;
+        assert_eq!(parse_table(&table), None);
+
+        assert_eq!(parse_table(&table[..40]), None);
+    }
+}
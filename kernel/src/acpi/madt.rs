@@ -0,0 +1,123 @@
+//! Parser for the Multiple APIC Description Table, which lists the Local APIC of every core and the I/O APICs.
+
+use core::mem::size_of;
+
+use super::{SdtHeader, find_table, read};
+
+/// Maximum number of Local APICs, as APIC IDs are 8 bits wide without x2APIC.
+pub const MAX_LAPICS: usize = 256;
+
+/// Signature of the MADT.
+const MADT_SIGNATURE: [u8; 4] = *b"APIC";
+/// Offset of the first entry, after the header, the Local APIC address and the flags.
+const ENTRIES_OFFSET: usize = size_of::<SdtHeader>() + 8;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+
+/// Local APIC flag: the core is usable.
+const LAPIC_ENABLED: u32 = 1 << 0;
+
+/// The information in the MADT.
+///
+/// The APIC IDs are stored in a fixed-size array, as the MADT might be parsed before the heap is available.
+pub struct MadtInfo {
+    lapic_ids: [u8; MAX_LAPICS],
+    lapic_count: usize,
+    /// Physical address of the first I/O APIC, or 0 if there is none.
+    pub ioapic_base: u64,
+}
+
+impl MadtInfo {
+    /// Returns the APIC ID of every enabled core, including the boot core.
+    pub fn lapic_ids(&self) -> &[u8] {
+        &self.lapic_ids[..self.lapic_count]
+    }
+}
+
+/// Follows the RSDP at the physical address `rsdp` to the MADT and parses it.
+///
+/// Returns an empty [`MadtInfo`] if there is no valid MADT.
+pub fn parse(rsdp: u64) -> MadtInfo {
+    match find_table(rsdp, &MADT_SIGNATURE) {
+        Some(table) => {
+            let info = parse_table(table);
+            info!("ACPI", "MADT lists {} cores, I/O APIC at {:#016X}", info.lapic_count, info.ioapic_base);
+            info
+        }
+        None => {
+            warning!("ACPI", "No MADT found");
+            parse_table(&[])
+        }
+    }
+}
+
+/// Parses the contents of the MADT `table`, including its header.
+fn parse_table(table: &[u8]) -> MadtInfo {
+    let mut info = MadtInfo {
+        lapic_ids: [0; MAX_LAPICS],
+        lapic_count: 0,
+        ioapic_base: 0,
+    };
+
+    let mut offset = ENTRIES_OFFSET;
+    while let (Some(entry_type), Some(length)) = (read::<u8>(table, offset), read::<u8>(table, offset + 1)) {
+        // A zero length would loop forever.
+        if length < 2 {
+            break;
+        }
+
+        match entry_type {
+            ENTRY_LOCAL_APIC => {
+                let apic_id = read::<u8>(table, offset + 3);
+                let flags = read::<u32>(table, offset + 4);
+                if let (Some(apic_id), Some(flags)) = (apic_id, flags) {
+                    if flags & LAPIC_ENABLED != 0 && info.lapic_count < MAX_LAPICS {
+                        info.lapic_ids[info.lapic_count] = apic_id;
+                        info.lapic_count += 1;
+                    }
+                }
+            }
+            ENTRY_IO_APIC if info.ioapic_base == 0 => {
+                if let Some(addr) = read::<u32>(table, offset + 4) {
+                    info.ioapic_base = addr as u64;
+                }
+            }
+            _ => {}
+        }
+
+        offset += length as usize;
+    }
+
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn entries() {
+        let mut table = vec![0u8; ENTRIES_OFFSET];
+        // Local APICs with ID 0 and 2, and a disabled one with ID 5.
+        table.extend_from_slice(&[ENTRY_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+        table.extend_from_slice(&[ENTRY_LOCAL_APIC, 8, 1, 2, 1, 0, 0, 0]);
+        table.extend_from_slice(&[ENTRY_LOCAL_APIC, 8, 2, 5, 0, 0, 0, 0]);
+        // I/O APIC at 0xFEC00000, followed by an unknown entry.
+        table.extend_from_slice(&[ENTRY_IO_APIC, 12, 0, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+        table.extend_from_slice(&[0x7F, 4, 0, 0]);
+
+        let info = parse_table(&table);
+        assert_eq!(info.lapic_ids(), &[0, 2]);
+        assert_eq!(info.ioapic_base, 0xFEC0_0000);
+
+        // A truncated entry is ignored.
+        let info = parse_table(&table[..ENTRIES_OFFSET + 12]);
+        assert_eq!(info.lapic_ids(), &[0]);
+
+        let info = parse_table(&[]);
+        assert!(info.lapic_ids().is_empty());
+        assert_eq!(info.ioapic_base, 0);
+    }
+}
@@ -0,0 +1,112 @@
+//! Parsing of the ACPI tables provided by the firmware.
+//!
+//! The tables are read through the kernel's mapping of physical memory, every table is checked against
+//! its checksum before it is used.
+
+use core::mem::size_of;
+
+use crate::memory;
+
+pub mod hpet;
+pub mod madt;
+
+/// "RSD PTR " in the first 8 bytes of the RSDP.
+const RSDP_SIGNATURE: [u8; 8] = *b"RSD PTR ";
+/// Number of bytes of the RSDP covered by its first checksum, the ACPI 1.0 part.
+const RSDP_V1_SIZE: usize = 20;
+
+/// Root System Description Pointer, passed to the kernel by the bootloader.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Only valid if revision >= 2.
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header shared by every System Description Table.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SdtHeader {
+    signature: [u8; 4],
+    /// Size of the table including this header.
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// Returns the contents of the first table with the given `signature`, including its header.
+///
+/// Follows the RSDP at the physical address `rsdp` to the XSDT, or to the RSDT if the firmware only supports ACPI 1.0.
+fn find_table(rsdp: u64, signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let rsdp_data = unsafe{core::slice::from_raw_parts(memory::phys_to_virt::<u8>(rsdp), size_of::<Rsdp>())};
+    let header = read::<Rsdp>(rsdp_data, 0)?;
+    if header.signature != RSDP_SIGNATURE || !checksum_valid(&rsdp_data[..RSDP_V1_SIZE]) {
+        warning!("ACPI", "Invalid RSDP at {:#016X}", rsdp);
+        return None;
+    }
+
+    // The XSDT holds 64-bit table addresses, the RSDT 32-bit ones.
+    let (root, entry_size) = if header.revision >= 2 && header.xsdt_address != 0 {
+        (table_data(header.xsdt_address)?, 8)
+    } else {
+        (table_data(header.rsdt_address as u64)?, 4)
+    };
+
+    let mut offset = size_of::<SdtHeader>();
+    while offset + entry_size <= root.len() {
+        let addr = if entry_size == 8 {
+            read::<u64>(root, offset)?
+        } else {
+            read::<u32>(root, offset)? as u64
+        };
+        offset += entry_size;
+
+        if let Some(table) = table_data(addr) {
+            if &table[..4] == signature {
+                return Some(table);
+            }
+        }
+    }
+    None
+}
+
+/// Returns the contents of the table at the physical address `phys`, or `None` if its checksum is invalid.
+fn table_data(phys: u64) -> Option<&'static [u8]> {
+    let header = unsafe{memory::phys_to_virt::<SdtHeader>(phys).read_unaligned()};
+    if (header.length as usize) < size_of::<SdtHeader>() {
+        return None;
+    }
+
+    let data = unsafe{core::slice::from_raw_parts(memory::phys_to_virt::<u8>(phys), header.length as usize)};
+    if !checksum_valid(data) {
+        warning!("ACPI", "Table {} at {:#016X} has an invalid checksum", core::str::from_utf8(&header.signature).unwrap_or("????"), phys);
+        return None;
+    }
+    Some(data)
+}
+
+/// Every byte covered by an ACPI checksum has to add up to zero.
+fn checksum_valid(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Reads a `T` at `offset` in `data`, or returns `None` if it does not fit.
+fn read<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    if offset.checked_add(size_of::<T>())? > data.len() {
+        return None;
+    }
+    Some(unsafe{(data.as_ptr().add(offset) as *const T).read_unaligned()})
+}
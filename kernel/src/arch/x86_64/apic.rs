@@ -0,0 +1,200 @@
+//! Driver for the Local APIC of every core.
+//!
+//! Every core sees its own Local APIC at the same physical address, so a single mapping is shared by all cores.
+//! The legacy PICs stay masked while the Local APIC is used.
+
+use crate::arch::{cpuid, msr};
+use crate::arch::interrupt::{HandlerResult, InterruptInfo, set_isr_handler};
+use crate::memory;
+use crate::mutex::OnceLock;
+
+/// Interrupt vector of the Local APIC timer.
+pub const VECTOR_TIMER: u8 = 0x30;
+/// Interrupt vector of Local APIC errors.
+pub const VECTOR_ERROR: u8 = 0xFD;
+/// Interrupt vector of spurious Local APIC interrupts. The lower 4 bits have to be set on older CPUs.
+pub const VECTOR_SPURIOUS: u8 = 0xFF;
+
+/// IA32_APIC_BASE bit 11: the Local APIC is enabled.
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// Bits of IA32_APIC_BASE that hold the physical base address.
+const APIC_BASE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xB0;
+const REG_SPURIOUS: usize = 0xF0;
+const REG_ERROR_STATUS: usize = 0x280;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_LVT_ERROR: usize = 0x370;
+const REG_TIMER_INITIAL_COUNT: usize = 0x380;
+const REG_TIMER_CURRENT_COUNT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3E0;
+
+/// Spurious Interrupt Vector Register bit 8: software enable.
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+/// LVT bit 16: the interrupt is masked.
+const LVT_MASKED: u32 = 1 << 16;
+/// LVT timer bit 17: periodic mode, the timer restarts with the initial count after firing.
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Timer divide configuration: the timer counts down once every 16 bus clock cycles.
+const TIMER_DIVIDE_16: u32 = 0b0011;
+/// ICR bit 12: the previous IPI has not been delivered yet.
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// ICR bit 14: level assert, has to be set for every IPI except INIT level de-assert.
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+/// ICR delivery mode INIT: resets the target core into the wait-for-SIPI state.
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+/// ICR delivery mode Start-Up: the target core starts executing in real mode at vector * 4096.
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+
+/// The memory mapped registers of the Local APIC.
+pub struct LocalApic {
+    base: *mut u32,
+}
+
+impl LocalApic {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe {
+            self.base.add(reg / 4).read_volatile()
+        }
+    }
+
+    fn write(&self, reg: usize, val: u32) {
+        unsafe {
+            self.base.add(reg / 4).write_volatile(val);
+        }
+    }
+
+    /// Returns the APIC ID of the current core.
+    pub fn id(&self) -> u8 {
+        (self.read(REG_ID) >> 24) as u8
+    }
+
+    /// Signals the end of the current interrupt handler.
+    pub fn eoi(&self) {
+        self.write(REG_EOI, 0);
+    }
+
+    /// Sends `vector` to the core with the APIC ID `dest_apic_id` and waits until it has been delivered.
+    pub fn send_ipi(&self, dest_apic_id: u8, vector: u8) {
+        // Fixed delivery mode.
+        self.send_icr(dest_apic_id, vector as u32 | ICR_LEVEL_ASSERT);
+    }
+
+    /// Sends an INIT IPI to the core with the APIC ID `dest_apic_id`, the first step of starting a secondary core.
+    pub fn send_init(&self, dest_apic_id: u8) {
+        self.send_icr(dest_apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// Sends a Start-Up IPI to the core with the APIC ID `dest_apic_id`, which starts executing in real mode
+    /// at the physical address `page * 4096`.
+    pub fn send_startup(&self, dest_apic_id: u8, page: u8) {
+        self.send_icr(dest_apic_id, page as u32 | ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT);
+    }
+
+    /// Writes the Interrupt Command Register, physical destination, and waits until the IPI has been delivered.
+    fn send_icr(&self, dest_apic_id: u8, low: u32) {
+        self.write(REG_ICR_HIGH, (dest_apic_id as u32) << 24);
+        // Writing the low half sends the IPI.
+        self.write(REG_ICR_LOW, low);
+
+        while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Starts the timer of the current core. It fires [`VECTOR_TIMER`] after `count` timer ticks,
+    /// and then again every `count` ticks if `periodic` is set.
+    pub fn start_timer(&self, count: u32, periodic: bool) {
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        self.write(REG_LVT_TIMER, VECTOR_TIMER as u32 | if periodic { LVT_TIMER_PERIODIC } else { 0 });
+        // Writing the initial count starts the timer.
+        self.write(REG_TIMER_INITIAL_COUNT, count);
+    }
+
+    /// Stops and masks the timer of the current core.
+    pub fn stop_timer(&self) {
+        self.write(REG_LVT_TIMER, VECTOR_TIMER as u32 | LVT_MASKED);
+        self.write(REG_TIMER_INITIAL_COUNT, 0);
+    }
+
+    /// Returns the number of ticks left until the timer of the current core fires.
+    pub fn get_timer_count(&self) -> u32 {
+        self.read(REG_TIMER_CURRENT_COUNT)
+    }
+
+    /// Enables the Local APIC of the current core with the timer masked.
+    fn enable(&self) {
+        self.write(REG_LVT_TIMER, VECTOR_TIMER as u32 | LVT_MASKED);
+        self.write(REG_LVT_ERROR, VECTOR_ERROR as u32);
+        // Clear errors that happened before the error vector was set up.
+        self.write(REG_ERROR_STATUS, 0);
+        self.write(REG_SPURIOUS, VECTOR_SPURIOUS as u32 | SPURIOUS_ENABLE);
+    }
+}
+
+// The registers are core-local hardware, the pointer itself never changes.
+unsafe impl Send for LocalApic {}
+unsafe impl Sync for LocalApic {}
+
+static LOCAL_APIC: OnceLock<LocalApic> = OnceLock::new();
+
+/// Maps the Local APIC and enables it on the boot core.
+///
+/// Has to be called after the interrupt initialization and the virtual memory manager.
+pub fn init() {
+    assert!(cpuid::features().has_apic(), "CPU has no Local APIC");
+
+    let apic_base = unsafe{msr::rdmsr(msr::IA32_APIC_BASE)};
+    unsafe {
+        msr::wrmsr(msr::IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE);
+    }
+
+    let phys = apic_base & APIC_BASE_ADDR_MASK;
+    let base = memory::map_mmio(phys, 4096) as *mut u32;
+    LOCAL_APIC.init(LocalApic {
+        base,
+    });
+
+    set_isr_handler(VECTOR_ERROR, error_handler);
+    set_isr_handler(VECTOR_SPURIOUS, spurious_handler);
+
+    init_core();
+    info!("APIC", "Local APIC at {:#016X}, boot core ID {}", phys, local_apic().id());
+}
+
+/// Enables the Local APIC of the calling core. Has to be called on every core after [`init()`].
+pub fn init_core() {
+    local_apic().enable();
+}
+
+/// Returns the Local APIC, usable after [`init()`].
+pub fn local_apic() -> &'static LocalApic {
+    LOCAL_APIC.get()
+}
+
+/// Signals the end of the current interrupt handler to the Local APIC of the current core.
+pub fn eoi() {
+    local_apic().eoi();
+}
+
+/// Sends `vector` to the core with the APIC ID `dest_apic_id`.
+pub fn send_ipi(dest_apic_id: u8, vector: u8) {
+    local_apic().send_ipi(dest_apic_id, vector);
+}
+
+fn error_handler(_info: &mut InterruptInfo) -> HandlerResult {
+    let apic = local_apic();
+    // The error status register has to be written before reading it to latch the current errors.
+    apic.write(REG_ERROR_STATUS, 0);
+    warning!("APIC", "Local APIC error {:#X}", apic.read(REG_ERROR_STATUS));
+    apic.eoi();
+    HandlerResult::Resume
+}
+
+/// Spurious interrupts must not be acknowledged with an EOI.
+fn spurious_handler(_info: &mut InterruptInfo) -> HandlerResult {
+    HandlerResult::Resume
+}
@@ -0,0 +1,80 @@
+//! Switching between kernel stacks.
+//!
+//! A suspended task is fully described by its stack pointer: [`switch_context()`] pushes the callee-saved
+//! registers onto the old stack and pops them from the new one, everything else has already been saved
+//! by the compiler around the call.
+
+use crate::interrupt;
+
+/// Number of registers pushed by [`switch_context()`]: rbx, rbp and r12 to r15.
+const SAVED_REGISTERS: usize = 6;
+
+/// Saves the callee-saved registers and the stack pointer of the current task into `old_rsp`
+/// and continues the task whose stack pointer is `new_rsp`.
+///
+/// Returns once another task switches back to the saved stack pointer.
+///
+/// # Safety
+/// `new_rsp` has to be a stack pointer saved by this function or created by [`init_task_stack()`].
+#[naked]
+pub unsafe extern "C" fn switch_context(old_rsp: *mut u64, new_rsp: u64) {
+    asm!(
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        // Returns into the switched-to task, or into task_trampoline for a new task.
+        "ret",
+
+        options(noreturn)
+    );
+}
+
+/// Prepares the stack ending at `stack_top` so that switching to it calls `entry`, and returns the
+/// stack pointer to switch to.
+///
+/// # Safety
+/// `stack_top` has to be 16-byte aligned and the end of mapped, otherwise unused memory.
+pub unsafe fn init_task_stack(stack_top: u64, entry: fn() -> !) -> u64 {
+    let stack = stack_top as *mut u64;
+
+    // Fake return address of task_start, so that the stack is aligned like after a CALL.
+    stack.sub(1).write(0);
+    stack.sub(2).write(task_trampoline as u64);
+    // r15, r14, r13, r12, rbp and rbx, r12 holds the entry point.
+    let regs = stack.sub(2 + SAVED_REGISTERS);
+    regs.write_bytes(0, SAVED_REGISTERS);
+    regs.add(3).write(entry as u64);
+
+    regs as u64
+}
+
+/// First code executed by a new task, passes the entry point that [`init_task_stack()`] put into r12 on to [`task_start()`].
+#[naked]
+extern "C" fn task_trampoline() {
+    unsafe{asm!(
+        "mov rdi, r12",
+        "jmp {start}",
+
+        start = sym task_start,
+
+        options(noreturn)
+    )};
+}
+
+extern "C" fn task_start(entry: fn() -> !) -> ! {
+    // The task that switched to this one disabled interrupts and will not enable them again for us.
+    interrupt::enable();
+    entry()
+}
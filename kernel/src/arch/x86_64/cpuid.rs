@@ -0,0 +1,175 @@
+//! CPU feature detection via the CPUID instruction.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+use crate::mutex::OnceLock;
+
+/// Leaf 1, EDX bit 9: the core has a Local APIC.
+const LEAF1_EDX_APIC: u32 = 1 << 9;
+/// Leaf 1, EDX bit 19: CLFLUSH is supported and EBX bits 8-15 contain the cache line size.
+const LEAF1_EDX_CLFSH: u32 = 1 << 19;
+/// Leaf 1, EDX bit 26: SSE2 is supported.
+const LEAF1_EDX_SSE2: u32 = 1 << 26;
+/// Leaf 1, EDX bit 28: EBX bits 16-23 contain the number of logical processors per package.
+const LEAF1_EDX_HTT: u32 = 1 << 28;
+/// Leaf 7, EBX bit 5: AVX2 is supported.
+const LEAF7_EBX_AVX2: u32 = 1 << 5;
+/// Leaf 0x80000001, EDX bit 20: the No-Execute page table bit is supported.
+const EXT1_EDX_NX: u32 = 1 << 20;
+
+/// Leaf 0x0B level type of the SMT (hyperthreading) level.
+const TOPOLOGY_LEVEL_SMT: u32 = 1;
+/// Leaf 0x0B level type of the core level.
+const TOPOLOGY_LEVEL_CORE: u32 = 2;
+
+/// The features of the boot core, as reported by CPUID.
+pub struct CpuFeatures {
+    vendor: [u8; 12],
+    leaf1_edx: u32,
+    leaf7_ebx: u32,
+    ext1_edx: u32,
+    /// Number of logical cores per package.
+    logical_cores: u32,
+    /// Number of logical cores sharing a physical core.
+    threads_per_core: u32,
+    /// Cache line size in bytes, 0 if unknown.
+    cache_line_size: u32,
+}
+
+impl CpuFeatures {
+    /// Executes every needed CPUID leaf on the current core.
+    pub fn detect() -> Self {
+        let leaf0 = unsafe{__cpuid(0)};
+        let max_leaf = leaf0.eax;
+
+        let mut vendor = [0u8; 12];
+        vendor[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+
+        let leaf1 = unsafe{__cpuid(1)};
+        let leaf7_ebx = if max_leaf >= 7 {
+            unsafe{__cpuid_count(7, 0)}.ebx
+        } else {
+            0
+        };
+
+        let max_ext_leaf = unsafe{__cpuid(0x8000_0000)}.eax;
+        let ext1_edx = if max_ext_leaf >= 0x8000_0001 {
+            unsafe{__cpuid(0x8000_0001)}.edx
+        } else {
+            0
+        };
+
+        let cache_line_size = if leaf1.edx & LEAF1_EDX_CLFSH != 0 {
+            ((leaf1.ebx >> 8) & 0xFF) * 8
+        } else {
+            0
+        };
+
+        // Without the topology leaf, only the number of logical cores is known.
+        let mut logical_cores = if leaf1.edx & LEAF1_EDX_HTT != 0 {
+            ((leaf1.ebx >> 16) & 0xFF).max(1)
+        } else {
+            1
+        };
+        let mut threads_per_core = 1;
+        if max_leaf >= 0x0B {
+            for level in 0.. {
+                let res = unsafe{__cpuid_count(0x0B, level)};
+                let count = res.ebx & 0xFFFF;
+                match (res.ecx >> 8) & 0xFF {
+                    TOPOLOGY_LEVEL_SMT => threads_per_core = count.max(1),
+                    TOPOLOGY_LEVEL_CORE => logical_cores = count.max(1),
+                    0 => break,
+                    _ => {}
+                }
+            }
+        }
+
+        Self {
+            vendor,
+            leaf1_edx: leaf1.edx,
+            leaf7_ebx,
+            ext1_edx,
+            logical_cores,
+            threads_per_core,
+            cache_line_size,
+        }
+    }
+
+    pub fn has_apic(&self) -> bool {
+        self.leaf1_edx & LEAF1_EDX_APIC != 0
+    }
+
+    pub fn has_sse2(&self) -> bool {
+        self.leaf1_edx & LEAF1_EDX_SSE2 != 0
+    }
+
+    pub fn has_avx2(&self) -> bool {
+        self.leaf7_ebx & LEAF7_EBX_AVX2 != 0
+    }
+
+    pub fn has_nx(&self) -> bool {
+        self.ext1_edx & EXT1_EDX_NX != 0
+    }
+
+    /// Number of logical cores (hardware threads) per package.
+    pub fn logical_core_count(&self) -> u32 {
+        self.logical_cores
+    }
+
+    /// Number of physical cores per package.
+    pub fn physical_core_count(&self) -> u32 {
+        (self.logical_cores / self.threads_per_core).max(1)
+    }
+
+    /// Size of a cache line in bytes, or 0 if the CPU does not report it.
+    pub fn cache_line_size_bytes(&self) -> u32 {
+        self.cache_line_size
+    }
+
+    /// The vendor identification string, e.g. `GenuineIntel` or `AuthenticAMD`.
+    pub fn vendor_string(&self) -> [u8; 12] {
+        self.vendor
+    }
+}
+
+/// Returns the initial APIC ID of the calling core, which is available before its Local APIC is enabled.
+pub fn initial_apic_id() -> u8 {
+    (unsafe{__cpuid(1)}.ebx >> 24) as u8
+}
+
+static FEATURES: OnceLock<CpuFeatures> = OnceLock::new();
+
+/// Detects the features of the boot core. Has to be called before [`features()`] is used.
+pub fn init() {
+    let features = CpuFeatures::detect();
+    info!("CPUID", "{}, {} logical / {} physical cores, {} byte cache lines",
+        core::str::from_utf8(&features.vendor_string()).unwrap_or("unknown vendor"),
+        features.logical_core_count(), features.physical_core_count(), features.cache_line_size_bytes());
+    verbose!("CPUID", "SSE2: {}, AVX2: {}, NX: {}", features.has_sse2(), features.has_avx2(), features.has_nx());
+
+    FEATURES.init(features);
+}
+
+/// Returns the features detected by [`init()`].
+pub fn features() -> &'static CpuFeatures {
+    FEATURES.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_host() {
+        let features = CpuFeatures::detect();
+
+        // SSE2 is part of the x86_64 baseline.
+        assert!(features.has_sse2());
+        assert!(features.vendor_string().iter().all(|c| c.is_ascii()));
+        assert!(features.physical_core_count() >= 1);
+        assert!(features.logical_core_count() >= features.physical_core_count());
+    }
+}
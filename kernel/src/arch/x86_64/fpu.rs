@@ -0,0 +1,72 @@
+/// CR0.MP (Monitor Coprocessor): makes WAIT/FWAIT respect the TS flag.
+const CR0_MP: u64 = 1 << 1;
+/// CR0.EM (Emulation): if set, every x87/SSE instruction raises #UD.
+const CR0_EM: u64 = 1 << 2;
+/// CR4.OSFXSR: signals that the OS supports FXSAVE/FXRSTOR, enables SSE instructions.
+const CR4_OSFXSR: u64 = 1 << 9;
+
+/// Memory image of the x87/MMX/SSE register state as written by `fxsave`.
+///
+/// `fxsave` and `fxrstor` fault if the buffer is not 16-byte aligned.
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+/// Offset of the x87 control word in [`FpuState`].
+const FCW_OFFSET: usize = 0;
+/// Offset of the MXCSR register in [`FpuState`].
+const MXCSR_OFFSET: usize = 24;
+/// x87 control word after `finit`: every exception masked, double extended precision.
+const FCW_DEFAULT: u16 = 0x037F;
+/// MXCSR after reset: every SSE exception masked, round to nearest.
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+impl FpuState {
+    /// Returns the state of a freshly initialized FPU, with every exception masked.
+    pub fn new() -> Self {
+        let mut data = [0; 512];
+        data[FCW_OFFSET..FCW_OFFSET + 2].copy_from_slice(&FCW_DEFAULT.to_le_bytes());
+        data[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+        Self(data)
+    }
+}
+
+/// Enables the FPU and SSE on the current core and resets the x87 FPU.
+///
+/// Has to be called once on every core, as CR0 and CR4 are core-local.
+pub fn init_fpu() {
+    let mut cr0: u64;
+    let mut cr4: u64;
+    unsafe{asm!(
+        "mov {0}, cr0",
+        "mov {1}, cr4",
+        out(reg) cr0,
+        out(reg) cr4,
+    )};
+
+    cr0 = (cr0 & !CR0_EM) | CR0_MP;
+    cr4 |= CR4_OSFXSR;
+
+    unsafe{asm!(
+        "mov cr0, {0}",
+        "mov cr4, {1}",
+        "finit",
+        in(reg) cr0,
+        in(reg) cr4,
+    )};
+}
+
+/// Saves the FPU/SSE state of the current core into `dest`.
+pub fn save_fpu_state(dest: &mut FpuState) {
+    unsafe{asm!(
+        "fxsave [{}]",
+        in(reg) dest as *mut FpuState,
+    )};
+}
+
+/// Loads the FPU/SSE state of the current core from `src`.
+pub fn restore_fpu_state(src: &FpuState) {
+    unsafe{asm!(
+        "fxrstor [{}]",
+        in(reg) src as *const FpuState,
+    )};
+}
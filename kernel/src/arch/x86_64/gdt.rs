@@ -1,4 +1,5 @@
 use crate::memory;
+use common_structures::config::INTERRUPT_STACK_SIZE;
 use core::mem::size_of;
 use core::ptr::null_mut;
 
@@ -11,6 +12,8 @@ use core::ptr::null_mut;
 
     Since a program will never need to change CS, DS, ES and SS while running, we only ever need to change those values
     through IRET (used when switching processes), which does not check any rules for SS, meaning we don't need any user data descriptors.
+    The only exception is SYSRET, which loads SS from a fixed offset relative to the user code selector, so a user data
+    descriptor (SELECTOR_USER_DATA) is provided anyways. SYSRET expects it directly in front of the user code descriptor.
 
     The following selectors will be used:
     - Kernel Mode:
@@ -27,81 +30,98 @@ use core::ptr::null_mut;
 
 pub const SELECTOR_NULL: u16 = 0;
 pub const SELECTOR_KERNEL_CODE: u16 = 8;
-pub const SELECTOR_USER_CODE: u16 = 16 | 3;
+pub const SELECTOR_USER_DATA: u16 = 16 | 3;
+pub const SELECTOR_USER_CODE: u16 = 24 | 3;
 
-/// Pointer to the Task State Segment, which is mainly used to determine which stack should
+/// Number of GDT slots in front of the TSS entries.
+const NUM_FIXED_ENTRIES: usize = 4;
+
+/// Maximum number of cores that can have their own GDT and TSS.
+pub const MAX_CORES: usize = 64;
+
+/// Pointers to the per-core Task State Segments, which are mainly used to determine which stack should
 /// be used for interrupts.
-static mut TSS: *mut Tss = null_mut();
+static mut TSS: [*mut Tss; MAX_CORES] = [null_mut(); MAX_CORES];
+/// The entries every core's GDT starts with. Only used as a template by [`init_core`].
 static mut GDT: *mut GDTEntry = null_mut();
 
-pub fn init(num_cores: usize) {
+pub fn init() {
     info!("GDT", "Initializing...");
 
-    let num_tss_entries = num_cores;
-    let num_gdt_pages = ((3 + num_tss_entries * 2) * size_of::<GDTEntry>() + 4095) / 4096;
-
-    let mem = memory::phys_to_virt::<GDTEntry>(memory::phys_manager().alloc_linear_pages(num_gdt_pages as u64));
-    verbose!("GDT", "GDT at {:#016X} ({} entries, {} pages)", mem as u64, 3 + num_tss_entries, num_gdt_pages);
-
-    let tss_mem = memory::phys_to_virt::<Tss>(memory::phys_manager().alloc_linear_pages(((num_tss_entries * size_of::<Tss>() + 4095) / 4096) as u64));
+    let mem = memory::phys_to_virt::<GDTEntry>(memory::phys_manager().alloc_page());
+    verbose!("GDT", "GDT template at {:#016X} ({} entries)", mem as u64, NUM_FIXED_ENTRIES);
 
     unsafe {
         mem.offset(0).write(GDTEntry::null());
         mem.offset(1).write(GDTEntry::new_code(false));
-        mem.offset(2).write(GDTEntry::new_code(true));
-
-        for i in 0..num_cores {
-            let tss_ptr = unsafe{tss_mem.offset(i as isize)};
-
-            // The TSS needs an entry in the GDT that points to the actual TSS memory.
-            // This entry takes up two GDT entry slots.
-            let tss_entry = GDTEntryTSS {
-                limit0: size_of::<Tss>() as u16 - 1,
-                base0: tss_ptr as u16,
-                base1: ((tss_ptr as u64) >> 16) as u8,
-                type_dpl_p: 0b10001001,
-                limi1: 0,
-                base2: ((tss_ptr as u64) >> 24) as u8,
-                base3: ((tss_ptr as u64) >> 32) as u32,
-                reserved: 0,
-            };
-            (mem.offset(3 + i as isize) as *mut GDTEntryTSS).write(tss_entry);
-
-            let tss = Tss {
-                reserved0: 0,
-                rsp0: 0,
-                rsp1: 0,
-                rsp2: 0,
-                reserved1: 0,
-                ist1: 0,
-                ist2: 0,
-                ist3: 0,
-                ist4: 0,
-                ist5: 0,
-                ist6: 0,
-                ist7: 0,
-                reserved2: 0,
-                reserved3: 0,
-            };
-            tss_mem.write(tss);
-        }
-
-        TSS = tss_mem;
+        mem.offset(2).write(GDTEntry::new_data(true));
+        mem.offset(3).write(GDTEntry::new_code(true));
+
         GDT = mem;
     }
 
     info!("GDT", "Initialized");
 }
 
+/// Creates and loads the GDT and TSS of the calling core.
+/// 
+/// Every core needs its own TSS, as the interrupt stacks differ per core.
+/// Since the TSS descriptor lives in the GDT and is marked busy by LTR, every core gets its own GDT as well.
 pub fn init_core(core_id: usize) {
-    let limit = (5 + 2 * core_id as u16) * 8 - 1;
+    assert!(core_id < MAX_CORES, "Core id {} exceeds the maximum of {} cores", core_id, MAX_CORES);
+
+    let mem = memory::phys_to_virt::<GDTEntry>(memory::phys_manager().alloc_page());
+    let tss_ptr = memory::phys_to_virt::<Tss>(memory::phys_manager().alloc_page());
+
+    // Double faults run on their own stack, as the normal interrupt stack might be the cause of the double fault.
+    // Every core gets a separate stack of INTERRUPT_STACK_SIZE bytes.
+    let double_fault_stack = memory::phys_to_virt::<u8>(memory::phys_manager().alloc_linear_pages(INTERRUPT_STACK_SIZE as u64 / 4096)) as u64;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(GDT, mem, NUM_FIXED_ENTRIES);
+
+        // The TSS needs an entry in the GDT that points to the actual TSS memory.
+        // This entry takes up two GDT entry slots.
+        let tss_entry = GDTEntryTSS {
+            limit0: size_of::<Tss>() as u16 - 1,
+            base0: tss_ptr as u16,
+            base1: ((tss_ptr as u64) >> 16) as u8,
+            type_dpl_p: 0b10001001,
+            limi1: 0,
+            base2: ((tss_ptr as u64) >> 24) as u8,
+            base3: ((tss_ptr as u64) >> 32) as u32,
+            reserved: 0,
+        };
+        (mem.offset(NUM_FIXED_ENTRIES as isize) as *mut GDTEntryTSS).write(tss_entry);
+
+        let tss = Tss {
+            reserved0: 0,
+            rsp0: 0,
+            rsp1: 0,
+            rsp2: 0,
+            reserved1: 0,
+            ist1: 0,
+            ist2: double_fault_stack + INTERRUPT_STACK_SIZE as u64,
+            ist3: 0,
+            ist4: 0,
+            ist5: 0,
+            ist6: 0,
+            ist7: 0,
+            reserved2: 0,
+            reserved3: 0,
+        };
+        tss_ptr.write(tss);
+
+        TSS[core_id] = tss_ptr;
+    }
+    verbose!("GDT", "Core {}: GDT at {:#016X}, TSS at {:#016X}", core_id, mem as u64, tss_ptr as u64);
 
     // This structure is used by LGDT.
     // base + limit is the last *accessible* byte in the GDT, so
     // it has to be one less than the *size*.
     let desc = Gdtr {
-        base: unsafe{GDT} as u64,
-        limit,
+        base: mem as u64,
+        limit: ((NUM_FIXED_ENTRIES + 2) * size_of::<GDTEntry>() - 1) as u16,
     };
     unsafe{asm!(
         "lgdt [{desc}]",            // use the newly created GDT
@@ -125,14 +145,24 @@ pub fn init_core(core_id: usize) {
     unsafe{asm!(
         "ltr {sel:x}",              // Load the selector for the GDT entry that describes the location of the TSS.
                                     // Why this indirection is needed is beyond me.
-        sel=in(reg) (3 + core_id * 2) * 8,
+        sel=in(reg) NUM_FIXED_ENTRIES * size_of::<GDTEntry>(),
     )};
 }
 
+/// Sets the address of the stack that is used when switching from user mode to kernel mode.
+/// 
+/// Has to be updated on every switch to a thread with a different kernel stack.
+#[inline]
+pub fn set_rsp0(core_id: usize, val: u64) {
+    unsafe {
+        (*TSS[core_id]).rsp0 = val;
+    }
+}
+
 /// Sets the address of the stack used for most interrupts.
 pub fn set_ist1(core_id: usize, val: u64) {
     unsafe {
-        (*TSS.offset(core_id as isize)).ist1 = val;
+        (*TSS[core_id]).ist1 = val;
     }
 }
 
@@ -157,6 +187,15 @@ impl GDTEntry {
 
         Self{_data}
     }
+    fn new_data(user_mode: bool) -> Self {
+        let _data = if user_mode {
+            DESC_DATA_BASE | DESC_USER_DPL
+        } else {
+            DESC_DATA_BASE
+        };
+
+        Self{_data}
+    }
     fn null() -> Self {
         Self{_data: 0}
     }
@@ -165,6 +204,9 @@ impl GDTEntry {
 /// L and P set
 const DESC_CODE_BASE: u64 = (1 << 43) | (1 << 44) | (1 << 47) | (1 << 53);
 
+/// W, S and P set
+const DESC_DATA_BASE: u64 = (1 << 41) | (1 << 44) | (1 << 47);
+
 const DESC_USER_DPL: u64 = 3 << 45;
 
 
@@ -0,0 +1,138 @@
+//! Handlers for the CPU exceptions (vectors 0-31).
+
+use super::{HandlerResult, InterruptInfo, set_isr_handler};
+
+/// Interrupt vector of the Double Fault, which runs on IST2.
+pub const VECTOR_DOUBLE_FAULT: u8 = 8;
+/// Interrupt vector of the Page Fault.
+const VECTOR_PAGE_FAULT: u8 = 14;
+
+/// How an exception is handled.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Execution can continue after the instruction that caused the exception, just log it.
+    Trap,
+    /// The faulting instruction cannot continue, so the kernel panics.
+    Fault,
+}
+
+/// How the error code pushed by an exception has to be interpreted.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ErrorCode {
+    /// No error code is pushed, or it is always zero.
+    None,
+    /// The error code references a segment selector.
+    Selector,
+    /// The error code of a page fault.
+    PageFault,
+}
+
+/// Every defined exception with its vector, name, kind and error code type.
+const EXCEPTIONS: &[(u8, &str, Kind, ErrorCode)] = &[
+    (0, "Divide Error", Kind::Fault, ErrorCode::None),
+    (1, "Debug", Kind::Trap, ErrorCode::None),
+    (2, "Non-Maskable Interrupt", Kind::Trap, ErrorCode::None),
+    (3, "Breakpoint", Kind::Trap, ErrorCode::None),
+    (4, "Overflow", Kind::Trap, ErrorCode::None),
+    (5, "BOUND Range Exceeded", Kind::Fault, ErrorCode::None),
+    (6, "Invalid Opcode", Kind::Fault, ErrorCode::None),
+    (7, "Device Not Available", Kind::Fault, ErrorCode::None),
+    (8, "Double Fault", Kind::Fault, ErrorCode::None),
+    (9, "Coprocessor Segment Overrun", Kind::Fault, ErrorCode::None),
+    (10, "Invalid TSS", Kind::Fault, ErrorCode::Selector),
+    (11, "Segment Not Present", Kind::Fault, ErrorCode::Selector),
+    (12, "Stack Fault", Kind::Fault, ErrorCode::Selector),
+    (13, "General Protection Fault", Kind::Fault, ErrorCode::Selector),
+    (14, "Page Fault", Kind::Fault, ErrorCode::PageFault),
+    (16, "x87 Floating-Point Exception", Kind::Fault, ErrorCode::None),
+    (17, "Alignment Check", Kind::Fault, ErrorCode::None),
+    (18, "Machine Check", Kind::Fault, ErrorCode::None),
+    (19, "SIMD Floating-Point Exception", Kind::Fault, ErrorCode::None),
+    (20, "Virtualization Exception", Kind::Fault, ErrorCode::None),
+];
+
+/// Installs [`exception_handler()`] for every defined exception, 
+/// page faults are handled by [`page_fault_handler()`].
+pub fn init_exception_handlers() {
+    for &(vector, _, _, _) in EXCEPTIONS {
+        set_isr_handler(vector, exception_handler);
+    }
+    set_isr_handler(VECTOR_DOUBLE_FAULT, double_fault_handler);
+    set_isr_handler(VECTOR_PAGE_FAULT, page_fault_handler);
+}
+
+/// A double fault cannot be recovered from, so just print a message and halt.
+/// 
+/// Only the boot core is running for now, so there are no other cores to stop.
+pub fn double_fault_handler(info: &mut InterruptInfo) -> HandlerResult {
+    error!("Exception", "Double Fault at {:#016X}, halting", info.rip);
+
+    loop {
+        unsafe{asm!(
+            "cli",
+            "hlt",
+        )};
+    }
+}
+
+/// Prints the faulting address from CR2 and the decoded error code, then panics.
+pub fn page_fault_handler(info: &mut InterruptInfo) -> HandlerResult {
+    let cr2: u64;
+    unsafe{asm!(
+        "mov {}, cr2",
+        out(reg) cr2,
+    )};
+
+    error!("Exception", "Page Fault accessing {:#016X} at {:#016X}", cr2, info.rip);
+    print_page_fault_error_code(info.error_code);
+
+    panic!("Unhandled Page Fault at {:#016X}", cr2);
+}
+
+/// Prints the name and error code of the exception that occurred.
+/// 
+/// Traps are only logged, every other exception results in a kernel panic.
+pub fn exception_handler(info: &mut InterruptInfo) -> HandlerResult {
+    let (_, name, kind, error_code) = match EXCEPTIONS.iter().find(|e| e.0 as u64 == info.int_number) {
+        Some(e) => *e,
+        None => panic!("Unknown exception {:#02X}", info.int_number),
+    };
+
+    if kind == Kind::Trap {
+        warning!("Exception", "{} at {:#016X}", name, info.rip);
+        return HandlerResult::Resume;
+    }
+
+    error!("Exception", "{} at {:#016X}", name, info.rip);
+    match error_code {
+        ErrorCode::None => {}
+        ErrorCode::Selector => print_selector_error_code(info.error_code),
+        ErrorCode::PageFault => print_page_fault_error_code(info.error_code),
+    }
+
+    panic!("Unhandled CPU exception: {}", name);
+}
+
+fn print_selector_error_code(error_code: u64) {
+    // bit 0: the exception was caused by an external event,
+    // bit 1: the index references the IDT, else bit 2 decides between GDT and LDT.
+    let table = if error_code & 0b10 != 0 {
+        "IDT"
+    } else if error_code & 0b100 != 0 {
+        "LDT"
+    } else {
+        "GDT"
+    };
+
+    error!("Exception", "Error code {:#X}: {} index {}{}", error_code, table, (error_code >> 3) & 0x1FFF, if error_code & 1 != 0 { ", external" } else { "" });
+}
+
+fn print_page_fault_error_code(error_code: u64) {
+    error!("Exception", "Error code {:#X}: {}, {}, {}{}",
+        error_code,
+        if error_code & 0b1 != 0 { "protection violation" } else { "page not present" },
+        if error_code & 0b10 != 0 { "write" } else { "read" },
+        if error_code & 0b100 != 0 { "user mode" } else { "kernel mode" },
+        if error_code & 0b1_0000 != 0 { ", instruction fetch" } else { "" }
+    );
+}
@@ -1,17 +1,39 @@
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use crate::{arch::gdt, memory};
+use common_structures::config::INTERRUPT_STACK_SIZE;
+
+use crate::{arch::{gdt, percpu}, memory};
+
+mod exceptions;
+pub use exceptions::init_exception_handlers;
+mod msr_breakpoint;
+pub use msr_breakpoint::{msr_watchpoint_init, register_msr_breakpoint};
+mod spurious;
+pub use spurious::spurious_filter_init;
 
 /// Pointer to the low-level Interrupt Descriptor Table.
 static mut IDT: *mut IDTEntry = null_mut();
 /// Array of high-level handlers that are called for the respective interrupts.
-static mut HANDLERS: [fn (&mut InterruptInfo); 256] = [isr_default_handler; 256];
+static mut HANDLERS: [fn (&mut InterruptInfo) -> HandlerResult; 256] = [isr_default_handler; 256];
+/// Number of times every interrupt has fired since boot, summed up over all cores.
+static COUNTS: [AtomicU64; 256] = [ZERO_COUNT; 256];
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Called for handlers returning [`HandlerResult::SwitchTask`], see [`set_task_switch_handler()`].
+static mut TASK_SWITCH_HANDLER: Option<fn(&mut InterruptInfo)> = None;
+/// Copy of the [`InterruptInfo`] of the interrupt that is currently handled, printed by the panic handler.
+/// 
+/// Shared by every core, if several cores handle interrupts at once, it holds the one that started last.
+static mut LAST_INTERRUPT_INFO: Option<InterruptInfo> = None;
 
 pub fn init() {
     info!("IDT", "Initializing...");
 
     // Allocate 256 * 16 bytes for the IDT, exactly one page.
-    let idt = memory::phys_to_virt::<IDTEntry>(memory::phys_manager().alloc_page());
+    // The page is only leaked once the IDT has been filled out, so it gets freed again on failure.
+    let idt_page = memory::alloc_page_guarded();
+    let idt = memory::phys_to_virt::<IDTEntry>(idt_page.addr());
     unsafe {
         idt.write_bytes(0, 4096);
         IDT = idt;
@@ -30,16 +52,40 @@ pub fn init() {
     // So for every possible interrupt number, the respective stub will be registered to the IDT.
     include!("set_isrs.rs");
 
+    // The double fault handler uses the separate stack set up by gdt::init_core().
+    unsafe {
+        (*IDT.offset(exceptions::VECTOR_DOUBLE_FAULT as isize)).ist = 2;
+    }
+
+    idt_page.leak();
+
+    init_exception_handlers();
+    msr_watchpoint_init();
+    spurious_filter_init();
+
     info!("IDT", "Initialized...");
 }
 
+/// Initializes interrupt handling on the calling core.
+/// 
+/// Has to be called on every core after [`gdt::init_core`], as it writes the core's TSS.
+/// The IDT register is core-local, so every core has to execute its own LIDT.
+/// The IDT itself however is shared: it is only written during [`init`], before any other core
+/// is started, and never freed. Every entry only names a stub and an IST index, which each core
+/// resolves through its *own* TSS, so no entry refers to per-core state.
 pub fn init_core(core_id: usize) {
     // Allocate a 16KB interrupt stack that will be used by every interrupt.
     // This ensures that every interrupt has 16 KB stack space in every situation,
     // but also makes nested interrupts impossible, since the two interrupts would corrupt each others
     // stack space.
-    let int_stack = memory::phys_to_virt::<u8>(memory::phys_manager().alloc_linear_pages(4)) as u64;
-    gdt::set_ist1(core_id, int_stack + 4 * 4096);
+    // The lowest page is used as a guard page to catch stack overflows.
+    let int_stack = memory::alloc_linear_pages_guarded(INTERRUPT_STACK_SIZE as u64 / 4096 + 1);
+    let int_stack_base = memory::phys_to_virt::<u8>(int_stack.addr()) as u64 + 4096;
+    memory::guard_page(int_stack_base);
+    let int_stack_top = int_stack_base + INTERRUPT_STACK_SIZE as u64;
+    gdt::set_ist1(core_id, int_stack_top);
+    // Until the first thread is started, privilege level changes use the interrupt stack as well.
+    gdt::set_rsp0(core_id, int_stack_top);
 
     unsafe {
         let idt_desc = IDTDesc {
@@ -47,22 +93,85 @@ pub fn init_core(core_id: usize) {
             address: IDT as u64,
         };
         asm!(
-            "lidt [{idt_desc}]",                    // use the newly created IDT.
+            "lidt [{idt_desc}]",                    // use the shared IDT on this core.
             idt_desc=in(reg) &idt_desc as *const _,
         );
     }
+
+    // The stack is now in use by the processor.
+    int_stack.leak();
+}
+
+/// RFLAGS.IF: if set, maskable interrupts are enabled.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Disables maskable interrupts on the current core.
+pub fn disable() {
+    unsafe{asm!("cli")};
+}
+
+/// Enables maskable interrupts on the current core.
+pub fn enable() {
+    unsafe{asm!("sti")};
+}
+
+/// Saves RFLAGS and disables maskable interrupts on the current core. Returns the saved RFLAGS.
+pub fn save_flags_and_disable() -> u64 {
+    let rflags: u64;
+    unsafe{asm!(
+        "pushfq",
+        "pop {}",
+        "cli",
+        out(reg) rflags,
+    )};
+    rflags
+}
+
+/// Restores RFLAGS returned by [`save_flags_and_disable()`], which enables interrupts again if they were enabled before.
+pub fn restore_flags(rflags: u64) {
+    unsafe{asm!(
+        "push {}",
+        "popfq",
+        in(reg) rflags,
+    )};
+}
+
+/// Returns whether maskable interrupts are enabled on the current core.
+pub fn are_enabled() -> bool {
+    let rflags: u64;
+    unsafe{asm!(
+        "pushfq",
+        "pop {}",
+        out(reg) rflags,
+    )};
+    rflags & RFLAGS_IF != 0
 }
 
 /// Sets the low-level stub for a given interrupt index. 
 /// This function should only ever be used on IDT initialization, 
 /// as the required low-level code is always the same.
 fn set_idt_entry(index: u8, handler: extern "C" fn()) {
+    write_idt_entry(index, handler, IDT_TYPE_KERNEL);
+}
+
+/// Same as [`set_idt_entry`], but the interrupt can also be fired from user mode via the INT instruction,
+/// which is needed for software traps like syscalls.
+fn set_idt_entry_user(index: u8, handler: extern "C" fn()) {
+    write_idt_entry(index, handler, IDT_TYPE_USER);
+}
+
+/// Present, DPL=0, 64-bit interrupt gate.
+const IDT_TYPE_KERNEL: u8 = 0b10001110;
+/// Present, DPL=3, 64-bit interrupt gate.
+const IDT_TYPE_USER: u8 = 0b11101110;
+
+fn write_idt_entry(index: u8, handler: extern "C" fn(), type_dpl_p: u8) {
     unsafe {
         IDT.offset(index as isize).write(IDTEntry {
             offset_low: handler as usize as u16,
             target_selector: gdt::SELECTOR_KERNEL_CODE,
             ist: 1,
-            type_dpl_p: 0b10001110,
+            type_dpl_p,
             offset_mid: ((handler as usize) >> 16) as u16,
             offset_high: ((handler as usize) >> 32) as u32,
             reserved: 0,
@@ -70,23 +179,101 @@ fn set_idt_entry(index: u8, handler: extern "C" fn()) {
     }
 }
 
+/// Returns the low-level stub that is currently registered for the given interrupt index.
+fn get_idt_entry(index: u8) -> extern "C" fn() {
+    unsafe {
+        let entry = &*IDT.offset(index as isize);
+        let addr = entry.offset_low as usize | (entry.offset_mid as usize) << 16 | (entry.offset_high as usize) << 32;
+        core::mem::transmute::<usize, extern "C" fn()>(addr)
+    }
+}
+
 /// Sets the high-level interrupt handler for a given interrupt index.
-pub fn set_isr_handler(index: u8, handler: fn(&mut InterruptInfo)) {
+/// 
+/// Returns the previously installed handler, so that the new handler can pass on interrupts it does not handle itself.
+pub fn set_isr_handler(index: u8, handler: fn(&mut InterruptInfo) -> HandlerResult) -> fn(&mut InterruptInfo) -> HandlerResult {
+    unsafe {
+        core::mem::replace(&mut HANDLERS[index as usize], handler)
+    }
+}
+
+/// Sets the high-level interrupt handler for a given interrupt index and allows user mode code
+/// to fire the interrupt via the INT instruction.
+/// 
+/// Returns the previously installed handler, like [`set_isr_handler`].
+pub fn set_isr_user_handler(index: u8, handler: fn(&mut InterruptInfo) -> HandlerResult) -> fn(&mut InterruptInfo) -> HandlerResult {
+    // The stub stays the same, only the privilege level of the entry changes.
+    set_idt_entry_user(index, get_idt_entry(index));
+    set_isr_handler(index, handler)
+}
+
+/// Returns how often the given interrupt has fired since boot.
+pub fn get_irq_count(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Prints how often every interrupt has fired since boot, skipping interrupts that never fired.
+pub fn print_irq_stats() {
+    info!("IDT", "Interrupt statistics:");
+    for (vector, count) in COUNTS.iter().enumerate() {
+        let count = count.load(Ordering::Relaxed);
+        if count != 0 {
+            info!("IDT", "  {:#04X}: {}", vector, count);
+        }
+    }
+}
+
+/// Sets the handler that switches tasks when a high-level handler returns [`HandlerResult::SwitchTask`].
+/// 
+/// It runs after the high-level handler, right before the registers in the [`InterruptInfo`] are restored,
+/// so it can resume a different task by replacing them.
+pub fn set_task_switch_handler(handler: fn(&mut InterruptInfo)) {
     unsafe {
-        HANDLERS[index as usize] = handler;
+        TASK_SWITCH_HANDLER = Some(handler);
     }
 }
 
 /// The default high-level interrupt handler. Just prints out a warning and returns.
-fn isr_default_handler(info: &mut InterruptInfo) {
+fn isr_default_handler(info: &mut InterruptInfo) -> HandlerResult {
     warning!("IDT", "Interrupt {:#02X} occured and no handler installed", info.int_number);
+    HandlerResult::Resume
 }
 
 /// The common interrupt handler entry point that will be called by the 
 /// low-level stubs.
-extern "sysv64" fn isr_common_handler(info: &mut InterruptInfo) {
+/// 
+/// Returns the result of the high-level handler to [`isr_common_stub`].
+extern "sysv64" fn isr_common_handler(info: &mut InterruptInfo) -> HandlerResult {
+    COUNTS[info.int_number as usize].fetch_add(1, Ordering::Relaxed);
+
+    // An exception inside of a handler has to restore the info of the interrupt it interrupted.
+    let prev_info = unsafe{LAST_INTERRUPT_INFO.replace(*info)};
+
+    let cpu = percpu::current_cpu();
+    cpu.enter_interrupt();
+    let result = unsafe {
+        HANDLERS[info.int_number as usize](info)
+    };
+    cpu.leave_interrupt();
+
+    unsafe {
+        LAST_INTERRUPT_INFO = prev_info;
+    }
+    result
+}
+
+/// Called by [`isr_common_stub`] if the high-level handler returned [`HandlerResult::SwitchTask`].
+extern "sysv64" fn switch_task_impl(info: &mut InterruptInfo) {
+    match unsafe{TASK_SWITCH_HANDLER} {
+        Some(handler) => handler(info),
+        None => warning!("IDT", "Interrupt {:#02X} requested a task switch, but there is no task switch handler", info.int_number),
+    }
+}
+
+/// Returns the registers of the interrupt that is currently handled, or `None` outside of interrupt handlers.
+pub fn last_interrupt_info() -> Option<InterruptInfo> {
     unsafe {
-        HANDLERS[info.int_number as usize](info);
+        LAST_INTERRUPT_INFO
     }
 }
 
@@ -116,9 +303,21 @@ struct IDTDesc {
     address: u64,
 }
 
+/// Tells [`isr_common_stub`] what to do after a high-level interrupt handler returned.
+#[repr(u64)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HandlerResult {
+    /// Return to the interrupted code.
+    Resume,
+    /// Call the task switch handler (see [`set_task_switch_handler()`]) first, which may replace the
+    /// registers in the [`InterruptInfo`] to return to a different task.
+    SwitchTask,
+}
+
 /// This structure is passed to the high-level interrupt handlers. It can be modified by those handlers
 /// to change the processor state that will be set when returning.
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct InterruptInfo {
     r15: u64,
     r14: u64,
@@ -148,6 +347,83 @@ pub struct InterruptInfo {
     ss: u64,
 }
 
+/// RFLAGS.TF: if set, a Debug exception fires after every instruction.
+const RFLAGS_TF: u64 = 1 << 8;
+
+impl InterruptInfo {
+    /// Returns rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp and r8 to r15, in the order used by debuggers.
+    pub fn get_registers(&self) -> [u64; 16] {
+        [
+            self.rax, self.rbx, self.rcx, self.rdx, self.rsi, self.rdi, self.rbp, self.rsp,
+            self.r8, self.r9, self.r10, self.r11, self.r12, self.r13, self.r14, self.r15,
+        ]
+    }
+
+    /// Sets the registers returned by [`InterruptInfo::get_registers()`].
+    pub fn set_registers(&mut self, regs: &[u64; 16]) {
+        self.rax = regs[0];
+        self.rbx = regs[1];
+        self.rcx = regs[2];
+        self.rdx = regs[3];
+        self.rsi = regs[4];
+        self.rdi = regs[5];
+        self.rbp = regs[6];
+        self.rsp = regs[7];
+        self.r8 = regs[8];
+        self.r9 = regs[9];
+        self.r10 = regs[10];
+        self.r11 = regs[11];
+        self.r12 = regs[12];
+        self.r13 = regs[13];
+        self.r14 = regs[14];
+        self.r15 = regs[15];
+    }
+
+    pub fn get_rip(&self) -> u64 {
+        self.rip
+    }
+
+    pub fn set_rip(&mut self, rip: u64) {
+        self.rip = rip;
+    }
+
+    pub fn get_rflags(&self) -> u64 {
+        self.rflags
+    }
+
+    pub fn get_int_number(&self) -> u64 {
+        self.int_number
+    }
+
+    pub fn get_error_code(&self) -> u64 {
+        self.error_code
+    }
+
+    pub fn get_cs(&self) -> u64 {
+        self.cs
+    }
+
+    pub fn get_ss(&self) -> u64 {
+        self.ss
+    }
+
+    /// Sets the arithmetic and direction flags of RFLAGS. System flags like IF cannot be changed.
+    pub fn set_status_flags(&mut self, rflags: u64) {
+        // CF, PF, AF, ZF, SF, DF and OF.
+        const STATUS_FLAGS: u64 = 0b1100_1101_0101;
+        self.rflags = (self.rflags & !STATUS_FLAGS) | (rflags & STATUS_FLAGS);
+    }
+
+    /// Enables or disables single stepping: if enabled, a Debug exception fires after the next instruction.
+    pub fn set_single_step(&mut self, enabled: bool) {
+        if enabled {
+            self.rflags |= RFLAGS_TF;
+        } else {
+            self.rflags &= !RFLAGS_TF;
+        }
+    }
+}
+
 /// The common stub code for every low-level interrupt handler.
 #[naked]
 extern "C" fn isr_common_stub() {
@@ -183,6 +459,14 @@ extern "C" fn isr_common_stub() {
         // Call the common high-level handler
         "call {common}",
 
+        // The SystemV ABI returns the HandlerResult in rax.
+        // The task switch has to happen before the registers are restored from InterruptInfo.
+        "cmp rax, {switch_task}",
+        "jne 2f",
+        "lea rdi, [rsp + 8]",
+        "call {switch_task_impl}",
+        "2:",
+
         // restore the pre-interrupt processor state.
         "add rsp, 8",
         "pop r15",
@@ -207,6 +491,8 @@ extern "C" fn isr_common_stub() {
         "iretq",
 
         common = sym isr_common_handler,
+        switch_task = const HandlerResult::SwitchTask as u64,
+        switch_task_impl = sym switch_task_impl,
 
         options(noreturn)
     )};
@@ -248,3 +534,84 @@ macro_rules! isr {
 // This file cannot be the same as the one used in init() because rusts macro system
 // is very limited.
 include!("isrs.rs");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Vector that is not used by the kernel itself.
+    const TEST_VECTOR: u8 = 0xF0;
+
+    static mut CALLS: [bool; 2] = [false; 2];
+    static mut PREVIOUS: fn(&mut InterruptInfo) -> HandlerResult = isr_default_handler;
+
+    fn first_handler(_info: &mut InterruptInfo) -> HandlerResult {
+        unsafe {
+            CALLS[0] = true;
+        }
+        HandlerResult::SwitchTask
+    }
+
+    fn chained_handler(info: &mut InterruptInfo) -> HandlerResult {
+        unsafe {
+            CALLS[1] = true;
+            PREVIOUS(info)
+        }
+    }
+
+    fn empty_handler(_info: &mut InterruptInfo) -> HandlerResult {
+        HandlerResult::Resume
+    }
+
+    #[test]
+    fn count_irqs() {
+        const COUNT_VECTOR: u8 = 0xF1;
+
+        let mut info = InterruptInfo {
+            r15: 0, r14: 0, r13: 0, r12: 0, r11: 0, r10: 0, r9: 0, r8: 0,
+            rbp: 0, rdi: 0, rsi: 0, rdx: 0, rcx: 0, rbx: 0, rax: 0,
+            int_number: COUNT_VECTOR as u64,
+            error_code: 0,
+            rip: 0, cs: 0, rflags: 0, rsp: 0, ss: 0,
+        };
+
+        set_isr_handler(COUNT_VECTOR, empty_handler);
+        assert_eq!(get_irq_count(COUNT_VECTOR), 0);
+
+        isr_common_handler(&mut info);
+        isr_common_handler(&mut info);
+        assert_eq!(get_irq_count(COUNT_VECTOR), 2);
+    }
+
+    #[test]
+    fn chain_handlers() {
+        let mut info = InterruptInfo {
+            r15: 0, r14: 0, r13: 0, r12: 0, r11: 0, r10: 0, r9: 0, r8: 0,
+            rbp: 0, rdi: 0, rsi: 0, rdx: 0, rcx: 0, rbx: 0, rax: 0,
+            int_number: TEST_VECTOR as u64,
+            error_code: 0,
+            rip: 0, cs: 0, rflags: 0, rsp: 0, ss: 0,
+        };
+
+        set_isr_handler(TEST_VECTOR, first_handler);
+        unsafe {
+            PREVIOUS = set_isr_handler(TEST_VECTOR, chained_handler);
+        }
+
+        // The result of the chained handler has to reach the stub.
+        assert_eq!(isr_common_handler(&mut info), HandlerResult::SwitchTask);
+
+        unsafe {
+            assert!(CALLS[0] && CALLS[1]);
+        }
+
+        // Removing the chained handler has to return it again.
+        unsafe {
+            CALLS = [false; 2];
+        }
+        set_isr_handler(TEST_VECTOR, isr_default_handler)(&mut info);
+        unsafe {
+            assert!(CALLS[0] && CALLS[1]);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! Software breakpoints triggered by writing to a non-existent "debug MSR".
+//! 
+//! A `wrmsr` to [`DEBUG_MSR`] raises a #GP, since the MSR does not exist. The #GP handler installed by
+//! [`msr_watchpoint_init()`] recognizes this case, calls the registered breakpoint handler and skips the
+//! `wrmsr` instruction. This is useful where INT3 is already consumed by a different debugger layer.
+
+use super::{HandlerResult, InterruptInfo, isr_default_handler, set_isr_handler};
+
+/// The MSR number that triggers a breakpoint when written to.
+pub const DEBUG_MSR: u32 = 0xDEAD;
+
+/// Interrupt vector of the General Protection Fault.
+const VECTOR_GP: u8 = 13;
+
+/// Encoding of the `wrmsr` instruction.
+const WRMSR_OPCODE: [u8; 2] = [0x0F, 0x30];
+
+/// The #GP handler that was installed before [`gp_handler()`], called for every other #GP.
+static mut PREVIOUS_GP_HANDLER: fn(&mut InterruptInfo) -> HandlerResult = isr_default_handler;
+
+/// Handler that is called when a breakpoint is hit, if any.
+static mut BREAKPOINT_HANDLER: Option<fn(&mut InterruptInfo) -> HandlerResult> = None;
+
+/// Installs the #GP handler that detects writes to [`DEBUG_MSR`].
+pub fn msr_watchpoint_init() {
+    unsafe {
+        PREVIOUS_GP_HANDLER = set_isr_handler(VECTOR_GP, gp_handler);
+    }
+}
+
+/// Sets the handler that is called when a breakpoint is triggered via [`DEBUG_MSR`].
+pub fn register_msr_breakpoint(handler: fn(&mut InterruptInfo) -> HandlerResult) {
+    unsafe {
+        BREAKPOINT_HANDLER = Some(handler);
+    }
+}
+
+fn gp_handler(info: &mut InterruptInfo) -> HandlerResult {
+    // wrmsr only uses the lower 32 bits of rcx.
+    let is_breakpoint = info.rcx as u32 == DEBUG_MSR && unsafe{(info.rip as *const [u8; 2]).read_unaligned()} == WRMSR_OPCODE;
+
+    match unsafe{BREAKPOINT_HANDLER} {
+        Some(handler) if is_breakpoint => {
+            let result = handler(info);
+            // resume after the wrmsr instruction.
+            info.rip += WRMSR_OPCODE.len() as u64;
+            result
+        }
+        _ => unsafe{PREVIOUS_GP_HANDLER(info)},
+    }
+}
@@ -0,0 +1,74 @@
+//! Filtering of spurious interrupts raised by the legacy 8259A PICs.
+//! 
+//! When an IRQ line is deasserted before the PIC could deliver it, the PIC raises its lowest-priority
+//! IRQ (7 on the master, 15 on the slave) instead. Such an interrupt has no In-Service bit set and
+//! must not be acknowledged with an EOI, as that could acknowledge a different, real interrupt.
+//! Assumes the PICs have been remapped to [`pic::MASTER_OFFSET`] and [`pic::SLAVE_OFFSET`].
+
+use super::{HandlerResult, InterruptInfo, isr_default_handler, set_isr_handler};
+use crate::arch::pic;
+
+/// Interrupt vector of IRQ 7 (master PIC).
+const VECTOR_IRQ7: u8 = pic::MASTER_OFFSET + 7;
+/// Interrupt vector of IRQ 15 (slave PIC).
+const VECTOR_IRQ15: u8 = pic::SLAVE_OFFSET + 7;
+
+/// Handler for real (non-spurious) IRQ 7 interrupts, if any.
+static mut IRQ7_HANDLER: Option<fn(&mut InterruptInfo) -> HandlerResult> = None;
+/// Handler for real (non-spurious) IRQ 15 interrupts, if any.
+static mut IRQ15_HANDLER: Option<fn(&mut InterruptInfo) -> HandlerResult> = None;
+
+/// Installs the filtering handlers for IRQ 7 and IRQ 15.
+pub fn spurious_filter_init() {
+    set_isr_handler(VECTOR_IRQ7, spurious_irq7_handler);
+    set_isr_handler(VECTOR_IRQ15, spurious_irq15_handler);
+}
+
+/// Sets the handler that is called for IRQ 7 interrupts that are not spurious.
+#[allow(dead_code)]
+pub fn set_irq7_handler(handler: fn(&mut InterruptInfo) -> HandlerResult) {
+    unsafe {
+        IRQ7_HANDLER = Some(handler);
+    }
+}
+
+/// Sets the handler that is called for IRQ 15 interrupts that are not spurious.
+#[allow(dead_code)]
+pub fn set_irq15_handler(handler: fn(&mut InterruptInfo) -> HandlerResult) {
+    unsafe {
+        IRQ15_HANDLER = Some(handler);
+    }
+}
+
+fn spurious_irq7_handler(info: &mut InterruptInfo) -> HandlerResult {
+    if pic::get_isr() & (1 << 7) == 0 {
+        verbose!("IDT", "Spurious IRQ 7 ignored");
+        return HandlerResult::Resume;
+    }
+
+    let result = call_handler(unsafe{IRQ7_HANDLER}, info);
+
+    pic::eoi(7);
+    result
+}
+
+fn spurious_irq15_handler(info: &mut InterruptInfo) -> HandlerResult {
+    if pic::get_isr() & (1 << 15) == 0 {
+        verbose!("IDT", "Spurious IRQ 15 ignored");
+        // The master did receive the cascade IRQ 2 from the slave, so it still has to be acknowledged.
+        pic::eoi(2);
+        return HandlerResult::Resume;
+    }
+
+    let result = call_handler(unsafe{IRQ15_HANDLER}, info);
+
+    pic::eoi(15);
+    result
+}
+
+fn call_handler(handler: Option<fn(&mut InterruptInfo) -> HandlerResult>, info: &mut InterruptInfo) -> HandlerResult {
+    match handler {
+        Some(handler) => handler(info),
+        None => isr_default_handler(info),
+    }
+}
@@ -0,0 +1,74 @@
+//! Access to the I/O port address space.
+//! 
+//! # Safety
+//! Every function here is unsafe, as writing to (and for some devices even reading from)
+//! an I/O port can change the state of arbitrary hardware.
+
+/// Reads a byte from `port`.
+pub unsafe fn inb(port: u16) -> u8 {
+    let res: u8;
+    asm!(
+        "in al, dx",
+        in("dx") port,
+        out("al") res,
+    );
+    res
+}
+
+/// Reads a 16-bit word from `port`.
+pub unsafe fn inw(port: u16) -> u16 {
+    let res: u16;
+    asm!(
+        "in ax, dx",
+        in("dx") port,
+        out("ax") res,
+    );
+    res
+}
+
+/// Reads a 32-bit doubleword from `port`.
+pub unsafe fn inl(port: u16) -> u32 {
+    let res: u32;
+    asm!(
+        "in eax, dx",
+        in("dx") port,
+        out("eax") res,
+    );
+    res
+}
+
+/// Writes a byte to `port`.
+pub unsafe fn outb(port: u16, val: u8) {
+    asm!(
+        "out dx, al",
+        in("dx") port,
+        in("al") val,
+    );
+}
+
+/// Writes a 16-bit word to `port`.
+pub unsafe fn outw(port: u16, val: u16) {
+    asm!(
+        "out dx, ax",
+        in("dx") port,
+        in("ax") val,
+    );
+}
+
+/// Writes a 32-bit doubleword to `port`.
+pub unsafe fn outl(port: u16, val: u32) {
+    asm!(
+        "out dx, eax",
+        in("dx") port,
+        in("eax") val,
+    );
+}
+
+/// Waits roughly 1-4 microseconds by writing to the unused port 0x80 (the POST code port).
+/// 
+/// Old devices like the PIC need some time between consecutive commands.
+pub fn io_wait() {
+    unsafe {
+        outb(0x80, 0);
+    }
+}
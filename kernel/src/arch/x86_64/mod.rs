@@ -1,17 +1,40 @@
 
+pub mod apic;
+pub mod context;
+pub mod cpuid;
+pub mod fpu;
 pub mod gdt;
 pub mod interrupt;
+pub mod io;
+pub mod msr;
+pub mod percpu;
+pub mod pic;
+pub mod smp;
+pub mod syscall;
 pub mod virt_manager;
 
 pub fn init_platform() {
-    gdt::init(1);
+    cpuid::init();
+    percpu::init(0);
+    fpu::init_fpu();
+    virt_manager::init_nx();
+
+    gdt::init();
     gdt::init_core(0);
 
+    // The PICs have to be remapped before any IRQ can arrive, their default vectors collide with CPU exceptions.
+    pic::init(pic::MASTER_OFFSET, pic::SLAVE_OFFSET);
     interrupt::init();
     interrupt::init_core(0);
+    apic::init();
 }
 
 pub fn init_secondary_core(core_id: usize) {
+    percpu::init(core_id);
+    fpu::init_fpu();
+    virt_manager::init_nx();
+
     gdt::init_core(core_id);
     interrupt::init_core(core_id);
+    apic::init_core();
 }
@@ -0,0 +1,46 @@
+//! Access to Model Specific Registers.
+
+/// Physical base address and enable bit of the Local APIC.
+pub const IA32_APIC_BASE: u32 = 0x1B;
+/// Extended Feature Enable Register, controls long mode, `syscall` and the No-Execute bit.
+pub const IA32_EFER: u32 = 0xC000_0080;
+/// Segment selectors loaded by `syscall` and `sysret`.
+pub const IA32_STAR: u32 = 0xC000_0081;
+/// Entry point of `syscall` in 64-bit mode.
+pub const IA32_LSTAR: u32 = 0xC000_0082;
+/// RFLAGS bits that are cleared by `syscall`.
+pub const IA32_FMASK: u32 = 0xC000_0084;
+/// Base address of the GS segment.
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+/// GS base that is swapped in by `swapgs`.
+pub const IA32_KERNEL_GSBASE: u32 = 0xC000_0102;
+
+/// Reads the MSR `msr` of the current core.
+/// 
+/// # Safety
+/// Reading an MSR that does not exist raises a General Protection Fault.
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let low: u32;
+    let high: u32;
+    asm!(
+        "rdmsr",
+        in("ecx") msr,
+        out("eax") low,
+        out("edx") high,
+    );
+    (high as u64) << 32 | low as u64
+}
+
+/// Writes `val` to the MSR `msr` of the current core.
+/// 
+/// # Safety
+/// Writing an MSR that does not exist or an invalid value raises a General Protection Fault.
+/// Many MSRs change the behavior of the processor in ways that can break memory safety.
+pub unsafe fn wrmsr(msr: u32, val: u64) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") val as u32,
+        in("edx") (val >> 32) as u32,
+    );
+}
@@ -0,0 +1,104 @@
+//! Data that exists once per core, reachable through the GS segment.
+//!
+//! Every core points IA32_GS_BASE at its own [`PerCpu`], whose first field holds its own address,
+//! so a single `mov reg, gs:0` yields a reference to the current core's data.
+
+use alloc::boxed::Box;
+use core::cell::Cell;
+use core::ptr::null;
+
+use crate::arch::{cpuid, msr};
+use crate::scheduler::Task;
+
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this struct, read by [`current_cpu()`]. Has to be the first field.
+    self_ptr: *const PerCpu,
+    pub apic_id: u8,
+    pub core_id: usize,
+    /// Number of interrupt handlers currently executing on this core.
+    interrupt_depth: Cell<u32>,
+    /// Whether the core is currently running softirq handlers.
+    in_softirq: Cell<bool>,
+    /// The task running on this core, null before the scheduler is initialized.
+    current_task: Cell<*const Task>,
+}
+
+impl PerCpu {
+    fn new(core_id: usize) -> Self {
+        Self {
+            self_ptr: null(),
+            apic_id: cpuid::initial_apic_id(),
+            core_id,
+            interrupt_depth: Cell::new(0),
+            in_softirq: Cell::new(false),
+            current_task: Cell::new(null()),
+        }
+    }
+
+    pub fn interrupt_depth(&self) -> u32 {
+        self.interrupt_depth.get()
+    }
+
+    /// Whether the core is currently executing an interrupt handler.
+    pub fn in_interrupt(&self) -> bool {
+        self.interrupt_depth.get() != 0
+    }
+
+    pub fn enter_interrupt(&self) {
+        self.interrupt_depth.set(self.interrupt_depth.get() + 1);
+    }
+
+    pub fn leave_interrupt(&self) {
+        self.interrupt_depth.set(self.interrupt_depth.get() - 1);
+    }
+
+    pub fn in_softirq(&self) -> bool {
+        self.in_softirq.get()
+    }
+
+    pub fn set_in_softirq(&self, in_softirq: bool) {
+        self.in_softirq.set(in_softirq);
+    }
+
+    pub fn current_task(&self) -> *const Task {
+        self.current_task.get()
+    }
+
+    pub fn set_current_task(&self, task: *const Task) {
+        self.current_task.set(task);
+    }
+}
+
+/// Allocates the [`PerCpu`] of the calling core and points IA32_GS_BASE at it.
+///
+/// Has to be called on every core after the heap was initialized, before anything uses [`current_cpu()`].
+pub fn init(core_id: usize) {
+    let percpu = Box::leak(Box::new(PerCpu::new(core_id)));
+    let ptr = percpu as *const PerCpu;
+    percpu.self_ptr = ptr;
+
+    unsafe {
+        msr::wrmsr(msr::IA32_GS_BASE, ptr as u64);
+    }
+}
+
+/// Returns the [`PerCpu`] of the calling core.
+#[cfg(not(test))]
+pub fn current_cpu() -> &'static PerCpu {
+    let ptr: *const PerCpu;
+    unsafe{asm!(
+        "mov {}, qword ptr gs:[0]",
+        out(reg) ptr
+    )};
+    unsafe{&*ptr}
+}
+
+/// Unit tests run in user mode, where GS cannot be set up, so every test thread gets its own [`PerCpu`].
+#[cfg(test)]
+pub fn current_cpu() -> &'static PerCpu {
+    std::thread_local! {
+        static CPU: &'static PerCpu = Box::leak(Box::new(PerCpu::new(0)));
+    }
+    CPU.with(|cpu| *cpu)
+}
@@ -0,0 +1,105 @@
+//! Driver for the two cascaded legacy 8259A Programmable Interrupt Controllers.
+//! 
+//! After a reset, the PICs deliver IRQ 0-7 at vectors 0x08-0x0F, which collide with CPU exceptions,
+//! so they have to be remapped with [`init()`] before interrupts are enabled.
+
+use crate::arch::io::{inb, io_wait, outb};
+
+/// Vector of IRQ 0 after [`init()`] was called with the kernel's default offsets.
+pub const MASTER_OFFSET: u8 = 0x20;
+/// Vector of IRQ 8 after [`init()`] was called with the kernel's default offsets.
+pub const SLAVE_OFFSET: u8 = 0x28;
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xA0;
+const SLAVE_DATA: u16 = 0xA1;
+
+/// ICW1: start the initialization sequence, ICW4 will be sent.
+const ICW1_INIT_ICW4: u8 = 0x11;
+/// ICW4: 8086 mode.
+const ICW4_8086: u8 = 0x01;
+/// IRQ line of the master PIC the slave PIC is connected to.
+const CASCADE_IRQ: u8 = 2;
+
+/// OCW3 command: the next read from the command port returns the In-Service Register.
+const OCW3_READ_ISR: u8 = 0x0B;
+/// Non-specific End Of Interrupt command.
+const EOI: u8 = 0x20;
+
+/// Remaps IRQ 0-7 to the vectors `master_offset..master_offset + 8` and IRQ 8-15 to `slave_offset..slave_offset + 8`.
+/// 
+/// Every IRQ is masked afterwards, use [`set_irq_mask()`] to enable the ones that have a handler.
+pub fn init(master_offset: u8, slave_offset: u8) {
+    unsafe {
+        outb(MASTER_COMMAND, ICW1_INIT_ICW4);
+        io_wait();
+        outb(SLAVE_COMMAND, ICW1_INIT_ICW4);
+        io_wait();
+
+        // ICW2: vector offsets.
+        outb(MASTER_DATA, master_offset);
+        io_wait();
+        outb(SLAVE_DATA, slave_offset);
+        io_wait();
+
+        // ICW3: the master gets a bitmask of the lines with a slave attached, the slave gets its line number.
+        outb(MASTER_DATA, 1 << CASCADE_IRQ);
+        io_wait();
+        outb(SLAVE_DATA, CASCADE_IRQ);
+        io_wait();
+
+        outb(MASTER_DATA, ICW4_8086);
+        io_wait();
+        outb(SLAVE_DATA, ICW4_8086);
+        io_wait();
+
+        outb(MASTER_DATA, 0xFF);
+        outb(SLAVE_DATA, 0xFF);
+    }
+
+    verbose!("PIC", "IRQs remapped to {:#04X} and {:#04X}", master_offset, slave_offset);
+}
+
+/// Signals the end of the handler for `irq`, so that the PICs can deliver the next interrupt.
+pub fn eoi(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            outb(SLAVE_COMMAND, EOI);
+        }
+        outb(MASTER_COMMAND, EOI);
+    }
+}
+
+/// Masks (disables) or unmasks (enables) the given IRQ.
+/// 
+/// Unmasking an IRQ of the slave PIC also unmasks the cascade line on the master PIC.
+pub fn set_irq_mask(irq: u8, masked: bool) {
+    assert!(irq < 16, "Invalid IRQ {}", irq);
+
+    let (port, bit) = if irq < 8 {
+        (MASTER_DATA, irq)
+    } else {
+        (SLAVE_DATA, irq - 8)
+    };
+
+    unsafe {
+        let mask = inb(port);
+        outb(port, if masked { mask | (1 << bit) } else { mask & !(1 << bit) });
+    }
+
+    if irq >= 8 && !masked {
+        set_irq_mask(CASCADE_IRQ, false);
+    }
+}
+
+/// Returns the In-Service Registers of both PICs, the slave in the upper 8 bits.
+/// 
+/// A set bit means the IRQ is currently being handled and did not receive an EOI yet.
+pub fn get_isr() -> u16 {
+    unsafe {
+        outb(MASTER_COMMAND, OCW3_READ_ISR);
+        outb(SLAVE_COMMAND, OCW3_READ_ISR);
+        (inb(SLAVE_COMMAND) as u16) << 8 | inb(MASTER_COMMAND) as u16
+    }
+}
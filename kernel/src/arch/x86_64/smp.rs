@@ -0,0 +1,314 @@
+//! Startup of the secondary cores via INIT-SIPI-SIPI.
+//!
+//! A Start-Up IPI lets a core begin executing in real mode at a page below 1MB. The trampoline copied to
+//! that page switches directly to long mode, using a temporary page table that identity maps the trampoline
+//! and contains the kernel's higher half, and then jumps to [`secondary_entry()`].
+//!
+//! Once other cores are online, changed mappings have to be invalidated on all of them with [`tlb_shootdown()`].
+
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use crate::arch::{self, apic, msr, percpu, virt_manager};
+use crate::arch::gdt::MAX_CORES;
+use crate::arch::interrupt::{self, HandlerResult, InterruptInfo};
+use crate::arch::virt_manager::{PAGE_PRESENT, PAGE_WRITABLE};
+use crate::drivers::apic_timer::pit_wait;
+use crate::memory::{self, Zone};
+use crate::mutex::{Lock, SpinLock};
+
+/// Physical address the trampoline is copied to. Has to be page aligned and below 1MB.
+const TRAMPOLINE_ADDR: u64 = 0x8000;
+/// Offset of the [`TrampolineData`] in the trampoline page. Has to match `SMP_TRAMPOLINE_DATA` below.
+const TRAMPOLINE_DATA_OFFSET: u64 = 0xF00;
+
+/// Size of the kernel stack of every secondary core in pages.
+const STACK_PAGES: u64 = 4;
+/// Maximum time a core has to report readiness after the Start-Up IPIs, in units of 10ms.
+const STARTUP_TIMEOUT_10MS: u32 = 10;
+
+/// CR4.PAE: has to be set before enabling long mode.
+const CR4_PAE: u64 = 1 << 5;
+/// CR4.LA57: 5-level paging.
+const CR4_LA57: u64 = 1 << 12;
+/// EFER.LME: enables long mode once paging is turned on.
+const EFER_LME: u64 = 1 << 8;
+/// EFER.NXE: the No-Execute bit is used by the kernel's page tables.
+const EFER_NXE: u64 = 1 << 11;
+
+/// Long mode code segment descriptor: present, DPL 0, executable, L set.
+const GDT_KERNEL_CODE: u64 = 0x00AF_9A00_0000_FFFF;
+
+/// Interrupt vector used to ask other cores to invalidate a TLB entry.
+pub const IPI_TLB_SHOOTDOWN: u8 = 0xFE;
+
+/// Values passed to the trampoline, at [`TRAMPOLINE_DATA_OFFSET`] in the trampoline page.
+/// The layout has to match the offsets used in the trampoline code.
+#[repr(C)]
+struct TrampolineData {
+    /// Physical address of the temporary page table, has to be below 4GB.
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    /// Top of the kernel stack of the core.
+    stack: u64,
+    core_id: u64,
+    /// Address of [`secondary_entry()`].
+    entry: u64,
+    /// Null descriptor and 64-bit kernel code descriptor, matching [`crate::arch::gdt::SELECTOR_KERNEL_CODE`].
+    gdt: [u64; 2],
+    /// Limit and 32-bit base of `gdt`, as loaded by LGDT.
+    gdt_ptr: [u16; 3],
+}
+
+/// Number of cores that finished [`secondary_entry()`], not counting the boot core.
+static READY_CORES: AtomicUsize = AtomicUsize::new(0);
+/// Root of the kernel's page table, which every secondary core switches to.
+static mut KERNEL_ROOT: *mut u64 = core::ptr::null_mut();
+
+/// Bit `n` is set once core `n` is ready to receive IPIs. Limits [`MAX_CORES`] to 64.
+static ONLINE_CORES: AtomicU64 = AtomicU64::new(0);
+/// APIC ID of every online core, indexed by core ID.
+static CORE_APIC_IDS: [AtomicU8; MAX_CORES] = [ZERO_U8; MAX_CORES];
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_U8: AtomicU8 = AtomicU8::new(0);
+
+/// Address every core receiving an [`IPI_TLB_SHOOTDOWN`] has to invalidate, indexed by core ID.
+static SHOOTDOWN_ADDR: [AtomicU64; MAX_CORES] = [ZERO_U64; MAX_CORES];
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_U64: AtomicU64 = AtomicU64::new(0);
+/// Number of cores that handled the current [`IPI_TLB_SHOOTDOWN`].
+static SHOOTDOWN_ACKS: AtomicU32 = AtomicU32::new(0);
+/// Only one shootdown can be in flight, as they share [`SHOOTDOWN_ACKS`].
+static SHOOTDOWN_LOCK: SpinLock = SpinLock::new();
+
+// The 16-bit code only uses absolute addresses, as it runs from the copy at SMP_TRAMPOLINE.
+global_asm!(r#"
+.equ SMP_TRAMPOLINE, 0x8000
+.equ SMP_TRAMPOLINE_DATA, 0x8F00
+
+.global smp_trampoline_start
+.global smp_trampoline_end
+
+.code16
+smp_trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+
+    lgdt [SMP_TRAMPOLINE_DATA + 64]
+
+    mov eax, dword ptr [SMP_TRAMPOLINE_DATA + 8]
+    mov cr4, eax
+    mov eax, dword ptr [SMP_TRAMPOLINE_DATA]
+    mov cr3, eax
+
+    mov ecx, 0xC0000080
+    mov eax, dword ptr [SMP_TRAMPOLINE_DATA + 16]
+    xor edx, edx
+    wrmsr
+
+    // Enable protected mode and paging at once, which activates long mode.
+    mov eax, cr0
+    or eax, 0x80000001
+    mov cr0, eax
+
+    // jmp far 0x08:smp_trampoline_64
+    .byte 0x66, 0xEA
+    .long SMP_TRAMPOLINE + (smp_trampoline_64 - smp_trampoline_start)
+    .word 0x08
+
+.code64
+smp_trampoline_64:
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+
+    mov rsp, qword ptr [SMP_TRAMPOLINE_DATA + 24]
+    mov rdi, qword ptr [SMP_TRAMPOLINE_DATA + 32]
+    // Fake return address, so that the stack is aligned like after a CALL.
+    push 0
+    mov rax, qword ptr [SMP_TRAMPOLINE_DATA + 40]
+    jmp rax
+smp_trampoline_end:
+"#);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_end: u8;
+}
+
+/// Starts the cores with the given APIC IDs, except the boot core, and waits until every core
+/// is initialized. Returns the number of cores that started, not counting the boot core.
+///
+/// Has to be called after the platform initialization of the boot core.
+pub fn start_secondary_cpus(apic_ids: &[u8]) -> usize {
+    let boot_apic_id = apic::local_apic().id();
+    interrupt::set_isr_handler(IPI_TLB_SHOOTDOWN, tlb_shootdown_handler);
+    set_online(0, boot_apic_id);
+
+    // The trampoline page and its page table are never freed, as a core that did not respond in time
+    // might still execute the trampoline later.
+    if memory::phys_manager().try_alloc_page_in_range(TRAMPOLINE_ADDR, TRAMPOLINE_ADDR + 4096).is_none() {
+        warning!("SMP", "Trampoline page at {:#X} is not available, secondary cores stay disabled", TRAMPOLINE_ADDR);
+        return 0;
+    }
+    let temp_root = init_trampoline_table();
+    copy_trampoline();
+
+    unsafe {
+        KERNEL_ROOT = virt_manager::active_root();
+    }
+
+    let cr4: u64;
+    unsafe{asm!(
+        "mov {}, cr4",
+        out(reg) cr4
+    )};
+    let efer = unsafe{msr::rdmsr(msr::IA32_EFER)};
+
+    let gdt_addr = TRAMPOLINE_ADDR + TRAMPOLINE_DATA_OFFSET + 48;
+
+    let mut core_id = 1;
+    for &apic_id in apic_ids.iter().filter(|&&id| id != boot_apic_id) {
+        if core_id >= MAX_CORES {
+            warning!("SMP", "More than {} cores, ignoring the rest", MAX_CORES);
+            break;
+        }
+
+        let data = TrampolineData {
+            cr3: temp_root,
+            cr4: cr4 & (CR4_PAE | CR4_LA57),
+            efer: efer & (EFER_LME | EFER_NXE),
+            stack: memory::alloc_stack(STACK_PAGES),
+            core_id: core_id as u64,
+            entry: secondary_entry as u64,
+            gdt: [0, GDT_KERNEL_CODE],
+            gdt_ptr: [15, gdt_addr as u16, (gdt_addr >> 16) as u16],
+        };
+        unsafe {
+            memory::phys_to_virt::<TrampolineData>(TRAMPOLINE_ADDR + TRAMPOLINE_DATA_OFFSET).write_volatile(data);
+        }
+
+        if start_core(apic_id, core_id) {
+            verbose!("SMP", "Core {} (APIC ID {}) started", core_id, apic_id);
+            core_id += 1;
+        } else {
+            warning!("SMP", "Core with APIC ID {} did not respond", apic_id);
+        }
+    }
+
+    let started = READY_CORES.load(Ordering::SeqCst);
+    info!("SMP", "{} secondary cores started", started);
+    started
+}
+
+/// Sends INIT and Start-Up IPIs to the core `apic_id`, and waits until it reports readiness as core `core_id`.
+fn start_core(apic_id: u8, core_id: usize) -> bool {
+    let apic = apic::local_apic();
+
+    apic.send_init(apic_id);
+    pit_wait(10);
+
+    // The second Start-Up IPI is only needed if the core missed the first one.
+    for _ in 0..2 {
+        apic.send_startup(apic_id, (TRAMPOLINE_ADDR / 4096) as u8);
+        for _ in 0..STARTUP_TIMEOUT_10MS {
+            pit_wait(10);
+            if READY_CORES.load(Ordering::SeqCst) >= core_id {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Creates the page table the trampoline uses to enter long mode: the kernel's higher half plus an
+/// identity mapping of the trampoline page. Returns its physical address, which lies below 4GB.
+fn init_trampoline_table() -> u64 {
+    let root_phys = memory::phys_manager().try_alloc_page_in_range(0, Zone::Dma4G.limit())
+        .expect("No page below 4GB left for the SMP trampoline page table");
+    let root = memory::phys_to_virt::<u64>(root_phys);
+    unsafe {
+        // The lower half of the kernel's page table is empty.
+        root.copy_from_nonoverlapping(virt_manager::active_root(), 512);
+    }
+
+    // The trampoline page is executed, so it must not be No-Execute.
+    virt_manager::map_4kb_page(virt_manager::user_pml4(root), TRAMPOLINE_ADDR, TRAMPOLINE_ADDR, PAGE_PRESENT | PAGE_WRITABLE);
+    root_phys
+}
+
+/// Copies the trampoline code to [`TRAMPOLINE_ADDR`].
+fn copy_trampoline() {
+    let start = unsafe{&smp_trampoline_start as *const u8};
+    let end = unsafe{&smp_trampoline_end as *const u8};
+    let size = end as usize - start as usize;
+    assert!(size as u64 <= TRAMPOLINE_DATA_OFFSET, "SMP trampoline overlaps its data");
+
+    unsafe {
+        memory::phys_to_virt::<u8>(TRAMPOLINE_ADDR).copy_from_nonoverlapping(start, size);
+    }
+}
+
+/// Entered by every secondary core from the trampoline, on its own stack.
+extern "C" fn secondary_entry(core_id: u64) -> ! {
+    // The trampoline's page table does not map anything but the kernel and the trampoline.
+    virt_manager::activate_table(unsafe{KERNEL_ROOT});
+
+    arch::init_secondary_core(core_id as usize);
+    set_online(core_id as usize, percpu::current_cpu().apic_id);
+    READY_CORES.fetch_add(1, Ordering::SeqCst);
+
+    // There is no per-core scheduling yet, so the core only waits for interrupts.
+    loop {
+        unsafe{asm!(
+            "sti",
+            "hlt",
+        )};
+    }
+}
+
+/// Marks the core `core_id` as ready to receive IPIs.
+fn set_online(core_id: usize, apic_id: u8) {
+    CORE_APIC_IDS[core_id].store(apic_id, Ordering::SeqCst);
+    ONLINE_CORES.fetch_or(1 << core_id, Ordering::SeqCst);
+}
+
+/// Invalidates the TLB entry of `virt` on every online core, and waits until all of them are done.
+///
+/// Has to be called with interrupts enabled once other cores are online. Otherwise two cores shooting
+/// down at the same time would wait for each other forever.
+pub fn tlb_shootdown(virt: u64) {
+    virt_manager::invlpg(virt);
+
+    // Before the secondary cores are started, the per-core data might not even be set up yet.
+    let online = ONLINE_CORES.load(Ordering::SeqCst);
+    if online.count_ones() <= 1 {
+        return;
+    }
+    let others = online & !(1 << percpu::current_cpu().core_id);
+
+    let _guard = SHOOTDOWN_LOCK.lock();
+    SHOOTDOWN_ACKS.store(0, Ordering::SeqCst);
+    for core_id in (0..MAX_CORES).filter(|&i| others & (1 << i) != 0) {
+        SHOOTDOWN_ADDR[core_id].store(virt, Ordering::SeqCst);
+        apic::send_ipi(CORE_APIC_IDS[core_id].load(Ordering::SeqCst), IPI_TLB_SHOOTDOWN);
+    }
+
+    while SHOOTDOWN_ACKS.load(Ordering::SeqCst) < others.count_ones() {
+        core::hint::spin_loop();
+    }
+}
+
+/// Handler for [`IPI_TLB_SHOOTDOWN`], invalidates the address published for the current core.
+fn tlb_shootdown_handler(_info: &mut InterruptInfo) -> HandlerResult {
+    let core_id = percpu::current_cpu().core_id;
+    virt_manager::invlpg(SHOOTDOWN_ADDR[core_id].load(Ordering::SeqCst));
+    SHOOTDOWN_ACKS.fetch_add(1, Ordering::SeqCst);
+    apic::eoi();
+    HandlerResult::Resume
+}
@@ -0,0 +1,152 @@
+//! Entry and exit of system calls through `syscall` and `sysret`.
+
+use crate::arch::{gdt, msr};
+use crate::memory;
+
+/// EFER bit 0: `syscall` and `sysret` are enabled.
+const EFER_SCE: u64 = 1 << 0;
+
+/// RFLAGS bits cleared on entry: TF, IF, DF and AC.
+/// Interrupts stay disabled until the handler switched to the kernel stack.
+const SYSCALL_RFLAGS_MASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 18);
+
+/// Top of the kernel stack used by system calls.
+static mut KERNEL_RSP: u64 = 0;
+/// User stack pointer of the current system call, only needed until it is pushed onto the kernel stack.
+static mut USER_RSP: u64 = 0;
+
+/// The registers of the calling task, as pushed by [`syscall_entry()`].
+///
+/// The number of the system call is passed in rax, the arguments in rdi, rsi, rdx, r10, r8 and r9,
+/// like on Linux. The result is returned in rax.
+#[repr(C)]
+#[derive(Default)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    /// User RFLAGS, saved in r11 by `syscall`.
+    pub rflags: u64,
+    /// User return address, saved in rcx by `syscall`.
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+/// Sets up the `syscall` instruction on the boot core and allocates the stack the handler runs on.
+///
+/// Only the boot core can execute system calls for now, as every core would need its own stack.
+pub fn init() {
+    set_kernel_stack(memory::alloc_stack(4));
+
+    // syscall loads CS from bits 32-47 and SS from the following selector.
+    // sysret loads SS from bits 48-63 + 8 and CS from bits 48-63 + 16, with RPL 3.
+    let star = (gdt::SELECTOR_KERNEL_CODE as u64) << 32 | ((gdt::SELECTOR_USER_DATA & !3) as u64 - 8) << 48;
+    unsafe {
+        msr::wrmsr(msr::IA32_STAR, star);
+        msr::wrmsr(msr::IA32_LSTAR, syscall_entry as u64);
+        msr::wrmsr(msr::IA32_FMASK, SYSCALL_RFLAGS_MASK);
+        msr::wrmsr(msr::IA32_EFER, msr::rdmsr(msr::IA32_EFER) | EFER_SCE);
+    }
+}
+
+/// Sets the top of the kernel stack system calls run on.
+pub fn set_kernel_stack(top: u64) {
+    unsafe {
+        KERNEL_RSP = top;
+    }
+}
+
+/// Enters user mode with the registers in `frame`, as if returning from a system call.
+/// Every other general purpose register is cleared, so that no kernel data leaks to user mode.
+/// 
+/// # Safety
+/// The active address space has to map `frame.rip` and `frame.rsp` as user accessible.
+pub unsafe fn enter_user(frame: &SyscallFrame) -> ! {
+    asm!(
+        "mov rsp, {frame}",
+        "xor ebx, ebx",
+        "xor ebp, ebp",
+        "xor r11, r11",
+        "xor r12, r12",
+        "xor r13, r13",
+        "xor r14, r14",
+        "xor r15, r15",
+
+        "pop r9",
+        "pop r8",
+        "pop r10",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rax",
+        "pop r11",
+        "pop rcx",
+        "pop rsp",
+
+        "sysretq",
+
+        frame = in(reg) frame as *const SyscallFrame,
+
+        options(noreturn)
+    );
+}
+
+/// Entry point of `syscall`. Saves the user state on the kernel stack, calls [`syscall_handler()`]
+/// and returns to user mode with `sysret`.
+#[naked]
+extern "C" fn syscall_entry() {
+    unsafe{asm!(
+        // syscall does not switch stacks.
+        "mov [rip + {user_rsp}], rsp",
+        "mov rsp, [rip + {kernel_rsp}]",
+
+        // Build the SyscallFrame, in reverse order.
+        "push qword ptr [rip + {user_rsp}]",
+        "push rcx",
+        "push r11",
+        "push rax",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push r10",
+        "push r8",
+        "push r9",
+
+        // syscall loaded SS with the user data selector, which would fail the checks of IRETQ
+        // in the kernel. Use the null selector like everywhere else in the kernel.
+        "xor eax, eax",
+        "mov ss, ax",
+
+        // The kernel stack top and the 10 pushes keep the stack 16-byte aligned for the call.
+        "mov rdi, rsp",
+        "call {handler}",
+
+        "pop r9",
+        "pop r8",
+        "pop r10",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rax",
+        "pop r11",
+        "pop rcx",
+        "pop rsp",
+
+        "sysretq",
+
+        user_rsp = sym USER_RSP,
+        kernel_rsp = sym KERNEL_RSP,
+        handler = sym syscall_handler,
+
+        options(noreturn)
+    )};
+}
+
+extern "C" fn syscall_handler(frame: &mut SyscallFrame) {
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    frame.rax = crate::syscall::dispatch(frame.rax, &args) as u64;
+}
@@ -1,18 +1,409 @@
-use common_structures::PagingInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use common_structures::{PagingInfo, PagingLevel};
+
+use crate::arch::{cpuid, msr, smp};
 use crate::memory::*;
 
+/// CR4.LA57: if set, the processor uses 5-level paging.
+const CR4_LA57: u64 = 1 << 12;
+
+/// Page table entry flag: the entry is valid.
+pub const PAGE_PRESENT: u64 = 1 << 0;
+/// Page table entry flag: the page can be written to.
+pub const PAGE_WRITABLE: u64 = 1 << 1;
+/// Page table entry flag: the page can be accessed from user mode.
+pub const PAGE_USER: u64 = 1 << 2;
+/// Page table entry flag: writes go directly to memory instead of only to the cache.
+pub const PAGE_WRITE_THROUGH: u64 = 1 << 3;
+/// Page table entry flag: the page is not cached. Needed for memory mapped device registers.
+pub const PAGE_CACHE_DISABLE: u64 = 1 << 4;
+/// Page table entry flag: instruction fetches from the page fire a page fault.
+/// Should be passed for every mapping that does not contain code.
+pub const PAGE_NO_EXECUTE: u64 = 1 << 63;
+/// Page table entry flag: the entry maps a 2MB or 1GB page instead of pointing to the next table.
+const PAGE_HUGE: u64 = 1 << 7;
+/// Page table entry flag of a 4KB page: selects the PAT entry together with the caching flags.
+const PAGE_PAT_4KB: u64 = 1 << 7;
+/// Page table entry flag of a 2MB page: selects the PAT entry together with the caching flags.
+const PAGE_PAT_2MB: u64 = 1 << 12;
+/// Bits of a page table entry that hold the physical address.
+const PAGE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// First address of the kernel half of the address space. Everything below belongs to user space.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// EFER.NXE: if set, the processor honors [`PAGE_NO_EXECUTE`].
+const EFER_NXE: u64 = 1 << 11;
+
+/// Whether [`PAGE_NO_EXECUTE`] can be used. If not, the bit is reserved and must not be set.
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
 
 pub fn init(paging_info: &PagingInfo) {
-    let pml4 = paging_info.page_buffer;
-    for i in 0..paging_info.pml4_entries {
-        unsafe{pml4.offset(i as isize).write(0);}
+    let root = paging_info.page_buffer;
+    if paging_info.paging_levels == PagingLevel::Pml5 as u8 {
+        // PML5 entry 0 holds the identity mapping, entry 511 the higher half mirror.
+        unsafe{page_buffer_entry(paging_info, 0).write(0);}
+        verbose!("VirtManager", "PML5 at phys address {:#016X}", virt_to_phys(root));
+    } else {
+        for i in 0..paging_info.pml4_entries {
+            unsafe{page_buffer_entry(paging_info, i).write(0);}
+        }
+        verbose!("VirtManager", "PML4 at phys address {:#016X}", virt_to_phys(root));
+    }
+
+    // CR4.LA57 has to match the layout of the page table. It cannot be toggled in long mode,
+    // the bootloader only selects 5-level paging if the firmware already enabled it.
+    let mut cr4: u64;
+    unsafe{asm!(
+        "mov {}, cr4",
+        out(reg) cr4
+    )};
+    if paging_info.paging_levels == PagingLevel::Pml5 as u8 {
+        cr4 |= CR4_LA57;
+    } else {
+        cr4 &= !CR4_LA57;
     }
-    verbose!("VirtManager", "PML4 at phys address {:#016X}", virt_to_phys(pml4));
 
     let cr3 = virt_to_phys(paging_info.page_buffer);
     unsafe{asm!(
+        "mov cr4, {}",
         "mov cr3, {}",
+        in(reg) cr4,
         in(reg) cr3
     )};
+
+    // Address spaces created by init_user_table() only get a copy of the kernel's PML4 entries, so all of them
+    // have to exist from the start. With 5-level paging, the whole kernel PML4 is shared instead.
+    if paging_info.paging_levels != PagingLevel::Pml5 as u8 {
+        let pml4 = kernel_pml4();
+        for i in 256..512 {
+            get_or_create_table(pml4, i, PAGE_PRESENT | PAGE_WRITABLE);
+        }
+    }
+}
+
+/// Returns a pointer to the `index`th entry of the page table buffer passed by the bootloader.
+fn page_buffer_entry(paging_info: &PagingInfo, index: u64) -> *mut u64 {
+    debug_assert!(index < paging_info.page_buffer_size * 512, "Entry {} is outside of the page table buffer of {} pages", index, paging_info.page_buffer_size);
+    unsafe{paging_info.page_buffer.add(index as usize)}
+}
+
+/// Maps the 4KB page at `virt` to `phys` in the page table `pml4`, using `flags` for the page table entry.
+/// 
+/// Missing intermediate tables are allocated. They are always writable and only accessible from
+/// user mode if [`PAGE_USER`] is given, so the final entry decides the actual permissions.
+/// Does not invalidate any TLB entries, so this should only be used for pages that are not mapped yet.
+/// If the page is part of a 2MB page, the 2MB page is split into 4KB pages first.
+/// 
+/// Callers should pass [`PAGE_NO_EXECUTE`] for every data mapping. It is ignored if the CPU does not support it.
+pub fn map_4kb_page(pml4: *mut u64, virt: u64, phys: u64, flags: u64) {
+    let flags = if NX_ENABLED.load(Ordering::Relaxed) {
+        flags
+    } else {
+        flags & !PAGE_NO_EXECUTE
+    };
+    let table_flags = PAGE_PRESENT | PAGE_WRITABLE | (flags & PAGE_USER);
+
+    let pdp = get_or_create_table(pml4, table_index(virt, 39), table_flags);
+    let pd = get_or_create_table(pdp, table_index(virt, 30), table_flags);
+    split_2mb_page(pd, table_index(virt, 21), virt);
+    let pt = get_or_create_table(pd, table_index(virt, 21), table_flags);
+    unsafe {
+        pt.offset(table_index(virt, 12)).write((phys & PAGE_ADDR_MASK) | flags);
+    }
+}
+
+/// Removes the mapping of the 4KB page at `virt` from the page table `pml4` and invalidates its TLB entry
+/// on every core.
+/// 
+/// Does nothing if the page is not mapped. Page tables that become empty are not freed.
+/// If the page is part of a 2MB page, the 2MB page is split into 4KB pages first.
+pub fn unmap_4kb_page(pml4: *mut u64, virt: u64) {
+    let pd = get_table(pml4, table_index(virt, 39))
+        .and_then(|pdp| get_table(pdp, table_index(virt, 30)));
+    let pt = pd.and_then(|pd| {
+        split_2mb_page(pd, table_index(virt, 21), virt);
+        get_table(pd, table_index(virt, 21))
+    });
+
+    if let Some(pt) = pt {
+        unsafe {
+            pt.offset(table_index(virt, 12)).write(0);
+        }
+        smp::tlb_shootdown(virt);
+    }
+}
+
+/// Returns the PML4 that contains the kernel's higher half mappings in the active page table.
+/// 
+/// With 5-level paging, this is the PML4 referenced by the last PML5 entry.
+pub fn kernel_pml4() -> *mut u64 {
+    let cr3: u64;
+    let cr4: u64;
+    unsafe{asm!(
+        "mov {}, cr3",
+        "mov {}, cr4",
+        out(reg) cr3,
+        out(reg) cr4
+    )};
+
+    let root = phys_to_virt::<u64>(cr3 & PAGE_ADDR_MASK);
+    if cr4 & CR4_LA57 != 0 {
+        get_table(root, 511).expect("PML5 contains no higher half mapping")
+    } else {
+        root
+    }
+}
+
+/// Allocates the root table of a new address space that shares the kernel's higher half mappings
+/// with the active page table and has an empty user half.
+/// 
+/// The kernel's top level entries are copied. [`init()`] allocates all of them up front, so kernel mappings
+/// added later are visible in every address space.
+pub fn init_user_table() -> *mut u64 {
+    let active = active_root();
+
+    let page = phys_manager().alloc_page();
+    let root = phys_to_virt::<u64>(page);
+    unsafe {
+        root.write_bytes(0, 512);
+        if is_la57_enabled() {
+            // The higher half is the last PML5 entry.
+            root.offset(511).write(active.offset(511).read());
+        } else {
+            root.offset(256).copy_from_nonoverlapping(active.offset(256), 256);
+        }
+    }
+    root
+}
+
+/// Returns the PML4 that contains the user half of the address space created by [`init_user_table()`],
+/// to be passed to [`map_4kb_page()`].
+/// 
+/// With 5-level paging, user space is limited to the lowest 256 TB, which are covered by the first PML5 entry.
+pub fn user_pml4(root: *mut u64) -> *mut u64 {
+    if is_la57_enabled() {
+        get_or_create_table(root, 0, PAGE_PRESENT | PAGE_WRITABLE | PAGE_USER)
+    } else {
+        root
+    }
+}
+
+/// Switches to the address space whose root table was created by [`init_user_table()`].
+pub fn activate_table(root: *mut u64) {
+    let cr3 = virt_to_phys(root);
+    unsafe{asm!(
+        "mov cr3, {}",
+        in(reg) cr3
+    )};
+}
+
+/// Returns the root table of the active page table, a PML5 with 5-level paging and a PML4 otherwise.
+pub fn active_root() -> *mut u64 {
+    let cr3: u64;
+    unsafe{asm!(
+        "mov {}, cr3",
+        out(reg) cr3
+    )};
+    phys_to_virt::<u64>(cr3 & PAGE_ADDR_MASK)
+}
+
+fn is_la57_enabled() -> bool {
+    let cr4: u64;
+    unsafe{asm!(
+        "mov {}, cr4",
+        out(reg) cr4
+    )};
+    cr4 & CR4_LA57 != 0
+}
+
+/// Translates `virt` to a physical address by walking the currently active page table.
+/// 
+/// Handles 4KB, 2MB and 1GB pages. Returns `None` if `virt` is not mapped.
+pub fn page_table_walk(virt: u64) -> Option<u64> {
+    let cr3: u64;
+    let cr4: u64;
+    unsafe{asm!(
+        "mov {}, cr3",
+        "mov {}, cr4",
+        out(reg) cr3,
+        out(reg) cr4
+    )};
+
+    let mut table = phys_to_virt::<u64>(cr3 & PAGE_ADDR_MASK);
+    let mut shift = if cr4 & CR4_LA57 != 0 { 48 } else { 39 };
+    loop {
+        let entry = unsafe{table.offset(table_index(virt, shift)).read()};
+        if entry & PAGE_PRESENT == 0 {
+            return None;
+        }
+
+        // The last level always maps a page, PDPT and PD entries only if the huge bit is set.
+        if shift == 12 || (shift <= 30 && entry & PAGE_HUGE != 0) {
+            let page_mask = (1u64 << shift) - 1;
+            return Some((entry & PAGE_ADDR_MASK & !page_mask) | (virt & page_mask));
+        }
+
+        table = phys_to_virt(entry & PAGE_ADDR_MASK);
+        shift -= 9;
+    }
+}
+
+/// Prints every present PML4 and PDP entry of the active page table. Page Directories are only summarized,
+/// as the linear physical memory mapping alone consists of thousands of 2MB pages.
+pub fn print_page_tables() {
+    let root = active_root();
+    if is_la57_enabled() {
+        for i in 0..512 {
+            // PML5 entries always point to a PML4.
+            if let Some(pml4) = get_table(root, i) {
+                print_pml4(pml4, canonical_address((i as u64) << 48, 57));
+            }
+        }
+    } else {
+        print_pml4(root, 0);
+    }
+}
+
+/// Prints the entries of `pml4`, whose first entry maps the virtual address `base`.
+fn print_pml4(pml4: *mut u64, base: u64) {
+    let va_bits = if is_la57_enabled() { 57 } else { 48 };
+    for i in 0..512 {
+        let entry = unsafe{pml4.offset(i).read()};
+        if entry & PAGE_PRESENT == 0 {
+            continue;
+        }
+        let virt = canonical_address(base | ((i as u64) << 39), va_bits);
+        info!("VirtManager", "PML4[{:3}] {:#018X} -> {:#016X} flags {:#X}", i, virt, entry & PAGE_ADDR_MASK, entry & !PAGE_ADDR_MASK);
+
+        let pdp = phys_to_virt::<u64>(entry & PAGE_ADDR_MASK);
+        for j in 0..512 {
+            let entry = unsafe{pdp.offset(j).read()};
+            if entry & PAGE_PRESENT == 0 {
+                continue;
+            }
+            let virt = virt + ((j as u64) << 30);
+            if entry & PAGE_HUGE != 0 {
+                info!("VirtManager", "  PDP[{:3}] {:#018X}: 1GB page at {:#016X}", j, virt, entry & PAGE_ADDR_MASK);
+                continue;
+            }
+
+            let pd = phys_to_virt::<u64>(entry & PAGE_ADDR_MASK);
+            let (mut huge_pages, mut page_tables) = (0, 0);
+            for k in 0..512 {
+                let entry = unsafe{pd.offset(k).read()};
+                if entry & PAGE_PRESENT == 0 {
+                    continue;
+                }
+                if entry & PAGE_HUGE != 0 {
+                    huge_pages += 1;
+                } else {
+                    page_tables += 1;
+                }
+            }
+            info!("VirtManager", "  PDP[{:3}] {:#018X}: {} 2MB pages, {} page tables", j, virt, huge_pages, page_tables);
+        }
+    }
+}
+
+/// Sign extends `addr` from bit `va_bits - 1`, like the processor requires for virtual addresses.
+fn canonical_address(addr: u64, va_bits: u32) -> u64 {
+    (((addr << (64 - va_bits)) as i64) >> (64 - va_bits)) as u64
+}
+
+/// Returns the index into the page table whose entries each cover `1 << shift` bytes.
+fn table_index(virt: u64, shift: u64) -> isize {
+    ((virt >> shift) & 0x1FF) as isize
+}
+
+/// Returns the table referenced by `table[index]`, or `None` if the entry is not present.
+fn get_table(table: *mut u64, index: isize) -> Option<*mut u64> {
+    let entry = unsafe{table.offset(index).read()};
+    if entry & PAGE_PRESENT == 0 {
+        return None;
+    }
+    assert!(entry & PAGE_HUGE == 0, "Tried to use a huge page mapping as a page table");
+
+    Some(phys_to_virt(entry & PAGE_ADDR_MASK))
+}
+
+/// Replaces the 2MB page containing `virt`, mapped by `pd[index]`, with a page table containing the same mapping
+/// in 4KB pages.
+/// 
+/// Does nothing if the entry is not a present 2MB page. The translations do not change, but the TLB entries of the
+/// 2MB page are invalidated on the current core anyway, as the SDM requires for changes of the page size.
+fn split_2mb_page(pd: *mut u64, index: isize, virt: u64) {
+    let entry = unsafe{pd.offset(index).read()};
+    if entry & PAGE_PRESENT == 0 || entry & PAGE_HUGE == 0 {
+        return;
+    }
+
+    let phys = entry & PAGE_ADDR_MASK & !0x1F_FFFF;
+    // A 2MB page keeps its PAT bit in bit 12, a 4KB page in bit 7, where the PD entry has PAGE_HUGE.
+    let mut flags = entry & !PAGE_ADDR_MASK & !PAGE_HUGE;
+    if entry & PAGE_PAT_2MB != 0 {
+        flags |= PAGE_PAT_4KB;
+    }
+
+    let page = phys_manager().alloc_page();
+    let pt = phys_to_virt::<u64>(page);
+    for i in 0..512 {
+        unsafe {
+            pt.offset(i).write((phys + i as u64 * 4096) | flags);
+        }
+    }
+
+    // Like every intermediate table, the new one does not restrict the permissions of its 4KB pages.
+    unsafe {
+        pd.offset(index).write(page | PAGE_PRESENT | PAGE_WRITABLE | (flags & PAGE_USER));
+    }
+
+    let base = virt & !0x1F_FFFF;
+    for i in 0..512 {
+        invlpg(base + i * 4096);
+    }
+}
+
+/// Returns the table referenced by `table[index]`, allocating an empty one if the entry is not present.
+fn get_or_create_table(table: *mut u64, index: isize, flags: u64) -> *mut u64 {
+    if let Some(next) = get_table(table, index) {
+        // An existing table might have been created for kernel mappings only.
+        unsafe {
+            *table.offset(index) |= flags;
+        }
+        return next;
+    }
+
+    let page = phys_manager().alloc_page();
+    let next = phys_to_virt::<u64>(page);
+    unsafe {
+        next.write_bytes(0, 512);
+        table.offset(index).write(page | flags);
+    }
+    next
+}
+
+/// Enables the No-Execute bit on the current core, if supported by the CPU.
+/// 
+/// Has to be called once on every core before any page marked with [`PAGE_NO_EXECUTE`] is accessed, as EFER is core-local.
+/// Has to be called after [`cpuid::init()`].
+pub fn init_nx() {
+    if !cpuid::features().has_nx() {
+        warning!("VirtManager", "CPU does not support the No-Execute bit");
+        return;
+    }
+
+    unsafe {
+        msr::wrmsr(msr::IA32_EFER, msr::rdmsr(msr::IA32_EFER) | EFER_NXE);
+    }
+    NX_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Invalidates the TLB entry of `virt` on the current core.
+pub fn invlpg(virt: u64) {
+    unsafe{asm!(
+        "invlpg [{}]",
+        in(reg) virt
+    )};
 }
@@ -0,0 +1,89 @@
+//! Stack backtraces, found by following the chain of saved frame pointers.
+//!
+//! The kernel is built with `-Cforce-frame-pointers=yes`, so every function starts by pushing RBP and
+//! pointing RBP to the pushed value. `[rbp]` then holds the frame pointer of the caller and `[rbp + 8]`
+//! the return address into the caller.
+
+use crate::memory;
+
+/// Maximum number of frames printed by [`print_backtrace()`].
+const MAX_FRAMES: usize = 32;
+
+/// Iterates over the return addresses of a chain of stack frames, innermost first.
+struct Frames<F: Fn(u64) -> bool> {
+    rbp: u64,
+    /// Returns whether the given address can be read without faulting.
+    readable: F,
+}
+
+impl<F: Fn(u64) -> bool> Iterator for Frames<F> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.rbp == 0 || self.rbp % 8 != 0 || !(self.readable)(self.rbp) || !(self.readable)(self.rbp + 8) {
+            return None;
+        }
+
+        let frame = self.rbp as *const u64;
+        let (caller_rbp, return_addr) = unsafe{(frame.read(), frame.add(1).read())};
+        if return_addr == 0 {
+            return None;
+        }
+        // The stack grows downward, so the frame of the caller is always at a higher address.
+        // Anything else means that the chain is corrupted.
+        self.rbp = if caller_rbp > self.rbp { caller_rbp } else { 0 };
+        Some(return_addr)
+    }
+}
+
+/// Prints the return addresses of the functions that led to the call of this function.
+/// 
+/// The addresses can be resolved with `addr2line` after subtracting the printed address of the entry point
+/// and adding the address of `_start` in the kernel ELF file.
+pub fn print_backtrace() {
+    let rbp: u64;
+    unsafe{asm!(
+        "mov {}, rbp",
+        out(reg) rbp
+    )};
+
+    error!("Backtrace", "Kernel entry point at {:#018X}", crate::_start as usize);
+    let frames = Frames {
+        rbp,
+        readable: |addr| memory::virt_to_phys_safe(addr as *const u8).is_some(),
+    };
+    for (i, return_addr) in frames.take(MAX_FRAMES).enumerate() {
+        error!("Backtrace", "#{:<2} {:#018X}", i, return_addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walk_frames() {
+        // Three frames, each holding the saved frame pointer followed by the return address.
+        // The walk reads the stack through `base`, so it is only ever written through the same pointer.
+        let mut stack = [0u64; 6];
+        let ptr = stack.as_mut_ptr();
+        let base = ptr as u64;
+        let contents = [base + 16, 0x1111, base + 32, 0x2222, 0, 0x3333];
+        unsafe {
+            ptr.copy_from_nonoverlapping(contents.as_ptr(), contents.len());
+        }
+
+        let frames = Frames { rbp: base, readable: |_| true };
+        assert_eq!(frames.collect::<alloc::vec::Vec<_>>(), [0x1111, 0x2222, 0x3333]);
+
+        // A frame pointer pointing downward ends the walk.
+        unsafe {
+            ptr.add(2).write(base);
+        }
+        let frames = Frames { rbp: base, readable: |_| true };
+        assert_eq!(frames.count(), 2);
+
+        let frames = Frames { rbp: base, readable: |addr| addr < base + 16 };
+        assert_eq!(frames.count(), 1);
+    }
+}
@@ -0,0 +1,344 @@
+//! A GDB Remote Serial Protocol stub on the COM1 serial port.
+//!
+//! The stub takes over the serial port whenever a Breakpoint (`int3`), a write to the debug MSR or,
+//! while single stepping, a Debug exception fires, and answers packets until GDB continues or steps.
+//! Connect with `target remote <serial device>` after enabling the stub with `gdb` on the kernel command line.
+//!
+//! Supported packets: `?`, `g`, `G`, `m`, `M`, `c` and `s`. Every other packet gets an empty reply,
+//! which tells GDB that it is not supported.
+
+use crate::arch::interrupt::{HandlerResult, InterruptInfo, register_msr_breakpoint, set_isr_handler};
+use crate::drivers::serial;
+use crate::memory;
+
+/// Interrupt vector of the Debug exception, fired after every instruction while single stepping.
+const VECTOR_DEBUG: u8 = 1;
+/// Interrupt vector of the Breakpoint exception, fired by `int3`.
+const VECTOR_BREAKPOINT: u8 = 3;
+
+/// Maximum size of a packet. Also reported to GDB, so that `m`/`M` requests never exceed it.
+const PACKET_SIZE: usize = 4096;
+
+/// Signal reported to GDB for every stop, SIGTRAP.
+const SIGTRAP: u8 = 5;
+
+/// Number of bytes in a `g` packet: 16 general purpose registers and rip (8 bytes each),
+/// followed by eflags, cs, ss, ds, es, fs and gs (4 bytes each).
+const REGISTERS_SIZE: usize = 17 * 8 + 7 * 4;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Whether GDB resumed execution and is waiting for a stop reply.
+static mut RESUMED: bool = false;
+
+/// Buffer for received packets.
+static mut RX_BUF: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
+/// Buffer for replies. Every byte of memory or registers is sent as two hex digits.
+static mut TX_BUF: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
+
+/// Installs the stub as handler for Breakpoint and Debug exceptions and for MSR breakpoints.
+///
+/// Has to be called after [`crate::drivers::serial::init()`] and the interrupt initialization.
+pub fn init() {
+    if !serial::is_present() {
+        warning!("GDB", "No serial port present, GDB stub disabled");
+        return;
+    }
+
+    set_isr_handler(VECTOR_BREAKPOINT, gdb_handler);
+    set_isr_handler(VECTOR_DEBUG, gdb_handler);
+    register_msr_breakpoint(gdb_handler);
+
+    info!("GDB", "Stub installed on COM1");
+}
+
+/// Stops in the debugger, if [`init()`] was called.
+pub fn breakpoint() {
+    unsafe{asm!("int3")};
+}
+
+/// Talks to GDB until it continues or single steps.
+fn gdb_handler(info: &mut InterruptInfo) -> HandlerResult {
+    info.set_single_step(false);
+
+    // On the first stop, GDB is not connected yet and asks for the stop reason itself.
+    if unsafe{RESUMED} {
+        let mut reply = Reply::new();
+        reply.push_str("S");
+        reply.push_hex(&[SIGTRAP]);
+        reply.send();
+    }
+
+    loop {
+        let packet = receive_packet();
+        let mut reply = Reply::new();
+
+        match packet.first() {
+            Some(b'?') => {
+                reply.push_str("S");
+                reply.push_hex(&[SIGTRAP]);
+            }
+            Some(b'g') => {
+                reply.push_hex(&read_registers(info));
+            }
+            Some(b'G') => {
+                let mut regs = [0u8; REGISTERS_SIZE];
+                if decode_hex(&packet[1..], &mut regs) == Some(REGISTERS_SIZE) {
+                    write_registers(info, &regs);
+                    reply.push_str("OK");
+                } else {
+                    reply.push_str("E01");
+                }
+            }
+            Some(b'm') => {
+                match parse_addr_len(&packet[1..]).filter(|&(_, len, _)| len * 2 <= PACKET_SIZE) {
+                    Some((addr, len, _)) if is_mapped(addr, len) => {
+                        let mem = unsafe{core::slice::from_raw_parts(addr as *const u8, len)};
+                        reply.push_hex(mem);
+                    }
+                    _ => reply.push_str("E14"),
+                }
+            }
+            Some(b'M') => {
+                match parse_addr_len(&packet[1..]) {
+                    Some((addr, len, data)) if is_mapped(addr, len) && data.len() == len * 2 => {
+                        let mem = unsafe{core::slice::from_raw_parts_mut(addr as *mut u8, len)};
+                        decode_hex(data, mem);
+                        reply.push_str("OK");
+                    }
+                    _ => reply.push_str("E14"),
+                }
+            }
+            Some(b'c') | Some(b's') => {
+                // An optional address to resume at follows the command.
+                if let Some(addr) = parse_hex(&packet[1..]) {
+                    info.set_rip(addr);
+                }
+                info.set_single_step(packet[0] == b's');
+                unsafe {
+                    RESUMED = true;
+                }
+                return HandlerResult::Resume;
+            }
+            Some(b'q') if packet.starts_with(b"qSupported") => {
+                reply.push_str("PacketSize=");
+                reply.push_hex(&(PACKET_SIZE as u16).to_be_bytes());
+            }
+            _ => {}
+        }
+
+        reply.send();
+    }
+}
+
+/// Returns the registers in the layout of a `g` packet.
+fn read_registers(info: &InterruptInfo) -> [u8; REGISTERS_SIZE] {
+    let mut res = [0u8; REGISTERS_SIZE];
+
+    let regs = info.get_registers();
+    for (i, reg) in regs.iter().chain(core::iter::once(&info.get_rip())).enumerate() {
+        res[i * 8..(i + 1) * 8].copy_from_slice(&reg.to_le_bytes());
+    }
+
+    // ds, es, fs and gs are not saved, they are always 0 in long mode.
+    let segments = [info.get_rflags() as u32, info.get_cs() as u32, info.get_ss() as u32, 0, 0, 0, 0];
+    for (i, seg) in segments.iter().enumerate() {
+        res[17 * 8 + i * 4..17 * 8 + (i + 1) * 4].copy_from_slice(&seg.to_le_bytes());
+    }
+
+    res
+}
+
+/// Sets the registers from the layout of a `G` packet.
+///
+/// Segment registers and system flags cannot be changed, as returning from the interrupt would fail.
+fn write_registers(info: &mut InterruptInfo, data: &[u8; REGISTERS_SIZE]) {
+    let read_u64 = |i: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&data[i * 8..(i + 1) * 8]);
+        u64::from_le_bytes(bytes)
+    };
+
+    let mut regs = [0u64; 16];
+    for (i, reg) in regs.iter_mut().enumerate() {
+        *reg = read_u64(i);
+    }
+    info.set_registers(&regs);
+    info.set_rip(read_u64(16));
+
+    let mut rflags = [0u8; 4];
+    rflags.copy_from_slice(&data[17 * 8..17 * 8 + 4]);
+    info.set_status_flags(u32::from_le_bytes(rflags) as u64);
+}
+
+/// Whether every page of `addr..addr + len` is mapped, so that accessing it will not fault.
+fn is_mapped(addr: u64, len: usize) -> bool {
+    let end = match addr.checked_add(len as u64) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    let mut page = addr & !4095;
+    while page < end {
+        if memory::virt_to_phys_safe(page as *const u8).is_none() {
+            return false;
+        }
+        page += 4096;
+    }
+    true
+}
+
+/// Receives the next packet with a valid checksum and acknowledges it.
+fn receive_packet() -> &'static [u8] {
+    let buf = unsafe{&mut RX_BUF};
+
+    loop {
+        while serial::read_byte() != b'$' {}
+
+        let mut len = 0;
+        let mut checksum = 0u8;
+        loop {
+            let c = serial::read_byte();
+            if c == b'#' {
+                break;
+            }
+            if len < buf.len() {
+                buf[len] = c;
+                len += 1;
+            }
+            checksum = checksum.wrapping_add(c);
+        }
+
+        let expected = [serial::read_byte(), serial::read_byte()];
+        if parse_hex(&expected) == Some(checksum as u64) && len < buf.len() {
+            serial::write_byte(b'+');
+            return &buf[..len];
+        }
+        serial::write_byte(b'-');
+    }
+}
+
+/// A reply packet that is built in [`TX_BUF`].
+struct Reply {
+    len: usize,
+}
+
+impl Reply {
+    fn new() -> Self {
+        Self {
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, b: u8) {
+        unsafe {
+            TX_BUF[self.len] = b;
+        }
+        self.len += 1;
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for b in s.bytes() {
+            self.push(b);
+        }
+    }
+
+    /// Appends every byte of `data` as two lowercase hex digits.
+    fn push_hex(&mut self, data: &[u8]) {
+        for b in data {
+            self.push(HEX_DIGITS[(b >> 4) as usize]);
+            self.push(HEX_DIGITS[(b & 0xF) as usize]);
+        }
+    }
+
+    /// Sends the packet, repeating it until GDB acknowledges it.
+    fn send(&self) {
+        let data = unsafe{&TX_BUF[..self.len]};
+        let checksum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+
+        loop {
+            serial::write_byte(b'$');
+            for &b in data {
+                serial::write_byte(b);
+            }
+            serial::write_byte(b'#');
+            serial::write_byte(HEX_DIGITS[(checksum >> 4) as usize]);
+            serial::write_byte(HEX_DIGITS[(checksum & 0xF) as usize]);
+
+            if serial::read_byte() == b'+' {
+                return;
+            }
+        }
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parses a big-endian hex number. Returns `None` if `s` is empty or contains anything else.
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter().try_fold(0u64, |val, &c| Some(val << 4 | hex_digit(c)? as u64))
+}
+
+/// Decodes pairs of hex digits in `s` into `out` and returns the number of bytes written,
+/// or `None` if `s` contains anything else.
+fn decode_hex(s: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    for (pair, b) in s.chunks(2).zip(out.iter_mut()) {
+        if pair.len() != 2 {
+            return None;
+        }
+        *b = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+        len += 1;
+    }
+    Some(len)
+}
+
+/// Parses the `addr,len` arguments of `m` and `M` packets, returning the data following a `:` as well.
+fn parse_addr_len(s: &[u8]) -> Option<(u64, usize, &[u8])> {
+    let (args, data) = match s.iter().position(|&c| c == b':') {
+        Some(colon) => (&s[..colon], &s[colon + 1..]),
+        None => (s, &s[s.len()..]),
+    };
+    let comma = args.iter().position(|&c| c == b',')?;
+
+    let addr = parse_hex(&args[..comma])?;
+    let len = parse_hex(args.get(comma + 1..)?)? as usize;
+    Some((addr, len, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse_hex(b"ffff80001234abcd"), Some(0xffff80001234abcd));
+        assert_eq!(parse_hex(b"1F"), Some(0x1F));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"12g4"), None);
+
+        let mut out = [0u8; 4];
+        assert_eq!(decode_hex(b"deadbeef", &mut out), Some(4));
+        assert_eq!(out, [0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(decode_hex(b"abc", &mut out), None);
+    }
+
+    #[test]
+    fn parse_memory_packets() {
+        assert_eq!(parse_addr_len(b"ffff8000,10"), Some((0xffff8000, 0x10, &b""[..])));
+        assert_eq!(parse_addr_len(b"1000,2:abcd"), Some((0x1000, 2, &b"abcd"[..])));
+        assert_eq!(parse_addr_len(b"1000"), None);
+        assert_eq!(parse_addr_len(b"1000,"), None);
+        assert_eq!(parse_addr_len(b"1000:ab,2"), None);
+    }
+}
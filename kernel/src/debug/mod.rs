@@ -0,0 +1,4 @@
+//! Facilities for debugging the kernel itself.
+
+pub mod backtrace;
+pub mod gdb_stub;
@@ -0,0 +1,107 @@
+//! Periodic timer interrupt based on the Local APIC timer.
+//!
+//! The frequency of the APIC timer depends on the bus clock, so it is calibrated against the HPET if present,
+//! or otherwise against the legacy PIT, which always runs at 1.193182 MHz.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::arch::apic;
+use crate::arch::interrupt::{HandlerResult, InterruptInfo, set_isr_handler};
+use crate::arch::io::{inb, outb};
+use crate::drivers::hpet;
+use crate::interrupt::softirq;
+
+/// Frequency of the PIT input clock in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// Duration of the calibration in milliseconds.
+const CALIBRATION_MS: u64 = 10;
+
+/// Data port of PIT channel 2, which is the only channel whose output can be read back.
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Controls the gate input of PIT channel 2 (bit 0) and the speaker (bit 1), bit 5 reads the channel 2 output.
+const PIT_CONTROL: u16 = 0x61;
+/// PIT command: channel 2, low byte followed by high byte, mode 0 (interrupt on terminal count).
+const PIT_CMD_CHANNEL2_ONESHOT: u8 = 0b1011_0000;
+
+/// Handler that is called on every timer tick, if any.
+static mut TICK_HANDLER: Option<fn()> = None;
+/// Number of timer ticks since the timer was started.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the handler that is called on every timer tick.
+pub fn set_tick_handler(handler: fn()) {
+    unsafe {
+        TICK_HANDLER = Some(handler);
+    }
+}
+
+/// Returns the number of timer ticks since [`calibrate_and_start()`] was called.
+pub fn get_ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Measures the frequency of the APIC timer of the current core and starts it in periodic mode,
+/// firing every `period_ms` milliseconds.
+///
+/// Has to be called after the Local APIC and the HPET were initialized.
+pub fn calibrate_and_start(period_ms: u64) {
+    let apic = apic::local_apic();
+
+    apic.start_timer(u32::MAX, false);
+    if hpet::is_present() {
+        hpet::wait_ns(CALIBRATION_MS * 1_000_000);
+    } else {
+        pit_wait(CALIBRATION_MS);
+    }
+    let elapsed = u32::MAX - apic.get_timer_count();
+    apic.stop_timer();
+
+    let ticks_per_ms = elapsed as u64 / CALIBRATION_MS;
+    assert!(ticks_per_ms != 0, "APIC timer calibration failed");
+    let count = (ticks_per_ms * period_ms).min(u32::MAX as u64) as u32;
+
+    set_isr_handler(apic::VECTOR_TIMER, timer_handler);
+    apic.start_timer(count, true);
+
+    info!("APIC Timer", "{} ticks/ms, period {} ms", ticks_per_ms, period_ms);
+}
+
+/// Busy-waits for `ms` milliseconds using PIT channel 2. `ms` has to be less than 55.
+///
+/// Works without interrupts and before the APIC timer is calibrated.
+pub fn pit_wait(ms: u64) {
+    let count = PIT_FREQUENCY * ms / 1000;
+    assert!(count <= 0xFFFF, "PIT wait too long");
+
+    unsafe {
+        // Enable the gate of channel 2, but keep the speaker off.
+        let control = inb(PIT_CONTROL) & !0b11;
+        outb(PIT_CONTROL, control | 0b01);
+
+        outb(PIT_COMMAND, PIT_CMD_CHANNEL2_ONESHOT);
+        outb(PIT_CHANNEL2, count as u8);
+        outb(PIT_CHANNEL2, (count >> 8) as u8);
+
+        // The output goes high once the counter reaches zero.
+        while inb(PIT_CONTROL) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+
+        outb(PIT_CONTROL, control);
+    }
+}
+
+fn timer_handler(_info: &mut InterruptInfo) -> HandlerResult {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+
+    // The EOI has to be sent first, the tick handler might not return to this interrupt for a while.
+    apic::eoi();
+
+    if let Some(handler) = unsafe{TICK_HANDLER} {
+        handler();
+    }
+
+    softirq::run_pending_from_irq();
+    HandlerResult::Resume
+}
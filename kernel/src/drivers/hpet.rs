@@ -0,0 +1,238 @@
+//! Driver for the High Precision Event Timer.
+//!
+//! The HPET has a monotonic main counter running at a fixed frequency, which makes it the most precise clock
+//! source of the platform. Its first comparator can additionally fire periodic interrupts, which are routed
+//! to IRQ 0 of the legacy PIC.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::arch::interrupt::{HandlerResult, InterruptInfo, set_isr_handler};
+use crate::arch::pic;
+use crate::memory;
+use crate::mutex::OnceLock;
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_MAIN_COUNTER: usize = 0x0F0;
+const REG_TIMER0_CONFIG: usize = 0x100;
+const REG_TIMER0_COMPARATOR: usize = 0x108;
+
+/// Capabilities bit 13: the main counter is 64 bits wide.
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+/// Capabilities bit 15: the first two comparators can be routed like the PIT and the RTC.
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+/// Maximum counter period allowed by the specification, 100ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// Configuration bit 0: the main counter runs.
+const CONFIG_ENABLE: u64 = 1 << 0;
+/// Configuration bit 1: comparator 0 fires IRQ 0 and comparator 1 fires IRQ 8.
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+/// Timer configuration bit 2: the comparator fires interrupts.
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+/// Timer configuration bit 3: the comparator fires periodically.
+const TIMER_PERIODIC: u64 = 1 << 3;
+/// Timer capability bit 4: the comparator supports periodic mode.
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+/// Timer configuration bit 6: the next comparator write sets the accumulator of a periodic comparator.
+const TIMER_VAL_SET: u64 = 1 << 6;
+
+/// IRQ line comparator 0 fires in legacy routing mode.
+const TIMER0_IRQ: u8 = 0;
+
+/// Number of periodic interrupts of comparator 0.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Last value of the main counter, extended to 64 bits. See [`read_counter()`].
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The memory mapped registers of the HPET.
+struct Hpet {
+    base: *mut u64,
+    /// Duration of a counter tick in femtoseconds.
+    period_fs: u64,
+    capabilities: u64,
+    /// Bits of the main counter that are implemented, it wraps around after reaching this value.
+    counter_mask: u64,
+}
+
+impl Hpet {
+    fn read(&self, reg: usize) -> u64 {
+        unsafe {
+            self.base.add(reg / 8).read_volatile()
+        }
+    }
+
+    fn write(&self, reg: usize, val: u64) {
+        unsafe {
+            self.base.add(reg / 8).write_volatile(val);
+        }
+    }
+}
+
+// The registers are global hardware, the pointer itself never changes.
+unsafe impl Send for Hpet {}
+unsafe impl Sync for Hpet {}
+
+static HPET: OnceLock<Hpet> = OnceLock::new();
+
+/// Maps the HPET at the physical address `hpet_base_phys` and starts its main counter.
+///
+/// Has to be called after the virtual memory manager was initialized.
+pub fn init(hpet_base_phys: u64) {
+    let base = memory::map_mmio(hpet_base_phys, 1024) as *mut u64;
+    let capabilities = unsafe{base.add(REG_CAPABILITIES / 8).read_volatile()};
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        warning!("HPET", "Invalid counter period of {} fs, HPET disabled", period_fs);
+        return;
+    }
+    let counter_mask = if capabilities & CAP_COUNTER_64BIT != 0 {
+        u64::MAX
+    } else {
+        verbose!("HPET", "Main counter is only 32 bits wide and wraps around after {}s", ticks_to_ns(1 << 32, period_fs) / 1_000_000_000);
+        0xFFFF_FFFF
+    };
+
+    let hpet = Hpet {
+        base,
+        period_fs,
+        capabilities,
+        counter_mask,
+    };
+    hpet.write(REG_CONFIG, 0);
+    hpet.write(REG_MAIN_COUNTER, 0);
+    hpet.write(REG_CONFIG, CONFIG_ENABLE);
+    HPET.init(hpet);
+
+    info!("HPET", "Found at {:#016X}, {} Hz", hpet_base_phys, 1_000_000_000_000_000 / period_fs);
+}
+
+/// Whether [`init()`] found a working HPET.
+pub fn is_present() -> bool {
+    HPET.try_get().is_some()
+}
+
+/// Returns the nanoseconds elapsed since [`init()`], or 0 if there is no HPET.
+pub fn uptime_ns() -> u64 {
+    match HPET.try_get() {
+        Some(hpet) => ticks_to_ns(read_counter(hpet), hpet.period_fs),
+        None => 0,
+    }
+}
+
+/// Busy-waits for `ns` nanoseconds. Works without interrupts, but requires an HPET.
+pub fn wait_ns(ns: u64) {
+    assert!(is_present(), "wait_ns() requires an HPET");
+
+    let start = uptime_ns();
+    while uptime_ns() - start < ns {
+        core::hint::spin_loop();
+    }
+}
+
+/// Lets comparator 0 fire IRQ 0 every `period_ms` milliseconds. Returns false if the HPET
+/// does not support periodic interrupts.
+///
+/// Replaces the PIT as source of IRQ 0.
+pub fn start_periodic(period_ms: u64) -> bool {
+    let hpet = match HPET.try_get() {
+        Some(hpet) => hpet,
+        None => return false,
+    };
+    if hpet.capabilities & CAP_LEGACY_ROUTE == 0 || hpet.read(REG_TIMER0_CONFIG) & TIMER_PERIODIC_CAP == 0 {
+        warning!("HPET", "Comparator 0 does not support periodic interrupts");
+        return false;
+    }
+
+    let period = (period_ms as u128 * 1_000_000_000_000 / hpet.period_fs as u128) as u64;
+
+    set_isr_handler(pic::MASTER_OFFSET + TIMER0_IRQ, timer_handler);
+
+    // The counter has to be stopped while the comparator of a periodic timer is set.
+    let config = hpet.read(REG_CONFIG);
+    hpet.write(REG_CONFIG, config & !CONFIG_ENABLE);
+    let timer_config = hpet.read(REG_TIMER0_CONFIG);
+    hpet.write(REG_TIMER0_CONFIG, timer_config | TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_VAL_SET);
+    // With VAL_SET, the first write sets the time of the first interrupt, the second one the period.
+    hpet.write(REG_TIMER0_COMPARATOR, hpet.read(REG_MAIN_COUNTER) + period);
+    hpet.write(REG_TIMER0_COMPARATOR, period);
+    hpet.write(REG_CONFIG, config | CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+
+    pic::set_irq_mask(TIMER0_IRQ, false);
+    verbose!("HPET", "Comparator 0 fires every {} ms", period_ms);
+    true
+}
+
+/// Returns the number of periodic interrupts since [`start_periodic()`] was called.
+pub fn get_ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the main counter, extended to 64 bits if it is only 32 bits wide.
+/// 
+/// Wraparounds of a 32-bit counter are only detected if it is read at least once per wrap period,
+/// the periodic interrupt of [`start_periodic()`] takes care of that.
+fn read_counter(hpet: &Hpet) -> u64 {
+    let raw = hpet.read(REG_MAIN_COUNTER) & hpet.counter_mask;
+    let counter = extend_counter(COUNTER.load(Ordering::Relaxed), raw, hpet.counter_mask);
+    // Another core might have stored a newer value in the meantime, the counter must never go backwards.
+    COUNTER.fetch_max(counter, Ordering::Relaxed).max(counter)
+}
+
+/// Extends the `raw` counter value, whose implemented bits are `mask`, to 64 bits,
+/// given the `last` extended value.
+fn extend_counter(last: u64, raw: u64, mask: u64) -> u64 {
+    let epoch = last & !mask;
+    let last_raw = last & mask;
+    if raw >= last_raw {
+        epoch | raw
+    } else if last_raw - raw > mask / 2 {
+        // The counter wrapped around since the last read.
+        epoch.wrapping_add(mask).wrapping_add(1) | raw
+    } else {
+        // Read before a newer value that another core already stored.
+        last
+    }
+}
+
+/// Converts `ticks` of a counter with a period of `period_fs` femtoseconds to nanoseconds.
+fn ticks_to_ns(ticks: u64, period_fs: u64) -> u64 {
+    // The product easily exceeds 64 bits after a few hours.
+    (ticks as u128 * period_fs as u128 / 1_000_000) as u64
+}
+
+fn timer_handler(_info: &mut InterruptInfo) -> HandlerResult {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    // Keeps track of the wraparounds of a 32-bit main counter.
+    read_counter(HPET.get());
+    pic::eoi(TIMER0_IRQ);
+    HandlerResult::Resume
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tick_conversion() {
+        // QEMU's HPET runs at 100 MHz.
+        assert_eq!(ticks_to_ns(100_000_000, 10_000_000), 1_000_000_000);
+        // A typical 14.318 MHz HPET, after a day.
+        let day_ticks = 86_400 * 14_318_180;
+        assert_eq!(ticks_to_ns(day_ticks, 69_841_279) / 1_000_000_000, 86_400);
+        assert_eq!(ticks_to_ns(1, 69_841_279), 69);
+    }
+
+    #[test]
+    fn counter_extension() {
+        let mask = 0xFFFF_FFFF;
+        assert_eq!(extend_counter(0x1_0000_1000, 0x2000, mask), 0x1_0000_2000);
+        // Wrapped around.
+        assert_eq!(extend_counter(0x1_FFFF_F000, 0x10, mask), 0x2_0000_0010);
+        // Slightly older than the last value.
+        assert_eq!(extend_counter(0x1_0000_2000, 0x1000, mask), 0x1_0000_2000);
+        // A 64-bit counter is used as is.
+        assert_eq!(extend_counter(0x1234, 0x5678_0000_0000, u64::MAX), 0x5678_0000_0000);
+    }
+}
@@ -0,0 +1,274 @@
+//! Interrupt driven driver for a keyboard on the first port of the PS/2 controller.
+//!
+//! The controller translates every scancode to scancode set 1, in which bit 7 of a scancode
+//! distinguishes key releases (break codes) from key presses (make codes).
+
+use core::cell::UnsafeCell;
+
+use crate::arch::interrupt::{HandlerResult, InterruptInfo, set_isr_handler};
+use crate::arch::io::{inb, outb};
+use crate::arch::pic;
+use crate::mutex::IrqSpinLock;
+
+const DATA_PORT: u16 = 0x60;
+/// Reads return the status register, writes send a command to the controller.
+const STATUS_PORT: u16 = 0x64;
+
+/// Status register bit: a byte can be read from [`DATA_PORT`].
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// Status register bit: the controller has not processed the last written byte yet.
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xA7;
+const CMD_DISABLE_PORT1: u8 = 0xAD;
+const CMD_ENABLE_PORT1: u8 = 0xAE;
+
+/// Configuration bit: IRQ 1 fires when the keyboard sends a byte.
+const CONFIG_PORT1_IRQ: u8 = 1 << 0;
+/// Configuration bit: IRQ 12 fires when the second port sends a byte.
+const CONFIG_PORT2_IRQ: u8 = 1 << 1;
+/// Configuration bit: scancodes are translated to scancode set 1.
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// Keyboard command: start sending scancodes.
+const KBD_ENABLE_SCANNING: u8 = 0xF4;
+
+/// IRQ line of the keyboard.
+const KEYBOARD_IRQ: u8 = 1;
+
+/// Bit of a scancode that marks a break code.
+const SCANCODE_BREAK: u8 = 0x80;
+/// Prefix of the scancodes of the extended keys, like the arrow keys.
+const SCANCODE_EXTENDED: u8 = 0xE0;
+
+/// Maximum number of polls while waiting for the controller, so that a missing controller does not hang the kernel.
+const MAX_POLLS: u32 = 100_000;
+
+/// Keycodes of keys that do not produce an ASCII character. Printable keys use their unshifted character.
+pub const KEY_ESCAPE: u8 = 0x1B;
+pub const KEY_BACKSPACE: u8 = 0x08;
+pub const KEY_LEFT_CTRL: u8 = 0x80;
+pub const KEY_LEFT_SHIFT: u8 = 0x81;
+pub const KEY_RIGHT_SHIFT: u8 = 0x82;
+pub const KEY_LEFT_ALT: u8 = 0x83;
+pub const KEY_CAPS_LOCK: u8 = 0x84;
+
+/// Keycode of every scancode of set 1 on a US-QWERTY keyboard, 0 for keys without a keycode.
+const SCANCODE_TABLE: [u8; 0x3B] = [
+    0, KEY_ESCAPE, b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'0', b'-', b'=', KEY_BACKSPACE, b'\t',
+    b'q', b'w', b'e', b'r', b't', b'y', b'u', b'i', b'o', b'p', b'[', b']', b'\n', KEY_LEFT_CTRL, b'a', b's',
+    b'd', b'f', b'g', b'h', b'j', b'k', b'l', b';', b'\'', b'`', KEY_LEFT_SHIFT, b'\\', b'z', b'x', b'c', b'v',
+    b'b', b'n', b'm', b',', b'.', b'/', KEY_RIGHT_SHIFT, b'*', KEY_LEFT_ALT, b' ', KEY_CAPS_LOCK,
+];
+
+/// Size of the buffer of unread key events. Further events are dropped while it is full.
+const BUFFER_SIZE: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeyEvent {
+    pub keycode: u8,
+    /// Whether the key was pressed or released.
+    pub pressed: bool,
+}
+
+/// Ring buffer of key events that have not been read by [`poll_key()`] yet.
+struct KeyBuffer {
+    events: [KeyEvent; BUFFER_SIZE],
+    /// Index of the oldest event.
+    head: usize,
+    len: usize,
+}
+
+impl KeyBuffer {
+    const fn new() -> Self {
+        Self {
+            events: [KeyEvent { keycode: 0, pressed: false }; BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Appends `event`, returns false if the buffer is full.
+    fn push(&mut self, event: KeyEvent) -> bool {
+        if self.len == BUFFER_SIZE {
+            return false;
+        }
+        self.events[(self.head + self.len) % BUFFER_SIZE] = event;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<KeyEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.head];
+        self.head = (self.head + 1) % BUFFER_SIZE;
+        self.len -= 1;
+        Some(event)
+    }
+}
+
+struct Keyboard {
+    /// Taken by the interrupt handler, so interrupts have to be disabled while it is held.
+    lock: IrqSpinLock,
+    buffer: UnsafeCell<KeyBuffer>,
+    /// Whether the last scancode was [`SCANCODE_EXTENDED`].
+    extended: UnsafeCell<bool>,
+}
+
+// The buffer is only accessed while holding the lock.
+unsafe impl Sync for Keyboard {}
+
+static KEYBOARD: Keyboard = Keyboard {
+    lock: IrqSpinLock::new(),
+    buffer: UnsafeCell::new(KeyBuffer::new()),
+    extended: UnsafeCell::new(false),
+};
+
+/// Enables the keyboard on the first port of the PS/2 controller and unmasks its IRQ.
+///
+/// Has to be called after the platform initialization, as it needs the remapped PIC.
+pub fn init() {
+    unsafe {
+        // Nothing may be sent while the controller is reconfigured.
+        write_command(CMD_DISABLE_PORT1);
+        write_command(CMD_DISABLE_PORT2);
+        if !flush_output() {
+            warning!("Keyboard", "PS/2 controller not responding");
+            return;
+        }
+
+        write_command(CMD_READ_CONFIG);
+        let config = match read_data() {
+            Some(config) => config,
+            None => {
+                warning!("Keyboard", "PS/2 controller not responding");
+                return;
+            }
+        };
+        write_command(CMD_WRITE_CONFIG);
+        write_data((config | CONFIG_PORT1_IRQ | CONFIG_TRANSLATION) & !CONFIG_PORT2_IRQ);
+
+        write_command(CMD_ENABLE_PORT1);
+        write_data(KBD_ENABLE_SCANNING);
+        // The keyboard acknowledges the command, this must not end up in the key buffer.
+        read_data();
+    }
+
+    set_isr_handler(pic::MASTER_OFFSET + KEYBOARD_IRQ, keyboard_handler);
+    pic::set_irq_mask(KEYBOARD_IRQ, false);
+    info!("Keyboard", "Initialized");
+}
+
+/// Returns the oldest key event that has not been read yet, if any.
+pub fn poll_key() -> Option<KeyEvent> {
+    let _guard = KEYBOARD.lock.lock();
+    unsafe{&mut *KEYBOARD.buffer.get()}.pop()
+}
+
+/// Translates a scancode of set 1 to a key event, or `None` if the key has no keycode.
+fn translate(scancode: u8) -> Option<KeyEvent> {
+    let keycode = *SCANCODE_TABLE.get((scancode & !SCANCODE_BREAK) as usize)?;
+    if keycode == 0 {
+        return None;
+    }
+    Some(KeyEvent {
+        keycode,
+        pressed: scancode & SCANCODE_BREAK == 0,
+    })
+}
+
+fn keyboard_handler(_info: &mut InterruptInfo) -> HandlerResult {
+    let scancode = unsafe{inb(DATA_PORT)};
+
+    {
+        let _guard = KEYBOARD.lock.lock();
+        let extended = unsafe{&mut *KEYBOARD.extended.get()};
+        if scancode == SCANCODE_EXTENDED {
+            *extended = true;
+        } else if *extended {
+            // Extended keys are not supported yet, but their second byte must not be taken for a regular key.
+            *extended = false;
+        } else if let Some(event) = translate(scancode) {
+            if !unsafe{&mut *KEYBOARD.buffer.get()}.push(event) {
+                verbose!("Keyboard", "Key buffer full, dropping key {:#04X}", event.keycode);
+            }
+        }
+    }
+
+    pic::eoi(KEYBOARD_IRQ);
+    HandlerResult::Resume
+}
+
+/// Discards every byte waiting in the output buffer. Returns false if it does not become empty,
+/// e.g. because the status port of a missing controller reads as 0xFF.
+unsafe fn flush_output() -> bool {
+    (0..MAX_POLLS).any(|_| {
+        if inb(STATUS_PORT) & STATUS_OUTPUT_FULL == 0 {
+            return true;
+        }
+        inb(DATA_PORT);
+        false
+    })
+}
+
+/// Waits until the controller can accept a byte. Returns false on timeout.
+unsafe fn wait_input_empty() -> bool {
+    (0..MAX_POLLS).any(|_| inb(STATUS_PORT) & STATUS_INPUT_FULL == 0)
+}
+
+unsafe fn write_command(cmd: u8) {
+    wait_input_empty();
+    outb(STATUS_PORT, cmd);
+}
+
+unsafe fn write_data(data: u8) {
+    wait_input_empty();
+    outb(DATA_PORT, data);
+}
+
+/// Waits for a byte from the controller or the keyboard. Returns `None` on timeout.
+unsafe fn read_data() -> Option<u8> {
+    if (0..MAX_POLLS).any(|_| inb(STATUS_PORT) & STATUS_OUTPUT_FULL != 0) {
+        Some(inb(DATA_PORT))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scancodes() {
+        assert_eq!(translate(0x1E), Some(KeyEvent { keycode: b'a', pressed: true }));
+        assert_eq!(translate(0x1E | SCANCODE_BREAK), Some(KeyEvent { keycode: b'a', pressed: false }));
+        assert_eq!(translate(0x1C), Some(KeyEvent { keycode: b'\n', pressed: true }));
+        assert_eq!(translate(0x2A).map(|e| e.keycode), Some(KEY_LEFT_SHIFT));
+        assert_eq!(translate(0), None);
+        assert_eq!(translate(0x7F), None);
+    }
+
+    #[test]
+    fn buffer_wraps() {
+        let mut buffer = KeyBuffer::new();
+        let key = |keycode| KeyEvent { keycode, pressed: true };
+
+        for i in 0..BUFFER_SIZE as u8 {
+            assert!(buffer.push(key(i)));
+        }
+        assert!(!buffer.push(key(0xFF)));
+
+        assert_eq!(buffer.pop(), Some(key(0)));
+        assert!(buffer.push(key(0xFE)));
+        for i in 1..BUFFER_SIZE as u8 {
+            assert_eq!(buffer.pop(), Some(key(i)));
+        }
+        assert_eq!(buffer.pop(), Some(key(0xFE)));
+        assert_eq!(buffer.pop(), None);
+    }
+}
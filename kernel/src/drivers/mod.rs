@@ -0,0 +1,9 @@
+//! Drivers for hardware devices.
+
+pub mod apic_timer;
+pub mod hpet;
+pub mod keyboard;
+pub mod pci;
+#[cfg(any(feature="integration-test", feature="qemu-exit"))]
+pub mod qemu;
+pub mod serial;
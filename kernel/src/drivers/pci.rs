@@ -0,0 +1,116 @@
+//! Enumeration of PCI devices through the legacy configuration space access mechanism.
+//!
+//! The configuration space of every function is reached by writing its address to [`CONFIG_ADDRESS`]
+//! and then reading [`CONFIG_DATA`]. This only covers the first 256 bytes of the configuration space.
+
+use alloc::vec::Vec;
+
+use crate::arch::io::{inl, outl};
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// Configuration address bit 31: the access goes to the configuration space.
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+const REG_VENDOR_DEVICE: u8 = 0x00;
+const REG_CLASS: u8 = 0x08;
+const REG_HEADER_TYPE: u8 = 0x0C;
+
+/// Vendor ID read from functions that do not exist.
+const VENDOR_NONE: u16 = 0xFFFF;
+/// Header type bit 7: the device implements more than one function.
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+}
+
+/// Scans every bus for devices and returns every function found.
+pub fn enumerate() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+
+    for bus in 0..=255 {
+        for device in 0..DEVICES_PER_BUS {
+            // Function 0 has to exist if any function of the device exists.
+            if read_config(bus, device, 0, REG_VENDOR_DEVICE) as u16 == VENDOR_NONE {
+                continue;
+            }
+            let header_type = (read_config(bus, device, 0, REG_HEADER_TYPE) >> 16) as u8;
+            let functions = if header_type & HEADER_MULTIFUNCTION != 0 { FUNCTIONS_PER_DEVICE } else { 1 };
+
+            for function in 0..functions {
+                if let Some(dev) = read_device(bus, device, function) {
+                    verbose!("PCI", "{:02X}:{:02X}.{} vendor {:04X} device {:04X} class {:02X}:{:02X}",
+                        dev.bus, dev.device, dev.function, dev.vendor, dev.device_id, dev.class, dev.subclass);
+                    devices.push(dev);
+                }
+            }
+        }
+    }
+
+    devices
+}
+
+/// Returns the first function with the given class and subclass, e.g. 0x01:0x08 for NVMe controllers.
+pub fn find_by_class(class: u8, subclass: u8) -> Option<PciDevice> {
+    enumerate().into_iter().find(|dev| dev.class == class && dev.subclass == subclass)
+}
+
+/// Reads the identification of the given function, or returns `None` if it does not exist.
+fn read_device(bus: u8, device: u8, function: u8) -> Option<PciDevice> {
+    let ids = read_config(bus, device, function, REG_VENDOR_DEVICE);
+    if ids as u16 == VENDOR_NONE {
+        return None;
+    }
+    let class = read_config(bus, device, function, REG_CLASS);
+
+    Some(PciDevice {
+        bus,
+        device,
+        function,
+        vendor: ids as u16,
+        device_id: (ids >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+    })
+}
+
+/// Reads the 32-bit register at `offset` in the configuration space of the given function.
+fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    unsafe {
+        outl(CONFIG_ADDRESS, config_address(bus, device, function, offset));
+        inl(CONFIG_DATA)
+    }
+}
+
+/// Returns the value for [`CONFIG_ADDRESS`] that selects the register at `offset`, rounded down to 4 bytes.
+fn config_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    CONFIG_ENABLE
+        | (bus as u32) << 16
+        | (device as u32 & 0x1F) << 11
+        | (function as u32 & 0x7) << 8
+        | (offset as u32 & 0xFC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        assert_eq!(config_address(0, 0, 0, 0), 0x8000_0000);
+        assert_eq!(config_address(1, 2, 3, 0x08), 0x8001_1308);
+        assert_eq!(config_address(255, 31, 7, 0xFF), 0x80FF_FFFC);
+    }
+}
@@ -0,0 +1,20 @@
+//! QEMU's `isa-debug-exit` device, which shuts down QEMU with an exit code chosen by the kernel.
+//!
+//! Only available with the `integration-test` or `qemu-exit` feature, as writing to the port
+//! does nothing on real hardware.
+
+use crate::arch::io::outl;
+
+/// I/O port of the device (see `-device isa-debug-exit,iobase=0xf4,iosize=0x04`).
+const QEMU_EXIT_PORT: u16 = 0xF4;
+
+/// Shuts down QEMU, which will exit with the status `(code << 1) | 1`.
+///
+/// Hangs if the kernel does not run in QEMU or the device is missing.
+pub fn qemu_exit(code: u32) -> ! {
+    unsafe {
+        outl(QEMU_EXIT_PORT, code);
+    }
+
+    loop {}
+}
@@ -0,0 +1,127 @@
+//! Minimal polling driver for 16550 compatible serial ports.
+
+use crate::arch::io::{inb, outb};
+
+/// I/O port of the first serial port.
+const COM1: u16 = 0x3F8;
+
+/// Line Status Register bit: a received byte is ready to be read.
+const LSR_DATA_READY: u8 = 0x01;
+/// Line Status Register bit: the transmit buffer is empty.
+const LSR_TRANSMIT_EMPTY: u8 = 0x20;
+
+/// A 16550 UART at a fixed I/O port.
+pub struct Uart16550 {
+    /// First I/O port of the UART's register block.
+    base_port: u16,
+    /// Whether [`Uart16550::init()`] found a working UART.
+    present: bool,
+}
+
+impl Uart16550 {
+    pub const fn new(base_port: u16) -> Self {
+        Self {
+            base_port,
+            present: false,
+        }
+    }
+
+    /// Sets the UART to 115200 baud, 8N1 with FIFOs enabled and checks whether it is actually present.
+    pub fn init(&mut self) -> bool {
+        let port = self.base_port;
+        self.present = unsafe {
+            outb(port + 1, 0x00);   // disable interrupts
+            outb(port + 3, 0x80);   // enable DLAB to set the baud rate divisor
+            outb(port    , 0x01);   // divisor low byte (115200 baud)
+            outb(port + 1, 0x00);   // divisor high byte
+            outb(port + 3, 0x03);   // 8 bits, no parity, one stop bit
+            outb(port + 2, 0xC7);   // enable and clear FIFO
+
+            // Send a byte in loopback mode, a missing port will not echo it back.
+            outb(port + 4, 0x1E);
+            outb(port    , 0xAE);
+            let echo = inb(port);
+            outb(port + 4, 0x0F);   // back to normal operation
+
+            echo == 0xAE
+        };
+
+        self.present
+    }
+
+    /// Whether [`Uart16550::init()`] found a working UART.
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    /// Sends a single byte, blocking until the transmit buffer is empty.
+    /// Does nothing if the UART is not present.
+    pub fn write_byte(&self, b: u8) {
+        if !self.present {
+            return;
+        }
+
+        while unsafe{inb(self.base_port + 5)} & LSR_TRANSMIT_EMPTY == 0 {}
+        unsafe{outb(self.base_port, b)};
+    }
+
+    /// Blocks until a byte is received and returns it.
+    pub fn read_byte(&self) -> u8 {
+        while unsafe{inb(self.base_port + 5)} & LSR_DATA_READY == 0 {}
+        unsafe{inb(self.base_port)}
+    }
+}
+
+impl core::fmt::Write for Uart16550 {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for b in s.bytes() {
+            self.write_byte(b);
+        }
+        Ok(())
+    }
+}
+
+/// The UART at COM1, used for debug output and the shell.
+static mut PORT: Uart16550 = Uart16550::new(COM1);
+
+/// Initializes COM1 and checks whether it is actually present.
+pub fn init() -> bool {
+    let present = stream().init();
+
+    if present {
+        verbose!("Serial", "COM1 initialized");
+    } else {
+        warning!("Serial", "COM1 not present");
+    }
+
+    present
+}
+
+/// Whether [`init()`] found a working serial port.
+pub fn is_present() -> bool {
+    stream().is_present()
+}
+
+/// Sends a single byte to COM1, blocking until the transmit buffer is empty.
+pub fn write_byte(b: u8) {
+    stream().write_byte(b);
+}
+
+/// Blocks until a byte is received on COM1 and returns it.
+pub fn read_byte() -> u8 {
+    stream().read_byte()
+}
+
+/// Sends `s` to COM1. Does nothing before [`init()`] or if no serial port is present.
+#[allow(dead_code)]
+pub fn serial_write_str(s: &str) {
+    for b in s.bytes() {
+        write_byte(b);
+    }
+}
+
+pub fn stream() -> &'static mut Uart16550 {
+    unsafe {
+        &mut PORT
+    }
+}
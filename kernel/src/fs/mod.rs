@@ -0,0 +1,3 @@
+//! Filesystem support.
+
+pub mod vfs;
@@ -0,0 +1,180 @@
+//! Common interface of all filesystem drivers and the global mount table.
+//!
+//! Every filesystem is mounted at an absolute path. [`open()`] picks the mount with the longest matching
+//! path and passes the rest of the path to its root directory, so nested paths are resolved by the
+//! filesystem itself.
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+
+use crate::mutex::RwSpinLock;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VfsError {
+    /// The offset lies beyond the end of the file.
+    InvalidOffset,
+    /// The file cannot be written to.
+    ReadOnly,
+    /// The underlying device reported an error.
+    Io,
+}
+
+/// A file of a mounted filesystem.
+///
+/// Nodes are shared between every caller that opened them, so `write` takes `&self`
+/// and implementations have to synchronize internally.
+pub trait VfsNode: Send + Sync {
+    /// Reads up to `buf.len()` bytes at `offset` and returns the number of bytes read, 0 at the end of the file.
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError>;
+    /// Writes `buf` at `offset` and returns the number of bytes written.
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, VfsError>;
+    /// Size of the file in bytes.
+    fn size(&self) -> u64;
+}
+
+/// Entry returned by [`VfsDir::readdir()`].
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+pub type VfsDirIter = alloc::vec::IntoIter<DirEntry>;
+
+/// A directory of a mounted filesystem.
+pub trait VfsDir: Send + Sync {
+    /// Returns the file at the path `name`, relative to this directory and without a leading '/'.
+    fn lookup(&self, name: &str) -> Option<Arc<dyn VfsNode>>;
+    /// Returns every entry of this directory.
+    fn readdir(&self) -> VfsDirIter;
+}
+
+struct MountTable {
+    lock: RwSpinLock,
+    /// Normalized mount path and root directory of every mounted filesystem.
+    mounts: UnsafeCell<Vec<(String, Box<dyn VfsDir>)>>,
+}
+
+// The mounts are only accessed while holding the lock.
+unsafe impl Sync for MountTable {}
+
+static MOUNTS: MountTable = MountTable {
+    lock: RwSpinLock::new(),
+    mounts: UnsafeCell::new(Vec::new()),
+};
+
+/// Mounts the filesystem with the root directory `fs` at the absolute `path`.
+///
+/// A later mount at the same path hides the earlier one.
+pub fn mount(path: &str, fs: Box<dyn VfsDir>) {
+    assert!(path.starts_with('/'), "Mount path '{}' is not absolute", path);
+    let path = normalize(path);
+
+    let _guard = MOUNTS.lock.write();
+    let mounts = unsafe{&mut *MOUNTS.mounts.get()};
+    mounts.push((path.to_string(), fs));
+    info!("VFS", "Mounted filesystem at /{}", path);
+}
+
+/// Opens the file at the absolute `path`, or returns `None` if it does not exist.
+pub fn open(path: &str) -> Option<Arc<dyn VfsNode>> {
+    let path = normalize(path);
+
+    let _guard = MOUNTS.lock.read();
+    let mounts = unsafe{&*MOUNTS.mounts.get()};
+    // max_by_key() returns the last of several equal mounts, which is the latest one.
+    let (mount_path, root) = mounts.iter()
+        .filter(|(mount_path, _)| relative_path(path, mount_path).is_some())
+        .max_by_key(|(mount_path, _)| mount_path.len())?;
+    root.lookup(relative_path(path, mount_path)?)
+}
+
+/// Strips leading and trailing '/' from `path`, the root directory becomes the empty string.
+fn normalize(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+/// Returns the part of the normalized `path` below the normalized `mount_path`,
+/// or `None` if `path` does not lie below `mount_path`.
+fn relative_path<'a>(path: &'a str, mount_path: &str) -> Option<&'a str> {
+    if mount_path.is_empty() {
+        return Some(path);
+    }
+    let rest = path.strip_prefix(mount_path)?;
+    // "/initrdx" does not lie below "/initrd".
+    if rest.is_empty() {
+        Some(rest)
+    } else {
+        rest.strip_prefix('/')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    struct TestFile(&'static [u8]);
+
+    impl VfsNode for TestFile {
+        fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+            let data = self.0.get(offset as usize..).ok_or(VfsError::InvalidOffset)?;
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok(len)
+        }
+
+        fn write(&self, _offset: u64, _buf: &[u8]) -> Result<usize, VfsError> {
+            Err(VfsError::ReadOnly)
+        }
+
+        fn size(&self) -> u64 {
+            self.0.len() as u64
+        }
+    }
+
+    /// Directory containing a single file, named after its contents.
+    struct TestDir(&'static str);
+
+    impl VfsDir for TestDir {
+        fn lookup(&self, name: &str) -> Option<Arc<dyn VfsNode>> {
+            if name == self.0 {
+                Some(Arc::new(TestFile(self.0.as_bytes())))
+            } else {
+                None
+            }
+        }
+
+        fn readdir(&self) -> VfsDirIter {
+            vec![DirEntry { name: self.0.to_string(), is_dir: false }].into_iter()
+        }
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(relative_path("a/b", ""), Some("a/b"));
+        assert_eq!(relative_path("initrd/a/b", "initrd"), Some("a/b"));
+        assert_eq!(relative_path("initrd", "initrd"), Some(""));
+        assert_eq!(relative_path("initrdx/a", "initrd"), None);
+        assert_eq!(relative_path("a", "initrd"), None);
+    }
+
+    #[test]
+    fn open_longest_mount() {
+        mount("/", Box::new(TestDir("root")));
+        mount("/mnt/", Box::new(TestDir("nested/file")));
+
+        let file = open("/root").unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(file.read(1, &mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"oot");
+        assert_eq!(file.write(0, b"x"), Err(VfsError::ReadOnly));
+
+        assert_eq!(open("/mnt/nested/file").unwrap().size(), 11);
+        // "/mnt" hides the files of "/" below it.
+        assert!(open("/mnt/root").is_none());
+        assert!(open("/missing").is_none());
+    }
+}
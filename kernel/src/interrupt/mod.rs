@@ -1,5 +1,7 @@
 use crate::arch::interrupt as arch;
 
+pub mod softirq;
+
 /// Initializes whatever interrupt mechanism the platform uses.
 /// 
 /// Has to be called after [`crate::memory::init_virt_manager()`] and [`crate::memory::init_phys_manager()`]
@@ -11,3 +13,49 @@ pub fn init() {
 
     info!("IDT", "Initialized...");
 }
+
+/// Disables interrupts on the current core.
+pub fn disable() {
+    arch::disable();
+}
+
+/// Enables interrupts on the current core.
+pub fn enable() {
+    arch::enable();
+}
+
+/// Disables interrupts on the current core and returns the previous state for [`restore_flags()`].
+pub fn save_flags_and_disable() -> u64 {
+    arch::save_flags_and_disable()
+}
+
+/// Restores the interrupt state returned by [`save_flags_and_disable()`].
+pub fn restore_flags(flags: u64) {
+    arch::restore_flags(flags);
+}
+
+/// Returns whether interrupts are enabled on the current core.
+pub fn are_enabled() -> bool {
+    arch::are_enabled()
+}
+
+/// Prints the number of interrupts that occured on every vector since boot.
+pub fn print_irq_stats() {
+    arch::print_irq_stats();
+}
+
+/// Runs `f` with interrupts disabled on the current core.
+/// 
+/// Interrupts are only enabled again afterwards if they were enabled before, 
+/// so calls can be nested.
+pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+    let were_enabled = are_enabled();
+    disable();
+
+    let res = f();
+
+    if were_enabled {
+        enable();
+    }
+    res
+}
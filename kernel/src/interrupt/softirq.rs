@@ -0,0 +1,116 @@
+//! Deferred work that interrupt handlers hand off to run outside of the handler itself.
+//!
+//! Interrupt handlers should return quickly, so they only [`raise()`] a softirq for expensive work.
+//! Pending softirqs run at the end of every timer tick or whenever [`run_pending()`] is called.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::arch::percpu;
+
+/// Number of available softirqs.
+pub const SOFTIRQ_COUNT: usize = 32;
+
+struct SoftirqEntry {
+    pending: AtomicBool,
+    /// The registered `fn()` as an address, 0 if there is none.
+    handler: AtomicUsize,
+}
+
+const EMPTY_ENTRY: SoftirqEntry = SoftirqEntry {
+    pending: AtomicBool::new(false),
+    handler: AtomicUsize::new(0),
+};
+
+static SOFTIRQS: [SoftirqEntry; SOFTIRQ_COUNT] = [EMPTY_ENTRY; SOFTIRQ_COUNT];
+
+/// Sets the handler of the softirq `index`.
+///
+/// Has to be called before the softirq is raised for the first time.
+pub fn register(index: u8, handler: fn()) {
+    assert!((index as usize) < SOFTIRQ_COUNT, "Invalid softirq {}", index);
+    SOFTIRQS[index as usize].handler.store(handler as usize, Ordering::Release);
+}
+
+/// Marks the softirq `index` as pending, its handler runs once on the next call to [`run_pending()`].
+/// Raising it again before that has no effect.
+pub fn raise(index: u8) {
+    assert!((index as usize) < SOFTIRQ_COUNT, "Invalid softirq {}", index);
+    SOFTIRQS[index as usize].pending.store(true, Ordering::Release);
+}
+
+/// Runs the handler of every pending softirq, in order of their index.
+///
+/// The pending flag is cleared before the handler runs, so a softirq raised by its own handler runs again next time.
+pub fn run_pending() {
+    for (index, entry) in SOFTIRQS.iter().enumerate() {
+        if !entry.pending.swap(false, Ordering::Acquire) {
+            continue;
+        }
+        let handler = entry.handler.load(Ordering::Acquire);
+        if handler == 0 {
+            warning!("Softirq", "Softirq {} raised without a handler", index);
+        } else {
+            // Only ever set from a valid fn() by register().
+            let handler = unsafe{core::mem::transmute::<usize, fn()>(handler)};
+            handler();
+        }
+    }
+}
+
+/// Like [`run_pending()`], but does nothing if the current core is already running softirqs,
+/// e.g. when the interrupt arrived during a softirq handler. The remaining softirqs stay pending until then.
+pub fn run_pending_from_irq() {
+    let cpu = percpu::current_cpu();
+    if cpu.in_softirq() {
+        return;
+    }
+
+    cpu.set_in_softirq(true);
+    run_pending();
+    cpu.set_in_softirq(false);
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicU32;
+
+    use super::*;
+
+    // run_pending() runs the softirqs of every thread, so everything is tested in a single test.
+
+    static RUNS: AtomicU32 = AtomicU32::new(0);
+
+    fn count_handler() {
+        RUNS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn nested_handler() {
+        RUNS.fetch_add(10, Ordering::Relaxed);
+        // Simulates a timer tick during the handler.
+        raise(2);
+        run_pending_from_irq();
+    }
+
+    fn raised_handler() {
+        RUNS.fetch_add(100, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn pending_handlers() {
+        register(0, count_handler);
+        raise(0);
+        raise(0);
+        run_pending();
+        assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+        run_pending();
+        assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+
+        // Softirq 2 is raised while softirq 1 runs, it only runs because it comes later in order.
+        register(1, nested_handler);
+        register(2, raised_handler);
+        raise(1);
+        run_pending_from_irq();
+        assert_eq!(RUNS.load(Ordering::Relaxed), 111);
+        assert!(!percpu::current_cpu().in_softirq());
+    }
+}
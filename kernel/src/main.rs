@@ -2,17 +2,57 @@
 #![cfg_attr(not(test), no_main)]
 
 #![feature(maybe_uninit_extra)]
+#![cfg_attr(not(test), feature(alloc_error_handler))]
+#![feature(global_asm)]
 #![feature(asm)]
 #![feature(naked_functions)]
 
-use common_structures::KernelHeader;
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use common_structures::{KERNEL_HEADER_VERSION, KernelHeader};
 
 #[macro_use]
 mod terminal;
 mod mutex;
 mod memory;
+mod acpi;
 mod arch;
 mod interrupt;
+mod drivers;
+mod debug;
+mod fs;
+mod process;
+mod scheduler;
+mod syscall;
+mod shell;
+mod version;
+#[cfg(feature="integration-test")]
+mod test_runner;
+
+/// Interval of the periodic timer interrupt in milliseconds.
+const TIMER_PERIOD_MS: u64 = 10;
+
+/// The kernel command line passed by the bootloader, see [`cmdline()`].
+static mut CMDLINE: &str = "";
+
+/// Returns the kernel command line, consisting of arguments separated by spaces.
+/// 
+/// Empty if the bootloader did not pass a command line or it is not valid UTF-8.
+pub fn cmdline() -> &'static str {
+    unsafe {
+        CMDLINE
+    }
+}
+
+/// Returns the value of the first `key=value` argument on the kernel command line, if present.
+pub fn cmdline_arg(key: &str) -> Option<&'static str> {
+    cmdline().split(' ')
+        .filter_map(|arg| arg.split_once('='))
+        .find(|&(k, _)| k == key)
+        .map(|(_, v)| v)
+}
 
 /// The kernel entry point.
 /// This function will be called by the bootloader after preparing the environment.
@@ -28,20 +68,114 @@ extern "C" fn _start(kernel_header: *const KernelHeader) -> ! {
 fn main(kernel_header: *const KernelHeader) -> ! {
     let kh = unsafe{&*kernel_header};
 
+    // COM1 does not depend on the header, so a version mismatch can at least be reported there.
+    drivers::serial::init();
+
+    // The rest of the header can only be interpreted if the bootloader uses the same layout.
+    if kh.version != KERNEL_HEADER_VERSION {
+        panic!("KernelHeader version mismatch: bootloader passed version {}, kernel expects version {}", kh.version, KERNEL_HEADER_VERSION);
+    }
+
     memory::set_high_mem_base(kh.high_memory_base);
 
+    let cmdline_len = (kh.cmdline_len as usize).min(kh.cmdline_buf.len());
+    unsafe {
+        CMDLINE = core::str::from_utf8(&kh.cmdline_buf[..cmdline_len]).unwrap_or("");
+    }
+    match cmdline_arg("loglevel") {
+        Some("verbose") => terminal::set_verbose(true),
+        Some(_) => terminal::set_verbose(false),
+        None => {}
+    }
+
     terminal::init(kh);
     terminal::clear();
+    info!("Kernel", "Version: {} built {}", version::KERNEL_VERSION, version::BUILD_DATE);
     info!("Kernel", "Starting kernel...");
+    info!("Kernel", "Command line: {}", cmdline());
     warning!("Test", "Warning");
     error!("Test", "Error");
 
+    if kh.initrd_size != 0 {
+        info!("Kernel", "Initrd at {:#016X} ({} bytes)", kh.initrd_base, kh.initrd_size);
+    } else {
+        info!("Kernel", "No initrd found");
+    }
+
+    if kh.smbios3_ptr != 0 {
+        info!("Kernel", "SMBIOS 3.0 entry point at {:#016X}", kh.smbios3_ptr);
+    } else if kh.smbios2_ptr != 0 {
+        info!("Kernel", "SMBIOS 2.x entry point at {:#016X}", kh.smbios2_ptr);
+    }
+
     memory::init_phys_manager(kh);
-    memory::init_virt_manager(&kh.paging_info);
+    memory::init_virt_manager(kh);
+    memory::guard_page(kh.kernel_stack_base);
+    memory::init_heap();
 
     arch::init_platform();
+    scheduler::init();
+    syscall::init();
 
-    loop {}
+    // With `gdb` on the command line, wait for a debugger right after the interrupt handlers are set up.
+    if cmdline().split(' ').any(|arg| arg == "gdb") {
+        debug::gdb_stub::init();
+        debug::gdb_stub::breakpoint();
+    }
+
+    let rsdp = kh.acpi_rsdp;
+    if rsdp == 0 {
+        warning!("Kernel", "No ACPI RSDP found");
+    }
+
+    match (rsdp != 0).then(|| acpi::hpet::parse(rsdp)).flatten() {
+        Some(hpet_base) => {
+            drivers::hpet::init(hpet_base);
+            drivers::hpet::start_periodic(TIMER_PERIOD_MS);
+        }
+        None => warning!("Kernel", "No HPET found"),
+    }
+
+    drivers::apic_timer::calibrate_and_start(TIMER_PERIOD_MS);
+    drivers::keyboard::init();
+    interrupt::enable();
+
+    let pci_devices = drivers::pci::enumerate();
+    info!("Kernel", "Found {} PCI functions", pci_devices.len());
+
+    let madt = (rsdp != 0).then(|| acpi::madt::parse(rsdp));
+    match madt.as_ref().map(|m| m.lapic_ids()).filter(|ids| !ids.is_empty()) {
+        Some(apic_ids) => {
+            arch::smp::start_secondary_cpus(apic_ids);
+        }
+        None => {
+            // Without a MADT, guess that the APIC IDs are numbered contiguously.
+            let cpu_count = arch::cpuid::features().logical_core_count().min(u8::MAX as u32) as u8;
+            arch::smp::start_secondary_cpus(&(0..cpu_count).collect::<Vec<u8>>());
+        }
+    }
+
+    #[cfg(feature="integration-test")]
+    test_runner::run();
+
+    // The boot task is done, the shell continues in its own task.
+    #[cfg(not(feature="integration-test"))]
+    {
+        scheduler::create_task(shell::run_shell);
+        scheduler::exit_current();
+    }
+}
+
+/// Prints every register saved in `info`.
+fn print_interrupt_info(info: &arch::interrupt::InterruptInfo) {
+    let [rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15] = info.get_registers();
+    error!("===PANIC===", "In interrupt {:#04X}, error code {:#X}", info.get_int_number(), info.get_error_code());
+    error!("===PANIC===", "RIP={:#018X} RSP={:#018X} RFLAGS={:#018X}", info.get_rip(), rsp, info.get_rflags());
+    error!("===PANIC===", "RAX={:#018X} RBX={:#018X} RCX={:#018X} RDX={:#018X}", rax, rbx, rcx, rdx);
+    error!("===PANIC===", "RSI={:#018X} RDI={:#018X} RBP={:#018X}", rsi, rdi, rbp);
+    error!("===PANIC===", "R8 ={:#018X} R9 ={:#018X} R10={:#018X} R11={:#018X}", r8, r9, r10, r11);
+    error!("===PANIC===", "R12={:#018X} R13={:#018X} R14={:#018X} R15={:#018X}", r12, r13, r14, r15);
+    error!("===PANIC===", "CS={:#06X} SS={:#06X}", info.get_cs(), info.get_ss());
 }
 
 /// Will be called by functions like panic!(), expect(), unwrap(), etc. when errors occur.
@@ -51,6 +185,20 @@ pub fn panic_handler(info: &core::panic::PanicInfo) -> ! {
     // Terminal initialization should theoretically be unfailable, let's hope.
 
     error!("===PANIC===", "{}", info);
+    // Needed to match the panic to the source it happened in.
+    error!("===PANIC===", "Kernel commit {} built {}", version::GIT_HASH, version::BUILD_DATE);
+    // Panics in exception handlers are hard to track down without the registers of the interrupted code.
+    if let Some(regs) = arch::interrupt::last_interrupt_info() {
+        print_interrupt_info(&regs);
+    }
+    debug::backtrace::print_backtrace();
+
+    #[cfg(feature="integration-test")]
+    test_runner::on_panic(info);
+
+    #[cfg(all(feature="qemu-exit", not(feature="integration-test")))]
+    drivers::qemu::qemu_exit(1);
 
+    #[cfg(not(any(feature="integration-test", feature="qemu-exit")))]
     loop {}
 }
@@ -0,0 +1,294 @@
+//! The kernel heap, used for allocations smaller than a page.
+//!
+//! A simple first-fit allocator over a fixed virtual memory region. Free blocks are kept in a
+//! linked list sorted by address, so that neighboring free blocks can be merged again.
+
+use core::{cell::UnsafeCell, mem::size_of, ptr::null_mut};
+#[cfg(not(test))]
+use core::alloc::{GlobalAlloc, Layout};
+
+use common_structures::config::KERNEL_HEAP_SIZE;
+
+#[cfg(not(test))]
+use crate::mutex::IrqSpinLock;
+#[cfg(test)]
+use crate::mutex::{Lock, SpinLock};
+
+/// Every block starts and ends at a multiple of this, so that a [`FreeBlock`] fits into every block.
+const BLOCK_ALIGN: usize = 16;
+
+/// Header of a block in the free list, stored at the start of the free block itself.
+struct FreeBlock {
+    /// Size of the whole block in bytes.
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// Stored directly in front of every pointer returned by [`KernelHeap::alloc()`].
+struct AllocHeader {
+    /// Size of the whole block in bytes.
+    size: usize,
+    /// Distance from the start of the block to the returned pointer.
+    offset: usize,
+}
+
+const HEADER_SIZE: usize = size_of::<AllocHeader>();
+
+/// Lock used by the [`KernelHeap`].
+/// 
+/// Interrupts are disabled while it is held, so that interrupt handlers can allocate as well.
+/// Unit tests run in user mode, where interrupts cannot be disabled.
+#[cfg(not(test))]
+type HeapLock = IrqSpinLock;
+#[cfg(test)]
+type HeapLock = SpinLock;
+
+pub struct KernelHeap {
+    /// Lock to ensure thread-safe access to the free list.
+    lock: HeapLock,
+    /// First block of the free list, sorted by address.
+    free_list: UnsafeCell<*mut FreeBlock>,
+}
+
+impl KernelHeap {
+    /// Creates a heap without any memory. Every allocation fails until [`KernelHeap::add_region()`] is called.
+    pub const fn new() -> Self {
+        Self {
+            lock: HeapLock::new(),
+            free_list: UnsafeCell::new(null_mut()),
+        }
+    }
+
+    /// Adds the memory at `start..start + size` to the heap.
+    ///
+    /// # Safety
+    /// The memory has to be valid and unused for the lifetime of the heap.
+    pub unsafe fn add_region(&self, start: *mut u8, size: usize) {
+        let aligned_start = align_up(start as usize, BLOCK_ALIGN);
+        let end = (start as usize + size) & !(BLOCK_ALIGN - 1);
+        if end <= aligned_start {
+            return;
+        }
+
+        let _guard = self.lock.lock();
+        self.insert_free_block(aligned_start, end - aligned_start);
+    }
+
+    /// Allocates `size` bytes aligned to `align`. Returns null if no free block is large enough.
+    pub fn alloc(&self, size: usize, align: usize) -> *mut u8 {
+        let align = align.max(BLOCK_ALIGN);
+
+        let _guard = self.lock.lock();
+
+        let mut prev: *mut *mut FreeBlock = self.free_list.get();
+        unsafe {
+            while !(*prev).is_null() {
+                let block = *prev;
+                let block_start = block as usize;
+                let block_end = block_start + (*block).size;
+
+                let ptr = align_up(block_start + HEADER_SIZE, align);
+                let end = align_up(ptr + size, BLOCK_ALIGN);
+
+                if end <= block_end {
+                    // Split off the rest of the block, if it is large enough to be a block itself.
+                    // Otherwise, the rest is just part of the allocation.
+                    let alloc_end = if block_end - end >= BLOCK_ALIGN {
+                        let rest = end as *mut FreeBlock;
+                        rest.write(FreeBlock {
+                            size: block_end - end,
+                            next: (*block).next,
+                        });
+                        *prev = rest;
+                        end
+                    } else {
+                        *prev = (*block).next;
+                        block_end
+                    };
+
+                    ((ptr - HEADER_SIZE) as *mut AllocHeader).write(AllocHeader {
+                        size: alloc_end - block_start,
+                        offset: ptr - block_start,
+                    });
+                    return ptr as *mut u8;
+                }
+
+                prev = &mut (*block).next;
+            }
+        }
+
+        null_mut()
+    }
+
+    /// Frees memory returned by [`KernelHeap::alloc()`].
+    pub fn free(&self, ptr: *mut u8) {
+        if ptr.is_null() {
+            return;
+        }
+
+        let header = unsafe{((ptr as usize - HEADER_SIZE) as *const AllocHeader).read()};
+        let block_start = ptr as usize - header.offset;
+
+        let _guard = self.lock.lock();
+        unsafe {
+            self.insert_free_block(block_start, header.size);
+        }
+    }
+
+    /// Returns the number of free bytes in the heap.
+    pub fn get_free_size(&self) -> usize {
+        let _guard = self.lock.lock();
+
+        let mut size = 0;
+        let mut block = unsafe{*self.free_list.get()};
+        while !block.is_null() {
+            unsafe {
+                size += (*block).size;
+                block = (*block).next;
+            }
+        }
+        size
+    }
+
+    /// Inserts the given block into the free list, merging it with its neighbors if possible.
+    ///
+    /// Has to be called with the lock held.
+    unsafe fn insert_free_block(&self, start: usize, size: usize) {
+        let mut prev: *mut *mut FreeBlock = self.free_list.get();
+        let mut prev_block: *mut FreeBlock = null_mut();
+        while !(*prev).is_null() && (*prev as usize) < start {
+            prev_block = *prev;
+            prev = &mut (**prev).next;
+        }
+        let next = *prev;
+
+        let block = start as *mut FreeBlock;
+        block.write(FreeBlock {
+            size,
+            next,
+        });
+        *prev = block;
+
+        // Merge with the following block.
+        if !next.is_null() && start + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+        // Merge with the preceding block.
+        if !prev_block.is_null() && prev_block as usize + (*prev_block).size == start {
+            (*prev_block).size += (*block).size;
+            (*prev_block).next = (*block).next;
+        }
+    }
+}
+
+// The free list is only accessed while holding the lock.
+unsafe impl Sync for KernelHeap {}
+
+fn align_up(val: usize, align: usize) -> usize {
+    (val + align - 1) & !(align - 1)
+}
+
+static HEAP: KernelHeap = KernelHeap::new();
+
+/// Maps the kernel heap region. Has to be called after the virtual memory manager was initialized.
+pub fn init_heap() {
+    let start = super::virt_manager::map_kernel_pages((KERNEL_HEAP_SIZE / 4096) as u64);
+    unsafe {
+        HEAP.add_region(start, KERNEL_HEAP_SIZE);
+    }
+
+    info!("Heap", "{} KB at {:#016X}", KERNEL_HEAP_SIZE / 1024, start as u64);
+}
+
+/// Allocates `size` bytes aligned to `align` from the kernel heap. Returns null if the heap is exhausted.
+pub fn kmalloc(size: usize, align: usize) -> *mut u8 {
+    HEAP.alloc(size, align)
+}
+
+/// Frees memory returned by [`kmalloc()`].
+pub fn kfree(ptr: *mut u8) {
+    HEAP.free(ptr);
+}
+
+/// Makes the `alloc` crate use the kernel heap.
+#[cfg(not(test))]
+struct HeapAllocator;
+
+#[cfg(not(test))]
+unsafe impl GlobalAlloc for HeapAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        kmalloc(layout.size(), layout.align())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        kfree(ptr);
+    }
+}
+
+#[cfg(not(test))]
+#[global_allocator]
+static ALLOCATOR: HeapAllocator = HeapAllocator;
+
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    panic!("Kernel heap exhausted while allocating {} bytes", layout.size());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a heap over a leaked buffer of `size` bytes.
+    fn test_heap(size: usize) -> (KernelHeap, usize) {
+        let buffer = Box::leak(vec![0u8; size + BLOCK_ALIGN].into_boxed_slice());
+        let heap = KernelHeap::new();
+        unsafe {
+            heap.add_region(buffer.as_mut_ptr(), buffer.len());
+        }
+        let free = heap.get_free_size();
+        (heap, free)
+    }
+
+    #[test]
+    fn alloc_free() {
+        let (heap, free) = test_heap(4096);
+
+        let a = heap.alloc(100, 1);
+        let b = heap.alloc(100, 1);
+        assert!(!a.is_null() && !b.is_null());
+        assert!(b as usize >= a as usize + 100);
+        assert!(heap.get_free_size() < free);
+
+        heap.free(a);
+        heap.free(b);
+        assert_eq!(heap.get_free_size(), free);
+
+        // After merging, the whole heap has to be usable for a single allocation again.
+        assert!(!heap.alloc(free - HEADER_SIZE, 1).is_null());
+    }
+
+    #[test]
+    fn alignment() {
+        let (heap, free) = test_heap(4096);
+
+        for align in [16, 64, 256, 1024].iter() {
+            let ptr = heap.alloc(8, *align);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0);
+            heap.free(ptr);
+        }
+        assert_eq!(heap.get_free_size(), free);
+    }
+
+    #[test]
+    fn exhausted() {
+        let (heap, free) = test_heap(4096);
+
+        assert!(heap.alloc(free, 1).is_null());
+        let ptr = heap.alloc(free / 2, 1);
+        assert!(!ptr.is_null());
+        assert!(heap.alloc(free / 2, 1).is_null());
+    }
+}
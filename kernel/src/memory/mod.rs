@@ -2,9 +2,48 @@
 mod phys_manager;
 pub use phys_manager::init_phys_manager;
 pub use phys_manager::phys_manager;
+pub use phys_manager::alloc_page_guarded;
+pub use phys_manager::alloc_linear_pages_guarded;
+pub use phys_manager::Zone;
 
 mod virt_manager;
 pub use virt_manager::init_virt_manager;
 pub use virt_manager::set_high_mem_base;
 pub use virt_manager::phys_to_virt;
 pub use virt_manager::virt_to_phys;
+pub use virt_manager::virt_to_phys_safe;
+pub use virt_manager::map_mmio;
+pub use virt_manager::print_page_tables;
+#[cfg(feature="integration-test")]
+pub use virt_manager::virt_addr_allocator;
+
+mod heap;
+pub use heap::init_heap;
+
+// Not used by any kernel object yet.
+#[allow(dead_code)]
+mod slab;
+
+use crate::arch;
+
+/// Makes the page directly below `stack_base` inaccessible, so that a stack overflow fires a page fault
+/// with CR2 just below `stack_base` instead of silently corrupting memory.
+/// 
+/// The page below the stack has to be reserved together with the stack, as it can not be accessed through
+/// the linear physical memory mapping anymore either.
+pub fn guard_page(stack_base: u64) {
+    arch::virt_manager::unmap_4kb_page(arch::virt_manager::kernel_pml4(), stack_base - 4096);
+}
+
+/// Allocates a kernel stack of `pages` pages with a guard page below it and returns the address of its top.
+/// 
+/// The stack is never freed.
+pub fn alloc_stack(pages: u64) -> u64 {
+    // The lowest page is used as a guard page to catch stack overflows.
+    let stack = alloc_linear_pages_guarded(pages + 1);
+    let stack_base = phys_to_virt::<u8>(stack.addr()) as u64 + 4096;
+    guard_page(stack_base);
+    stack.leak();
+
+    stack_base + pages * 4096
+}
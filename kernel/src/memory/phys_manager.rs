@@ -1,17 +1,32 @@
-use core::{mem::MaybeUninit, slice, ptr::null_mut};
+use core::{ops::{Deref, DerefMut}, slice, ptr::null_mut};
 use core::cell::UnsafeCell;
 
 use common_structures::{KernelHeader, MemorySegment, MemorySegmentState};
 
+use crate::mutex::OnceLock;
+#[cfg(not(test))]
+use crate::mutex::IrqSpinLock;
+#[cfg(test)]
 use crate::mutex::{Lock, SpinLock};
 
 use super::{phys_to_virt, virt_to_phys};
 
-/// Maximum order a buddy allocation can have.
+/// Maximum order a buddy allocation can have in the [`DefaultPhysMemoryManager`].
 /// 
 /// 2^8 pages = 256 pages = 1MB
 const MAX_ORDER: usize = 8;
 
+/// Physical address ranges of devices present on every PC, which are excluded
+/// even if the memory map does not mark them as [`MemorySegmentState::Mmio`].
+const KNOWN_MMIO_RANGES: [(u64, u64); 3] = [
+    // IO APIC
+    (0xFEC0_0000, 0xFEC0_1000),
+    // HPET
+    (0xFED0_0000, 0xFED0_1000),
+    // Local APIC
+    (0xFEE0_0000, 0xFEE0_1000),
+];
+
 /// Interface to tell the [`PhysMemoryManager`] where to place its structures.
 /// 
 /// Mainly used to allow unit testing of the [`PhysMemoryManager`]. When running the kernel normally,
@@ -32,17 +47,108 @@ pub trait PhysManagerStorage {
     fn get_index(&mut self, entry: *mut FreeEntry) -> u64;
 }
 
+/// Lock used by the [`PhysMemoryManager`].
+/// 
+/// Interrupts are disabled while it is held, so that pages can be allocated from interrupt handlers.
+/// Unit tests run in user mode, where interrupts cannot be disabled.
+#[cfg(not(test))]
+type PhysLock = IrqSpinLock;
+#[cfg(test)]
+type PhysLock = SpinLock;
+
 /// Manages allocation and deallocation of physical memory.
-pub struct PhysMemoryManager<Storage: PhysManagerStorage = InlineStorage> {
+/// 
+/// `ORDER` is the maximum order a buddy allocation can have, i.e. the largest block is 2^`ORDER` pages.
+pub struct PhysMemoryManager<Storage: PhysManagerStorage, const ORDER: usize> {
     /// Lock to ensure thread-safe access to all the other fields.
-    lock: SpinLock,
+    lock: PhysLock,
     /// Array of linked lists, containing all free areas of a given
     /// size order.
-    free_lists: UnsafeCell<[*mut FreeEntry; MAX_ORDER+1]>,
+    free_lists: UnsafeCell<PerOrder<*mut FreeEntry, ORDER>>,
+    /// XOR of the page indices of every entry in the respective free list.
+    /// 
+    /// Since XOR is self-inverse, adding or removing an entry is a single XOR.
+    /// With the `debug-buddy` feature, the checksums are compared against the actual
+    /// free lists after every operation to detect corruption early.
+    free_list_checksums: UnsafeCell<PerOrder<u64, ORDER>>,
+    /// Number of pages that were marked as free in the memory map on initialization.
+    total_pages: u64,
     /// The storage backend object. See [`PhysManagerStorage`].
     storage: UnsafeCell<Storage>,
 }
 
+/// The [`PhysMemoryManager`] used by the kernel.
+pub type DefaultPhysMemoryManager = PhysMemoryManager<InlineStorage, MAX_ORDER>;
+
+/// An array with one element for every order from 0 to `ORDER`, usable as a slice.
+/// 
+/// `[T; ORDER+1]` cannot be expressed with const generics, so the element
+/// of the highest order is stored separately. `repr(C)` guarantees that it directly follows the others.
+#[repr(C)]
+struct PerOrder<T, const ORDER: usize> {
+    lower: [T; ORDER],
+    highest: T,
+}
+
+impl<T: Copy, const ORDER: usize> PerOrder<T, ORDER> {
+    fn new(value: T) -> Self {
+        Self {
+            lower: [value; ORDER],
+            highest: value,
+        }
+    }
+}
+
+impl<T, const ORDER: usize> Deref for PerOrder<T, ORDER> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const T, ORDER + 1) }
+    }
+}
+
+impl<T, const ORDER: usize> DerefMut for PerOrder<T, ORDER> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self as *mut Self as *mut T, ORDER + 1) }
+    }
+}
+
+/// Physical memory zones, for devices that can only address part of physical memory.
+/// 
+/// Zone boundaries are aligned far beyond the largest buddy block, so every free block lies within a single zone
+/// and the zone of a page follows directly from its address.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Zone {
+    /// Memory below 16MB, reachable by legacy ISA DMA.
+    Dma16M,
+    /// Memory below 4GB, reachable by 32-bit devices.
+    Dma4G,
+    /// All of physical memory.
+    Normal,
+}
+
+impl Zone {
+    /// Returns the first physical address that does not belong to the zone.
+    pub fn limit(self) -> u64 {
+        match self {
+            Zone::Dma16M => 16 * 1024 * 1024,
+            Zone::Dma4G => 4 * 1024 * 1024 * 1024,
+            Zone::Normal => u64::MAX,
+        }
+    }
+
+    /// Returns the most restrictive zone the page at `addr` belongs to.
+    pub fn of(addr: u64) -> Zone {
+        if addr < Zone::Dma16M.limit() {
+            Zone::Dma16M
+        } else if addr < Zone::Dma4G.limit() {
+            Zone::Dma4G
+        } else {
+            Zone::Normal
+        }
+    }
+}
+
 /// Describes an unallocated area of physical memory.
 pub struct FreeEntry {
     /// Size order of the memory area.
@@ -112,62 +218,165 @@ impl PhysManagerStorage for InlineStorage {
 /// The Singleton [`PhysMemoryManager`] instance.
 /// 
 /// Starts unitialized, use [`api::init_phys_manager()`] to initialize.
-static mut INSTANCE: MaybeUninit<PhysMemoryManager> = MaybeUninit::uninit();
+static INSTANCE: OnceLock<DefaultPhysMemoryManager> = OnceLock::new();
 
 pub fn init_phys_manager(kernel_header: &KernelHeader) {
-    unsafe {
-        INSTANCE.write(PhysMemoryManager::new(slice::from_raw_parts_mut(kernel_header.memory_map, kernel_header.memory_map_entries as usize)));
+    let memory_map = unsafe{slice::from_raw_parts_mut(kernel_header.memory_map, kernel_header.memory_map_entries as usize)};
+    INSTANCE.init(DefaultPhysMemoryManager::new(memory_map));
+
+    let manager = phys_manager();
+    info!("PhysManager", "{} MB free of {} MB total", manager.get_free_page_count() / 256, manager.get_total_page_count() / 256);
+}
+
+pub fn phys_manager() -> &'static DefaultPhysMemoryManager {
+    INSTANCE.get()
+}
+
+/// Allocates a single page that will automatically be freed when the returned [`PageGuard`] is dropped.
+pub fn alloc_page_guarded() -> PageGuard {
+    PageGuard {
+        phys_addr: phys_manager().alloc_page(),
+    }
+}
+
+/// Allocates `count` contiguous pages that will automatically be freed when the returned [`LinearPageGuard`] is dropped.
+pub fn alloc_linear_pages_guarded(count: u64) -> LinearPageGuard {
+    LinearPageGuard {
+        phys_addr: phys_manager().alloc_linear_pages(count),
+        count,
+    }
+}
+
+/// Shrinks every free segment of `memory_map` so that it does not overlap with any MMIO range,
+/// neither with a segment marked as [`MemorySegmentState::Mmio`] nor with one of [`KNOWN_MMIO_RANGES`].
+/// 
+/// If an MMIO range lies in the middle of a free segment, only the larger part of the segment is kept.
+fn exclude_mmio(memory_map: &mut [MemorySegment]) {
+    for i in 0..memory_map.len() {
+        if memory_map[i].state != MemorySegmentState::Free {
+            continue;
+        }
+
+        let (mut start, mut end) = (memory_map[i].start, memory_map[i].end_addr());
+        let mmio_ranges = memory_map.iter()
+            .filter(|e| e.state == MemorySegmentState::Mmio)
+            .map(|e| (e.start, e.end_addr()))
+            .chain(KNOWN_MMIO_RANGES.iter().copied());
+        for (mmio_start, mmio_end) in mmio_ranges {
+            if mmio_start >= end || mmio_end <= start {
+                continue;
+            }
+            if mmio_start.saturating_sub(start) >= end.saturating_sub(mmio_end) {
+                end = mmio_start.max(start);
+            } else {
+                start = mmio_end;
+            }
+        }
+
+        let entry = &mut memory_map[i];
+        if start != entry.start || end != entry.end_addr() {
+            warning!("PhysManager", "Free segment {:#016X} - {:#016X} overlaps with MMIO, shrunk to {:#016X} - {:#016X}", entry.start, entry.end_addr(), start, end);
+            entry.start = start;
+            entry.page_count = (end - start) / 4096;
+        }
+    }
+}
+
+/// Owns a single physical page allocated with [`alloc_page_guarded()`] and frees it on drop.
+pub struct PageGuard {
+    phys_addr: u64,
+}
+
+impl PageGuard {
+    /// Returns the physical address of the page.
+    pub fn addr(&self) -> u64 {
+        self.phys_addr
+    }
+
+    /// Consumes the guard without freeing the page and returns its physical address.
+    /// 
+    /// Use this once ownership of the page is handed off, e.g. to hardware.
+    pub fn leak(self) -> u64 {
+        let addr = self.phys_addr;
+        core::mem::forget(self);
+        addr
     }
 }
 
-pub fn phys_manager() -> &'static PhysMemoryManager {
-    unsafe {
-        &*INSTANCE.as_mut_ptr()
+impl Drop for PageGuard {
+    fn drop(&mut self) {
+        phys_manager().free_page(self.phys_addr);
     }
 }
 
-unsafe impl<Storage: PhysManagerStorage> Sync for PhysMemoryManager<Storage> {}
-unsafe impl<Storage: PhysManagerStorage> Send for PhysMemoryManager<Storage> {}
+/// Owns a contiguous region allocated with [`alloc_linear_pages_guarded()`] and frees it on drop.
+pub struct LinearPageGuard {
+    phys_addr: u64,
+    count: u64,
+}
 
-impl<Storage: PhysManagerStorage> PhysMemoryManager<Storage> {
+impl LinearPageGuard {
+    /// Returns the physical address of the first page.
+    pub fn addr(&self) -> u64 {
+        self.phys_addr
+    }
+
+    /// Consumes the guard without freeing the pages and returns the physical address of the first page.
+    /// 
+    /// Use this once ownership of the pages is handed off, e.g. to hardware.
+    pub fn leak(self) -> u64 {
+        let addr = self.phys_addr;
+        core::mem::forget(self);
+        addr
+    }
+}
+
+impl Drop for LinearPageGuard {
+    fn drop(&mut self) {
+        phys_manager().free_linear_pages(self.phys_addr, self.count);
+    }
+}
+
+unsafe impl<Storage: PhysManagerStorage, const ORDER: usize> Sync for PhysMemoryManager<Storage, ORDER> {}
+unsafe impl<Storage: PhysManagerStorage, const ORDER: usize> Send for PhysMemoryManager<Storage, ORDER> {}
+
+impl<Storage: PhysManagerStorage, const ORDER: usize> PhysMemoryManager<Storage, ORDER> {
     /// Create a new [`PhysMemoryManager`] from a given `memory_map`.
     pub fn new(memory_map: &mut [MemorySegment]) -> Self {
         info!("PhysManager", "Starting initialization");
 
         // find out the maximum address that is accessible according to the memory_map.
         let max_address = memory_map.iter()
-            .map(|entry| entry.start + entry.page_count * 4096)
+            .map(|entry| entry.end_addr())
             .max().expect("Memory Map is empty");
         verbose!("PhysManager", "max_address={:#016X}", max_address);
 
+        // Has to happen before the storage is created, as it might take memory from a free segment.
+        exclude_mmio(memory_map);
+
         let storage = Storage::new(max_address >> 12, memory_map).into();
 
+        let total_pages = memory_map.iter()
+            .filter(|e| e.state == MemorySegmentState::Free)
+            .map(|e| e.page_count)
+            .sum();
+
         let res = Self {
-            lock: SpinLock::new(),
-            free_lists: [null_mut(); MAX_ORDER+1].into(),
+            lock: PhysLock::new(),
+            free_lists: PerOrder::new(null_mut()).into(),
+            free_list_checksums: PerOrder::new(0).into(),
+            total_pages,
             storage,
         };
 
         // Inform the memory manager of every MemorySegment that is marked as free.
         for entry in memory_map.iter().filter(|&e| e.state == MemorySegmentState::Free) {
-            verbose!("PhysManager", "Free segment {:#016X} - {:#016X}    {}", entry.start, entry.start + entry.page_count * 4096, entry.page_count);
+            verbose!("PhysManager", "Free segment {:#016X} - {:#016X}    {}", entry.start, entry.end_addr(), entry.page_count);
             res.add_region(entry.start >> 12, entry.page_count);
         }
 
-        #[cfg(feature="verbose-logging")]
-        {
-            for order in 0..MAX_ORDER+1 {
-                let mut tmp = unsafe{&*res.free_lists.get()}[order];
-                let mut count = 0;
-                while !tmp.is_null() {
-                    count += 1;
-                    unsafe {
-                        tmp = (*tmp).next;
-                    }
-                }
-
-                verbose!("PhysManager", "{} regions of order {}", count, order);
-            }
+        if crate::terminal::is_verbose() {
+            res.print_stats();
         }
 
         info!("PhysManager", "Initialized");
@@ -179,20 +388,29 @@ impl<Storage: PhysManagerStorage> PhysMemoryManager<Storage> {
     /// 
     /// `index` and `page_count` don't need to fulfill any alignment requirements, 
     /// buddy splits will be done when necessary.
-    fn add_region(&self, mut index: u64, mut page_count: u64) {
+    fn add_region(&self, index: u64, page_count: u64) {
         let _guard = self.lock.lock();
         let storage = unsafe{&mut *self.storage.get()};
         let free_lists = unsafe{&mut *self.free_lists.get()};
+        let checksums = unsafe{&mut *self.free_list_checksums.get()};
 
+        Self::free_region(storage, free_lists, checksums, index, page_count);
+
+        #[cfg(any(test, feature="debug-buddy"))]
+        Self::verify_free_lists(storage, free_lists, checksums);
+    }
+
+    /// Frees a region of `page_count` pages at `index` by splitting it into the largest possible blocks.
+    fn free_region(storage: &mut Storage, free_lists: &mut [*mut FreeEntry], checksums: &mut [u64], mut index: u64, mut page_count: u64) {
         while page_count > 0 {
             // The maximum order that is allowed alignment-wise at the current index.
             let index_order = index.trailing_zeros();
             // The maximum order that can be filled with the number of remaining pages.
             let count_order = 63 - page_count.leading_zeros();
             // The order we will use.
-            let order = index_order.min(count_order).min(MAX_ORDER as u32);
+            let order = index_order.min(count_order).min(ORDER as u32);
 
-            Self::free_block(storage, free_lists, index, order);
+            Self::free_block(storage, free_lists, checksums, index, order);
 
             index += 1 << order;
             page_count -= 1 << order;
@@ -270,7 +488,7 @@ impl<Storage: PhysManagerStorage> PhysMemoryManager<Storage> {
     /// Mark a block at `index` with size order `order` as unallocated.
     /// 
     /// This function will automatically merge neighboring unallocated buddies when possible.
-    fn free_block(storage: &mut Storage, free_lists: &mut [*mut FreeEntry], index: u64, order: u32) {
+    fn free_block(storage: &mut Storage, free_lists: &mut [*mut FreeEntry], checksums: &mut [u64], index: u64, order: u32) {
         // calculate bitmap position of the new block.
         let entry = index / 64;
         let bit = index % 64;
@@ -284,16 +502,23 @@ impl<Storage: PhysManagerStorage> PhysMemoryManager<Storage> {
 
         let buddy_map = storage.get_buddy_map();
 
+        // A set bit means that a free block already starts at this page.
+        #[cfg(debug_assertions)]
+        if buddy_map[entry as usize] & (1 << bit) != 0 {
+            panic!("Double free of physical memory at {:#016X}", index << 12);
+        }
+
         // Merge if:
-        // - The block to be freed is smaller than MAX_ORDER
+        // - The block to be freed is smaller than the maximum order
         // - The bitmap entry of the neighbor is set (indicating that a free block of *some* order is present in the neighbor)
         // - The order of the neighboring FreeEntry is the same as ours.
-        if order < MAX_ORDER as u32 && buddy_map[buddy_entry as usize] & (1 << buddy_bit) != 0 && unsafe{ (*buddy_ptr).order == order as usize } {
+        if order < ORDER as u32 && buddy_map[buddy_entry as usize] & (1 << buddy_bit) != 0 && unsafe{ (*buddy_ptr).order == order as usize } {
             buddy_map[buddy_entry as usize] &= !(1 << buddy_bit);
             // Remove the neighboring FreeEntry.
             Self::remove_buddy_list_entry(&mut free_lists[order as usize], buddy_ptr);
+            checksums[order as usize] ^= buddy_index;
             // Recursively free the next higher order block.
-            Self::free_block(storage, free_lists, Self::get_combined_index(index, order), order+1);
+            Self::free_block(storage, free_lists, checksums, Self::get_combined_index(index, order), order+1);
         } else {
             // Merging not possible, just add the new FreeEntry to the list.
             buddy_map[entry as usize] |= 1 << bit;
@@ -303,24 +528,25 @@ impl<Storage: PhysManagerStorage> PhysMemoryManager<Storage> {
                 prev: null_mut(),
             })};
             Self::push_buddy_list_entry(&mut free_lists[order as usize], entry_ptr);
+            checksums[order as usize] ^= index;
         }
     }
 
-    /// Allocate a block with size order `order` and return its index.
+    /// Allocate a block with size order `order` and return its index, or `None` if no block is available.
     /// 
     /// This function will automatically split higher order blocks when needed.
-    fn alloc_block(storage: &mut Storage, free_lists: &mut [*mut FreeEntry], order: u32) -> u64 {
+    fn alloc_block(storage: &mut Storage, free_lists: &mut [*mut FreeEntry], checksums: &mut [u64], order: u32) -> Option<u64> {
         let entry = Self::pop_buddy_list_entry(&mut free_lists[order as usize]);
 
         // No block of the requested order is available, try to split a higher order block.
         if entry.is_null() {
-            // If the requested order is MAX_ORDER, we cannot split a higher order block.
-            if (order as usize) == MAX_ORDER {
-                panic!("Out of physical memory");
+            // If the requested order is the maximum order, we cannot split a higher order block.
+            if (order as usize) == ORDER {
+                return None;
             }
 
             // recursively allocate a block of the next higher order.
-            let higher_block = Self::alloc_block(storage, free_lists, order+1);
+            let higher_block = Self::alloc_block(storage, free_lists, checksums, order+1)?;
             // calculate the index of the higher half block.
             let buddy_index = Self::get_buddy_index(higher_block, order);
             let buddy_entry = buddy_index / 64;
@@ -338,20 +564,95 @@ impl<Storage: PhysManagerStorage> PhysMemoryManager<Storage> {
                 prev: null_mut(),
             })};
             Self::push_buddy_list_entry(&mut free_lists[order as usize], buddy_ptr);
+            checksums[order as usize] ^= buddy_index;
 
             // return the lower half block
-            higher_block
+            Some(higher_block)
         } else {
             // block of the requested order is available, remove it from the list and return it.
             let index = storage.get_index(entry);
+            checksums[order as usize] ^= index;
             let entry = index / 64;
             let bit = index % 64;
 
             let buddy_map = storage.get_buddy_map();
 
+            // Every entry in the free lists has to be marked as free, 
+            // otherwise its memory was most likely written to after being freed.
+            #[cfg(debug_assertions)]
+            if buddy_map[entry as usize] & (1 << bit) == 0 {
+                panic!("Free list entry at {:#016X} is not marked as free", index << 12);
+            }
+
             buddy_map[entry as usize] &= !(1 << bit);
-            index
+            Some(index)
+        }
+    }
+
+    /// Walks every free list and panics if its entries don't match the respective checksum.
+    #[cfg(any(test, feature="debug-buddy"))]
+    fn verify_free_lists(storage: &mut Storage, free_lists: &[*mut FreeEntry], checksums: &[u64]) {
+        for (order, &head) in free_lists.iter().enumerate() {
+            let mut checksum = 0;
+            let mut entry = head;
+            while !entry.is_null() {
+                checksum ^= storage.get_index(entry);
+                entry = unsafe{(*entry).next};
+            }
+
+            if checksum != checksums[order] {
+                panic!("Buddy list corruption detected at order {}", order);
+            }
+        }
+    }
+
+    /// Returns the number of pages that are currently unallocated.
+    pub fn get_free_page_count(&self) -> u64 {
+        let _guard = self.lock.lock();
+        let free_lists = unsafe{&*self.free_lists.get()};
+
+        let mut free_pages = 0;
+        for (order, &head) in free_lists.iter().enumerate() {
+            let mut tmp = head;
+            while !tmp.is_null() {
+                free_pages += 1 << order;
+                unsafe {
+                    tmp = (*tmp).next;
+                }
+            }
+        }
+
+        free_pages
+    }
+
+    /// Returns the number of pages managed by this [`PhysMemoryManager`], 
+    /// i.e. every page that was marked as free in the memory map on initialization.
+    pub fn get_total_page_count(&self) -> u64 {
+        let _guard = self.lock.lock();
+        self.total_pages
+    }
+
+    /// Prints the number of free regions of every order and the total amount of free memory.
+    pub fn print_stats(&self) {
+        let _guard = self.lock.lock();
+        let free_lists = unsafe{&*self.free_lists.get()};
+
+        let mut free_pages = 0;
+        for (order, &head) in free_lists.iter().enumerate() {
+            let mut tmp = head;
+            let mut count = 0;
+            while !tmp.is_null() {
+                count += 1;
+                unsafe {
+                    tmp = (*tmp).next;
+                }
+            }
+
+            free_pages += count << order;
+            info!("PhysManager", "{} regions of order {}", count, order);
         }
+
+        info!("PhysManager", "{} free pages ({} KB)", free_pages, free_pages * 4);
     }
 
     /// Frees a single page of physical memory at the given `addr`.
@@ -359,8 +660,12 @@ impl<Storage: PhysManagerStorage> PhysMemoryManager<Storage> {
         let _guard = self.lock.lock();
         let storage = unsafe{&mut *self.storage.get()};
         let free_lists = unsafe{&mut *self.free_lists.get()};
+        let checksums = unsafe{&mut *self.free_list_checksums.get()};
+
+        Self::free_block(storage, free_lists, checksums, addr >> 12, 0);
 
-        Self::free_block(storage, free_lists, addr >> 12, 0);
+        #[cfg(any(test, feature="debug-buddy"))]
+        Self::verify_free_lists(storage, free_lists, checksums);
     }
 
     /// Frees a contiguous region of `count` pages of physical memory at the given `addr`.
@@ -370,8 +675,27 @@ impl<Storage: PhysManagerStorage> PhysMemoryManager<Storage> {
         let _guard = self.lock.lock();
         let storage = unsafe{&mut *self.storage.get()};
         let free_lists = unsafe{&mut *self.free_lists.get()};
+        let checksums = unsafe{&mut *self.free_list_checksums.get()};
+
+        Self::free_block(storage, free_lists, checksums, addr >> 12, Self::get_size_order(count));
 
-        Self::free_block(storage, free_lists, addr >> 12, Self::get_size_order(count));
+        #[cfg(any(test, feature="debug-buddy"))]
+        Self::verify_free_lists(storage, free_lists, checksums);
+    }
+
+    /// Frees a region allocated with [`Self::alloc_aligned()`].
+    /// 
+    /// `count` has to be the same value that was passed to [`Self::alloc_aligned()`].
+    pub fn free_aligned(&self, addr: u64, count: u64) {
+        let _guard = self.lock.lock();
+        let storage = unsafe{&mut *self.storage.get()};
+        let free_lists = unsafe{&mut *self.free_lists.get()};
+        let checksums = unsafe{&mut *self.free_list_checksums.get()};
+
+        Self::free_region(storage, free_lists, checksums, addr >> 12, count);
+
+        #[cfg(any(test, feature="debug-buddy"))]
+        Self::verify_free_lists(storage, free_lists, checksums);
     }
 
     /// Frees several single-page blocks, each address given in one entry of `addresses`.
@@ -379,28 +703,205 @@ impl<Storage: PhysManagerStorage> PhysMemoryManager<Storage> {
         let _guard = self.lock.lock();
         let storage = unsafe{&mut *self.storage.get()};
         let free_lists = unsafe{&mut *self.free_lists.get()};
+        let checksums = unsafe{&mut *self.free_list_checksums.get()};
 
         for addr in addresses {
-            Self::free_block(storage, free_lists, addr >> 12, 0);
+            Self::free_block(storage, free_lists, checksums, addr >> 12, 0);
         }
+
+        #[cfg(any(test, feature="debug-buddy"))]
+        Self::verify_free_lists(storage, free_lists, checksums);
     }
 
     /// Allocates and returns the physical address of a single memory page.
+    /// 
+    /// Panics if no memory is available, see [`Self::try_alloc_page()`].
     pub fn alloc_page(&self) -> u64 {
+        self.try_alloc_page().expect("Out of physical memory")
+    }
+
+    /// Allocates and returns the physical address of a single memory page, or `None` if no memory is available.
+    pub fn try_alloc_page(&self) -> Option<u64> {
         let _guard = self.lock.lock();
         let storage = unsafe{&mut *self.storage.get()};
         let free_lists = unsafe{&mut *self.free_lists.get()};
+        let checksums = unsafe{&mut *self.free_list_checksums.get()};
+
+        let index = Self::alloc_block(storage, free_lists, checksums, 0)?;
+
+        #[cfg(any(test, feature="debug-buddy"))]
+        Self::verify_free_lists(storage, free_lists, checksums);
 
-        Self::alloc_block(storage, free_lists, 0) << 12
+        Some(index << 12)
     }
 
     /// Allocates and returns the physical address of a contiguous region of memory with `count` pages.
+    /// 
+    /// Panics if no such region is available, see [`Self::try_alloc_linear_pages()`].
     pub fn alloc_linear_pages(&self, count: u64) -> u64 {
+        self.try_alloc_linear_pages(count).expect("Out of physical memory")
+    }
+
+    /// Allocates and returns the physical address of a contiguous region of memory with `count` pages,
+    /// or `None` if no such region is available.
+    pub fn try_alloc_linear_pages(&self, count: u64) -> Option<u64> {
+        let order = Self::get_size_order(count);
+        // Regions larger than the maximum block size can never be allocated.
+        if order as usize > ORDER {
+            return None;
+        }
+
+        let _guard = self.lock.lock();
+        let storage = unsafe{&mut *self.storage.get()};
+        let free_lists = unsafe{&mut *self.free_lists.get()};
+        let checksums = unsafe{&mut *self.free_list_checksums.get()};
+
+        let index = Self::alloc_block(storage, free_lists, checksums, order)?;
+
+        #[cfg(any(test, feature="debug-buddy"))]
+        Self::verify_free_lists(storage, free_lists, checksums);
+
+        Some(index << 12)
+    }
+
+    /// Allocates a single page whose physical address lies within `min_addr..max_addr`.
+    /// 
+    /// Searches the free lists from order 0 upwards and splits the first higher order block
+    /// that contains a suitable page. Returns `None` if no free page lies within the range.
+    /// Note that this walks the free lists, so it is a lot slower than [`Self::alloc_page()`].
+    pub fn try_alloc_page_in_range(&self, min_addr: u64, max_addr: u64) -> Option<u64> {
+        let _guard = self.lock.lock();
+        let storage = unsafe{&mut *self.storage.get()};
+        let free_lists = unsafe{&mut *self.free_lists.get()};
+        let checksums = unsafe{&mut *self.free_list_checksums.get()};
+
+        // page indices min_index..max_index lie within the requested range.
+        let min_index = (min_addr + 4095) >> 12;
+        let max_index = (max_addr + 4095) >> 12;
+
+        for order in 0..=ORDER as u32 {
+            let mut entry = free_lists[order as usize];
+            while !entry.is_null() {
+                let mut index = storage.get_index(entry);
+
+                // Does the block overlap with the requested range?
+                if index < max_index && index + (1 << order) > min_index {
+                    // The page we will return.
+                    let target = index.max(min_index);
+
+                    Self::remove_buddy_list_entry(&mut free_lists[order as usize], entry);
+                    checksums[order as usize] ^= index;
+                    storage.get_buddy_map()[(index / 64) as usize] &= !(1 << (index % 64));
+
+                    // Split the block until only the target page is left,
+                    // freeing the halves that don't contain it.
+                    for split_order in (0..order).rev() {
+                        let half = 1 << split_order;
+                        if target < index + half {
+                            Self::free_block(storage, free_lists, checksums, index + half, split_order);
+                        } else {
+                            Self::free_block(storage, free_lists, checksums, index, split_order);
+                            index += half;
+                        }
+                    }
+
+                    #[cfg(any(test, feature="debug-buddy"))]
+                    Self::verify_free_lists(storage, free_lists, checksums);
+
+                    return Some(index << 12);
+                }
+
+                entry = unsafe{(*entry).next};
+            }
+        }
+
+        None
+    }
+
+    /// Allocates a single page that lies within `zone`.
+    /// 
+    /// Panics if the zone has no free memory left, see [`Self::try_alloc_page_in_zone()`].
+    pub fn alloc_page_in_zone(&self, zone: Zone) -> u64 {
+        self.try_alloc_page_in_zone(zone).unwrap_or_else(|| panic!("Out of physical memory in zone {:?}", zone))
+    }
+
+    /// Allocates a single page that lies within `zone`, or returns `None` if the zone has no free memory left.
+    pub fn try_alloc_page_in_zone(&self, zone: Zone) -> Option<u64> {
+        match zone {
+            Zone::Normal => self.try_alloc_page(),
+            _ => self.alloc_pages_below(zone.limit(), 1),
+        }
+    }
+
+    /// Allocates `count` contiguous pages that lie entirely below `limit_addr`, e.g. for DMA buffers.
+    /// 
+    /// Searches the free lists from the required order upwards. Higher order blocks are split,
+    /// so only the lowest part of a block has to lie below `limit_addr`. Returns `None` if no suitable
+    /// block exists. Like [`Self::try_alloc_page_in_range()`], this walks the free lists.
+    pub fn alloc_pages_below(&self, limit_addr: u64, count: u64) -> Option<u64> {
+        let order = Self::get_size_order(count);
+        if order as usize > ORDER {
+            return None;
+        }
+
         let _guard = self.lock.lock();
         let storage = unsafe{&mut *self.storage.get()};
         let free_lists = unsafe{&mut *self.free_lists.get()};
+        let checksums = unsafe{&mut *self.free_list_checksums.get()};
+
+        let limit_index = limit_addr >> 12;
+
+        for block_order in order..=ORDER as u32 {
+            let mut entry = free_lists[block_order as usize];
+            while !entry.is_null() {
+                let index = storage.get_index(entry);
+
+                if index + (1 << order) <= limit_index {
+                    Self::remove_buddy_list_entry(&mut free_lists[block_order as usize], entry);
+                    checksums[block_order as usize] ^= index;
+                    storage.get_buddy_map()[(index / 64) as usize] &= !(1 << (index % 64));
 
-        Self::alloc_block(storage, free_lists, Self::get_size_order(count)) << 12
+                    // Keep the lowest part of the block, free the upper halves.
+                    for split_order in (order..block_order).rev() {
+                        Self::free_block(storage, free_lists, checksums, index + (1 << split_order), split_order);
+                    }
+
+                    #[cfg(any(test, feature="debug-buddy"))]
+                    Self::verify_free_lists(storage, free_lists, checksums);
+
+                    return Some(index << 12);
+                }
+
+                entry = unsafe{(*entry).next};
+            }
+        }
+
+        None
+    }
+
+    /// Allocates `count` contiguous pages whose physical address is aligned to 2^`align_order` pages.
+    /// 
+    /// A buddy block that satisfies both the size and the alignment is allocated, the pages behind the first `count` pages
+    /// are put back into the free lists. Has to be freed with [`Self::free_aligned()`].
+    pub fn alloc_aligned(&self, align_order: u32, count: u64) -> u64 {
+        let order = Self::get_size_order(count).max(align_order);
+        if order as usize > ORDER {
+            panic!("Cannot allocate {} pages aligned to 2^{} pages, maximum order is {}", count, align_order, ORDER);
+        }
+
+        let _guard = self.lock.lock();
+        let storage = unsafe{&mut *self.storage.get()};
+        let free_lists = unsafe{&mut *self.free_lists.get()};
+        let checksums = unsafe{&mut *self.free_list_checksums.get()};
+
+        let index = Self::alloc_block(storage, free_lists, checksums, order).expect("Out of physical memory");
+        // Blocks are always aligned to their size, so only the remainder has to be freed again.
+        Self::free_region(storage, free_lists, checksums, index + count, (1 << order) - count);
+
+        #[cfg(any(test, feature="debug-buddy"))]
+        Self::verify_free_lists(storage, free_lists, checksums);
+
+        index << 12
     }
 
     /// Allocates `addresses.len()` single-page blocks and returns each address in the given slice. 
@@ -410,10 +911,14 @@ impl<Storage: PhysManagerStorage> PhysMemoryManager<Storage> {
         let _guard = self.lock.lock();
         let storage = unsafe{&mut *self.storage.get()};
         let free_lists = unsafe{&mut *self.free_lists.get()};
+        let checksums = unsafe{&mut *self.free_list_checksums.get()};
 
         for out_addr in addresses {
-            *out_addr = Self::alloc_block(storage, free_lists, 0) << 12;
+            *out_addr = Self::alloc_block(storage, free_lists, checksums, 0).expect("Out of physical memory") << 12;
         }
+
+        #[cfg(any(test, feature="debug-buddy"))]
+        Self::verify_free_lists(storage, free_lists, checksums);
     }
 }
 
@@ -457,18 +962,383 @@ mod tests {
         }
     }
 
+    /// Size of the emulated physical memory of [`HeapInlineStorage`].
+    const HEAP_MEMORY_SIZE: u64 = 16 * 1024 * 1024;
+
+    /// [`PhysManagerStorage`] implementation that mirrors [`InlineStorage`] on a heap buffer.
+    ///
+    /// The buffer takes the place of the linear physical memory mapping: the buddy bitmap is placed
+    /// into the emulated physical memory and addresses are converted with the same OR/AND-NOT logic
+    /// as [`phys_to_virt()`] and [`virt_to_phys()`].
+    struct HeapInlineStorage {
+        buddy_map: *mut [u64],
+        /// Start of the emulated physical memory, aligned to [`HEAP_MEMORY_SIZE`].
+        base: u64,
+        _memory: Vec<u8>,
+    }
+
+    impl PhysManagerStorage for HeapInlineStorage {
+        fn new(num_pages: u64, _memory_map: &mut [MemorySegment]) -> Self {
+            let num_entries = (num_pages + 63) / 64;
+            let num_storage_pages = (num_entries * 8 + 4095) / 4096;
+            assert!((num_pages + num_storage_pages) * 4096 <= HEAP_MEMORY_SIZE, "Memory map too large for HeapInlineStorage");
+
+            // OR-ing physical addresses onto the base only works if the base is aligned to the size
+            // of the emulated memory, so allocate twice the size and use the aligned half.
+            let memory = vec![0; 2 * HEAP_MEMORY_SIZE as usize];
+            let base = (memory.as_ptr() as u64 + HEAP_MEMORY_SIZE - 1) & !(HEAP_MEMORY_SIZE - 1);
+
+            // The test memory maps do not leave room for the bitmap, so it is placed at the
+            // end of the emulated memory instead of inside a free MemorySegment.
+            let map_addr = HEAP_MEMORY_SIZE - num_storage_pages * 4096;
+            let buddy_map = unsafe { slice::from_raw_parts_mut((base | map_addr) as *mut u64, num_entries as usize) as *mut [u64] };
+
+            Self {
+                buddy_map,
+                base,
+                _memory: memory,
+            }
+        }
+
+        fn get_buddy_map(&mut self) -> &mut [u64] {
+            unsafe { &mut *self.buddy_map }
+        }
+
+        fn get_entry(&mut self, index: u64) -> *mut FreeEntry {
+            (self.base | (index << 12)) as *mut FreeEntry
+        }
+
+        fn get_index(&mut self, entry: *mut FreeEntry) -> u64 {
+            (entry as u64 & !self.base) >> 12
+        }
+    }
+
+    /// Generates a module containing one test per [`PhysManagerStorage`] implementation
+    /// for the given generic test function.
+    macro_rules! storage_test {
+        ($(#[$attr:meta])* $name:ident) => {
+            mod $name {
+                use super::*;
+
+                #[test]
+                $(#[$attr])*
+                fn test_storage() {
+                    super::$name::<TestStorage>();
+                }
+
+                #[test]
+                $(#[$attr])*
+                fn heap_inline_storage() {
+                    super::$name::<HeapInlineStorage>();
+                }
+            }
+        };
+    }
+
+    fn page_counts<S: PhysManagerStorage>() {
+        let mmap = &mut [
+            MemorySegment {
+                start: 0,
+                page_count: 13,
+                state: MemorySegmentState::Free,
+            },
+            MemorySegment {
+                start: 13 * 4096,
+                page_count: 3,
+                state: MemorySegmentState::Occupied,
+            },
+            MemorySegment {
+                start: 16 * 4096,
+                page_count: 4,
+                state: MemorySegmentState::Free,
+            },
+        ];
+
+        let manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
+
+        assert!(manager.get_total_page_count() == 17);
+        assert!(manager.get_free_page_count() == 17);
+
+        let a = manager.alloc_page();
+        let b = manager.alloc_linear_pages(4);
+        assert!(manager.get_free_page_count() == 12);
+
+        manager.free_page(a);
+        manager.free_linear_pages(b, 4);
+        assert!(manager.get_free_page_count() == 17);
+
+        // freeing previously occupied memory does not change the total.
+        manager.free_page(13 * 4096);
+        assert!(manager.get_free_page_count() == 18);
+        assert!(manager.get_total_page_count() == 17);
+    }
+
     #[test]
-    fn count_to_order() {
-        assert!(PhysMemoryManager::<TestStorage>::get_size_order(1) == 0);
-        assert!(PhysMemoryManager::<TestStorage>::get_size_order(2) == 1);
-        assert!(PhysMemoryManager::<TestStorage>::get_size_order(3) == 2);
-        assert!(PhysMemoryManager::<TestStorage>::get_size_order(4) == 2);
+    fn mmio_excluded() {
+        let mmap = &mut [
+            MemorySegment {
+                start: 0,
+                page_count: 16,
+                state: MemorySegmentState::Free,
+            },
+            MemorySegment {
+                start: 12 * 4096,
+                page_count: 4,
+                state: MemorySegmentState::Mmio,
+            },
+            // contains the IO APIC and the HPET, only the larger part between them is kept.
+            MemorySegment {
+                start: 0xFEC0_0000,
+                page_count: 0x180,
+                state: MemorySegmentState::Free,
+            },
+            MemorySegment {
+                start: 0xFEE0_0000,
+                page_count: 1,
+                state: MemorySegmentState::Free,
+            },
+        ];
+
+        exclude_mmio(mmap);
+
+        assert!(mmap[0].start == 0 && mmap[0].page_count == 12);
+        assert!(mmap[1].start == 12 * 4096 && mmap[1].page_count == 4);
+        assert!(mmap[2].start == 0xFEC0_1000 && mmap[2].end_addr() == 0xFED0_0000);
+        assert!(mmap[3].page_count == 0);
+    }
+
+    fn try_alloc_exhausted<S: PhysManagerStorage>() {
+        let mmap = &mut [
+            MemorySegment {
+                start: 0,
+                page_count: 3,
+                state: MemorySegmentState::Free,
+            },
+        ];
+
+        let manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
+
+        // 3 pages = blocks of order 1 at 0 and order 0 at 2.
+        assert!(manager.try_alloc_linear_pages(4) == None);
+        assert!(manager.try_alloc_linear_pages(2) == Some(0));
+        assert!(manager.try_alloc_linear_pages(2) == None);
+        assert!(manager.try_alloc_page() == Some(2 * 4096));
+        assert!(manager.try_alloc_page() == None);
+
+        // larger than the maximum order
+        assert!(manager.try_alloc_linear_pages((1 << MAX_ORDER) + 1) == None);
 
-        assert!(PhysMemoryManager::<TestStorage>::get_size_order(13) == 4);
+        // failed allocations must not have modified anything.
+        manager.free_page(2 * 4096);
+        assert!(manager.try_alloc_page() == Some(2 * 4096));
+    }
+
+    fn alloc_below<S: PhysManagerStorage>() {
+        let mmap = &mut [
+            MemorySegment {
+                start: 0,
+                page_count: 1,
+                state: MemorySegmentState::Free,
+            },
+            MemorySegment {
+                start: 8 * 4096,
+                page_count: 8,
+                state: MemorySegmentState::Free,
+            },
+        ];
+
+        let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
+
+        // only a single page lies below 4 pages.
+        assert!(manager.alloc_pages_below(4 * 4096, 2) == None);
+
+        // the lower two pages of the order 3 block at page 8 have to be split out.
+        assert!(manager.alloc_pages_below(12 * 4096, 2) == Some(8 * 4096));
+        unsafe {
+            let map = manager.storage.get_mut().get_buddy_map()[0];
+            assert!(map & (1 << 8) == 0);
+            assert!(map & (1 << 10) != 0);
+            assert!(map & (1 << 12) != 0);
+
+            assert!((*manager.free_lists.get_mut()[1]).order == 1);
+            assert!((*manager.free_lists.get_mut()[2]).order == 2);
+            assert!(manager.free_lists.get_mut()[3] == null_mut());
+        }
+
+        assert!(manager.alloc_pages_below(4096, 1) == Some(0));
+        assert!(manager.alloc_pages_below(11 * 4096, 1) == Some(10 * 4096));
+        assert!(manager.alloc_pages_below(11 * 4096, 1) == None);
+
+        // larger than the maximum order
+        assert!(manager.alloc_pages_below(u64::MAX, (1 << MAX_ORDER) + 1) == None);
+    }
+
+    fn custom_max_order<S: PhysManagerStorage>() {
+        let mmap = &mut [
+            MemorySegment {
+                start: 0,
+                page_count: 8,
+                state: MemorySegmentState::Free,
+            },
+        ];
+
+        let mut manager = PhysMemoryManager::<S, 2>::new(mmap);
+
+        // 8 pages = two blocks of order 2, as they cannot be merged.
+        unsafe {
+            assert!(manager.free_lists.get_mut().len() == 3);
+            assert!(manager.free_lists.get_mut()[2] != null_mut());
+            assert!((*manager.free_lists.get_mut()[2]).next != null_mut());
+        }
+
+        assert!(manager.try_alloc_linear_pages(8) == None);
+        // the block at page 4 was added last and is at the head of the list.
+        assert!(manager.try_alloc_linear_pages(4) == Some(4 * 4096));
+        manager.free_linear_pages(4 * 4096, 4);
+
+        unsafe {
+            assert!((*manager.free_lists.get_mut()[2]).next != null_mut());
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn detect_double_free<S: PhysManagerStorage>() {
+        let mmap = &mut [
+            MemorySegment {
+                start: 0,
+                page_count: 4,
+                state: MemorySegmentState::Free,
+            },
+        ];
+
+        let manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
+
+        let page = manager.alloc_page();
+        manager.free_page(page);
+        manager.free_page(page);
+    }
+
+    #[cfg(debug_assertions)]
+    fn detect_unmarked_free_entry<S: PhysManagerStorage>() {
+        let mmap = &mut [
+            MemorySegment {
+                start: 0,
+                page_count: 1,
+                state: MemorySegmentState::Free,
+            },
+        ];
+
+        let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
+
+        // The page is still in the free list, but marked as allocated.
+        manager.storage.get_mut().get_buddy_map()[0] &= !1;
+        manager.alloc_page();
+    }
+
+    fn alloc_aligned<S: PhysManagerStorage>() {
+        let mmap = &mut [
+            MemorySegment {
+                start: 0,
+                page_count: 1,
+                state: MemorySegmentState::Free,
+            },
+            MemorySegment {
+                start: 8 * 4096,
+                page_count: 8,
+                state: MemorySegmentState::Free,
+            },
+        ];
+
+        let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
+
+        // 3 pages aligned to 8 pages, the remaining 5 pages of the order 3 block are freed again.
+        let addr = manager.alloc_aligned(3, 3);
+        assert!(addr == 8 * 4096);
+        unsafe {
+            let map = manager.storage.get_mut().get_buddy_map()[0];
+            assert!(map & (1 << 8) == 0);
+            assert!(map & (1 << 11) != 0);
+            assert!(map & (1 << 12) != 0);
+
+            assert!((*manager.free_lists.get_mut()[0]).next != null_mut());
+            assert!((*manager.free_lists.get_mut()[2]).order == 2);
+            assert!(manager.free_lists.get_mut()[3] == null_mut());
+        }
+        assert!(manager.get_free_page_count() == 6);
+
+        // Freeing has to merge everything back into the order 3 block.
+        manager.free_aligned(addr, 3);
+        assert!(manager.get_free_page_count() == 9);
+        unsafe {
+            assert!(manager.free_lists.get_mut()[2] == null_mut());
+            assert!(manager.free_lists.get_mut()[3] != null_mut());
+        }
+
+        // A single page with a larger alignment than its size.
+        assert!(manager.alloc_aligned(1, 1) == 8 * 4096);
+        manager.free_aligned(8 * 4096, 1);
+        assert!(manager.get_free_page_count() == 9);
     }
 
     #[test]
-    fn free_single() {
+    fn alloc_in_zone() {
+        // Pages at both sides of the 16MB boundary, only TestStorage can hold that much memory.
+        let mmap = &mut [
+            MemorySegment {
+                start: 0,
+                page_count: 1,
+                state: MemorySegmentState::Free,
+            },
+            MemorySegment {
+                start: 16 * 1024 * 1024,
+                page_count: 2,
+                state: MemorySegmentState::Free,
+            },
+        ];
+
+        let manager = PhysMemoryManager::<TestStorage, MAX_ORDER>::new(mmap);
+
+        assert!(Zone::of(0) == Zone::Dma16M);
+        assert!(Zone::of(16 * 1024 * 1024) == Zone::Dma4G);
+        assert!(Zone::of(4 * 1024 * 1024 * 1024) == Zone::Normal);
+
+        assert!(manager.try_alloc_page_in_zone(Zone::Dma16M) == Some(0));
+        assert!(manager.try_alloc_page_in_zone(Zone::Dma16M) == None);
+        assert!(manager.try_alloc_page_in_zone(Zone::Dma4G) == Some(16 * 1024 * 1024));
+        assert!(manager.try_alloc_page_in_zone(Zone::Normal) == Some(16 * 1024 * 1024 + 4096));
+        assert!(manager.try_alloc_page_in_zone(Zone::Normal) == None);
+    }
+
+    storage_test!(free_single);
+    storage_test!(free_merge_forward);
+    storage_test!(free_merge_backward);
+    storage_test!(free_dont_merge_different_orders);
+    storage_test!(init_dont_merge_max_order);
+    storage_test!(alloc_single);
+    storage_test!(alloc_split);
+    storage_test!(init_free_regions);
+    storage_test!(alloc_in_range);
+    storage_test!(checksums_track_free_lists);
+    storage_test!(page_counts);
+    storage_test!(try_alloc_exhausted);
+    storage_test!(alloc_below);
+    storage_test!(custom_max_order);
+    storage_test!(alloc_aligned);
+    storage_test!(#[cfg(debug_assertions)] #[should_panic(expected = "Double free of physical memory at 0x00000000000000")] detect_double_free);
+    storage_test!(#[cfg(debug_assertions)] #[should_panic(expected = "Free list entry at 0x00000000000000 is not marked as free")] detect_unmarked_free_entry);
+    storage_test!(#[should_panic(expected = "Buddy list corruption detected at order 1")] checksums_detect_corruption);
+
+    #[test]
+    fn count_to_order() {
+        assert!(PhysMemoryManager::<TestStorage, MAX_ORDER>::get_size_order(1) == 0);
+        assert!(PhysMemoryManager::<TestStorage, MAX_ORDER>::get_size_order(2) == 1);
+        assert!(PhysMemoryManager::<TestStorage, MAX_ORDER>::get_size_order(3) == 2);
+        assert!(PhysMemoryManager::<TestStorage, MAX_ORDER>::get_size_order(4) == 2);
+
+        assert!(PhysMemoryManager::<TestStorage, MAX_ORDER>::get_size_order(13) == 4);
+    }
+
+    fn free_single<S: PhysManagerStorage>() {
         let mmap = &mut [
             MemorySegment {
                 start: 0,
@@ -477,7 +1347,7 @@ mod tests {
             },
         ];
 
-        let mut manager = PhysMemoryManager::<TestStorage>::new(mmap);
+        let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
 
         manager.free_page(7 * 4096);
         
@@ -491,8 +1361,7 @@ mod tests {
         }
     }
 
-    #[test]
-    fn free_merge_forward() {
+    fn free_merge_forward<S: PhysManagerStorage>() {
         let mmap = &mut [
             MemorySegment {
                 start: 0,
@@ -501,7 +1370,7 @@ mod tests {
             },
         ];
 
-        let mut manager = PhysMemoryManager::<TestStorage>::new(mmap);
+        let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
 
         manager.free_page(6 * 4096);
         manager.free_page(7 * 4096);
@@ -519,8 +1388,7 @@ mod tests {
         }
     }
 
-    #[test]
-    fn free_merge_backward() {
+    fn free_merge_backward<S: PhysManagerStorage>() {
         let mmap = &mut [
             MemorySegment {
                 start: 0,
@@ -529,7 +1397,7 @@ mod tests {
             },
         ];
 
-        let mut manager = PhysMemoryManager::<TestStorage>::new(mmap);
+        let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
 
         manager.free_page(7 * 4096);
         manager.free_page(6 * 4096);
@@ -547,8 +1415,7 @@ mod tests {
         }
     }
 
-    #[test]
-    fn free_dont_merge_different_orders() {
+    fn free_dont_merge_different_orders<S: PhysManagerStorage>() {
         let mmap = &mut [
             MemorySegment {
                 start: 0,
@@ -562,7 +1429,7 @@ mod tests {
             }
         ];
 
-        let mut manager = PhysMemoryManager::<TestStorage>::new(mmap);
+        let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
 
         manager.free_linear_pages(2 * 4096, 2);
 
@@ -584,8 +1451,7 @@ mod tests {
         }
     }
 
-    #[test]
-    fn init_dont_merge_max_order() {
+    fn init_dont_merge_max_order<S: PhysManagerStorage>() {
         let mmap = &mut [
             MemorySegment {
                 start: 0,
@@ -594,7 +1460,7 @@ mod tests {
             },
         ];
 
-        let mut manager = PhysMemoryManager::<TestStorage>::new(mmap);
+        let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
 
         let index = 1 << MAX_ORDER;
         let entry = index / 64;
@@ -611,8 +1477,7 @@ mod tests {
         }
     }
 
-    #[test]
-    fn alloc_single() {
+    fn alloc_single<S: PhysManagerStorage>() {
         let mmap = &mut [
             MemorySegment {
                 start: 0,
@@ -621,7 +1486,7 @@ mod tests {
             },
         ];
 
-        let mut manager = PhysMemoryManager::<TestStorage>::new(mmap);
+        let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
 
         let page = manager.alloc_page();
         assert!(page == 0);
@@ -630,8 +1495,7 @@ mod tests {
         assert!(manager.free_lists.get_mut()[0] == null_mut());
     }
 
-    #[test]
-    fn alloc_split() {
+    fn alloc_split<S: PhysManagerStorage>() {
         let mmap = &mut [
             MemorySegment {
                 start: 0,
@@ -640,7 +1504,7 @@ mod tests {
             },
         ];
 
-        let mut manager = PhysMemoryManager::<TestStorage>::new(mmap);
+        let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
 
         let page = manager.alloc_page();
         assert!(page == 0);
@@ -651,8 +1515,7 @@ mod tests {
         assert!(manager.free_lists.get_mut()[1] == null_mut());
     }
 
-    #[test]
-    fn init_free_regions() {
+    fn init_free_regions<S: PhysManagerStorage>() {
         {
             let mmap = &mut [
                 MemorySegment {
@@ -667,7 +1530,7 @@ mod tests {
                 },
             ];
 
-            let mut manager = PhysMemoryManager::<TestStorage>::new(mmap);
+            let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
 
             unsafe {
                 assert!(manager.storage.get_mut().get_buddy_map()[1] & (1 << 4) != 0);
@@ -696,7 +1559,7 @@ mod tests {
                 },
             ];
 
-            let mut manager = PhysMemoryManager::<TestStorage>::new(mmap);
+            let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
 
             unsafe {
                 assert!(manager.storage.get_mut().get_buddy_map()[1] & (1 << 4) != 0);
@@ -717,4 +1580,102 @@ mod tests {
         }
     }
 
+    fn alloc_in_range<S: PhysManagerStorage>() {
+        let mmap = &mut [
+            MemorySegment {
+                start: 3 * 4096,
+                page_count: 1,
+                state: MemorySegmentState::Free,
+            },
+            MemorySegment {
+                start: 8 * 4096,
+                page_count: 8,
+                state: MemorySegmentState::Free,
+            },
+            MemorySegment {
+                start: 20 * 4096,
+                page_count: 1,
+                state: MemorySegmentState::Free,
+            },
+        ];
+
+        let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
+
+        // no free page in between the segments
+        assert!(manager.try_alloc_page_in_range(4 * 4096, 8 * 4096) == None);
+        assert!(manager.try_alloc_page_in_range(16 * 4096, 20 * 4096) == None);
+
+        // single free page, unaligned bounds
+        assert!(manager.try_alloc_page_in_range(2 * 4096 + 1, 4 * 4096 - 1) == Some(3 * 4096));
+        assert!(manager.try_alloc_page_in_range(0, 8 * 4096) == None);
+
+        // page 10 has to be split out of the order 3 block at page 8.
+        assert!(manager.try_alloc_page_in_range(10 * 4096, 11 * 4096) == Some(10 * 4096));
+
+        unsafe {
+            let map = manager.storage.get_mut().get_buddy_map()[0];
+            assert!(map & (1 << 8) != 0);
+            assert!(map & (1 << 10) == 0);
+            assert!(map & (1 << 11) != 0);
+            assert!(map & (1 << 12) != 0);
+            assert!(map & (1 << 20) != 0);
+
+            assert!((*manager.free_lists.get_mut()[1]).order == 1);
+            assert!((*manager.free_lists.get_mut()[2]).order == 2);
+            assert!(manager.free_lists.get_mut()[3] == null_mut());
+        }
+
+        // page 20 is still free, page 10 is not.
+        assert!(manager.try_alloc_page_in_range(10 * 4096, 11 * 4096) == None);
+        assert!(manager.try_alloc_page_in_range(17 * 4096, 64 * 4096) == Some(20 * 4096));
+    }
+
+    fn checksums_track_free_lists<S: PhysManagerStorage>() {
+        let mmap = &mut [
+            MemorySegment {
+                start: 0,
+                page_count: 13,
+                state: MemorySegmentState::Free,
+            },
+        ];
+
+        let mut manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
+
+        // 13 pages = blocks of order 3 at 0, order 2 at 8, order 0 at 12.
+        assert!(manager.free_list_checksums.get_mut()[0] == 12);
+        assert!(manager.free_list_checksums.get_mut()[2] == 8);
+        assert!(manager.free_list_checksums.get_mut()[3] == 0);
+
+        let a = manager.alloc_page();
+        let b = manager.alloc_page();
+        let c = manager.alloc_linear_pages(2);
+        manager.free_page(b);
+        manager.free_linear_pages(c, 2);
+        manager.free_page(a);
+
+        assert!(manager.free_list_checksums.get_mut()[0] == 12);
+        assert!(manager.free_list_checksums.get_mut()[2] == 8);
+        assert!(manager.free_list_checksums.get_mut()[3] == 0);
+    }
+
+    fn checksums_detect_corruption<S: PhysManagerStorage>() {
+        let mmap = &mut [
+            MemorySegment {
+                start: 0,
+                page_count: 4,
+                state: MemorySegmentState::Free,
+            },
+        ];
+
+        let manager = PhysMemoryManager::<S, MAX_ORDER>::new(mmap);
+
+        // Unlink the order 1 block that is split off by the first allocation behind the manager's back.
+        manager.alloc_page();
+        unsafe {
+            (&mut *manager.free_lists.get())[1] = null_mut();
+        }
+
+        manager.alloc_page();
+    }
+
 }
@@ -0,0 +1,152 @@
+//! Allocator for many objects of the same size.
+
+use core::{cell::UnsafeCell, mem::size_of, ptr::null_mut};
+
+use crate::mutex::{Lock, SpinLock};
+
+use super::{phys_manager, phys_to_virt};
+
+/// Stored in every free slot, links the free slots together.
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+/// Hands out `SIZE`-byte slots from a contiguous block of memory.
+///
+/// `SIZE` has to be at least the size of a pointer, as free slots store the free list.
+pub struct Slab<const SIZE: usize> {
+    /// Lock to ensure thread-safe access to the free list.
+    lock: SpinLock,
+    /// First free slot.
+    free_list: UnsafeCell<*mut FreeSlot>,
+    /// Start of the memory block the slots are carved from.
+    start: usize,
+    /// Number of slots in the memory block.
+    slot_count: usize,
+}
+
+impl<const SIZE: usize> Slab<SIZE> {
+    /// Creates a slab with enough slots to fill `num_pages` contiguous physical pages.
+    ///
+    /// The pages are never freed.
+    pub fn new(num_pages: u64) -> Self {
+        let start = phys_to_virt::<u8>(phys_manager().alloc_linear_pages(num_pages));
+        unsafe {
+            Self::from_region(start, num_pages as usize * 4096)
+        }
+    }
+
+    /// Creates a slab over the memory at `start..start + size`.
+    ///
+    /// # Safety
+    /// The memory has to be valid and unused for the lifetime of the slab.
+    pub unsafe fn from_region(start: *mut u8, size: usize) -> Self {
+        assert!(SIZE >= size_of::<FreeSlot>(), "Slab slots have to be at least {} bytes", size_of::<FreeSlot>());
+
+        let slot_count = size / SIZE;
+
+        // Link the slots in ascending order.
+        let mut free_list = null_mut();
+        for i in (0..slot_count).rev() {
+            let slot = start.add(i * SIZE) as *mut FreeSlot;
+            slot.write_unaligned(FreeSlot {
+                next: free_list,
+            });
+            free_list = slot;
+        }
+
+        Self {
+            lock: SpinLock::new(),
+            free_list: UnsafeCell::new(free_list),
+            start: start as usize,
+            slot_count,
+        }
+    }
+
+    /// Returns an unused slot, or null if every slot is in use.
+    pub fn alloc(&self) -> *mut u8 {
+        let _guard = self.lock.lock();
+
+        unsafe {
+            let slot = *self.free_list.get();
+            if !slot.is_null() {
+                *self.free_list.get() = slot.read_unaligned().next;
+            }
+            slot as *mut u8
+        }
+    }
+
+    /// Returns a slot returned by [`Slab::alloc()`] to the slab.
+    pub fn free(&self, ptr: *mut u8) {
+        #[cfg(debug_assertions)]
+        {
+            let addr = ptr as usize;
+            assert!(addr >= self.start && addr < self.start + self.slot_count * SIZE, "Freed pointer {:#016X} does not belong to the slab", addr);
+            assert!((addr - self.start) % SIZE == 0, "Freed pointer {:#016X} is not the start of a slot", addr);
+        }
+
+        let _guard = self.lock.lock();
+
+        unsafe {
+            let slot = ptr as *mut FreeSlot;
+            slot.write_unaligned(FreeSlot {
+                next: *self.free_list.get(),
+            });
+            *self.free_list.get() = slot;
+        }
+    }
+
+    /// Returns the total number of slots in the slab.
+    pub fn get_slot_count(&self) -> usize {
+        self.slot_count
+    }
+}
+
+// The free list is only accessed while holding the lock.
+unsafe impl<const SIZE: usize> Sync for Slab<SIZE> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_slab<const SIZE: usize>(size: usize) -> Slab<SIZE> {
+        let buffer = Box::leak(vec![0u8; size].into_boxed_slice());
+        unsafe {
+            Slab::from_region(buffer.as_mut_ptr(), size)
+        }
+    }
+
+    #[test]
+    fn alloc_all() {
+        let slab = test_slab::<24>(240);
+        assert_eq!(slab.get_slot_count(), 10);
+
+        let slots: Vec<_> = (0..10).map(|_| slab.alloc()).collect();
+        assert!(slots.iter().all(|s| !s.is_null()));
+        for (i, s) in slots.iter().enumerate() {
+            assert_eq!(*s as usize - slab.start, i * 24);
+        }
+        assert!(slab.alloc().is_null());
+
+        slab.free(slots[3]);
+        assert_eq!(slab.alloc(), slots[3]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "is not the start of a slot")]
+    fn detect_misaligned_free() {
+        let slab = test_slab::<32>(256);
+        let slot = slab.alloc();
+        slab.free(unsafe{slot.add(8)});
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "does not belong to the slab")]
+    fn detect_foreign_free() {
+        let slab = test_slab::<32>(256);
+        let mut other = 0u64;
+        slab.free(&mut other as *mut u64 as *mut u8);
+    }
+}
@@ -1,8 +1,61 @@
-use common_structures::PagingInfo;
+use core::slice;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use common_structures::KernelHeader;
 
 use crate::arch;
 
 static mut HIGH_MEM_BASE: u64 = 0;
+/// Size of the linear physical memory mapping starting at [`HIGH_MEM_BASE`].
+static mut HIGH_MEM_SIZE: u64 = 0;
+
+/// Start of the virtual address region handed out by the [`VirtAddrAllocator`].
+/// 
+/// The linear physical memory mapping always lies at the end of the address space,
+/// so the region ends at [`HIGH_MEM_BASE`] instead.
+const KERNEL_VIRT_BASE: u64 = 0xFFFF_8000_0000_0000;
+
+/// Hands out ranges of kernel virtual address space that do not collide with the linear physical memory mapping.
+/// 
+/// Should be used to find a virtual address before mapping pages with `map_4kb_page()`.
+pub struct VirtAddrAllocator {
+    /// Next unused virtual address.
+    next: AtomicU64,
+    /// End of the managed region.
+    end: u64,
+}
+
+impl VirtAddrAllocator {
+    pub const fn new(base: u64, end: u64) -> Self {
+        Self {
+            next: AtomicU64::new(base),
+            end,
+        }
+    }
+
+    /// Reserves `size` bytes (rounded up to whole pages) of virtual address space and returns the start address.
+    pub fn alloc_range(&self, size: u64) -> u64 {
+        let size = (size + 4095) & !4095;
+        let base = self.next.fetch_add(size, Ordering::Relaxed);
+        assert!(base.checked_add(size).map_or(false, |end| end <= self.end), "Kernel virtual address space exhausted");
+        base
+    }
+
+    /// Returns a range reserved by [`VirtAddrAllocator::alloc_range()`].
+    /// 
+    /// Address space is not reclaimed yet, the range simply stays unused.
+    pub fn free_range(&self, _base: u64, _size: u64) {
+    }
+}
+
+static mut VIRT_ADDR_ALLOCATOR: VirtAddrAllocator = VirtAddrAllocator::new(0, 0);
+
+/// Returns the allocator for kernel virtual address space. Only usable after [`init_virt_manager()`].
+pub fn virt_addr_allocator() -> &'static VirtAddrAllocator {
+    unsafe {
+        &VIRT_ADDR_ALLOCATOR
+    }
+}
 
 pub fn set_high_mem_base(high_mem_base: u64) {
     unsafe {
@@ -22,12 +75,108 @@ pub fn virt_to_phys<T>(virt: *mut T) -> u64 {
     }
 }
 
-pub fn init_virt_manager(paging_info: &PagingInfo) {
+/// Converts `virt` to a physical address.
+/// 
+/// Unlike [`virt_to_phys()`], this also works for pointers outside of the linear physical memory mapping
+/// (`HIGH_MEM_BASE..HIGH_MEM_BASE + physical memory size`) by walking the page table, 
+/// and returns `None` instead of a bogus address for unmapped pointers.
+pub fn virt_to_phys_safe<T>(virt: *const T) -> Option<u64> {
+    let addr = virt as u64;
+    unsafe {
+        if addr >= HIGH_MEM_BASE && addr - HIGH_MEM_BASE < HIGH_MEM_SIZE {
+            return Some(addr - HIGH_MEM_BASE);
+        }
+    }
+    arch::virt_manager::page_table_walk(addr)
+}
+
+/// Maps the device memory at `phys..phys + size` into kernel address space and returns a pointer to `phys`.
+/// 
+/// The memory is mapped uncached and not executable, so it can be used for memory mapped device registers.
+pub fn map_mmio(phys: u64, size: u64) -> *mut u8 {
+    use arch::virt_manager::{PAGE_PRESENT, PAGE_WRITABLE, PAGE_CACHE_DISABLE, PAGE_WRITE_THROUGH, PAGE_NO_EXECUTE};
+
+    let offset = phys & 4095;
+    let first_page = phys - offset;
+    let num_pages = (offset + size + 4095) / 4096;
+
+    let virt = virt_addr_allocator().alloc_range(num_pages * 4096);
+
+    let pml4 = arch::virt_manager::kernel_pml4();
+    for i in 0..num_pages {
+        arch::virt_manager::map_4kb_page(pml4, virt + i * 4096, first_page + i * 4096, 
+            PAGE_PRESENT | PAGE_WRITABLE | PAGE_CACHE_DISABLE | PAGE_WRITE_THROUGH | PAGE_NO_EXECUTE);
+    }
+
+    verbose!("VirtManager", "Mapped MMIO {:#016X} ({} pages) to {:#016X}", phys, num_pages, virt);
+    (virt + offset) as *mut u8
+}
+
+/// Maps `num_pages` newly allocated physical pages to contiguous virtual addresses in kernel address space.
+/// 
+/// Unlike `phys_manager().alloc_linear_pages()`, the physical pages do not have to be contiguous.
+/// The pages are writable and not executable and are never freed.
+pub fn map_kernel_pages(num_pages: u64) -> *mut u8 {
+    use arch::virt_manager::{PAGE_PRESENT, PAGE_WRITABLE, PAGE_NO_EXECUTE};
+
+    let virt = virt_addr_allocator().alloc_range(num_pages * 4096);
+
+    let pml4 = arch::virt_manager::kernel_pml4();
+    for i in 0..num_pages {
+        let phys = super::phys_manager().alloc_page();
+        arch::virt_manager::map_4kb_page(pml4, virt + i * 4096, phys, PAGE_PRESENT | PAGE_WRITABLE | PAGE_NO_EXECUTE);
+    }
+
+    virt as *mut u8
+}
+
+/// Prints a summary of the active page table.
+pub fn print_page_tables() {
+    arch::virt_manager::print_page_tables();
+}
+
+pub fn init_virt_manager(kernel_header: &KernelHeader) {
     info!("VirtManager", "Starting initialization");
 
-    verbose!("VirtManager", "high_mem_base={:#016X}", unsafe{HIGH_MEM_BASE});
+    // The bootloader maps all of physical memory up to the highest address in the memory map.
+    let memory_map = unsafe{slice::from_raw_parts(kernel_header.memory_map, kernel_header.memory_map_entries as usize)};
+    let high_mem_size = memory_map.iter()
+        .map(|entry| entry.end_addr())
+        .max().unwrap_or(0);
+    unsafe {
+        HIGH_MEM_SIZE = high_mem_size;
+    }
+
+    verbose!("VirtManager", "high_mem_base={:#016X}, high_mem_size={:#016X}", unsafe{HIGH_MEM_BASE}, high_mem_size);
+
+    arch::virt_manager::init(&kernel_header.paging_info);
 
-    arch::virt_manager::init(paging_info);
+    unsafe {
+        VIRT_ADDR_ALLOCATOR = VirtAddrAllocator::new(KERNEL_VIRT_BASE, HIGH_MEM_BASE);
+    }
 
     info!("VirtManager", "Initialized");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_ranges() {
+        let allocator = VirtAddrAllocator::new(0x10000, 0x20000);
+
+        assert_eq!(allocator.alloc_range(1), 0x10000);
+        assert_eq!(allocator.alloc_range(0x2000), 0x11000);
+        assert_eq!(allocator.alloc_range(0x1000), 0x13000);
+    }
+
+    #[test]
+    #[should_panic(expected = "exhausted")]
+    fn alloc_exhausted() {
+        let allocator = VirtAddrAllocator::new(0x10000, 0x20000);
+
+        allocator.alloc_range(0x8000);
+        allocator.alloc_range(0x8001);
+    }
+}
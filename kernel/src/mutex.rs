@@ -1,4 +1,9 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::{cell::UnsafeCell, mem::MaybeUninit};
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU8, Ordering};
+
+#[cfg(debug_assertions)]
+use crate::drivers::hpet;
+use crate::interrupt;
 
 /// Interface for generic Locks.
 pub trait Lock {
@@ -14,6 +19,19 @@ pub trait Lock {
         }
     }
 
+    /// Try to lock up to `max_spins` times, return `None` if the lock could not be acquired.
+    /// 
+    /// Useful to detect deadlocks instead of spinning forever.
+    fn try_lock_for(&self, max_spins: u32) -> Option<LockGuard<Self>> {
+        for _ in 0..max_spins {
+            let lg = self.try_lock();
+            if lg.is_some() {
+                return lg;
+            }
+        }
+        None
+    }
+
     /// Unlock the lock.
     fn unlock(&self);
 }
@@ -42,6 +60,16 @@ impl SpinLock {
     }
 }
 
+/// Time after which [`SpinLock::lock()`] assumes a deadlock in debug builds, if there is an HPET.
+#[cfg(debug_assertions)]
+const DEADLOCK_TIMEOUT_NS: u64 = 5_000_000_000;
+/// Number of spins after which [`SpinLock::lock()`] assumes a deadlock in debug builds without an HPET.
+#[cfg(debug_assertions)]
+const DEADLOCK_SPINS: u32 = 1_000_000_000;
+/// Number of spins between two checks of the elapsed time in [`SpinLock::lock()`].
+#[cfg(debug_assertions)]
+const DEADLOCK_CHECK_SPINS: u32 = 100_000;
+
 impl Lock for SpinLock {
     fn try_lock(&self) -> Option<LockGuard<Self>> {
         if !self.locked.swap(true, Ordering::Acquire) {
@@ -53,7 +81,348 @@ impl Lock for SpinLock {
         }
     }
 
+    #[cfg(debug_assertions)]
+    fn lock(&self) -> LockGuard<Self> {
+        // Uncontended locks never read the clock.
+        if let Some(lg) = self.try_lock_for(DEADLOCK_CHECK_SPINS) {
+            return lg;
+        }
+
+        if !hpet::is_present() {
+            return self.try_lock_for(DEADLOCK_SPINS).expect("SpinLock deadlock suspected");
+        }
+
+        let start = hpet::uptime_ns();
+        loop {
+            if let Some(lg) = self.try_lock_for(DEADLOCK_CHECK_SPINS) {
+                return lg;
+            }
+            assert!(hpet::uptime_ns().wrapping_sub(start) < DEADLOCK_TIMEOUT_NS, "SpinLock deadlock suspected");
+        }
+    }
+
+    fn try_lock_for(&self, max_spins: u32) -> Option<LockGuard<Self>> {
+        let mut spins = 0;
+        loop {
+            if let Some(lg) = self.try_lock() {
+                return Some(lg);
+            }
+
+            // Only read the lock while it is taken, so that waiting cores do not fight over the cache line.
+            while self.locked.load(Ordering::Relaxed) {
+                if spins >= max_spins {
+                    return None;
+                }
+                spins += 1;
+                core::hint::spin_loop();
+            }
+        }
+    }
+
     fn unlock(&self) {
         self.locked.store(false, Ordering::Release);
     }
 }
+
+/// [`SpinLock`] that disables interrupts on the current core while it is held, like `spin_lock_irqsave` in Linux.
+/// 
+/// Has to be used for locks that are also taken by interrupt handlers. With a plain [`SpinLock`],
+/// an interrupt handler that tries to take a lock held by the code it interrupted would spin forever.
+pub struct IrqSpinLock {
+    inner: SpinLock,
+}
+
+impl IrqSpinLock {
+    pub const fn new() -> Self {
+        Self {
+            inner: SpinLock::new(),
+        }
+    }
+
+    /// Try to lock, return an [`IrqLockGuard`] if successful.
+    pub fn try_lock(&self) -> Option<IrqLockGuard> {
+        let flags = interrupt::save_flags_and_disable();
+
+        match self.inner.try_lock() {
+            Some(lg) => {
+                // The inner lock is released manually when the IrqLockGuard is dropped.
+                core::mem::forget(lg);
+                Some(IrqLockGuard {
+                    lock: self,
+                    flags,
+                })
+            }
+            None => {
+                interrupt::restore_flags(flags);
+                None
+            }
+        }
+    }
+
+    /// Block until the lock can be acquired. Interrupts are restored between the attempts.
+    pub fn lock(&self) -> IrqLockGuard {
+        loop {
+            if let Some(lg) = self.try_lock() {
+                return lg;
+            }
+        }
+    }
+}
+
+/// Unlocks an [`IrqSpinLock`] when dropped and restores the interrupt flag saved when it was locked.
+/// 
+/// Guards of several [`IrqSpinLock`]s have to be dropped in the reverse order of locking,
+/// otherwise interrupts are enabled again while a lock is still held.
+pub struct IrqLockGuard<'a> {
+    lock: &'a IrqSpinLock,
+    /// RFLAGS from before the lock was acquired.
+    flags: u64,
+}
+
+impl<'a> Drop for IrqLockGuard<'a> {
+    fn drop(&mut self) {
+        self.lock.inner.unlock();
+        interrupt::restore_flags(self.flags);
+    }
+}
+
+/// Reader-writer SpinLock. Any number of readers or a single writer can hold the lock at the same time.
+/// 
+/// Readers are preferred, so a steady stream of readers can starve a writer.
+pub struct RwSpinLock {
+    /// Number of readers holding the lock, or [`RwSpinLock::WRITER`] if a writer holds it.
+    state: AtomicI32,
+}
+
+impl RwSpinLock {
+    const WRITER: i32 = -1;
+
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicI32::new(0),
+        }
+    }
+
+    /// Try to lock for reading, return a [`RwReadGuard`] if no writer holds the lock.
+    pub fn try_read(&self) -> Option<RwReadGuard> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state >= 0 && self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            Some(RwReadGuard {
+                lock: self,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Block until the lock can be acquired for reading.
+    pub fn read(&self) -> RwReadGuard {
+        loop {
+            if let Some(lg) = self.try_read() {
+                return lg;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Try to lock for writing, return a [`RwWriteGuard`] if nobody holds the lock.
+    pub fn try_write(&self) -> Option<RwWriteGuard> {
+        if self.state.compare_exchange(0, Self::WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            Some(RwWriteGuard {
+                lock: self,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Block until the lock can be acquired for writing.
+    pub fn write(&self) -> RwWriteGuard {
+        loop {
+            if let Some(lg) = self.try_write() {
+                return lg;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Releases a read lock of a [`RwSpinLock`] when dropped.
+pub struct RwReadGuard<'a> {
+    lock: &'a RwSpinLock,
+}
+
+impl<'a> Drop for RwReadGuard<'a> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Releases the write lock of a [`RwSpinLock`] when dropped.
+pub struct RwWriteGuard<'a> {
+    lock: &'a RwSpinLock,
+}
+
+impl<'a> Drop for RwWriteGuard<'a> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+    }
+}
+
+/// A value that is initialized exactly once, intended for kernel globals that are set up during boot.
+/// 
+/// Replaces the `static mut X: MaybeUninit<T>` pattern: initializing twice or accessing the value
+/// before initialization panics instead of causing undefined behavior.
+pub struct OnceLock<T> {
+    /// One of [`OnceLock::UNINIT`], [`OnceLock::INITIALIZING`] or [`OnceLock::READY`].
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> OnceLock<T> {
+    const UNINIT: u8 = 0;
+    const INITIALIZING: u8 = 1;
+    const READY: u8 = 2;
+
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(Self::UNINIT),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Sets the value. Panics if the value was already set.
+    pub fn init(&self, val: T) {
+        // Claim the cell first, so that concurrent calls to get() cannot see a partially written value.
+        if self.state.compare_exchange(Self::UNINIT, Self::INITIALIZING, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            panic!("OnceLock initialized twice");
+        }
+
+        unsafe {
+            (*self.value.get()).as_mut_ptr().write(val);
+        }
+        self.state.store(Self::READY, Ordering::Release);
+    }
+
+    /// Returns the value, or `None` if it was not set yet.
+    pub fn try_get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == Self::READY {
+            Some(unsafe{&*(*self.value.get()).as_ptr()})
+        } else {
+            None
+        }
+    }
+
+    /// Returns the value. Panics if it was not set yet.
+    pub fn get(&self) -> &T {
+        self.try_get().expect("OnceLock used before initialization")
+    }
+}
+
+// The value is only written once, before any shared access to it is possible.
+unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_lock_for_timeout() {
+        let lock = SpinLock::new();
+
+        let guard = lock.try_lock_for(10);
+        assert!(guard.is_some());
+        assert!(lock.try_lock_for(1000).is_none());
+
+        drop(guard);
+        assert!(lock.try_lock_for(0).is_some());
+    }
+
+    #[test]
+    fn rw_exclusion() {
+        let lock = RwSpinLock::new();
+
+        let r1 = lock.read();
+        let r2 = lock.try_read();
+        assert!(r2.is_some());
+        assert!(lock.try_write().is_none());
+
+        drop(r1);
+        drop(r2);
+        let w = lock.try_write();
+        assert!(w.is_some());
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_write().is_none());
+
+        drop(w);
+        assert!(lock.try_read().is_some());
+    }
+
+    #[test]
+    fn rw_threads() {
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicU32;
+
+        /// Protected by the lock, two halves that writers always keep equal.
+        struct Shared {
+            lock: RwSpinLock,
+            a: AtomicU32,
+            b: AtomicU32,
+        }
+
+        let shared = Arc::new(Shared {
+            lock: RwSpinLock::new(),
+            a: AtomicU32::new(0),
+            b: AtomicU32::new(0),
+        });
+
+        let threads: Vec<_> = (0..4).map(|i| {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                for _ in 0..10000 {
+                    if i % 2 == 0 {
+                        let _guard = shared.lock.write();
+                        assert_eq!(shared.lock.state.load(Ordering::Relaxed), RwSpinLock::WRITER);
+                        shared.a.fetch_add(1, Ordering::Relaxed);
+                        shared.b.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        let _guard = shared.lock.read();
+                        assert!(shared.lock.state.load(Ordering::Relaxed) > 0);
+                        assert_eq!(shared.a.load(Ordering::Relaxed), shared.b.load(Ordering::Relaxed));
+                    }
+                }
+            })
+        }).collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(shared.lock.state.load(Ordering::Relaxed), 0);
+        assert_eq!(shared.a.load(Ordering::Relaxed), 20000);
+    }
+
+    #[test]
+    fn once_lock() {
+        let once = OnceLock::new();
+        assert!(once.try_get().is_none());
+
+        once.init(42u32);
+        assert_eq!(*once.get(), 42);
+    }
+
+    #[test]
+    #[should_panic(expected = "initialized twice")]
+    fn once_lock_double_init() {
+        let once = OnceLock::new();
+        once.init(1u32);
+        once.init(2u32);
+    }
+
+    #[test]
+    #[should_panic(expected = "before initialization")]
+    fn once_lock_uninit() {
+        let once = OnceLock::<u32>::new();
+        once.get();
+    }
+}
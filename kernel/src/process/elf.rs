@@ -0,0 +1,212 @@
+//! Parser for statically linked x86_64 ELF executables.
+//!
+//! Unlike the kernel image in the bootloader, user programs are untrusted, so every offset is checked.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use super::ProcessError;
+
+/// A loadable segment of an executable.
+pub struct LoadSegment<'a> {
+    pub virt_addr: u64,
+    pub virt_size: u64,
+    /// Contents of the segment, the rest up to `virt_size` is zero.
+    pub data: &'a [u8],
+    pub writable: bool,
+    pub executable: bool,
+}
+
+/// The parts of an executable needed to load it.
+pub struct ElfImage<'a> {
+    pub entry_point: u64,
+    /// Every `PT_LOAD` segment, sorted by virtual address.
+    pub segments: Vec<LoadSegment<'a>>,
+}
+
+/// Parses the executable `data`.
+pub fn parse(data: &[u8]) -> Result<ElfImage, ProcessError> {
+    let header = read::<Header>(data, 0).ok_or(ProcessError::InvalidElf)?;
+    if header.magic != ELF_MAGIC
+        || header.bits != ELF_CLASS_64
+        || header.endian != ELF_LITTLE_ENDIAN
+        || header.machine_type != ELF_MACHINE_X86_64
+        || header.object_type != ELF_TYPE_EXEC
+        || (header.ph_entry_size as usize) < size_of::<SegmentHeader>() {
+        return Err(ProcessError::InvalidElf);
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..header.ph_entry_count as u64 {
+        let offset = header.ph_offset.checked_add(i * header.ph_entry_size as u64).ok_or(ProcessError::InvalidElf)?;
+        let seg = read::<SegmentHeader>(data, offset).ok_or(ProcessError::InvalidElf)?;
+        if seg.seg_type != SEGTYPE_LOAD {
+            continue;
+        }
+
+        let data_end = seg.data_offset.checked_add(seg.data_size);
+        if !matches!(data_end, Some(end) if end <= data.len() as u64) || seg.data_size > seg.virt_size {
+            return Err(ProcessError::InvalidSegment);
+        }
+
+        segments.push(LoadSegment {
+            virt_addr: seg.virt_addr,
+            virt_size: seg.virt_size,
+            data: &data[seg.data_offset as usize..(seg.data_offset + seg.data_size) as usize],
+            writable: seg.flags & PF_W != 0,
+            executable: seg.flags & PF_X != 0,
+        });
+    }
+
+    // The ELF specification requires this order, but nothing keeps a file from violating it.
+    segments.sort_unstable_by_key(|s| s.virt_addr);
+
+    Ok(ElfImage {
+        entry_point: header.entry_point,
+        segments,
+    })
+}
+
+/// Reads a `T` at `offset` in `data`, or returns `None` if it does not fit.
+fn read<T: Copy>(data: &[u8], offset: u64) -> Option<T> {
+    let end = offset.checked_add(size_of::<T>() as u64)?;
+    if end > data.len() as u64 {
+        return None;
+    }
+    Some(unsafe{(data.as_ptr().add(offset as usize) as *const T).read_unaligned()})
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Header {
+    magic: u32,
+    bits: u8,
+    endian: u8,
+    version: u8,
+    abi: u8,
+    padding: [u8; 8],
+    object_type: u16,
+    machine_type: u16,
+    x_version: u32,
+    entry_point: u64,
+    ph_offset: u64,
+    sh_offset: u64,
+    flags: u32,
+    header_size: u16,
+    ph_entry_size: u16,
+    ph_entry_count: u16,
+    sh_entry_size: u16,
+    sh_entry_count: u16,
+    name_string_table_index: u16,
+}
+
+/// "\x7FELF" read as a little-endian u32.
+const ELF_MAGIC: u32 = 0x464C_457F;
+const ELF_CLASS_64: u8 = 2;
+const ELF_LITTLE_ENDIAN: u8 = 1;
+const ELF_MACHINE_X86_64: u16 = 0x3E;
+/// Executable file, as opposed to relocatable objects and position independent executables.
+const ELF_TYPE_EXEC: u16 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SegmentHeader {
+    seg_type: u32,
+    flags: u32,
+    data_offset: u64,
+    virt_addr: u64,
+    unused: u64,
+    data_size: u64,
+    virt_size: u64,
+    alignment: u64,
+}
+
+const SEGTYPE_LOAD: u32 = 1;
+
+/// Segment flag: the segment is executable.
+const PF_X: u32 = 1 << 0;
+/// Segment flag: the segment is writable.
+const PF_W: u32 = 1 << 1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Builds an executable with one segment per `(virt_addr, virt_size, data, flags)` entry.
+    fn build_elf(entry_point: u64, segments: &[(u64, u64, &[u8], u32)]) -> Vec<u8> {
+        let ph_offset = size_of::<Header>();
+        let data_offset = ph_offset + segments.len() * size_of::<SegmentHeader>();
+
+        let header = Header {
+            magic: ELF_MAGIC,
+            bits: ELF_CLASS_64,
+            endian: ELF_LITTLE_ENDIAN,
+            version: 1,
+            abi: 0,
+            padding: [0; 8],
+            object_type: ELF_TYPE_EXEC,
+            machine_type: ELF_MACHINE_X86_64,
+            x_version: 1,
+            entry_point,
+            ph_offset: ph_offset as u64,
+            sh_offset: 0,
+            flags: 0,
+            header_size: size_of::<Header>() as u16,
+            ph_entry_size: size_of::<SegmentHeader>() as u16,
+            ph_entry_count: segments.len() as u16,
+            sh_entry_size: 0,
+            sh_entry_count: 0,
+            name_string_table_index: 0,
+        };
+
+        let mut file = vec![0u8; data_offset];
+        unsafe{(file.as_mut_ptr() as *mut Header).write_unaligned(header)};
+        for (i, &(virt_addr, virt_size, data, flags)) in segments.iter().enumerate() {
+            let seg = SegmentHeader {
+                seg_type: SEGTYPE_LOAD,
+                flags,
+                data_offset: file.len() as u64,
+                virt_addr,
+                unused: 0,
+                data_size: data.len() as u64,
+                virt_size,
+                alignment: 4096,
+            };
+            unsafe{(file.as_mut_ptr().add(ph_offset + i * size_of::<SegmentHeader>()) as *mut SegmentHeader).write_unaligned(seg)};
+            file.extend_from_slice(data);
+        }
+        file
+    }
+
+    #[test]
+    fn parse_segments() {
+        let file = build_elf(0x40_1000, &[(0x40_2000, 0x2000, b"data", PF_W), (0x40_1000, 0x10, b"code", PF_X)]);
+        let image = parse(&file).ok().unwrap();
+
+        assert_eq!(image.entry_point, 0x40_1000);
+        assert_eq!(image.segments.len(), 2);
+        assert_eq!(image.segments[0].virt_addr, 0x40_1000);
+        assert_eq!(image.segments[0].data, b"code");
+        assert!(image.segments[0].executable && !image.segments[0].writable);
+        assert_eq!(image.segments[1].virt_size, 0x2000);
+        assert!(image.segments[1].writable && !image.segments[1].executable);
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let file = build_elf(0x40_1000, &[(0x40_1000, 0x10, b"code", PF_X)]);
+
+        assert!(matches!(parse(&file[..10]), Err(ProcessError::InvalidElf)));
+        // Truncating the file cuts off the segment data.
+        assert!(matches!(parse(&file[..file.len() - 1]), Err(ProcessError::InvalidSegment)));
+
+        let mut bad_magic = file.clone();
+        bad_magic[0] = 0;
+        assert!(matches!(parse(&bad_magic), Err(ProcessError::InvalidElf)));
+
+        // The file contents do not fit into the segment.
+        let file = build_elf(0x40_1000, &[(0x40_1000, 2, b"code", PF_X)]);
+        assert!(matches!(parse(&file), Err(ProcessError::InvalidSegment)));
+    }
+}
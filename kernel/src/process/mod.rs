@@ -0,0 +1,208 @@
+//! User mode processes, each with its own address space.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use common_structures::config;
+
+use crate::arch::syscall::{self, SyscallFrame};
+use crate::arch::virt_manager::{self, PAGE_NO_EXECUTE, PAGE_PRESENT, PAGE_USER, PAGE_WRITABLE, USER_SPACE_END};
+use crate::memory;
+
+mod elf;
+
+/// Size of the user mode stack in pages.
+const USER_STACK_PAGES: u64 = config::USER_STACK_SIZE / 4096;
+/// Top of the user mode stack. The highest user page stays unmapped, so that an underflow faults.
+const USER_STACK_TOP: u64 = USER_SPACE_END - 4096;
+/// Lowest address of the user mode stack, segments have to end below.
+const USER_STACK_BOTTOM: u64 = USER_STACK_TOP - USER_STACK_PAGES * 4096;
+/// Size of the kernel stack that system calls of a process run on, in pages.
+const KERNEL_STACK_PAGES: u64 = 4;
+
+/// RFLAGS.IF: processes run with interrupts enabled.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// PID of the next process to be created.
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug)]
+pub enum ProcessError {
+    /// The file is not a 64-bit x86_64 ELF executable or its headers are truncated.
+    InvalidElf,
+    /// A segment does not fit into the file or its contents are larger than the segment.
+    InvalidSegment,
+    /// A segment or the entry point lies outside of the usable user address range,
+    /// or two segments share a page.
+    InvalidAddress,
+    /// [`config::MAX_PROCESSES`] processes already exist.
+    TooManyProcesses,
+}
+
+pub struct Process {
+    pid: u64,
+    /// Root table of the address space of the process.
+    pml4: *mut u64,
+    /// Top of the kernel stack that system calls of the process run on.
+    kernel_stack: u64,
+    /// Registers the process starts with.
+    regs: SyscallFrame,
+}
+
+impl Process {
+    pub fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    /// Switches to the address space of the process and starts executing it in user mode.
+    pub fn enter(&self) -> ! {
+        virt_manager::activate_table(self.pml4);
+        syscall::set_kernel_stack(self.kernel_stack);
+        unsafe {
+            syscall::enter_user(&self.regs);
+        }
+    }
+}
+
+/// Creates a new process that executes the statically linked ELF executable `data`.
+///
+/// Every segment is copied into freshly allocated pages, so `data` is not needed afterwards.
+pub fn create_from_elf(data: &[u8]) -> Result<Process, ProcessError> {
+    let image = elf::parse(data)?;
+    // Validate everything up front, so nothing has to be freed on error.
+    validate_layout(&image)?;
+    // Processes never exit, so the PID also counts the processes created so far.
+    let pid = NEXT_PID.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pid| (pid <= config::MAX_PROCESSES as u64).then(|| pid + 1))
+        .map_err(|_| ProcessError::TooManyProcesses)?;
+
+    let pml4 = virt_manager::init_user_table();
+    let user_pml4 = virt_manager::user_pml4(pml4);
+
+    for seg in image.segments.iter() {
+        let mut flags = PAGE_PRESENT | PAGE_USER;
+        if seg.writable {
+            flags |= PAGE_WRITABLE;
+        }
+        if !seg.executable {
+            flags |= PAGE_NO_EXECUTE;
+        }
+
+        let data_end = seg.virt_addr + seg.data.len() as u64;
+        let mut page = seg.virt_addr & !4095;
+        while page < seg.virt_addr + seg.virt_size {
+            let phys = memory::phys_manager().alloc_page();
+            let dest = memory::phys_to_virt::<u8>(phys);
+            unsafe {
+                dest.write_bytes(0, 4096);
+            }
+
+            // Copy the part of the file contents that falls into this page.
+            let copy_start = page.max(seg.virt_addr);
+            let copy_end = (page + 4096).min(data_end);
+            if copy_start < copy_end {
+                let src = &seg.data[(copy_start - seg.virt_addr) as usize..(copy_end - seg.virt_addr) as usize];
+                unsafe {
+                    dest.add((copy_start - page) as usize).copy_from_nonoverlapping(src.as_ptr(), src.len());
+                }
+            }
+
+            virt_manager::map_4kb_page(user_pml4, page, phys, flags);
+            page += 4096;
+        }
+    }
+
+    for i in 0..USER_STACK_PAGES {
+        let phys = memory::phys_manager().alloc_page();
+        unsafe {
+            memory::phys_to_virt::<u8>(phys).write_bytes(0, 4096);
+        }
+        virt_manager::map_4kb_page(user_pml4, USER_STACK_BOTTOM + i * 4096, phys, PAGE_PRESENT | PAGE_USER | PAGE_WRITABLE | PAGE_NO_EXECUTE);
+    }
+
+    verbose!("Process", "Created process {} with {} segments, entry point {:#016X}", pid, image.segments.len(), image.entry_point);
+
+    Ok(Process {
+        pid,
+        pml4,
+        kernel_stack: memory::alloc_stack(KERNEL_STACK_PAGES),
+        regs: SyscallFrame {
+            rip: image.entry_point,
+            rsp: USER_STACK_TOP,
+            rflags: RFLAGS_IF,
+            ..Default::default()
+        },
+    })
+}
+
+/// Checks that every segment lies between the null page and the user stack, that no two segments share a page,
+/// and that the entry point lies in an executable segment.
+fn validate_layout(image: &elf::ElfImage) -> Result<(), ProcessError> {
+    // The null page stays unmapped.
+    let mut prev_end = 4096;
+    for seg in image.segments.iter() {
+        let end = seg.virt_addr.checked_add(seg.virt_size).ok_or(ProcessError::InvalidAddress)?;
+        if seg.virt_addr & !4095 < prev_end || end > USER_STACK_BOTTOM {
+            return Err(ProcessError::InvalidAddress);
+        }
+        prev_end = (end + 4095) & !4095;
+    }
+
+    let entry_valid = image.segments.iter()
+        .any(|s| s.executable && (s.virt_addr..s.virt_addr + s.virt_size).contains(&image.entry_point));
+    if !entry_valid {
+        return Err(ProcessError::InvalidAddress);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use elf::{ElfImage, LoadSegment};
+
+    fn segment(virt_addr: u64, virt_size: u64, executable: bool) -> LoadSegment<'static> {
+        LoadSegment {
+            virt_addr,
+            virt_size,
+            data: &[],
+            writable: !executable,
+            executable,
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let valid = ElfImage {
+            entry_point: 0x40_1000,
+            segments: vec![segment(0x40_0000, 0x1800, true), segment(0x40_2000, 0x100, false)],
+        };
+        assert!(validate_layout(&valid).is_ok());
+
+        // Segments sharing a page.
+        let shared = ElfImage {
+            entry_point: 0x40_1000,
+            segments: vec![segment(0x40_0000, 0x1800, true), segment(0x40_1800, 0x100, false)],
+        };
+        assert!(matches!(validate_layout(&shared), Err(ProcessError::InvalidAddress)));
+
+        let null_page = ElfImage {
+            entry_point: 0x10,
+            segments: vec![segment(0, 0x1000, true)],
+        };
+        assert!(matches!(validate_layout(&null_page), Err(ProcessError::InvalidAddress)));
+
+        let kernel_half = ElfImage {
+            entry_point: 0xFFFF_8000_0000_0000,
+            segments: vec![segment(0xFFFF_8000_0000_0000, 0x1000, true)],
+        };
+        assert!(matches!(validate_layout(&kernel_half), Err(ProcessError::InvalidAddress)));
+
+        // The entry point is not executable.
+        let data_entry = ElfImage {
+            entry_point: 0x40_2000,
+            segments: vec![segment(0x40_0000, 0x1800, true), segment(0x40_2000, 0x100, false)],
+        };
+        assert!(matches!(validate_layout(&data_entry), Err(ProcessError::InvalidAddress)));
+    }
+}
@@ -0,0 +1,197 @@
+//! A minimal round-robin scheduler for kernel tasks.
+//!
+//! Scheduling is cooperative: a task runs until it calls [`schedule()`]. Tasks cannot be preempted by the
+//! timer interrupt, as every interrupt handler shares a single stack per core.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+
+use crate::arch::{context, percpu};
+use crate::arch::fpu::{self, FpuState};
+use crate::interrupt;
+use crate::memory;
+use crate::mutex::{Lock, SpinLock};
+
+/// Size of the stack of every task in pages, not counting the guard page.
+const TASK_STACK_PAGES: u64 = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TaskState {
+    /// The task is currently executing.
+    Running,
+    /// The task is waiting to be picked by [`schedule()`].
+    Ready,
+    /// The task is blocked and will not be picked by [`schedule()`].
+    Sleeping,
+    /// The task has terminated and will never run again.
+    Exited,
+}
+
+pub struct Task {
+    id: u64,
+    /// Saved stack pointer while the task is not running.
+    stack: u64,
+    state: TaskState,
+    /// Saved x87/SSE registers while the task is not running. Boxing the task keeps it 16-byte aligned.
+    fpu_state: FpuState,
+}
+
+impl Task {
+    fn new(id: u64, stack: u64, state: TaskState) -> Self {
+        Self {
+            id,
+            stack,
+            state,
+            fpu_state: FpuState::new(),
+        }
+    }
+}
+
+struct Scheduler {
+    lock: SpinLock,
+    /// Every task, boxed so that [`schedule()`] can hold on to a task while the list grows.
+    tasks: UnsafeCell<Vec<Box<Task>>>,
+}
+
+// The task list is only accessed while holding the lock.
+unsafe impl Sync for Scheduler {}
+
+static SCHEDULER: Scheduler = Scheduler {
+    lock: SpinLock::new(),
+    tasks: UnsafeCell::new(Vec::new()),
+};
+
+/// Turns the calling code into the first task. Has to be called after the heap was initialized.
+pub fn init() {
+    let _guard = SCHEDULER.lock.lock();
+    let tasks = unsafe{&mut *SCHEDULER.tasks.get()};
+    assert!(tasks.is_empty(), "Scheduler initialized twice");
+
+    // The stack pointer is saved on the first switch away from this task.
+    tasks.push(Box::new(Task::new(0, 0, TaskState::Running)));
+    percpu::current_cpu().set_current_task(&*tasks[0]);
+
+    info!("Scheduler", "Initialized");
+}
+
+/// Creates a new task that starts executing `entry` once it is picked by [`schedule()`], and returns its ID.
+pub fn create_task(entry: fn() -> !) -> u64 {
+    // Exited tasks are never removed, so the stack is never freed.
+    let stack_top = memory::alloc_stack(TASK_STACK_PAGES);
+    let rsp = unsafe{context::init_task_stack(stack_top, entry)};
+
+    interrupt::without_interrupts(|| {
+        let _guard = SCHEDULER.lock.lock();
+        let tasks = unsafe{&mut *SCHEDULER.tasks.get()};
+        assert!(!tasks.is_empty(), "Scheduler used before initialization");
+
+        let id = tasks.len() as u64;
+        tasks.push(Box::new(Task::new(id, rsp, TaskState::Ready)));
+
+        verbose!("Scheduler", "Created task {} with stack top at {:#016X}", id, stack_top);
+        id
+    })
+}
+
+/// Switches to the next ready task. Returns immediately if no other task is ready.
+pub fn schedule() {
+    interrupt::without_interrupts(|| {
+        let (old_rsp, new_rsp, old_fpu, new_fpu) = {
+            let _guard = SCHEDULER.lock.lock();
+            let tasks = unsafe{&mut *SCHEDULER.tasks.get()};
+            let current = current_index(tasks);
+
+            let next = match next_ready(tasks, current) {
+                Some(next) => next,
+                None => return,
+            };
+
+            if tasks[current].state == TaskState::Running {
+                tasks[current].state = TaskState::Ready;
+            }
+            tasks[next].state = TaskState::Running;
+
+            let old_rsp = &mut tasks[current].stack as *mut u64;
+            let old_fpu = &mut tasks[current].fpu_state as *mut FpuState;
+            percpu::current_cpu().set_current_task(&*tasks[next]);
+            (old_rsp, tasks[next].stack, old_fpu, &tasks[next].fpu_state as *const FpuState)
+        };
+
+        // The lock cannot be held across the switch, the next task might never return here.
+        // Tasks are boxed, so the pointers stay valid even if another task is added in the meantime.
+        // switch_context() only saves the general purpose registers, the SSE registers are switched here.
+        // New tasks do not return from switch_context(), so the state of the next task is restored before.
+        unsafe {
+            fpu::save_fpu_state(&mut *old_fpu);
+            fpu::restore_fpu_state(&*new_fpu);
+            context::switch_context(old_rsp, new_rsp);
+        }
+    });
+}
+
+/// Terminates the running task and switches to the next ready task.
+pub fn exit_current() -> ! {
+    interrupt::without_interrupts(|| {
+        let _guard = SCHEDULER.lock.lock();
+        let tasks = unsafe{&mut *SCHEDULER.tasks.get()};
+        let current = current_index(tasks);
+        tasks[current].state = TaskState::Exited;
+    });
+
+    // The stack of the task is leaked, as it is still in use until the switch.
+    schedule();
+    panic!("Last task exited");
+}
+
+/// Returns the ID of the running task.
+pub fn current_task_id() -> u64 {
+    interrupt::without_interrupts(|| {
+        let _guard = SCHEDULER.lock.lock();
+        let tasks = unsafe{&*SCHEDULER.tasks.get()};
+        tasks[current_index(tasks)].id
+    })
+}
+
+/// Returns the index of the task running on the current core in `tasks`.
+///
+/// Every core tracks its own task in its [`percpu::PerCpu`], so this works with several cores scheduling at once.
+fn current_index(tasks: &[Box<Task>]) -> usize {
+    let current = percpu::current_cpu().current_task();
+    tasks.iter()
+        .position(|task| core::ptr::eq(&**task, current))
+        .expect("Scheduler used on a core without a task")
+}
+
+/// Returns the index of the first [`TaskState::Ready`] task after `current`, wrapping around.
+fn next_ready(tasks: &[Box<Task>], current: usize) -> Option<usize> {
+    (1..tasks.len())
+        .map(|offset| (current + offset) % tasks.len())
+        .find(|&i| tasks[i].state == TaskState::Ready)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tasks(states: &[TaskState]) -> Vec<Box<Task>> {
+        states.iter().enumerate()
+            .map(|(id, &state)| Box::new(Task::new(id as u64, 0, state)))
+            .collect()
+    }
+
+    #[test]
+    fn round_robin() {
+        use TaskState::*;
+
+        let list = tasks(&[Running, Ready, Sleeping, Ready, Exited]);
+        assert_eq!(next_ready(&list, 0), Some(1));
+        assert_eq!(next_ready(&list, 1), Some(3));
+        assert_eq!(next_ready(&list, 3), Some(1));
+
+        // The running task itself is never picked.
+        let list = tasks(&[Running, Sleeping]);
+        assert_eq!(next_ready(&list, 0), None);
+        assert_eq!(next_ready(&tasks(&[Running]), 0), None);
+    }
+}
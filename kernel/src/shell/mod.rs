@@ -0,0 +1,100 @@
+//! A line-based shell on the serial port for inspecting the kernel at runtime.
+
+use core::fmt::Write;
+
+use crate::interrupt;
+use crate::memory;
+use crate::drivers::serial;
+
+/// Maximum length of a single command line.
+const MAX_LINE: usize = 128;
+
+/// Every available command with its description and implementation.
+const COMMANDS: &[(&str, &str, fn())] = &[
+    ("help", "list available commands", cmd_help),
+    ("meminfo", "print physical memory statistics", cmd_meminfo),
+    ("pagetable", "print a summary of the active page table", cmd_pagetable),
+    ("interrupts", "print the number of interrupts per vector", cmd_interrupts),
+    ("panic", "trigger a kernel panic", cmd_panic),
+];
+
+/// Reads commands from the serial port and executes them. Never returns, should run in its own task.
+///
+/// Input is echoed back to the serial port. If no serial port is present, this just idles.
+pub fn run_shell() -> ! {
+    if !serial::is_present() {
+        loop {}
+    }
+
+    let mut line = [0u8; MAX_LINE];
+    let mut len = 0;
+
+    write!(serial::stream(), "\r\nSimpleOS-rs shell, type 'help' for a list of commands\r\n> ").unwrap();
+    loop {
+        let c = serial::read_byte();
+        match c {
+            b'\r' | b'\n' => {
+                write!(serial::stream(), "\r\n").unwrap();
+                dispatch(&line[..len]);
+                len = 0;
+                write!(serial::stream(), "> ").unwrap();
+            }
+            // backspace / delete
+            0x08 | 0x7F => {
+                if len > 0 {
+                    len -= 1;
+                    write!(serial::stream(), "\x08 \x08").unwrap();
+                }
+            }
+            _ => {
+                // ignore input that does not fit into the line buffer
+                if len < MAX_LINE {
+                    line[len] = c;
+                    len += 1;
+                    serial::write_byte(c);
+                }
+            }
+        }
+    }
+}
+
+fn dispatch(line: &[u8]) {
+    let line = match core::str::from_utf8(line) {
+        Ok(line) => line.trim(),
+        Err(_) => {
+            write!(serial::stream(), "invalid input\r\n").unwrap();
+            return;
+        }
+    };
+
+    if line.is_empty() {
+        return;
+    }
+
+    match COMMANDS.iter().find(|(name, _, _)| *name == line) {
+        Some((_, _, cmd)) => cmd(),
+        None => write!(serial::stream(), "unknown command '{}'\r\n", line).unwrap(),
+    }
+}
+
+fn cmd_help() {
+    for (name, desc, _) in COMMANDS {
+        write!(serial::stream(), "{:<10} {}\r\n", name, desc).unwrap();
+    }
+}
+
+fn cmd_meminfo() {
+    memory::phys_manager().print_stats();
+}
+
+fn cmd_pagetable() {
+    memory::print_page_tables();
+}
+
+fn cmd_interrupts() {
+    interrupt::print_irq_stats();
+}
+
+fn cmd_panic() {
+    panic!("Panic triggered from shell");
+}
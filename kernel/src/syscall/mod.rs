@@ -0,0 +1,113 @@
+//! System calls available to user mode tasks.
+//!
+//! Numbers and error codes follow Linux, so existing tooling can be used to build user programs.
+
+use crate::arch;
+use crate::arch::virt_manager::USER_SPACE_END;
+use crate::memory;
+use crate::scheduler;
+
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_EXIT: u64 = 60;
+
+/// Bad file descriptor.
+const EBADF: i64 = 9;
+/// Bad address.
+const EFAULT: i64 = 14;
+/// Unknown system call.
+const ENOSYS: i64 = 38;
+
+/// File descriptor of the standard output.
+const STDOUT: u64 = 1;
+
+/// Enables system calls. Has to be called after the platform initialization.
+pub fn init() {
+    arch::syscall::init();
+    info!("Syscall", "Initialized");
+}
+
+/// Executes the system call `number` with the raw arguments `args` and returns the result,
+/// or a negative error code.
+pub fn dispatch(number: u64, args: &[u64; 6]) -> i64 {
+    match number {
+        SYS_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
+        SYS_EXIT => sys_exit(args[0] as i64),
+        _ => -ENOSYS,
+    }
+}
+
+/// Writes `len` bytes at `buf` to the file descriptor `fd`. Only the terminal (`fd` 1) is supported.
+///
+/// Returns the number of bytes written.
+fn sys_write(fd: u64, buf: *const u8, len: u64) -> i64 {
+    if fd != STDOUT {
+        return -EBADF;
+    }
+    if !is_user_range(buf as u64, len) || !is_mapped(buf as u64, len) {
+        return -EFAULT;
+    }
+
+    let data = unsafe{core::slice::from_raw_parts(buf, len as usize)};
+    let mut rest = data;
+    // Print everything that is valid UTF-8, replacing invalid sequences.
+    while !rest.is_empty() {
+        match core::str::from_utf8(rest) {
+            Ok(s) => {
+                crate::terminal::print(s);
+                break;
+            }
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                crate::terminal::print(unsafe{core::str::from_utf8_unchecked(valid)});
+                crate::terminal::print("\u{FFFD}");
+                rest = &invalid[e.error_len().unwrap_or(invalid.len())..];
+            }
+        }
+    }
+
+    len as i64
+}
+
+/// Terminates the calling task.
+fn sys_exit(code: i64) -> ! {
+    verbose!("Syscall", "Task {} exited with code {}", scheduler::current_task_id(), code);
+    scheduler::exit_current();
+}
+
+/// Whether `addr..addr + len` lies completely in the user half of the address space.
+fn is_user_range(addr: u64, len: u64) -> bool {
+    match addr.checked_add(len) {
+        Some(end) => end <= USER_SPACE_END,
+        None => false,
+    }
+}
+
+/// Whether every page of `addr..addr + len` is mapped in the active page table, so that the kernel
+/// can access it without a page fault. `addr..addr + len` has to pass [`is_user_range()`].
+fn is_mapped(addr: u64, len: u64) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let first_page = addr & !4095;
+    let last_page = (addr + len - 1) & !4095;
+    (first_page..=last_page).step_by(4096)
+        .all(|page| memory::virt_to_phys_safe(page as *const u8).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_ranges() {
+        assert!(is_user_range(0x1000, 0x100));
+        assert!(is_user_range(USER_SPACE_END - 16, 16));
+        assert!(!is_user_range(USER_SPACE_END - 16, 17));
+        assert!(!is_user_range(0xFFFF_8000_0000_0000, 1));
+        assert!(!is_user_range(u64::MAX, 2));
+
+        assert_eq!(dispatch(SYS_WRITE, &[2, 0x1000, 1, 0, 0, 0]), -EBADF);
+        assert_eq!(dispatch(SYS_WRITE, &[STDOUT, 0xFFFF_8000_0000_0000, 1, 0, 0, 0]), -EFAULT);
+        assert_eq!(dispatch(0xFFFF, &[0; 6]), -ENOSYS);
+    }
+}
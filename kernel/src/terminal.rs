@@ -1,4 +1,4 @@
-use core::{ptr::null_mut, slice};
+use core::{ptr::null_mut, slice, sync::atomic::{AtomicBool, Ordering}};
 
 use common_structures::{Format, KernelHeader};
 use font8x8::UnicodeFonts;
@@ -11,18 +11,54 @@ struct Info {
     lock: SpinLock,
     framebuffer: *mut u8,
     scan_width: u32,
+    width: u32,
     height: u32,
     format: Format,
 
+    /// Width and height of a character cell in pixels, see [`FontSize`].
+    glyph_size: u32,
     rows: u32,
     columns: u32,
     cursor_x: u32,
     cursor_y: u32,
+    /// Tabs advance the cursor to the next multiple of this many columns.
+    tab_stop: u32,
 
     color_r: u8,
     color_g: u8,
     color_b: u8,
+    bg_color_r: u8,
+    bg_color_g: u8,
+    bg_color_b: u8,
+    escape_parser: TerminalEscapeParser,
+}
+
+/// Size of the rendered characters.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FontSize {
+    /// The 8x8 pixel font.
+    Small,
+    /// The 8x8 pixel font scaled up to 16x16 pixels.
+    Large,
+}
+
+impl FontSize {
+    fn glyph_size(self) -> u32 {
+        match self {
+            FontSize::Small => 8,
+            FontSize::Large => 16,
+        }
+    }
+}
+
+/// State machine that filters the color escape sequences out of the printed characters.
+/// 
+/// `\x1B` followed by the R, G and B bytes sets the foreground color,
+/// `\x1C` followed by the R, G and B bytes sets the background color.
+struct TerminalEscapeParser {
     mode: Mode,
+    r: u8,
+    g: u8,
 }
 
 enum Mode {
@@ -30,22 +66,90 @@ enum Mode {
     SetR,
     SetG,
     SetB,
+    SetBgR,
+    SetBgG,
+    SetBgB,
+}
+
+/// The result of feeding a single character to a [`TerminalEscapeParser`].
+#[derive(Debug, PartialEq, Eq)]
+enum ParseResult {
+    /// The character was part of an unfinished escape sequence.
+    Consumed,
+    /// The character should be printed.
+    Emit(char),
+    SetFgColor(u8, u8, u8),
+    SetBgColor(u8, u8, u8),
+}
+
+impl TerminalEscapeParser {
+    const fn new() -> Self {
+        Self {
+            mode: Mode::Print,
+            r: 0,
+            g: 0,
+        }
+    }
+
+    fn feed(&mut self, c: char) -> ParseResult {
+        match self.mode {
+            Mode::Print => {
+                match c {
+                    '\x1B' => self.mode = Mode::SetR,
+                    '\x1C' => self.mode = Mode::SetBgR,
+                    _ => return ParseResult::Emit(c),
+                }
+            }
+            Mode::SetR => {
+                self.r = c as u8;
+                self.mode = Mode::SetG;
+            }
+            Mode::SetG => {
+                self.g = c as u8;
+                self.mode = Mode::SetB;
+            }
+            Mode::SetB => {
+                self.mode = Mode::Print;
+                return ParseResult::SetFgColor(self.r, self.g, c as u8);
+            }
+            Mode::SetBgR => {
+                self.r = c as u8;
+                self.mode = Mode::SetBgG;
+            }
+            Mode::SetBgG => {
+                self.g = c as u8;
+                self.mode = Mode::SetBgB;
+            }
+            Mode::SetBgB => {
+                self.mode = Mode::Print;
+                return ParseResult::SetBgColor(self.r, self.g, c as u8);
+            }
+        }
+
+        ParseResult::Consumed
+    }
 }
 
 static mut INFO: Info = Info{
     lock: SpinLock::new(),
     framebuffer: null_mut(),
     scan_width: 0,
+    width: 0,
     height: 0,
     format: Format::RGB,
+    glyph_size: 8,
     rows: 0,
     columns: 0,
     cursor_x: 0,
     cursor_y: 0,
+    tab_stop: 8,
     color_r: 255,
     color_g: 255,
     color_b: 255,
-    mode: Mode::Print,
+    bg_color_r: 0,
+    bg_color_g: 0,
+    bg_color_b: 0,
+    escape_parser: TerminalEscapeParser::new(),
 };
 
 pub fn init(kernel_header: &KernelHeader) {
@@ -53,16 +157,22 @@ pub fn init(kernel_header: &KernelHeader) {
         INFO = Info {
             lock: SpinLock::new(),
             framebuffer: kernel_header.screen_buffer,
+            width: kernel_header.screen_width,
             height: kernel_header.screen_height,
+            glyph_size: 8,
             rows: (kernel_header.screen_height - MARGIN * 2) / 8,
             columns: (kernel_header.screen_width - MARGIN * 2) / 8,
             scan_width: kernel_header.screen_scanline_width,
             cursor_x: 0,
             cursor_y: 0,
+            tab_stop: 8,
             color_r: 255,
             color_g: 255,
             color_b: 255,
-            mode: Mode::Print,
+            bg_color_r: 0,
+            bg_color_g: 0,
+            bg_color_b: 0,
+            escape_parser: TerminalEscapeParser::new(),
             format: kernel_header.screen_format,
         };
     }
@@ -77,16 +187,123 @@ pub fn clear() {
     }
 }
 
+/// Switches to the given font size.
+/// 
+/// Text that is already on the screen is kept as is, the cursor is moved
+/// into the last row if it would be outside the screen otherwise.
+pub fn set_font_size(size: FontSize) {
+    let info = unsafe{&mut INFO};
+    // The screen size is not known before init().
+    if info.framebuffer.is_null() {
+        return;
+    }
+    let _guard = info.lock.lock();
+
+    info.glyph_size = size.glyph_size();
+    info.rows = (info.height - MARGIN * 2) / info.glyph_size;
+    info.columns = (info.width - MARGIN * 2) / info.glyph_size;
+    info.cursor_x = info.cursor_x.min(info.columns - 1);
+    info.cursor_y = info.cursor_y.min(info.rows - 1);
+}
+
+/// Erases the text row `row` across the whole screen width.
+pub fn clear_line(row: u32) {
+    let info = unsafe{&mut INFO};
+    if info.framebuffer.is_null() || row >= info.rows {
+        return;
+    }
+    let _guard = info.lock.lock();
+
+    let row_size = (info.glyph_size * info.scan_width * 4) as usize;
+    let row_start = ((MARGIN + row * info.glyph_size) * info.scan_width * 4) as usize;
+    unsafe {
+        info.framebuffer.add(row_start).write_bytes(0, row_size);
+    }
+}
+
+/// Erases the current row from the cursor position to the right edge of the screen.
+/// 
+/// The cursor is not moved.
+pub fn erase_to_end_of_line() {
+    let info = unsafe{&mut INFO};
+    if info.framebuffer.is_null() {
+        return;
+    }
+    let _guard = info.lock.lock();
+
+    let x_start = MARGIN + info.cursor_x * info.glyph_size;
+    let y_start = MARGIN + info.cursor_y * info.glyph_size;
+    for y in y_start..y_start + info.glyph_size {
+        let line_start = ((x_start + y * info.scan_width) * 4) as usize;
+        unsafe {
+            info.framebuffer.add(line_start).write_bytes(0, ((info.scan_width - x_start) * 4) as usize);
+        }
+    }
+}
+
+/// Sets the tab stop width in columns, 0 is treated as 1.
+pub fn set_tab_stop(n: u32) {
+    let info = unsafe{&mut INFO};
+    let _guard = info.lock.lock();
+
+    info.tab_stop = n.max(1);
+}
+
+/// Scrolls the terminal content up by `rows` text rows and moves the cursor up accordingly.
+/// 
+/// Scrolling several rows at once is a lot faster than scrolling one row at a time,
+/// as every scroll has to copy the whole framebuffer.
+pub fn scroll_up(rows: u32) {
+    let info = unsafe{&mut INFO};
+    if info.framebuffer.is_null() {
+        return;
+    }
+    let _guard = info.lock.lock();
+
+    scroll(rows);
+}
+
+/// Implementation of [`scroll_up()`], expects the lock to be held.
+fn scroll(rows: u32) {
+    let info = unsafe{&mut INFO};
+    let rows = rows.min(info.rows);
+
+    // Size of a single text row in bytes.
+    let row_size = (info.glyph_size * info.scan_width * 4) as usize;
+    let text_start = (MARGIN * info.scan_width * 4) as usize;
+    let kept_rows = (info.rows - rows) as usize;
+
+    unsafe {
+        // Source and destination overlap, so this has to be a memmove.
+        core::ptr::copy(
+            info.framebuffer.add(text_start + rows as usize * row_size),
+            info.framebuffer.add(text_start),
+            kept_rows * row_size,
+        );
+    }
+    // clear the rows that became free at the bottom.
+    fill_background(text_start + kept_rows * row_size, rows as usize * row_size);
+
+    info.cursor_y = info.cursor_y.saturating_sub(rows);
+}
+
+/// Fills `len` bytes of the framebuffer, starting at byte `offset`, with the current background color.
+fn fill_background(offset: usize, len: usize) {
+    let info = unsafe{&mut INFO};
+
+    let bg_pixel = pixel(info.format, info.bg_color_r, info.bg_color_g, info.bg_color_b);
+    let fb = unsafe {slice::from_raw_parts_mut(info.framebuffer.add(offset), len)};
+    for p in fb.chunks_exact_mut(4) {
+        p.copy_from_slice(&bg_pixel);
+    }
+}
+
 fn advance_cursor() {
     let info = unsafe{&mut INFO};
 
     info.cursor_x += 1;
     if info.cursor_x >= info.columns {
-        info.cursor_y += 1;
-        info.cursor_x = 0;
-        if info.cursor_y >= info.rows {
-            info.cursor_y = 0;
-        }
+        new_line();
     }
 }
 
@@ -95,39 +312,43 @@ fn new_line() {
     info.cursor_x = 0;
     info.cursor_y += 1;
     if info.cursor_y >= info.rows {
-        info.cursor_y = 0;
+        // keeps the cursor in the last row.
+        scroll(1);
     }
 }
 
 fn print_char(c: char) {
     let info = unsafe{&mut INFO};
 
-    match info.mode {
-        Mode::SetR => {
-            info.color_r = c as u8;
-            info.mode = Mode::SetG;
-            return;
-        }
-        Mode::SetG => {
-            info.color_g = c as u8;
-            info.mode = Mode::SetB;
+    let c = match info.escape_parser.feed(c) {
+        ParseResult::Consumed => return,
+        ParseResult::Emit(c) => c,
+        ParseResult::SetFgColor(r, g, b) => {
+            info.color_r = r;
+            info.color_g = g;
+            info.color_b = b;
             return;
         }
-        Mode::SetB => {
-            info.color_b = c as u8;
-            info.mode = Mode::Print;
+        ParseResult::SetBgColor(r, g, b) => {
+            info.bg_color_r = r;
+            info.bg_color_g = g;
+            info.bg_color_b = b;
             return;
         }
-        _ => {}
-    }
+    };
 
-    if c == '\x1B' {
-        info.mode = Mode::SetR;
+    if c == '\n' {
+        new_line();
         return;
     }
 
-    if c == '\n' {
-        new_line();
+    if c == '\t' {
+        let next_stop = (info.cursor_x / info.tab_stop + 1) * info.tab_stop;
+        if next_stop >= info.columns {
+            new_line();
+        } else {
+            info.cursor_x = next_stop;
+        }
         return;
     }
 
@@ -140,22 +361,24 @@ fn print_char(c: char) {
         }
     };
 
-    let x_start = MARGIN + info.cursor_x * 8;
-    let y_start = MARGIN + info.cursor_y * 8;
+    let fg_pixel = pixel(info.format, info.color_r, info.color_g, info.color_b);
+    let bg_pixel = pixel(info.format, info.bg_color_r, info.bg_color_g, info.bg_color_b);
+
+    let x_start = MARGIN + info.cursor_x * info.glyph_size;
+    let y_start = MARGIN + info.cursor_y * info.glyph_size;
     let fb = unsafe {slice::from_raw_parts_mut(info.framebuffer, (info.scan_width * info.height * 4) as usize)};
+    // Larger fonts are drawn by repeating every pixel of the 8x8 glyph.
+    let scale = info.glyph_size / 8;
 
-    for y in 0..8 {
-        let row = glyph[y];
+    for y in 0..info.glyph_size {
+        let row = glyph[(y / scale) as usize];
 
-        for x in 0..8 {
-            if row & (1 << x) != 0 {
-                fb[((x_start + x + (y_start + y as u32) * info.scan_width) * 4) as usize    ] = if info.format == Format::BGR { info.color_b } else { info.color_r };
-                fb[((x_start + x + (y_start + y as u32) * info.scan_width) * 4) as usize + 1] = info.color_g;
-                fb[((x_start + x + (y_start + y as u32) * info.scan_width) * 4) as usize + 2] = if info.format == Format::BGR { info.color_r } else { info.color_b };
+        for x in 0..info.glyph_size {
+            let offset = ((x_start + x + (y_start + y) * info.scan_width) * 4) as usize;
+            if row & (1 << (x / scale)) != 0 {
+                fb[offset..offset + 4].copy_from_slice(&fg_pixel);
             } else {
-                fb[((x_start + x + (y_start + y as u32) * info.scan_width) * 4) as usize    ] = 0;
-                fb[((x_start + x + (y_start + y as u32) * info.scan_width) * 4) as usize + 1] = 0;
-                fb[((x_start + x + (y_start + y as u32) * info.scan_width) * 4) as usize + 2] = 0;
+                fb[offset..offset + 4].copy_from_slice(&bg_pixel);
             }
         }
     }
@@ -163,8 +386,22 @@ fn print_char(c: char) {
     advance_cursor();
 }
 
+/// Returns the bytes of a pixel with the given color in the framebuffer `format`.
+fn pixel(format: Format, r: u8, g: u8, b: u8) -> [u8; 4] {
+    match format {
+        Format::RGB => [r, g, b, 0],
+        Format::BGR => [b, g, r, 0],
+        Format::XRGB => [0, r, g, b],
+        Format::ARGB => [255, r, g, b],
+    }
+}
+
 pub fn print(msg: &str) {
     let info = unsafe{&mut INFO};
+    // Nothing to draw to before init(), e.g. when running unit tests.
+    if info.framebuffer.is_null() {
+        return;
+    }
     let _guard = info.lock.lock();
 
     for c in msg.chars() {
@@ -183,26 +420,66 @@ impl core::fmt::Write for TerminalStream {
 
 static mut STREAM: TerminalStream = TerminalStream{};
 
+/// Whether [`verbose!`] messages are printed.
+/// 
+/// Defaults to the `verbose-logging` feature, but can be changed at runtime with `loglevel=` on the kernel command line.
+static VERBOSE: AtomicBool = AtomicBool::new(cfg!(feature="verbose-logging"));
+
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
 pub fn stream() -> &'static mut TerminalStream {
     unsafe {
         &mut STREAM
     }
 }
 
-#[cfg(feature="verbose-logging")]
-macro_rules! verbose {
-    ($ctx:literal, $fmt:literal $(, $args:expr)*) => {
-        {
-            use core::fmt::Write;
-            writeln!(crate::terminal::stream(), concat!("\x1B\u{88}\u{88}\u{88}[{:^15}] ", $fmt, "\x1B\u{FF}\u{FF}\u{FF}"), $ctx $(, $args)*).unwrap();
+/// Prints `label`, followed by `buf` as a classic hex dump with 16 bytes per row.
+/// 
+/// Every row shows the offset, the bytes in hex and the bytes as ASCII, with non-printable bytes shown as `.`.
+pub fn print_hex_dump(label: &str, buf: &[u8]) {
+    use core::fmt::Write;
+
+    let out = stream();
+    writeln!(out, "{} ({} bytes)", label, buf.len()).unwrap();
+
+    for (row, chunk) in buf.chunks(16).enumerate() {
+        write!(out, "{:08X}  ", row * 16).unwrap();
+        for i in 0..16 {
+            match chunk.get(i) {
+                Some(b) => write!(out, "{:02X} ", b).unwrap(),
+                None => write!(out, "   ").unwrap(),
+            }
         }
-    };
+
+        write!(out, " |").unwrap();
+        for &b in chunk {
+            let c = if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' };
+            write!(out, "{}", c).unwrap();
+        }
+        writeln!(out, "|").unwrap();
+    }
 }
 
-#[cfg(not(feature="verbose-logging"))]
+// Every logging macro writes its line to the terminal and mirrors it without colors to the serial port.
+// The arguments are only evaluated once by binding format_args!() in a match.
+
 macro_rules! verbose {
-    ($fmt:literal $(, $args:expr)*) => {
-        
+    ($ctx:literal, $fmt:literal $(, $args:expr)*) => {
+        if crate::terminal::is_verbose() {
+            use core::fmt::Write;
+            match format_args!($fmt $(, $args)*) {
+                args => {
+                    writeln!(crate::terminal::stream(), "\x1B\u{88}\u{88}\u{88}[{:^15}] {}\x1B\u{FF}\u{FF}\u{FF}\x1C\u{00}\u{00}\u{00}", $ctx, args).unwrap();
+                    write!(crate::drivers::serial::stream(), "[{:^15}] {}\r\n", $ctx, args).unwrap();
+                }
+            }
+        }
     };
 }
 
@@ -210,7 +487,12 @@ macro_rules! info {
     ($ctx:literal, $fmt:literal $(, $args:expr)*) => {
         {
             use core::fmt::Write;
-            writeln!(crate::terminal::stream(), concat!("\x1B\u{00}\u{FF}\u{00}[{:^15}] \x1B\u{FF}\u{FF}\u{FF}", $fmt), $ctx $(, $args)*).unwrap();
+            match format_args!($fmt $(, $args)*) {
+                args => {
+                    writeln!(crate::terminal::stream(), "\x1B\u{00}\u{FF}\u{00}[{:^15}] \x1B\u{FF}\u{FF}\u{FF}{}\x1C\u{00}\u{00}\u{00}", $ctx, args).unwrap();
+                    write!(crate::drivers::serial::stream(), "[{:^15}] {}\r\n", $ctx, args).unwrap();
+                }
+            }
         }
     };
 }
@@ -219,7 +501,12 @@ macro_rules! warning {
     ($ctx:literal, $fmt:literal $(, $args:expr)*) => {
         {
             use core::fmt::Write;
-            writeln!(crate::terminal::stream(), concat!("\x1B\u{FF}\u{FF}\u{00}[{:^15}] ", $fmt, "\x1B\u{FF}\u{FF}\u{FF}"), $ctx $(, $args)*).unwrap();
+            match format_args!($fmt $(, $args)*) {
+                args => {
+                    writeln!(crate::terminal::stream(), "\x1B\u{FF}\u{FF}\u{00}[{:^15}] {}\x1B\u{FF}\u{FF}\u{FF}\x1C\u{00}\u{00}\u{00}", $ctx, args).unwrap();
+                    write!(crate::drivers::serial::stream(), "[{:^15}] {}\r\n", $ctx, args).unwrap();
+                }
+            }
         }
     };
 }
@@ -228,7 +515,70 @@ macro_rules! error {
     ($ctx:literal, $fmt:literal $(, $args:expr)*) => {
         {
             use core::fmt::Write;
-            writeln!(crate::terminal::stream(), concat!("\x1B\u{FF}\u{00}\u{00}[{:^15}] ", $fmt, "\x1B\u{FF}\u{FF}\u{FF}"), $ctx $(, $args)*).unwrap();
+            match format_args!($fmt $(, $args)*) {
+                args => {
+                    writeln!(crate::terminal::stream(), "\x1B\u{FF}\u{00}\u{00}[{:^15}] {}\x1B\u{FF}\u{FF}\u{FF}\x1C\u{00}\u{00}\u{00}", $ctx, args).unwrap();
+                    write!(crate::drivers::serial::stream(), "[{:^15}] {}\r\n", $ctx, args).unwrap();
+                }
+            }
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_str(parser: &mut TerminalEscapeParser, s: &str) -> ParseResult {
+        let mut res = ParseResult::Consumed;
+        for c in s.chars() {
+            res = parser.feed(c);
+        }
+        res
+    }
+
+    #[test]
+    fn emit_plain_chars() {
+        let mut parser = TerminalEscapeParser::new();
+
+        assert!(parser.feed('a') == ParseResult::Emit('a'));
+        assert!(parser.feed('\n') == ParseResult::Emit('\n'));
+    }
+
+    #[test]
+    fn fg_color() {
+        let mut parser = TerminalEscapeParser::new();
+
+        assert!(parser.feed('\x1B') == ParseResult::Consumed);
+        assert!(parser.feed('\u{12}') == ParseResult::Consumed);
+        assert!(parser.feed('\u{34}') == ParseResult::Consumed);
+        assert!(parser.feed('\u{FF}') == ParseResult::SetFgColor(0x12, 0x34, 0xFF));
+        assert!(parser.feed('a') == ParseResult::Emit('a'));
+    }
+
+    #[test]
+    fn bg_color() {
+        let mut parser = TerminalEscapeParser::new();
+
+        assert!(feed_str(&mut parser, "\x1C\u{00}\u{88}\u{FF}") == ParseResult::SetBgColor(0x00, 0x88, 0xFF));
+        assert!(parser.feed('a') == ParseResult::Emit('a'));
+    }
+
+    #[test]
+    fn pixel_formats() {
+        assert_eq!(pixel(Format::RGB, 1, 2, 3), [1, 2, 3, 0]);
+        assert_eq!(pixel(Format::BGR, 1, 2, 3), [3, 2, 1, 0]);
+        assert_eq!(pixel(Format::XRGB, 1, 2, 3), [0, 1, 2, 3]);
+        assert_eq!(pixel(Format::ARGB, 1, 2, 3), [255, 1, 2, 3]);
+    }
+
+    #[test]
+    fn escape_chars_as_color_values() {
+        let mut parser = TerminalEscapeParser::new();
+
+        // Color bytes are never interpreted as the start of another escape sequence.
+        assert!(feed_str(&mut parser, "\x1B\x1C\x1B\x1C") == ParseResult::SetFgColor(0x1C, 0x1B, 0x1C));
+        assert!(feed_str(&mut parser, "\x1C\x1B\x1B\x1B") == ParseResult::SetBgColor(0x1B, 0x1B, 0x1B));
+        assert!(parser.feed('\x1B') == ParseResult::Consumed);
+    }
+}
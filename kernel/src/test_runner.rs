@@ -0,0 +1,132 @@
+//! In-kernel integration tests.
+//!
+//! Only compiled with the `integration-test` feature. The results are written to the COM1 serial port
+//! as `TEST PASS: <name>` / `TEST FAIL: <name>` lines, which are collected by `cargo osbuild --test`.
+//! After all tests have run, QEMU is shut down through the `isa-debug-exit` device.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::memory;
+use crate::arch::interrupt::{self, HandlerResult, InterruptInfo};
+use crate::arch::virt_manager::{self, PAGE_NO_EXECUTE, PAGE_PRESENT, PAGE_WRITABLE};
+use crate::drivers::qemu::qemu_exit;
+use crate::drivers::serial;
+
+/// Vector not used by the kernel, raised by [`interrupt_delivery()`].
+const TEST_VECTOR: u8 = 0xF0;
+
+/// Every test that will be run by [`run()`].
+const TESTS: &[(&str, fn() -> bool)] = &[
+    ("phys_alloc_free", phys_alloc_free),
+    ("phys_alloc_linear", phys_alloc_linear),
+    ("phys_to_virt_roundtrip", phys_to_virt_roundtrip),
+    ("interrupt_delivery", interrupt_delivery),
+    ("map_unmap_page", map_unmap_page),
+];
+
+/// Number of times the handler of [`TEST_VECTOR`] ran.
+static TEST_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// Name of the currently running test, reported as failed if the test panics.
+static mut CURRENT_TEST: &str = "";
+
+/// Runs every test in [`TESTS`] and exits QEMU afterwards.
+pub fn run() -> ! {
+    info!("Test", "Running {} integration tests", TESTS.len());
+
+    let mut failed = 0;
+    for (name, test) in TESTS {
+        unsafe {
+            CURRENT_TEST = name;
+        }
+
+        if test() {
+            writeln!(serial::stream(), "TEST PASS: {}", name).unwrap();
+        } else {
+            writeln!(serial::stream(), "TEST FAIL: {}", name).unwrap();
+            failed += 1;
+        }
+    }
+
+    info!("Test", "{} of {} tests failed", failed, TESTS.len());
+    qemu_exit(if failed == 0 { 0 } else { 1 });
+}
+
+/// Called by the panic handler, reports the currently running test as failed.
+pub fn on_panic(info: &core::panic::PanicInfo) -> ! {
+    writeln!(serial::stream(), "{}", info).unwrap();
+    writeln!(serial::stream(), "TEST FAIL: {}", unsafe{CURRENT_TEST}).unwrap();
+    qemu_exit(1);
+}
+
+fn phys_alloc_free() -> bool {
+    let page = memory::phys_manager().alloc_page();
+    memory::phys_manager().free_page(page);
+
+    // Freeing and allocating again should result in the same page being returned.
+    let page2 = memory::phys_manager().alloc_page();
+    memory::phys_manager().free_page(page2);
+
+    page == page2 && page % 4096 == 0
+}
+
+fn phys_alloc_linear() -> bool {
+    let pages = memory::phys_manager().alloc_linear_pages(4);
+
+    // The region has to be writable in its entirety.
+    let ptr = memory::phys_to_virt::<u8>(pages);
+    unsafe {
+        ptr.write_bytes(0xAB, 4 * 4096);
+    }
+    let ok = unsafe{ptr.offset(4 * 4096 - 1).read()} == 0xAB;
+
+    memory::phys_manager().free_linear_pages(pages, 4);
+
+    ok && pages % (4 * 4096) == 0
+}
+
+fn phys_to_virt_roundtrip() -> bool {
+    let page = memory::phys_manager().alloc_page();
+    let ok = memory::virt_to_phys(memory::phys_to_virt::<u8>(page)) == page;
+    memory::phys_manager().free_page(page);
+
+    ok
+}
+
+fn interrupt_delivery() -> bool {
+    fn handler(_info: &mut InterruptInfo) -> HandlerResult {
+        TEST_INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+        HandlerResult::Resume
+    }
+
+    let previous = interrupt::set_isr_handler(TEST_VECTOR, handler);
+    // Has to match TEST_VECTOR.
+    unsafe{asm!("int 0xF0")};
+    unsafe{asm!("int 0xF0")};
+    interrupt::set_isr_handler(TEST_VECTOR, previous);
+
+    TEST_INTERRUPTS.load(Ordering::SeqCst) == 2 && interrupt::get_irq_count(TEST_VECTOR) == 2
+}
+
+fn map_unmap_page() -> bool {
+    let pml4 = virt_manager::kernel_pml4();
+    let virt = memory::virt_addr_allocator().alloc_range(4096);
+    let phys = memory::phys_manager().alloc_page();
+
+    virt_manager::map_4kb_page(pml4, virt, phys, PAGE_PRESENT | PAGE_WRITABLE | PAGE_NO_EXECUTE);
+    // Writes through the new mapping have to show up in the linear mapping of physical memory.
+    unsafe {
+        (virt as *mut u64).write_volatile(0x1234_5678_9ABC_DEF0);
+    }
+    let mapped = memory::virt_to_phys_safe(virt as *const u8) == Some(phys)
+        && unsafe{memory::phys_to_virt::<u64>(phys).read_volatile()} == 0x1234_5678_9ABC_DEF0;
+
+    virt_manager::unmap_4kb_page(pml4, virt);
+    let unmapped = memory::virt_to_phys_safe(virt as *const u8).is_none();
+
+    memory::phys_manager().free_page(phys);
+    memory::virt_addr_allocator().free_range(virt, 4096);
+
+    mapped && unmapped
+}
@@ -0,0 +1,8 @@
+//! Version information embedded into the kernel binary by the build script.
+
+/// Short hash of the git commit the kernel was built from, or `unknown` if it was not built from a git checkout.
+pub const GIT_HASH: &str = env!("VERGEN_GIT_SHA_SHORT");
+/// Crate version and short git commit hash of the running kernel, e.g. `0.1.0-1a2b3c4`.
+pub const KERNEL_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "-", env!("VERGEN_GIT_SHA_SHORT"));
+/// Date the kernel was built at, e.g. `2021-05-01`.
+pub const BUILD_DATE: &str = env!("VERGEN_BUILD_DATE");